@@ -1,13 +1,16 @@
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Result, bail};
 use cpal::{
     Device as CpalDevice,
     traits::{DeviceTrait, HostTrait},
 };
 use crossbeam_channel::{self as cbeam_chan};
 use std::{
+    any::Any,
     collections::HashMap,
-    path::Path,
-    sync::{Arc, RwLock},
+    mem,
+    panic::{self, AssertUnwindSafe},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
 };
 use tokio::sync::{
     mpsc::{self as tokio_chan},
@@ -17,9 +20,11 @@ use tokio::sync::{
 use crate::{
     constants,
     model::{
-        decoder::{Decoder, DecoderRequest, PlaybackTimer, Seek, Speed, Volume},
-        device::{Device, DeviceProxy},
+        decoder::{Decoder, DecoderRequest, FadeIn, PlaybackTimer, Seek, Speed, Volume},
+        device::{Device, DeviceConfig, DeviceProxy},
+        equalizer::Band,
         song::SongEvent,
+        wav_writer::WavWriter,
     },
     state::AudioState,
 };
@@ -37,7 +42,13 @@ struct Playback {
     state: PlaybackState,
     volume: Arc<RwLock<Volume>>,
     speed: Arc<RwLock<Speed>>,
+    // if true, changing the speed doesn't change the pitch
+    preserve_pitch: Arc<RwLock<bool>>,
+    eq_bands: Arc<RwLock<Vec<Band>>>,
     gapless: bool,
+    // the volume from before muting
+    muted: Option<u8>,
+    crossfade_secs: u16,
 }
 
 pub struct Audio {
@@ -45,6 +56,24 @@ pub struct Audio {
     devices: HashMap<String, Device>,
     n_enabled_devices: u8,
     tx_request: Option<cbeam_chan::Sender<DecoderRequest>>,
+    // the decoder of the song that's fading out
+    tx_request_fading: Option<cbeam_chan::Sender<DecoderRequest>>,
+    // if true, the next song played will be mixed with the current one
+    fade_next: bool,
+    // how much audio is buffered for each device
+    buffer_ms: u16,
+    pause_fade_ms: u16,
+    // output configs (by device name) to use instead of the default ones
+    device_configs: HashMap<String, DeviceConfig>,
+    // the song the decoder was told to continue with after the current one
+    next: Option<PathBuf>,
+    // whether songs whose duration the database doesn't know are read in full to find it out
+    exact_durations: bool,
+    // file devices can only write inside of it (and can't be used at all without it)
+    recording_dir: Option<PathBuf>,
+    // names of the system's output devices (enumerating them is slow, so it's done
+    // only when they're requested after some device got added or lost)
+    system_devices: Option<Vec<String>>,
     tx_event: tokio_chan::UnboundedSender<SongEvent>,
 }
 
@@ -60,7 +89,11 @@ impl Audio {
                 state: PlaybackState::default(),
                 volume: Arc::new(RwLock::new(s.volume)),
                 speed: Arc::new(RwLock::new(s.speed)),
+                preserve_pitch: Arc::default(),
+                eq_bands: Arc::default(),
                 gapless: s.gapless,
+                muted: None,
+                crossfade_secs: 0,
             })
             .unwrap_or_default();
 
@@ -69,16 +102,68 @@ impl Audio {
             devices: HashMap::new(),
             n_enabled_devices: 0,
             tx_request: None,
+            tx_request_fading: None,
+            fade_next: false,
+            buffer_ms: constants::DEFAULT_BUFFER_MS,
+            pause_fade_ms: constants::DEFAULT_PAUSE_FADE_MS,
+            device_configs: HashMap::new(),
+            next: None,
+            exact_durations: false,
+            recording_dir: None,
+            system_devices: None,
             tx_event,
         }
     }
 
-    pub fn play(&mut self, path: impl AsRef<Path>) -> Result<()> {
+    pub fn with_buffer_ms(mut self, buffer_ms: u16) -> Self {
+        self.buffer_ms = buffer_ms;
+        self
+    }
+
+    pub fn with_pause_fade_ms(mut self, pause_fade_ms: u16) -> Self {
+        self.pause_fade_ms = pause_fade_ms;
+        self
+    }
+
+    pub fn with_device_configs(mut self, device_configs: HashMap<String, DeviceConfig>) -> Self {
+        self.device_configs = device_configs;
+        self
+    }
+
+    pub fn with_exact_durations(mut self, exact_durations: bool) -> Self {
+        self.exact_durations = exact_durations;
+        self
+    }
+
+    pub fn with_recording_dir(mut self, recording_dir: Option<PathBuf>) -> Self {
+        self.recording_dir = recording_dir;
+        self
+    }
+
+    pub fn with_preserve_pitch(self, preserve_pitch: bool) -> Self {
+        *self.playback.preserve_pitch.write().unwrap() = preserve_pitch;
+        self
+    }
+
+    // `duration` is the song's duration known from the database, without it
+    // the decoder might have to scan the file (e.g. ADTS AAC) to find it out
+    pub fn play(&mut self, path: impl AsRef<Path>, duration: Option<u64>) -> Result<()> {
         let volume = Arc::clone(&self.playback.volume);
         let speed = Arc::clone(&self.playback.speed);
+        let preserve_pitch = Arc::clone(&self.playback.preserve_pitch);
+        let eq_bands = Arc::clone(&self.playback.eq_bands);
         let (tx_request, rx_request) = crossbeam_channel::unbounded();
+        let fade = mem::take(&mut self.fade_next)
+            && self.playback.crossfade_secs > 0
+            && self.tx_request.is_some()
+            && matches!(self.playback.state, PlaybackState::Playing);
         // activate enabled devices
-        for device in self.devices.values_mut().filter(|d| d.is_enabled()) {
+        // (when crossfading, active streams are kept so that there's no gap)
+        for device in self
+            .devices
+            .values_mut()
+            .filter(|d| d.is_enabled() && !(fade && d.is_active()))
+        {
             device.play(self.tx_event.clone())?;
         }
         // create proxies of active devices for the decoder
@@ -88,20 +173,46 @@ impl Audio {
             .filter_map(DeviceProxy::try_new)
             .collect();
         if device_proxies.is_empty() {
-            bail!("all audio devices are disabled");
+            bail!("all audio devices are disabled (enable one with `enable` first)");
         }
         // stop the current decoder instance (if it exists)
-        if let Some(tx_request) = &self.tx_request {
+        if !fade && let Some(tx_request) = &self.tx_request {
             let _ = tx_request.send(DecoderRequest::Stop);
         }
-        let mut decoder = Decoder::try_new(path, device_proxies, self.playback.gapless)?;
+        if let Some(tx_request) = self.tx_request_fading.take() {
+            let _ = tx_request.send(DecoderRequest::Stop);
+        }
+        let (fade_proxies, fade_in) = if fade {
+            audio_utils::fade_channels(&device_proxies, self.playback.crossfade_secs)
+        } else {
+            Default::default()
+        };
+        let mut decoder = Decoder::try_new(
+            path,
+            duration,
+            device_proxies,
+            self.playback.gapless,
+            self.exact_durations,
+        )?
+        .with_crossfade(self.playback.crossfade_secs, self.tx_event.clone())
+        .with_fade_in(fade_in)
+        .with_pause_fade(self.pause_fade_ms)
+        .with_chaining(self.tx_event.clone());
+        // the current decoder keeps running, but now sends its samples to the new one
+        if fade && let Some(tx_request) = self.tx_request.take() {
+            let _ = tx_request.send(DecoderRequest::FadeOut(fade_proxies));
+            self.tx_request_fading = Some(tx_request);
+        }
+        let tx_event = self.tx_event.clone();
         tokio::task::spawn_blocking(move || {
-            if let Err(e) = decoder.run(rx_request, volume, speed) {
-                log::error!("decoder error ({})", e);
-            }
+            audio_utils::run_decoder(
+                || decoder.run(rx_request, volume, speed, preserve_pitch, eq_bands),
+                tx_event,
+            );
         });
         self.tx_request = Some(tx_request);
         self.playback.state = PlaybackState::Playing;
+        self.next = None;
 
         Ok(())
     }
@@ -119,6 +230,8 @@ impl Audio {
                     self.add_device(device, &name)?;
                     self.enable_device(&name)?;
                 }
+                // the playback can still go to other devices (e.g. the HTTP stream)
+                None if self.has_enabled_device() => (),
                 None => bail!("no audio output devices found"),
             }
         }
@@ -126,37 +239,108 @@ impl Audio {
         Ok(self)
     }
 
+    // adds and enables a device that isn't one of the system's ones (e.g. the HTTP stream)
+    pub fn try_with_device(mut self, device: Device) -> Result<Self> {
+        let name = device.name()?;
+        self.devices
+            .insert(name.clone(), device.with_buffer_ms(self.buffer_ms));
+        self.enable_device(name)?;
+
+        Ok(self)
+    }
+
+    // enable all of the provided devices at once (so that the audio is played on each of them)
+    pub fn try_with_devices(mut self, device_names: &[String]) -> Result<Self> {
+        if device_names.is_empty() {
+            return self.try_with_default(None);
+        }
+        for name in device_names {
+            // errors if there's no such device
+            self.enable_device(name)?;
+        }
+
+        Ok(self)
+    }
+
     fn add_device(
         &mut self,
         cpal_device: CpalDevice,
         name: impl AsRef<str> + Into<String>,
     ) -> Result<()> {
-        let device = Device::try_from(cpal_device)?;
+        let device = match self.device_configs.get(name.as_ref()) {
+            Some(device_config) => Device::try_with_config(cpal_device, device_config)?,
+            None => Device::try_from(cpal_device)?,
+        }
+        .with_buffer_ms(self.buffer_ms);
         self.devices.insert(name.into(), device);
 
         Ok(())
     }
 
+    // the last enabled device can be disabled only if nothing is playing
+    // (`play` fails until some device is enabled again)
     pub fn disable_device(&mut self, device_name: String) -> Result<()> {
-        if self.n_enabled_devices == 1 {
-            bail!("at least one device must be enabled");
+        let Some(device) = self.devices.get_mut(&device_name) else {
+            bail!(format!("device {} not found", &device_name));
+        };
+        if !device.is_enabled() {
+            return Ok(());
         }
-        let res = self
-            .devices
-            .get_mut(&device_name)
-            .ok_or(anyhow!(format!("device {} not found", &device_name)))
-            .map(|d| d.disable());
-        if res.is_ok() {
-            self.n_enabled_devices -= 1;
-            if let Some(tx_request) = &self.tx_request {
-                let _ = tx_request.send(DecoderRequest::Disable(device_name));
-            }
+        if self.n_enabled_devices == 1 && !matches!(self.playback.state, PlaybackState::Stopped) {
+            bail!("at least one device must be enabled during playback (stop it first)");
+        }
+        device.disable();
+        self.n_enabled_devices -= 1;
+        if let Some(tx_request) = &self.tx_request {
+            let _ = tx_request.send(DecoderRequest::Disable(device_name));
         }
 
-        res
+        Ok(())
     }
 
+    // a file "device" records the playback to a WAV file
+    fn add_file_device(&mut self, name: impl AsRef<str> + Into<String>) -> Result<()> {
+        let Some(path) = name.as_ref().strip_prefix(constants::FILE_DEVICE_PREFIX) else {
+            bail!("`{}` isn't a file device", name.as_ref());
+        };
+        let Some(recording_dir) = &self.recording_dir else {
+            bail!("file devices are disabled (set `recording_dir` to enable them)");
+        };
+        let path = audio_utils::recording_path(recording_dir, Path::new(path))?;
+        let device_config = self
+            .device_configs
+            .get(name.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        let config = device_config.sink_config();
+        let writer = WavWriter::try_new(
+            &path,
+            config.sample_rate().0,
+            config.channels(),
+            config.sample_format(),
+        )?;
+        let device = Device::with_sink(name.as_ref(), Arc::new(Mutex::new(writer)), config)
+            .with_buffer_ms(self.buffer_ms);
+        self.devices.insert(name.into(), device);
+
+        Ok(())
+    }
+
+    // devices that haven't been used yet are looked up among the system's ones
+    // (unless they're file devices)
     pub fn enable_device(&mut self, device_name: impl AsRef<str>) -> Result<()> {
+        if !self.devices.contains_key(device_name.as_ref()) {
+            if device_name
+                .as_ref()
+                .starts_with(constants::FILE_DEVICE_PREFIX)
+            {
+                self.add_file_device(device_name.as_ref())?;
+            } else {
+                self.system_devices = None;
+                let device = audio_utils::device_by_name(&device_name)?;
+                self.add_device(device, device_name.as_ref())?;
+            }
+        }
         let res = match self.devices.get_mut(device_name.as_ref()) {
             Some(device) => match self.playback.state {
                 PlaybackState::Stopped => device.enable(None),
@@ -183,22 +367,116 @@ impl Audio {
         res.map(|_| ())
     }
 
-    pub fn list_devices(&self) -> Vec<(String, bool)> {
+    // drops a device that stopped working (e.g. a USB DAC that got unplugged),
+    // together with its stream (otherwise the decoder could block forever
+    // on sending samples to it), once it's back it can be enabled again
+    // returns true if some other device is still playing
+    pub fn handle_device_loss(&mut self, device_name: impl AsRef<str> + Into<String>) -> bool {
+        self.system_devices = None;
+        if let Some(device) = self.devices.remove(device_name.as_ref())
+            && device.is_enabled()
+        {
+            self.n_enabled_devices -= 1;
+        }
+        if let Some(tx_request) = &self.tx_request {
+            let _ = tx_request.send(DecoderRequest::Disable(device_name.into()));
+        }
+
+        self.devices.values().any(|d| d.is_active())
+    }
+
+    // if no device is enabled anymore, enables the first working one
+    // out of the system's devices (starting with the default one)
+    pub fn ensure_enabled_device(&mut self) -> Result<()> {
+        if self.n_enabled_devices > 0 {
+            return Ok(());
+        }
+        for name in audio_utils::output_device_names()? {
+            match self.enable_device(&name) {
+                Ok(_) => {
+                    log::warn!("switched to audio device `{}`", name);
+                    return Ok(());
+                }
+                Err(e) => log::error!("can't switch to audio device `{}` ({})", name, e),
+            }
+        }
+
+        bail!("no working audio devices left")
+    }
+
+    // the decoder reads the device's volume once per packet
+    // (in the same way as the global volume)
+    pub fn set_device_volume(&mut self, device_name: impl AsRef<str>, volume: u8) -> Result<()> {
+        match self.devices.get_mut(device_name.as_ref()) {
+            Some(device) => {
+                device.set_volume(volume);
+                Ok(())
+            }
+            None => bail!(format!("device {} not found", device_name.as_ref())),
+        }
+    }
+
+    // (name, enabled, volume) of every device
+    pub fn list_devices(&self) -> Vec<(String, bool, u8)> {
         self.devices
             .values()
             .map(|d| {
                 (
                     d.name().unwrap_or(constants::UNKNOWN_DEVICE.into()),
                     d.is_enabled(),
+                    d.volume(),
                 )
             })
             .collect()
     }
 
+    // false if every device was disabled or stopped working
+    pub fn has_enabled_device(&self) -> bool {
+        self.n_enabled_devices > 0
+    }
+
+    // names of all output devices of the system (including ones not added yet)
+    pub fn list_system_devices(&mut self) -> Vec<String> {
+        self.system_devices
+            .get_or_insert_with(|| audio_utils::output_device_names().unwrap_or_default())
+            .clone()
+    }
+
     pub fn toggle_gapless(&mut self) {
         self.playback.gapless ^= true;
     }
 
+    // the decoder picks up the change with the next packet
+    pub fn toggle_preserve_pitch(&mut self) {
+        *self.playback.preserve_pitch.write().unwrap() ^= true;
+    }
+
+    pub fn preserve_pitch(&self) -> bool {
+        *self.playback.preserve_pitch.read().unwrap()
+    }
+
+    // an empty list disables the equalizer
+    pub fn set_eq(&mut self, bands: Vec<Band>) {
+        *self.playback.eq_bands.write().unwrap() = bands;
+    }
+
+    pub fn eq(&self) -> Vec<Band> {
+        self.playback.eq_bands.read().unwrap().clone()
+    }
+
+    pub fn set_crossfade(&mut self, secs: u16) {
+        self.playback.crossfade_secs = secs;
+    }
+
+    pub fn crossfade(&self) -> u16 {
+        self.playback.crossfade_secs
+    }
+
+    // the next call to `play` will mix the new song into the current one
+    pub fn fade_next(&mut self) {
+        self.fade_next = true;
+    }
+
     pub async fn pause(&mut self) -> Result<()> {
         if let PlaybackState::Stopped = self.playback.state {
             return Ok(());
@@ -226,7 +504,17 @@ impl Audio {
             let _ = tx_request.send(DecoderRequest::Resume);
         }
         for device in self.devices.values_mut().filter(|d| d.is_enabled()) {
-            device.resume()?;
+            if device.is_active() {
+                device.resume()?;
+            } else {
+                // the device's stream was dropped while paused (because the device got lost)
+                device.play(self.tx_event.clone())?;
+                if let Some(tx_request) = &self.tx_request
+                    && let Some(proxy) = DeviceProxy::try_new(device)
+                {
+                    let _ = tx_request.send(DecoderRequest::Enable(proxy));
+                }
+            }
         }
         self.playback.state = PlaybackState::Playing;
 
@@ -241,7 +529,31 @@ impl Audio {
         if let Some(tx_request) = &self.tx_request {
             let _ = tx_request.send(DecoderRequest::Stop);
         }
+        if let Some(tx_request) = &self.tx_request_fading {
+            let _ = tx_request.send(DecoderRequest::Stop);
+        }
         let _ = self.tx_request.take();
+        let _ = self.tx_request_fading.take();
+        self.fade_next = false;
+        self.next = None;
+    }
+
+    // the decoder moved on to the next song, so it has to be told the one after it
+    pub fn next_started(&mut self) {
+        self.next = None;
+    }
+
+    // the decoder opens the next song shortly before the current one ends,
+    // and then continues with it without stopping the devices
+    pub fn set_next(&mut self, path: Option<&Path>, duration: Option<u64>) {
+        if self.next.as_deref() == path {
+            return;
+        }
+        self.next = path.map(PathBuf::from);
+        if let Some(tx_request) = &self.tx_request {
+            let next = self.next.clone().map(|path| (path, duration));
+            let _ = tx_request.send(DecoderRequest::SetNext(next));
+        }
     }
 
     pub async fn toggle(&mut self) -> Result<()> {
@@ -263,6 +575,31 @@ impl Audio {
         }
     }
 
+    pub fn seek_to(&mut self, secs: u64) {
+        if let Some(tx) = &self.tx_request {
+            let _ = tx.send(DecoderRequest::Seek(Seek::Absolute(secs)));
+        }
+    }
+
+    pub fn seek_from_end(&mut self, secs: u64) {
+        if let Some(tx) = &self.tx_request {
+            let _ = tx.send(DecoderRequest::Seek(Seek::FromEnd(secs)));
+        }
+    }
+
+    // moves `delta` chapters forwards (or backwards if it's negative)
+    pub async fn seek_chapter(&mut self, delta: i32) -> Result<()> {
+        let (Some(tx), Some(timer)) = (&self.tx_request, self.playback_timer().await) else {
+            bail!("playback is stopped");
+        };
+        if timer.n_chapters == 0 {
+            bail!("the current song has no chapters");
+        }
+        let _ = tx.send(DecoderRequest::SeekChapter(delta));
+
+        Ok(())
+    }
+
     // TODO: rewrite these two functions when
     // https://doc.rust-lang.org/std/primitive.u8.html#method.saturating_sub_signed
     // stabilizes
@@ -280,7 +617,10 @@ impl Audio {
         .into();
     }
 
+    // the decoder reads the volume once per packet, so rapid changes
+    // coalesce on their own (only the latest value is ever applied)
     pub fn change_volume(&mut self, delta: i8) {
+        self.unmute();
         let mut v_lock = self.playback.volume.write().unwrap();
         let v: u8 = (*v_lock).into();
         *v_lock = {
@@ -297,6 +637,30 @@ impl Audio {
         (*self.playback.volume.read().unwrap()).into()
     }
 
+    pub fn toggle_mute(&mut self) {
+        if self.playback.muted.is_some() {
+            self.unmute();
+        } else {
+            self.playback.muted = Some(self.volume());
+            *self.playback.volume.write().unwrap() = 0.into();
+        }
+    }
+
+    fn unmute(&mut self) {
+        if let Some(volume) = self.playback.muted.take() {
+            *self.playback.volume.write().unwrap() = volume.into();
+        }
+    }
+
+    pub fn muted(&self) -> bool {
+        self.playback.muted.is_some()
+    }
+
+    // the volume to restore after a restart (muting isn't persisted)
+    pub fn unmuted_volume(&self) -> u8 {
+        self.playback.muted.unwrap_or(self.volume())
+    }
+
     pub async fn playback_timer(&self) -> Option<PlaybackTimer> {
         if let Some(tx_request) = &self.tx_request {
             let (tx, rx) = oneshot::channel();
@@ -312,6 +676,14 @@ impl Audio {
         self.playback.gapless
     }
 
+    pub fn is_playing(&self) -> bool {
+        matches!(self.playback.state, PlaybackState::Playing)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        matches!(self.playback.state, PlaybackState::Paused)
+    }
+
     pub fn playback_state(&self) -> String {
         match self.playback.state {
             PlaybackState::Stopped => "stopped",
@@ -329,16 +701,121 @@ impl Audio {
 mod audio_utils {
     use super::*;
 
+    // the absolute path of a file device's WAV file, which has to be located inside of
+    // the recording directory (so that clients can't overwrite any other files)
+    pub fn recording_path(recording_dir: &Path, path: &Path) -> Result<PathBuf> {
+        let abs_path = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            recording_dir.join(path)
+        };
+        // symlinks are resolved, so that they can't lead outside either
+        // (the file may not exist yet, but its directory has to)
+        let canonicalize = |path: &Path| dunce::canonicalize(path).unwrap_or(path.into());
+        let canonical_path = match (abs_path.parent(), abs_path.file_name()) {
+            _ if abs_path.exists() => canonicalize(&abs_path),
+            (Some(parent), Some(file_name)) => canonicalize(parent).join(file_name),
+            _ => abs_path.clone(),
+        };
+        let escapes = abs_path
+            .components()
+            .any(|component| matches!(component, Component::ParentDir))
+            || !canonical_path.starts_with(canonicalize(recording_dir));
+        if escapes {
+            bail!(
+                "`{}` is outside of the recording directory",
+                path.to_string_lossy()
+            );
+        }
+        let is_wav = abs_path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"));
+        if !is_wav {
+            bail!("`{}` isn't a WAV file", path.to_string_lossy());
+        }
+
+        Ok(abs_path)
+    }
+
+    // a failing decoder (e.g. because of a corrupt file) reports it to the player,
+    // which moves on to the next song (otherwise the playback would silently die)
+    pub fn run_decoder(
+        run: impl FnOnce() -> Result<()>,
+        tx_event: tokio_chan::UnboundedSender<SongEvent>,
+    ) {
+        let reason = match panic::catch_unwind(AssertUnwindSafe(run)) {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(payload) => panic_message(payload),
+        };
+        log::error!("decoder error ({})", reason);
+        let _ = tx_event.send(SongEvent::Error(reason));
+    }
+
+    fn panic_message(payload: Box<dyn Any + Send>) -> String {
+        match payload.downcast::<String>() {
+            Ok(s) => *s,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(s) => s.to_string(),
+                Err(_) => "the decoder panicked".into(),
+            },
+        }
+    }
+
     pub fn default_output_device() -> Option<CpalDevice> {
         let host = cpal::default_host();
         host.default_output_device()
     }
 
-    pub fn device_by_name(device_name: impl AsRef<str>) -> Result<CpalDevice> {
+    // channels through which the decoder of the fading out song
+    // sends its samples to the decoder of the fading in song
+    pub fn fade_channels(proxies: &[DeviceProxy], secs: u16) -> (Vec<DeviceProxy>, Vec<FadeIn>) {
+        proxies
+            .iter()
+            .map(|proxy| {
+                // buffer as much audio as the device does (`buffer_ms` of all its channels)
+                let (tx_sample, rx_sample) = match proxy.tx_sample.capacity() {
+                    Some(capacity) => cbeam_chan::bounded(capacity),
+                    None => cbeam_chan::unbounded(),
+                };
+                let fade_proxy = DeviceProxy {
+                    name: proxy.name.clone(),
+                    sample_rate: proxy.sample_rate,
+                    tx_sample,
+                    volume: Arc::clone(&proxy.volume),
+                };
+
+                (fade_proxy, FadeIn::new(proxy.name.clone(), rx_sample, secs))
+            })
+            .unzip()
+    }
+
+    // the default device goes first (some hosts don't enumerate it)
+    pub fn output_device_names() -> Result<Vec<String>> {
         let host = cpal::default_host();
-        match host
+        let mut names: Vec<_> = default_output_device()
+            .and_then(|d| d.name().ok())
+            .into_iter()
+            .collect();
+        for name in host
             .output_devices()?
-            .find(|x| x.name().map(|s| s == device_name.as_ref()).unwrap_or(false))
+            .map(|d| d.name().unwrap_or(constants::UNKNOWN_DEVICE.into()))
+        {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    pub fn device_by_name(device_name: impl AsRef<str>) -> Result<CpalDevice> {
+        let host = cpal::default_host();
+        let is_named =
+            |x: &CpalDevice| x.name().map(|s| s == device_name.as_ref()).unwrap_or(false);
+        match default_output_device()
+            .filter(is_named)
+            .or(host.output_devices()?.find(is_named))
         {
             Some(device) => Ok(device),
             None => {
@@ -358,3 +835,190 @@ mod audio_utils {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::anyhow;
+    use std::{fs, time::Duration};
+
+    use crate::model::song;
+
+    #[test]
+    fn system_devices() {
+        let (tx_event, _rx_event) = tokio_chan::unbounded_channel();
+        let mut audio = Audio::new(None, tx_event);
+        // there might be no audio devices at all (e.g. in CI)
+        if let Some(device) = audio_utils::default_output_device()
+            && let Ok(name) = device.name()
+        {
+            assert!(audio.list_system_devices().contains(&name));
+        }
+        // the list is enumerated again only after a device comes or goes
+        assert!(audio.system_devices.is_some());
+        audio.handle_device_loss("lost");
+        assert!(audio.system_devices.is_none());
+    }
+
+    #[test]
+    fn disable_last_device_while_stopped() {
+        let (tx_event, _rx_event) = tokio_chan::unbounded_channel();
+        let mut audio = Audio::new(None, tx_event);
+        let res = audio.play("song.mp3", None);
+        assert!(res.is_err_and(|e| e.to_string().contains("all audio devices are disabled")));
+        assert!(audio.disable_device("nonexistent".into()).is_err());
+
+        // the rest needs an actual audio device
+        let Some(name) = audio_utils::default_output_device().and_then(|d| d.name().ok()) else {
+            return;
+        };
+        if audio.enable_device(&name).is_err() {
+            return;
+        }
+        assert!(audio.has_enabled_device());
+        assert!(audio.disable_device(name.clone()).is_ok());
+        assert!(!audio.has_enabled_device());
+        // disabling it again changes nothing
+        assert!(audio.disable_device(name.clone()).is_ok());
+        assert!(audio.enable_device(&name).is_ok());
+        assert!(audio.has_enabled_device());
+    }
+
+    #[test]
+    fn file_devices_stay_in_recording_dir() {
+        let dir =
+            std::env::temp_dir().join(format!("musing_test_recordings_{}", std::process::id()));
+        let _ = fs::create_dir(&dir);
+        let (tx_event, _rx_event) = tokio_chan::unbounded_channel();
+        let mut disabled = Audio::new(None, tx_event.clone());
+        let mut audio = Audio::new(None, tx_event).with_recording_dir(Some(dir.clone()));
+
+        let no_dir =
+            disabled.enable_device(format!("file:{}", dir.join("a.wav").to_string_lossy()));
+        let relative = audio.enable_device("file:b.wav");
+        let relative_created = dir.join("b.wav").exists();
+        let outside = audio.enable_device("file:/etc/musing_test.wav");
+        let parent = audio.enable_device("file:../musing_test.wav");
+        let not_wav = audio.enable_device("file:c.toml");
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(no_dir.is_err());
+        assert!(relative.is_ok() && relative_created);
+        assert!(outside.is_err());
+        assert!(parent.is_err());
+        assert!(not_wav.is_err());
+    }
+
+    #[tokio::test]
+    async fn record_to_file() {
+        let song_path =
+            std::env::temp_dir().join(format!("musing_test_record_{}.wav", std::process::id()));
+        let out_path =
+            std::env::temp_dir().join(format!("musing_test_record_out_{}.wav", std::process::id()));
+        song::write_silent_wav(&song_path, 8000, 2, 8000);
+        let (tx_event, mut rx_event) = tokio_chan::unbounded_channel();
+        let mut audio = Audio::new(None, tx_event).with_recording_dir(Some(std::env::temp_dir()));
+        let enabled = audio.enable_device(format!("file:{}", out_path.to_string_lossy()));
+        let played = audio.play(&song_path, None);
+        let over = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(event) = rx_event.recv().await {
+                if let SongEvent::Over = event {
+                    return true;
+                }
+            }
+            false
+        })
+        .await;
+        audio.stop();
+        let wav = fs::read(&out_path);
+        let _ = fs::remove_file(&song_path);
+        let _ = fs::remove_file(&out_path);
+
+        enabled.unwrap();
+        played.unwrap();
+        assert_eq!(over, Ok(true));
+        let wav = wav.unwrap();
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        // 44.1 kHz, 16-bit stereo by default (the song got resampled)
+        assert_eq!(u16::from_le_bytes([wav[22], wav[23]]), 2);
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 44100);
+        assert_eq!(u16::from_le_bytes([wav[34], wav[35]]), 16);
+        assert_eq!(&wav[36..40], b"data");
+        let data_len = u32::from_le_bytes(wav[40..44].try_into().unwrap()) as usize;
+        assert!(data_len > 0);
+        assert_eq!(data_len, wav.len() - 44);
+    }
+
+    #[tokio::test]
+    async fn pause_after_fade_out() {
+        let song_path =
+            std::env::temp_dir().join(format!("musing_test_pause_{}.wav", std::process::id()));
+        let out_path =
+            std::env::temp_dir().join(format!("musing_test_pause_out_{}.wav", std::process::id()));
+        // a second of a constant (non-zero) signal
+        song::write_silent_wav(&song_path, 44100, 2, 44100);
+        let mut song = fs::read(&song_path).unwrap();
+        for s in song[44..].chunks_exact_mut(2) {
+            s.copy_from_slice(&(i16::MAX / 2).to_le_bytes());
+        }
+        fs::write(&song_path, song).unwrap();
+        let (tx_event, _rx_event) = tokio_chan::unbounded_channel();
+        let mut audio = Audio::new(None, tx_event)
+            .with_pause_fade_ms(50)
+            .with_recording_dir(Some(std::env::temp_dir()));
+        let enabled = audio.enable_device(format!("file:{}", out_path.to_string_lossy()));
+        let played = audio.play(&song_path, None);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let paused = audio.pause().await;
+        audio.stop();
+        let wav = fs::read(&out_path);
+        let _ = fs::remove_file(&song_path);
+        let _ = fs::remove_file(&out_path);
+
+        enabled.unwrap();
+        played.unwrap();
+        paused.unwrap();
+        let wav = wav.unwrap();
+        assert!(wav.len() > 44);
+        // the device played the whole fade out before it got paused
+        let last = i16::from_le_bytes([wav[wav.len() - 2], wav[wav.len() - 1]]);
+        assert!(last.abs() < i16::MAX / 100, "last sample: {last}");
+    }
+
+    #[test]
+    fn failing_decoder() {
+        let (tx_event, mut rx_event) = tokio_chan::unbounded_channel();
+        audio_utils::run_decoder(|| Ok(()), tx_event.clone());
+        assert!(rx_event.try_recv().is_err());
+
+        audio_utils::run_decoder(|| Err(anyhow!("corrupt packet")), tx_event.clone());
+        assert!(
+            matches!(rx_event.try_recv(), Ok(SongEvent::Error(reason)) if reason == "corrupt packet")
+        );
+        audio_utils::run_decoder(|| panic!("bug"), tx_event);
+        assert!(matches!(rx_event.try_recv(), Ok(SongEvent::Error(reason)) if reason == "bug"));
+    }
+
+    #[test]
+    fn mute_and_unmute() {
+        let (tx_event, _rx_event) = tokio_chan::unbounded_channel();
+        let mut audio = Audio::new(None, tx_event);
+        audio.change_volume(-20);
+        let volume = audio.volume();
+
+        audio.toggle_mute();
+        assert!(audio.muted());
+        assert_eq!(audio.volume(), 0);
+        assert_eq!(audio.unmuted_volume(), volume);
+        audio.toggle_mute();
+        assert!(!audio.muted());
+        assert_eq!(audio.volume(), volume);
+
+        // changing the volume unmutes
+        audio.toggle_mute();
+        audio.change_volume(5);
+        assert!(!audio.muted());
+        assert_eq!(audio.volume(), volume + 5);
+    }
+}
@@ -1,12 +1,19 @@
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 use clap::Parser;
 use std::{
+    collections::{HashMap, HashSet},
     fs,
+    net::IpAddr,
+    num::{NonZeroU64, NonZeroUsize},
     path::{Path, PathBuf},
 };
 use toml::{Table, Value};
 
-use crate::constants;
+use crate::{
+    constants,
+    model::{device::DeviceConfig, queue::QueueEndAction},
+    server::Framing,
+};
 
 #[derive(Debug, Parser)]
 #[command(version, about, author, long_about = None)]
@@ -15,14 +22,21 @@ pub struct CliOptions {
     #[arg(short = 'd', long = "device")]
     pub audio_device: Option<String>,
 
-    /// Path to the directory containing music files (default: the process' CWD).
+    /// Path to the directory containing music files, can be repeated to use many directories
+    /// (default: the process' CWD).
     #[arg(short = 'm', long = "music")]
-    pub music_dir: Option<PathBuf>,
+    pub music_dirs: Vec<PathBuf>,
 
-    /// Path to the directory containing playlist files (default: <music_dir>/playlists).
+    /// Path to the directory containing playlist files
+    /// (default: <first music_dir>/playlists).
     #[arg(short = 'p', long = "playlists")]
     pub playlist_dir: Option<PathBuf>,
 
+    /// Path to the directory where file devices (`file:<path>`) can record the playback,
+    /// they're disabled without it (default: none).
+    #[arg(long = "recording-dir")]
+    pub recording_dir: Option<PathBuf>,
+
     /// Path to the config file (default: <config_dir>/musing/musing.toml).
     #[arg(short = 'c', long = "config")]
     pub config_file: Option<PathBuf>,
@@ -31,8 +45,8 @@ pub struct CliOptions {
     #[arg(short = 'l', long = "log")]
     pub log_file: Option<PathBuf>,
 
-    /// Path to the state file, used to retrieve the queue and some settings from the previous
-    /// run of musing
+    /// Path to the state file, used to retrieve the queue (together with the position in the
+    /// song that was playing) and some settings from the previous run of musing
     /// (default: <cache_dir>/musing.state).
     #[arg(short = 's', long = "state")]
     pub state_file: Option<PathBuf>,
@@ -41,6 +55,95 @@ pub struct CliOptions {
     #[arg(long = "port")]
     pub port: Option<u16>,
 
+    /// IP address on which musing will listen for clients, `0.0.0.0` or `::` make it
+    /// reachable from the network (default: 127.0.0.1).
+    #[arg(long = "bind")]
+    pub bind_addr: Option<IpAddr>,
+
+    /// Path to a Unix domain socket on which musing will listen for clients instead of
+    /// a TCP port (default: none).
+    #[arg(long = "socket")]
+    pub socket_path: Option<PathBuf>,
+
+    /// Port on which musing will stream the playback over HTTP (at `/stream`), available
+    /// only if musing was built with the `http` feature (default: none).
+    #[arg(long = "http-port")]
+    pub http_port: Option<u16>,
+
+    /// Maximum number of clients connected at the same time, any more are turned away
+    /// (default: 64).
+    #[arg(long = "max-connections")]
+    pub max_connections: Option<NonZeroUsize>,
+
+    /// How many seconds to wait for the player to respond to a request before
+    /// responding with an error (default: 30).
+    #[arg(long = "request-timeout")]
+    pub request_timeout_secs: Option<NonZeroU64>,
+
+    /// How messages are delimited: `length` (a 4-byte length before every message) or
+    /// `line` (one message per line) (default: length).
+    #[arg(long = "framing")]
+    pub framing: Option<Framing>,
+
+    /// Password that clients have to send (with `auth`) before making any other requests,
+    /// prefer setting it in the config file, as command line arguments can be seen by
+    /// other users (default: none).
+    #[arg(long = "password")]
+    pub password: Option<String>,
+
+    /// Pause the playback when an audio device stops working, e.g. when headphones
+    /// get unplugged (default: false).
+    #[arg(long = "pause-on-device-loss")]
+    pub pause_on_device_loss: bool,
+
+    /// What to do after the last song of the queue finishes: `stop`, `repeat` (start over)
+    /// or `random` (switch to random mode) (default: stop).
+    #[arg(long = "queue-end-action")]
+    pub queue_end_action: Option<QueueEndAction>,
+
+    /// Update the database automatically whenever files in the music directory change
+    /// (default: false).
+    #[arg(long = "watch")]
+    pub watch: bool,
+
+    /// Extension of audio files to add to the database, can be repeated to allow many
+    /// extensions, replaces the default ones
+    /// (default: aac, aif, aifc, aiff, flac, m4a, mp3, oga, ogg, wav).
+    #[arg(short = 'e', long = "ext")]
+    pub allowed_exts: Vec<String>,
+
+    /// Follow symbolic links when scanning the music directory (default: false).
+    #[arg(long = "follow-symlinks")]
+    pub follow_symlinks: bool,
+
+    /// Read songs of unknown length (e.g. some VBR files) in full to compute their exact
+    /// durations instead of estimating them, this slows down scanning (default: false).
+    #[arg(long = "exact-durations")]
+    pub exact_durations: bool,
+
+    /// Keep the pitch unchanged when changing the playback speed (default: false).
+    #[arg(long = "preserve-pitch")]
+    pub preserve_pitch: bool,
+
+    /// How much audio (in milliseconds, between 20 and 2000) to buffer for each output device,
+    /// more buffering helps against underruns, but delays seeks and volume changes
+    /// (default: 100).
+    #[arg(long = "buffer-ms", value_parser = clap::value_parser!(u16).range(
+        constants::MIN_BUFFER_MS as i64..=constants::MAX_BUFFER_MS as i64
+    ))]
+    pub buffer_ms: Option<u16>,
+
+    /// How long (in milliseconds, up to 1000) the playback fades out when pausing and
+    /// fades in when resuming, 0 disables fading (default: 50).
+    #[arg(long = "pause-fade-ms", value_parser = clap::value_parser!(u16).range(
+        0..=constants::MAX_PAUSE_FADE_MS as i64
+    ))]
+    pub pause_fade_ms: Option<u16>,
+
+    /// Use paths relative to the music directory in responses (default: false).
+    #[arg(long = "relative-paths")]
+    pub relative_paths: bool,
+
     /// Print logs to stderr (default: false).
     #[arg(long = "stderr")]
     pub log_stderr: bool,
@@ -49,14 +152,37 @@ pub struct CliOptions {
 #[derive(Debug)]
 pub struct ServerConfig {
     pub port: u16,
+    pub bind_addr: String,
+    pub socket_path: Option<PathBuf>,
+    pub http_port: Option<u16>,
+    pub max_connections: usize,
+    pub request_timeout_secs: u64,
+    pub framing: Framing,
+    pub password: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct PlayerConfig {
-    pub music_dir: PathBuf,
+    pub music_dirs: Vec<PathBuf>,
     pub state_file: PathBuf,
     pub audio_device: Option<String>,
+    // devices enabled at startup (instead of `audio_device`)
+    pub output_devices: Vec<String>,
+    // output configs of devices (by their names) to use instead of the default ones
+    pub device_configs: HashMap<String, DeviceConfig>,
     pub playlist_dir: Option<PathBuf>,
+    // file devices can only write inside of it
+    pub recording_dir: Option<PathBuf>,
+    pub relative_paths: bool,
+    pub pause_on_device_loss: bool,
+    pub queue_end_action: QueueEndAction,
+    pub watch: bool,
+    pub allowed_exts: HashSet<String>,
+    pub follow_symlinks: bool,
+    pub exact_durations: bool,
+    pub preserve_pitch: bool,
+    pub buffer_ms: u16,
+    pub pause_fade_ms: u16,
 }
 
 #[derive(Debug, Default)]
@@ -69,6 +195,13 @@ impl Default for ServerConfig {
     fn default() -> Self {
         ServerConfig {
             port: constants::DEFAULT_PORT,
+            bind_addr: constants::DEFAULT_BIND_ADDR.into(),
+            socket_path: None,
+            http_port: None,
+            max_connections: constants::DEFAULT_MAX_CONNECTIONS,
+            request_timeout_secs: constants::DEFAULT_REQUEST_TIMEOUT_SECS,
+            framing: Framing::default(),
+            password: None,
         }
     }
 }
@@ -78,8 +211,41 @@ impl ServerConfig {
         let mut config = Self::default();
         let table = content.as_ref().parse::<Table>()?;
         for (key, val) in table {
-            if let ("port", Value::Integer(port)) = (key.as_str(), val) {
-                config.port = u16::try_from(port)?;
+            match (key.as_str(), val) {
+                ("port", Value::Integer(port)) => {
+                    config.port = u16::try_from(port)?;
+                }
+                ("bind_addr", Value::String(bind_addr)) => {
+                    bind_addr.parse::<IpAddr>()?;
+                    config.bind_addr = bind_addr;
+                }
+                ("socket_path", Value::String(socket_path)) => {
+                    config.socket_path = Some(socket_path.into());
+                }
+                ("http_port", Value::Integer(http_port)) => {
+                    config.http_port = Some(u16::try_from(http_port)?);
+                }
+                ("max_connections", Value::Integer(max_connections)) => {
+                    let max_connections = usize::try_from(max_connections)?;
+                    if max_connections == 0 {
+                        bail!("`max_connections` must be at least 1");
+                    }
+                    config.max_connections = max_connections;
+                }
+                ("request_timeout_secs", Value::Integer(request_timeout_secs)) => {
+                    let request_timeout_secs = u64::try_from(request_timeout_secs)?;
+                    if request_timeout_secs == 0 {
+                        bail!("`request_timeout_secs` must be at least 1");
+                    }
+                    config.request_timeout_secs = request_timeout_secs;
+                }
+                ("framing", Value::String(framing)) => {
+                    config.framing = framing.parse()?;
+                }
+                ("password", Value::String(password)) => {
+                    config.password = Some(password);
+                }
+                _ => (),
             }
         }
 
@@ -90,12 +256,25 @@ impl ServerConfig {
 impl Default for PlayerConfig {
     fn default() -> Self {
         Self {
-            music_dir: PathBuf::from(constants::DEFAULT_MUSIC_DIR),
+            music_dirs: vec![PathBuf::from(constants::DEFAULT_MUSIC_DIR)],
             state_file: dirs::cache_dir()
                 .unwrap_or(".".into())
                 .join(constants::DEFAULT_STATE_FILE),
             audio_device: None,
+            output_devices: Vec::new(),
+            device_configs: HashMap::new(),
             playlist_dir: None,
+            recording_dir: None,
+            relative_paths: false,
+            pause_on_device_loss: false,
+            queue_end_action: QueueEndAction::default(),
+            watch: false,
+            allowed_exts: constants::DEFAULT_ALLOWED_EXTS.clone(),
+            follow_symlinks: false,
+            exact_durations: false,
+            preserve_pitch: false,
+            buffer_ms: constants::DEFAULT_BUFFER_MS,
+            pause_fade_ms: constants::DEFAULT_PAUSE_FADE_MS,
         }
     }
 }
@@ -107,7 +286,18 @@ impl PlayerConfig {
         for (key, val) in table {
             match (key.as_str(), val) {
                 ("music_dir", Value::String(music_dir)) => {
-                    config.music_dir = music_dir.into();
+                    config.music_dirs = vec![music_dir.into()];
+                }
+                ("music_dir", Value::Array(music_dirs)) => {
+                    config.music_dirs = music_dirs
+                        .into_iter()
+                        .map(|music_dir| match music_dir {
+                            Value::String(music_dir) => Ok(music_dir.into()),
+                            _ => Err(anyhow!(
+                                "`music_dir` must be a string or an array of strings"
+                            )),
+                        })
+                        .collect::<Result<_>>()?;
                 }
                 ("state_file", Value::String(state_file)) => {
                     config.state_file = state_file.into();
@@ -115,9 +305,89 @@ impl PlayerConfig {
                 ("audio_device", Value::String(audio_device)) => {
                     config.audio_device = Some(audio_device);
                 }
+                ("output_devices", Value::Array(output_devices)) => {
+                    config.output_devices = output_devices
+                        .into_iter()
+                        .map(|device| match device {
+                            Value::String(device) => Ok(device),
+                            _ => Err(anyhow!("`output_devices` must be an array of strings")),
+                        })
+                        .collect::<Result<_>>()?;
+                }
+                ("device_config", Value::Table(device_configs)) => {
+                    config.device_configs = device_configs
+                        .into_iter()
+                        .map(|(name, device_config)| match device_config {
+                            Value::Table(device_config) => {
+                                Ok((name, config_utils::device_config(device_config)?))
+                            }
+                            _ => Err(anyhow!("`device_config.{}` must be a table", name)),
+                        })
+                        .collect::<Result<_>>()?;
+                }
                 ("playlist_dir", Value::String(playlist_dir)) => {
                     config.playlist_dir = Some(playlist_dir.into());
                 }
+                ("recording_dir", Value::String(recording_dir)) => {
+                    config.recording_dir = Some(recording_dir.into());
+                }
+                ("relative_paths", Value::Boolean(relative_paths)) => {
+                    config.relative_paths = relative_paths;
+                }
+                ("pause_on_device_loss", Value::Boolean(pause_on_device_loss)) => {
+                    config.pause_on_device_loss = pause_on_device_loss;
+                }
+                ("queue_end_action", Value::String(queue_end_action)) => {
+                    config.queue_end_action = queue_end_action.parse()?;
+                }
+                ("watch", Value::Boolean(watch)) => {
+                    config.watch = watch;
+                }
+                ("allowed_exts", Value::Array(exts)) => {
+                    config.allowed_exts = exts
+                        .into_iter()
+                        .map(|ext| match ext {
+                            Value::String(ext) => Ok(config_utils::normalize_ext(&ext)),
+                            _ => Err(anyhow!("`allowed_exts` must be an array of strings")),
+                        })
+                        .collect::<Result<_>>()?;
+                    if config.allowed_exts.is_empty() {
+                        bail!("`allowed_exts` can't be empty");
+                    }
+                }
+                ("follow_symlinks", Value::Boolean(follow_symlinks)) => {
+                    config.follow_symlinks = follow_symlinks;
+                }
+                ("exact_durations", Value::Boolean(exact_durations)) => {
+                    config.exact_durations = exact_durations;
+                }
+                ("preserve_pitch", Value::Boolean(preserve_pitch)) => {
+                    config.preserve_pitch = preserve_pitch;
+                }
+                ("buffer_ms", Value::Integer(buffer_ms)) => {
+                    let range = constants::MIN_BUFFER_MS..=constants::MAX_BUFFER_MS;
+                    match u16::try_from(buffer_ms) {
+                        Ok(buffer_ms) if range.contains(&buffer_ms) => {
+                            config.buffer_ms = buffer_ms;
+                        }
+                        _ => bail!(
+                            "`buffer_ms` must be between {} and {}",
+                            range.start(),
+                            range.end()
+                        ),
+                    }
+                }
+                ("pause_fade_ms", Value::Integer(pause_fade_ms)) => {
+                    match u16::try_from(pause_fade_ms) {
+                        Ok(pause_fade_ms) if pause_fade_ms <= constants::MAX_PAUSE_FADE_MS => {
+                            config.pause_fade_ms = pause_fade_ms;
+                        }
+                        _ => bail!(
+                            "`pause_fade_ms` must be between 0 and {}",
+                            constants::MAX_PAUSE_FADE_MS
+                        ),
+                    }
+                }
                 _ => (),
             }
         }
@@ -146,12 +416,63 @@ impl Config {
     pub fn merge_with_cli(self, cli_opts: CliOptions) -> Self {
         let server_config = ServerConfig {
             port: cli_opts.port.unwrap_or(self.server_config.port),
+            bind_addr: cli_opts
+                .bind_addr
+                .map(|addr| addr.to_string())
+                .unwrap_or(self.server_config.bind_addr),
+            socket_path: cli_opts.socket_path.or(self.server_config.socket_path),
+            http_port: cli_opts.http_port.or(self.server_config.http_port),
+            max_connections: cli_opts
+                .max_connections
+                .map(NonZeroUsize::get)
+                .unwrap_or(self.server_config.max_connections),
+            request_timeout_secs: cli_opts
+                .request_timeout_secs
+                .map(NonZeroU64::get)
+                .unwrap_or(self.server_config.request_timeout_secs),
+            framing: cli_opts.framing.unwrap_or(self.server_config.framing),
+            password: cli_opts.password.or(self.server_config.password),
         };
         let player_config = PlayerConfig {
-            music_dir: cli_opts.music_dir.unwrap_or(self.player_config.music_dir),
+            music_dirs: if cli_opts.music_dirs.is_empty() {
+                self.player_config.music_dirs
+            } else {
+                cli_opts.music_dirs
+            },
             state_file: cli_opts.state_file.unwrap_or(self.player_config.state_file),
+            // a device chosen with the CLI option replaces the ones from the config file
+            output_devices: if cli_opts.audio_device.is_some() {
+                Vec::new()
+            } else {
+                self.player_config.output_devices
+            },
             audio_device: cli_opts.audio_device.or(self.player_config.audio_device),
+            device_configs: self.player_config.device_configs,
             playlist_dir: cli_opts.playlist_dir.or(self.player_config.playlist_dir),
+            recording_dir: cli_opts.recording_dir.or(self.player_config.recording_dir),
+            relative_paths: cli_opts.relative_paths || self.player_config.relative_paths,
+            pause_on_device_loss: cli_opts.pause_on_device_loss
+                || self.player_config.pause_on_device_loss,
+            queue_end_action: cli_opts
+                .queue_end_action
+                .unwrap_or(self.player_config.queue_end_action),
+            watch: cli_opts.watch || self.player_config.watch,
+            allowed_exts: if cli_opts.allowed_exts.is_empty() {
+                self.player_config.allowed_exts
+            } else {
+                cli_opts
+                    .allowed_exts
+                    .iter()
+                    .map(|ext| config_utils::normalize_ext(ext))
+                    .collect()
+            },
+            follow_symlinks: cli_opts.follow_symlinks || self.player_config.follow_symlinks,
+            exact_durations: cli_opts.exact_durations || self.player_config.exact_durations,
+            preserve_pitch: cli_opts.preserve_pitch || self.player_config.preserve_pitch,
+            buffer_ms: cli_opts.buffer_ms.unwrap_or(self.player_config.buffer_ms),
+            pause_fade_ms: cli_opts
+                .pause_fade_ms
+                .unwrap_or(self.player_config.pause_fade_ms),
         };
 
         Self {
@@ -160,3 +481,116 @@ impl Config {
         }
     }
 }
+
+mod config_utils {
+    use super::*;
+
+    // `.MP3` -> `mp3`
+    pub fn normalize_ext(ext: &str) -> String {
+        ext.trim().trim_start_matches('.').to_lowercase()
+    }
+
+    // e.g. `{ sample_rate = 48000, sample_format = "i24" }`
+    pub fn device_config(table: Table) -> Result<DeviceConfig> {
+        let mut device_config = DeviceConfig::default();
+        for (key, val) in table {
+            match (key.as_str(), val) {
+                ("sample_rate", Value::Integer(sample_rate)) => {
+                    device_config.sample_rate = Some(u32::try_from(sample_rate)?);
+                }
+                ("sample_format", Value::String(sample_format)) => {
+                    device_config.sample_format =
+                        Some(DeviceConfig::parse_sample_format(&sample_format)?);
+                }
+                ("channels", Value::Integer(channels)) => {
+                    device_config.channels = Some(u16::try_from(channels)?);
+                }
+                // typos would silently leave the default config in place
+                (key, _) => bail!("invalid device config option `{}`", key),
+            }
+        }
+
+        Ok(device_config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bind_addr_from_toml() {
+        let config = ServerConfig::try_new("port = 6600").unwrap();
+        assert_eq!(config.bind_addr, "127.0.0.1");
+
+        let config = ServerConfig::try_new("bind_addr = \"0.0.0.0\"\nport = 6600").unwrap();
+        assert_eq!(config.bind_addr, "0.0.0.0");
+        assert_eq!(config.port, 6600);
+
+        let config = ServerConfig::try_new("bind_addr = \"::1\"").unwrap();
+        assert_eq!(config.bind_addr, "::1");
+
+        assert!(ServerConfig::try_new("bind_addr = \"localhost:2137\"").is_err());
+
+        let config = ServerConfig::try_new("max_connections = 8").unwrap();
+        assert_eq!(config.max_connections, 8);
+        assert!(ServerConfig::try_new("max_connections = 0").is_err());
+    }
+
+    #[test]
+    fn allowed_exts_from_toml() {
+        let config = PlayerConfig::try_new("watch = true").unwrap();
+        assert_eq!(config.allowed_exts, *constants::DEFAULT_ALLOWED_EXTS);
+
+        let config = PlayerConfig::try_new("allowed_exts = [\"opus\", \".WV\"]").unwrap();
+        assert_eq!(
+            config.allowed_exts,
+            HashSet::from(["opus".into(), "wv".into()])
+        );
+
+        assert!(PlayerConfig::try_new("allowed_exts = []").is_err());
+        assert!(PlayerConfig::try_new("allowed_exts = [1]").is_err());
+    }
+
+    #[test]
+    fn output_devices_from_toml() {
+        let config = PlayerConfig::try_new("audio_device = \"a\"").unwrap();
+        assert!(config.output_devices.is_empty());
+
+        let config = PlayerConfig::try_new("output_devices = [\"a\", \"b c\"]").unwrap();
+        assert_eq!(config.output_devices, ["a", "b c"]);
+
+        assert!(PlayerConfig::try_new("output_devices = [\"a\", 1]").is_err());
+    }
+
+    #[test]
+    fn device_configs_from_toml() {
+        let config = PlayerConfig::try_new(
+            "[device_config.\"USB DAC\"]\nsample_rate = 48000\nsample_format = \"I24\"",
+        )
+        .unwrap();
+        assert_eq!(
+            config.device_configs["USB DAC"],
+            DeviceConfig {
+                sample_rate: Some(48000),
+                sample_format: Some(cpal::SampleFormat::I24),
+                channels: None,
+            }
+        );
+
+        assert!(PlayerConfig::try_new("[device_config.a]\nsample_format = \"i20\"").is_err());
+        assert!(PlayerConfig::try_new("[device_config.a]\nsamplerate = 48000").is_err());
+    }
+
+    #[test]
+    fn buffer_ms_from_toml() {
+        let config = PlayerConfig::try_new("watch = true").unwrap();
+        assert_eq!(config.buffer_ms, 100);
+
+        let config = PlayerConfig::try_new("buffer_ms = 250").unwrap();
+        assert_eq!(config.buffer_ms, 250);
+
+        assert!(PlayerConfig::try_new("buffer_ms = 10").is_err());
+        assert!(PlayerConfig::try_new("buffer_ms = 100000").is_err());
+    }
+}
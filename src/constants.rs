@@ -2,14 +2,58 @@ use lazy_static::lazy_static;
 use std::collections::HashSet;
 
 pub const DEFAULT_PORT: u16 = 2137;
+pub const DEFAULT_BIND_ADDR: &str = "127.0.0.1";
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+// how long a client waits for the player to respond to a request
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+// longer messages close the connection (they're not even read into memory)
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+// before authenticating, the only request that can be sent is a short `auth`
+pub const MAX_AUTH_FRAME_LEN: usize = 4096;
 pub const DEFAULT_MUSIC_DIR: &str = ".";
 pub const DEFAULT_PLAYLIST_DIR: &str = "playlists";
 pub const DEFAULT_LOG_FILE: &str = "musing.log";
+// how many of the most recent errors are reported by `health`
+pub const MAX_RECENT_ERRORS: usize = 16;
+// how many of the most recently played songs are returned by `history`
+pub const MAX_PLAYED_HISTORY: usize = 100;
 pub const DEFAULT_STATE_FILE: &str = "musing.state";
 pub const DEFAULT_CONFIG_FILE: &str = "musing.toml";
 pub const DEFAULT_CONFIG_DIR: &str = "musing";
 pub const DEFAULT_IGNORE_FILE: &str = ".musingignore";
 pub const UNKNOWN_DEVICE: &str = "[unknown]";
+pub const MAX_PLAYLIST_DEPTH: usize = 16;
+// how deeply `!` and `(` can be nested in a filter written as text
+pub const MAX_FILTER_DEPTH: usize = 64;
+pub const EXTINF_PREFIX: &str = "#EXTINF:";
+pub const WATCHER_DEBOUNCE_SECS: u64 = 2;
+// how much audio (in milliseconds) is buffered for each output device
+pub const DEFAULT_BUFFER_MS: u16 = 100;
+pub const MIN_BUFFER_MS: u16 = 20;
+pub const MAX_BUFFER_MS: u16 = 2000;
+// how long (in milliseconds) the playback fades out on pause and fades in on resume
+pub const DEFAULT_PAUSE_FADE_MS: u16 = 50;
+pub const MAX_PAUSE_FADE_MS: u16 = 1000;
+// limits of a single band of the equalizer (gain in dB, frequency in Hz)
+pub const MAX_EQ_GAIN: f64 = 24.0;
+pub const MIN_EQ_Q: f64 = 0.1;
+pub const MAX_EQ_Q: f64 = 10.0;
+pub const MAX_EQ_FREQ: f64 = 20000.0;
+pub const FILE_DEVICE_PREFIX: &str = "file:";
+// how often devices not backed by the system's audio (e.g. files) take samples from the decoder
+pub const SINK_OUTPUT_TICK_MS: u64 = 10;
+// how many packets to read to estimate the duration of a song of unknown length
+pub const DURATION_SCAN_PACKETS: usize = 200;
+// lagging behind only merges notifications, so there's no need for a large buffer
+pub const NOTIFY_CHANNEL_CAPACITY: usize = 16;
+// image files checked (in this order) when a song has no embedded cover art
+pub const COVER_ART_FILES: [&str; 4] = ["cover.jpg", "cover.png", "folder.jpg", "folder.png"];
+// how many songs' cover art is kept in memory
+pub const COVER_ART_CACHE_SIZE: usize = 64;
+// tags searched by `search` if the client doesn't choose any
+pub const DEFAULT_SEARCH_TAGS: [&str; 3] = ["artist", "album", "tracktitle"];
+// tags returned along with the paths of songs found by `search`
+pub const SEARCH_DISPLAY_TAGS: [&str; 3] = ["artist", "album", "tracktitle"];
 
 lazy_static! {
     pub static ref DEFAULT_ALLOWED_EXTS: HashSet<String> = HashSet::from([
@@ -25,5 +69,5 @@ lazy_static! {
         "wav".into(),
     ]);
     pub static ref DEFAULT_PLAYLIST_EXTS: HashSet<String> =
-        HashSet::from(["m3u".into(), "m3u8".into()]);
+        HashSet::from(["m3u".into(), "m3u8".into(), "pls".into()]);
 }
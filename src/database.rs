@@ -1,49 +1,80 @@
 use anyhow::{Result, bail};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use jwalk::WalkDir;
 use rayon::prelude::*;
-use serde_json::Map;
+use serde::Serialize;
+use serde_json::{Map, Value};
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
     fs::{self, File, OpenOptions},
     io::{BufReader, BufWriter, prelude::*},
     iter::{FromIterator, IntoIterator, Iterator},
-    path::{Path, PathBuf},
+    mem,
+    path::{Component, Path, PathBuf},
     time::SystemTime,
 };
 
 use crate::{
     constants,
     model::{
-        queue::Entry,
-        request::{LsArgs, MetadataArgs, SelectArgs},
-        response::Response,
-        song::{Metadata, Song},
+        comparator::Comparator,
+        cover_art_cache::CoverArtCache,
+        filter::FilterExpr,
+        play_count::PlayCounts,
+        queue::{Entry, Rng},
+        request::{
+            CountArgs, CoverArtArgs, DiskSizeArgs, LsArgs, MetadataArgs, SampleArgs, SearchArgs,
+            SelectArgs, UniqueArgs, WriteTagsArgs,
+        },
+        response::{ErrorCode, Response},
+        song::{self, CoverArt, Metadata, Song},
+        tag_key::{TagKey, TagKeyKind},
+        tag_writer,
     },
 };
 
 #[derive(Clone, Debug)]
 struct DataRow {
     song: Song,
+    root: usize, // index of the music directory in which the song was found
     pending_delete: bool,
 }
 
+// the info from an `#EXTINF` line preceding a song in an extended m3u playlist
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ExtInf {
+    pub duration: Option<u64>, // in seconds
+    pub title: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct Database {
-    music_dir: PathBuf,
+    // the first one is the "main" music directory
+    music_dirs: Vec<PathBuf>,
     playlist_dir: PathBuf,
     data_rows: Vec<DataRow>,
     playlists: HashSet<PathBuf>,
     last_update: SystemTime,
+    // if true, paths in responses are relative to the song's music directory
+    relative_paths: bool,
+    // extensions (lowercase, without the dot) of files that are added to the database
+    allowed_exts: HashSet<String>,
+    follow_symlinks: bool,
+    // if true, songs of unknown length are read in full to find out their duration
+    exact_durations: bool,
+    play_counts: PlayCounts,
+    cover_art_cache: CoverArtCache,
 }
 
 impl Database {
-    fn to_data_rows(files: &[PathBuf]) -> Vec<DataRow> {
+    fn to_data_rows(files: &[PathBuf], root: usize, exact_durations: bool) -> Vec<DataRow> {
         let mut rows: Vec<DataRow> = files
             .par_iter()
-            .filter_map(move |path| match Song::try_new(path) {
+            .filter_map(move |path| match Song::try_new(path, exact_durations) {
                 Ok(song) => Some(DataRow {
                     song,
+                    root,
                     pending_delete: false,
                 }),
                 Err(e) => {
@@ -62,25 +93,44 @@ impl Database {
             playlist_dir.as_ref(),
             SystemTime::UNIX_EPOCH,
             &constants::DEFAULT_PLAYLIST_EXTS,
+            false,
         )
         .unwrap_or_default();
 
         playlist_files.into_iter().collect()
     }
 
+    // songs from all `music_dirs` end up in one database
     pub fn try_new(
-        music_dir: impl AsRef<Path> + Into<PathBuf>,
+        music_dirs: &[PathBuf],
         playlist_dir: Option<&PathBuf>,
+        allowed_exts: Option<&HashSet<String>>,
+        follow_symlinks: bool,
+        exact_durations: bool,
     ) -> Result<Self> {
-        let files = db_utils::walk_dir(
-            music_dir.as_ref(),
-            SystemTime::UNIX_EPOCH,
-            &constants::DEFAULT_ALLOWED_EXTS,
-        )?;
-        let data_rows = Self::to_data_rows(&files);
-        let default_playlist_dir = music_dir
-            .as_ref()
-            .join(Path::new(constants::DEFAULT_PLAYLIST_DIR));
+        if music_dirs.is_empty() {
+            bail!("no music directory specified");
+        }
+        let allowed_exts = allowed_exts.unwrap_or(&constants::DEFAULT_ALLOWED_EXTS);
+        let mut canonical_music_dirs = Vec::with_capacity(music_dirs.len());
+        let mut data_rows = Vec::new();
+        for (root, music_dir) in music_dirs.iter().enumerate() {
+            let files = db_utils::walk_dir(
+                music_dir,
+                SystemTime::UNIX_EPOCH,
+                allowed_exts,
+                follow_symlinks,
+            )?;
+            // song paths are canonical, so the root has to be as well
+            // for relative paths to be stripped of it correctly
+            canonical_music_dirs.push(dunce::canonicalize(music_dir)?);
+            data_rows.extend(Self::to_data_rows(&files, root, exact_durations));
+        }
+        let music_dirs = canonical_music_dirs;
+        data_rows.par_sort_by(|lhs, rhs| lhs.song.path.cmp(&rhs.song.path));
+        // the same file could be reached from two (nested) music directories
+        data_rows.dedup_by(|lhs, rhs| lhs.song.path == rhs.song.path);
+        let default_playlist_dir = music_dirs[0].join(Path::new(constants::DEFAULT_PLAYLIST_DIR));
         let playlist_dir = playlist_dir.unwrap_or(&default_playlist_dir);
         let playlists = Self::build_playlists(playlist_dir);
         let last_update = SystemTime::now();
@@ -88,35 +138,165 @@ impl Database {
         log::warn!("{} playlists found", playlists.len());
 
         Ok(Self {
-            music_dir: music_dir.into(),
+            music_dirs,
             playlist_dir: playlist_dir.into(),
             data_rows,
             playlists,
             last_update,
+            relative_paths: false,
+            allowed_exts: allowed_exts.clone(),
+            follow_symlinks,
+            exact_durations,
+            play_counts: PlayCounts::default(),
+            cover_art_cache: CoverArtCache::default(),
         })
     }
 
+    pub fn with_relative_paths(mut self, relative_paths: bool) -> Self {
+        self.relative_paths = relative_paths;
+        self
+    }
+
+    pub fn with_play_counts(mut self, play_counts: PlayCounts) -> Self {
+        self.play_counts = play_counts;
+        self
+    }
+
+    pub fn play_counts(&self) -> &PlayCounts {
+        &self.play_counts
+    }
+
+    pub fn record_play(&mut self, path: impl Into<PathBuf>) {
+        self.play_counts.record(path);
+    }
+
+    pub fn music_dirs(&self) -> &[PathBuf] {
+        &self.music_dirs
+    }
+
+    pub fn playlist_dir(&self) -> &Path {
+        &self.playlist_dir
+    }
+
+    // converts an absolute song path to the form used in responses
+    pub fn output_path(&self, path: impl AsRef<Path>) -> String {
+        let path = path.as_ref();
+        if self.relative_paths {
+            self.strip_root(path)
+        } else {
+            path
+        }
+        .to_string_lossy()
+        .into_owned()
+    }
+
+    // songs within their music directory are saved to playlists by their relative paths,
+    // songs outside of it (e.g. reached by a symlink) have to stay absolute
+    fn playlist_entry_path<'a>(&self, abs_path: &'a Path) -> &'a Path {
+        self.strip_root(abs_path)
+    }
+
+    // strips the song's path of the music directory in which the song was found
+    fn strip_root<'a>(&self, abs_path: &'a Path) -> &'a Path {
+        let root = match db_utils::binary_search_by_path(&self.data_rows, abs_path) {
+            Some(i) => &self.music_dirs[self.data_rows[i].root],
+            None => &self.music_dirs[0],
+        };
+
+        abs_path.strip_prefix(root).unwrap_or(abs_path)
+    }
+
+    // relative paths are resolved against the first music directory
+    // in which a song (or a directory) with such a path exists
+    fn resolve_path(&self, path: impl AsRef<Path> + Into<PathBuf>) -> PathBuf {
+        if path.as_ref().is_absolute() {
+            return path.into();
+        }
+        let candidates = || self.music_dirs.iter().map(|dir| dir.join(path.as_ref()));
+
+        candidates()
+            .find(|abs_path| db_utils::binary_search_by_path(&self.data_rows, abs_path).is_some())
+            .or_else(|| candidates().find(|abs_path| abs_path.exists()))
+            .unwrap_or_else(|| self.music_dirs[0].join(path.as_ref()))
+    }
+
     // tries to find the song by the given (relative or absolute) path
     pub fn try_to_abs_path(&self, path: impl AsRef<Path>) -> Option<PathBuf> {
-        let abs_path = db_utils::to_abs_path(&self.music_dir, path.as_ref());
+        let abs_path = self.resolve_path(path.as_ref());
         db_utils::binary_search_by_path(&self.data_rows, &abs_path).map(|_| abs_path)
     }
 
+    // None if the song isn't in the database or its duration is unknown
+    pub fn duration(&self, path: impl AsRef<Path>) -> Option<u64> {
+        db_utils::binary_search_by_path(&self.data_rows, path)
+            .and_then(|i| self.data_rows[i].song.duration)
+    }
+
     pub fn playlists(&self) -> &HashSet<PathBuf> {
         &self.playlists
     }
 
-    pub fn load_playlist(&self, path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
-        let abs_path = db_utils::to_abs_path(&self.playlist_dir, path.as_ref());
+    // if `recursive` is true, lines pointing to other playlists
+    // are replaced with the songs from those playlists
+    pub fn load_playlist(&self, path: impl AsRef<Path>, recursive: bool) -> Result<Vec<PathBuf>> {
+        let playlist = self.load_playlist_with_info(path, recursive)?;
+
+        Ok(playlist.into_iter().map(|(path, _)| path).collect())
+    }
+
+    // like `load_playlist`, but songs come with the info
+    // from their `#EXTINF` lines (if the playlist has them)
+    pub fn load_playlist_with_info(
+        &self,
+        path: impl AsRef<Path>,
+        recursive: bool,
+    ) -> Result<Vec<(PathBuf, Option<ExtInf>)>> {
+        // playlists included by other playlists aren't checked,
+        // only clients' requests are
+        let abs_path = db_utils::playlist_path(&self.playlist_dir, path.as_ref())?;
+        let mut stack = Vec::new();
+        self.load_playlist_nested(&abs_path, recursive, &mut stack)
+    }
+
+    // `stack` contains (absolute) paths of playlists currently being loaded
+    fn load_playlist_nested(
+        &self,
+        path: &Path,
+        recursive: bool,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<Vec<(PathBuf, Option<ExtInf>)>> {
+        if stack.len() >= constants::MAX_PLAYLIST_DEPTH {
+            bail!(
+                "playlists nested deeper than {} levels",
+                constants::MAX_PLAYLIST_DEPTH
+            );
+        }
+        let abs_path = db_utils::to_abs_path(&self.playlist_dir, path);
         let file = File::open(&abs_path)?;
         let stream = BufReader::new(file);
-        // lines starting with `#` are comments in m3u files
-        let playlist: Vec<_> = stream
-            .lines()
-            .map_while(Result::ok)
-            .filter(|l| !l.starts_with("#"))
-            .map(|l| l.into())
-            .collect();
+        let lines = if db_utils::is_pls(&abs_path) {
+            db_utils::read_pls(stream)
+        } else {
+            db_utils::read_m3u(stream)
+        };
+        stack.push(abs_path);
+        let mut playlist = Vec::new();
+        for (line, ext_inf) in lines {
+            if recursive && db_utils::is_playlist(&line) {
+                let nested_abs_path = db_utils::to_abs_path(&self.playlist_dir, &line);
+                if stack.contains(&nested_abs_path) {
+                    log::warn!(
+                        "playlist `{}` includes itself, skipping",
+                        nested_abs_path.to_string_lossy()
+                    );
+                    continue;
+                }
+                playlist.extend(self.load_playlist_nested(&nested_abs_path, recursive, stack)?);
+            } else {
+                playlist.push((line, ext_inf));
+            }
+        }
+        stack.pop();
 
         Ok(playlist)
     }
@@ -127,92 +307,350 @@ impl Database {
         song_path: impl AsRef<Path> + Into<PathBuf>,
     ) -> Response {
         let Some(abs_song_path) = self.try_to_abs_path(&song_path) else {
-            return Response::new_err(format!(
-                "song `{}` not found in the database",
-                &song_path.as_ref().to_string_lossy()
-            ));
+            return Response::new_err(
+                ErrorCode::NotFound,
+                format!(
+                    "song `{}` not found in the database",
+                    &song_path.as_ref().to_string_lossy()
+                ),
+            );
         };
-        let abs_playlist_path = db_utils::to_abs_path(&self.playlist_dir, playlist_path.as_ref());
+        let abs_playlist_path =
+            match db_utils::playlist_path(&self.playlist_dir, playlist_path.as_ref()) {
+                Ok(abs_path) => abs_path,
+                Err(e) => return Response::new_err(ErrorCode::InvalidArg, e.to_string()),
+            };
+        // these operations edit m3u files line by line
+        if db_utils::is_pls(&abs_playlist_path) {
+            return Response::new_err(
+                ErrorCode::InvalidArg,
+                "PLS playlists can only be saved as a whole",
+            );
+        }
         let Ok(mut playlist_file) = OpenOptions::new()
             .append(true)
             .create(true)
             .open(&abs_playlist_path)
         else {
-            return Response::new_err(format!(
-                "playlist `{}` not found",
-                abs_playlist_path.to_string_lossy()
-            ));
+            return Response::new_err(
+                ErrorCode::NotFound,
+                format!(
+                    "playlist `{}` not found",
+                    abs_playlist_path.to_string_lossy()
+                ),
+            );
         };
         // we use relative song paths in playlist files, since that makes it cross-platform
         // (absolute paths differ between Unix and Windows, relative ones don't)
-        //
-        // this unwrap is fine because we know that the path is absolute and
-        // points to somewhere within the music_dir
-        let rel_song_path = abs_song_path.strip_prefix(&self.music_dir).unwrap();
+        let rel_song_path = self.playlist_entry_path(&abs_song_path);
 
-        playlist_file
+        let res = playlist_file
             .write_all(rel_song_path.as_os_str().as_encoded_bytes())
             .and_then(|_| playlist_file.write_all(b"\n"))
-            .map_err(|e| e.into())
-            .into()
+            .map_err(|e| e.into());
+        Response::from_result(res, ErrorCode::File)
     }
 
+    // `pos` is the position of a song (not counting comments and blank lines),
+    // its `#EXTINF` line is removed together with it
     pub fn remove_from_playlist(
         &mut self,
         playlist_path: impl AsRef<Path>,
         pos: usize,
     ) -> Response {
-        let abs_playlist_path = db_utils::to_abs_path(&self.playlist_dir, playlist_path.as_ref());
+        let abs_playlist_path =
+            match db_utils::playlist_path(&self.playlist_dir, playlist_path.as_ref()) {
+                Ok(abs_path) => abs_path,
+                Err(e) => return Response::new_err(ErrorCode::InvalidArg, e.to_string()),
+            };
+        // these operations edit m3u files line by line
+        if db_utils::is_pls(&abs_playlist_path) {
+            return Response::new_err(
+                ErrorCode::InvalidArg,
+                "PLS playlists can only be saved as a whole",
+            );
+        }
         let Ok(content) = fs::read_to_string(&abs_playlist_path) else {
-            return Response::new_err(format!(
-                "playlist `{}` not found",
-                abs_playlist_path.to_string_lossy()
-            ));
+            return Response::new_err(
+                ErrorCode::NotFound,
+                format!(
+                    "playlist `{}` not found",
+                    abs_playlist_path.to_string_lossy()
+                ),
+            );
         };
-        let new_content = content
-            .lines()
-            .enumerate()
-            .filter_map(|(i, line)| if i != pos { Some(line) } else { None })
-            .collect::<Vec<_>>()
-            .join("\n")
-            + "\n";
+        let mut lines: Vec<_> = content.lines().collect();
+        let song_line = (0..lines.len())
+            .filter(|&i| !lines[i].trim().is_empty() && !lines[i].starts_with("#"))
+            .nth(pos);
+        let Some(song_line) = song_line else {
+            return Response::new_ok();
+        };
+        lines.remove(song_line);
+        if song_line > 0 && lines[song_line - 1].starts_with(constants::EXTINF_PREFIX) {
+            lines.remove(song_line - 1);
+        }
+        // an empty playlist is an empty file, not a single blank line
+        let new_content = if lines.is_empty() {
+            String::new()
+        } else {
+            lines.join("\n") + "\n"
+        };
+
+        let res = fs::write(&abs_playlist_path, new_content).map_err(|e| e.into());
+        Response::from_result(res, ErrorCode::File)
+    }
+
+    // `from` and `to` are positions of songs (not counting comments),
+    // comment lines stay where they were, except for `#EXTINF` lines,
+    // which move together with the song they describe
+    pub fn move_in_playlist(
+        &mut self,
+        playlist_path: impl AsRef<Path>,
+        from: usize,
+        to: usize,
+    ) -> Response {
+        let abs_playlist_path =
+            match db_utils::playlist_path(&self.playlist_dir, playlist_path.as_ref()) {
+                Ok(abs_path) => abs_path,
+                Err(e) => return Response::new_err(ErrorCode::InvalidArg, e.to_string()),
+            };
+        // these operations edit m3u files line by line
+        if db_utils::is_pls(&abs_playlist_path) {
+            return Response::new_err(
+                ErrorCode::InvalidArg,
+                "PLS playlists can only be saved as a whole",
+            );
+        }
+        let Ok(content) = fs::read_to_string(&abs_playlist_path) else {
+            return Response::new_err(
+                ErrorCode::NotFound,
+                format!(
+                    "playlist `{}` not found",
+                    abs_playlist_path.to_string_lossy()
+                ),
+            );
+        };
+        let lines: Vec<_> = content.lines().collect();
+        let is_ext_inf = |i: usize| lines[i].starts_with(constants::EXTINF_PREFIX);
+        // a song together with its `#EXTINF` line
+        let mut songs: Vec<_> = (0..lines.len())
+            .filter(|&i| !lines[i].starts_with("#"))
+            .map(|i| {
+                if i > 0 && is_ext_inf(i - 1) {
+                    &lines[i - 1..=i]
+                } else {
+                    &lines[i..=i]
+                }
+            })
+            .collect();
+        if songs.is_empty() {
+            return Response::new_ok();
+        }
+        let from = from.min(songs.len() - 1);
+        let to = to.min(songs.len() - 1);
+        let song = songs.remove(from);
+        songs.insert(to, song);
+        let mut songs = songs.into_iter();
+        let mut new_lines = Vec::with_capacity(lines.len());
+        for (i, line) in lines.iter().enumerate() {
+            if !line.starts_with("#") {
+                new_lines.extend_from_slice(songs.next().unwrap_or_default());
+            } else if !(is_ext_inf(i) && lines.get(i + 1).is_some_and(|l| !l.starts_with("#"))) {
+                new_lines.push(*line);
+            }
+        }
+        let new_content = new_lines.join("\n") + "\n";
 
-        fs::write(&abs_playlist_path, new_content)
-            .map_err(|e| e.into())
-            .into()
+        let res = fs::write(&abs_playlist_path, new_content).map_err(|e| e.into());
+        Response::from_result(res, ErrorCode::File)
     }
 
-    pub fn save_as_playlist(&self, path: impl AsRef<Path>, entries: &[Entry]) -> Response {
-        let abs_path = db_utils::to_abs_path(&self.playlist_dir, path.as_ref());
+    // if `extended` is true, the playlist is saved in the extended m3u format
+    // (each song is preceded by an `#EXTINF` line with its duration and title)
+    // playlists with the `.pls` extension are saved in the PLS format
+    pub fn save_as_playlist(
+        &self,
+        path: impl AsRef<Path>,
+        entries: &[Entry],
+        extended: bool,
+    ) -> Response {
+        let abs_path = match db_utils::playlist_path(&self.playlist_dir, path.as_ref()) {
+            Ok(abs_path) => abs_path,
+            Err(e) => return Response::new_err(ErrorCode::InvalidArg, e.to_string()),
+        };
         let Ok(file) = File::create(&abs_path) else {
-            return Response::new_err(format!(
-                "couldn't open file `{}`",
-                abs_path.to_string_lossy()
-            ));
+            return Response::new_err(
+                ErrorCode::File,
+                format!("couldn't open file `{}`", abs_path.to_string_lossy()),
+            );
         };
         let mut stream = BufWriter::new(file);
-        for entry in entries {
-            // this unwrap is fine because we know that the path is absolute and
-            // points to somewhere within the music_dir
-            let rel_path = entry.path.strip_prefix(&self.music_dir).unwrap();
-            if let Err(e) = stream
-                .write_all(rel_path.as_os_str().as_encoded_bytes())
-                .and_then(|_| stream.write_all(b"\n"))
-            {
-                return Response::new_err(e.to_string());
+        let songs = entries.iter().map(|entry| {
+            let song = db_utils::binary_search_by_path(&self.data_rows, &entry.path)
+                .map(|i| &self.data_rows[i].song);
+            let (duration, title) = db_utils::ext_inf(&entry.path, song);
+            (self.playlist_entry_path(&entry.path), duration, title)
+        });
+        let res = if db_utils::is_pls(&abs_path) {
+            db_utils::write_pls(&mut stream, songs)
+        } else {
+            db_utils::write_m3u(&mut stream, songs, extended)
+        };
+        if let Err(e) = res.and_then(|_| stream.flush()) {
+            return Response::new_err(ErrorCode::File, e.to_string());
+        }
+
+        Response::new_ok()
+    }
+
+    pub fn rename_playlist(
+        &mut self,
+        playlist_path: impl AsRef<Path>,
+        new_path: impl AsRef<Path>,
+    ) -> Response {
+        let (abs_path, new_abs_path) = match (
+            db_utils::playlist_path(&self.playlist_dir, playlist_path.as_ref()),
+            db_utils::playlist_path(&self.playlist_dir, new_path.as_ref()),
+        ) {
+            (Ok(abs_path), Ok(new_abs_path)) => (abs_path, new_abs_path),
+            (Err(e), _) | (_, Err(e)) => {
+                return Response::new_err(ErrorCode::InvalidArg, e.to_string());
             }
+        };
+        if !abs_path.is_file() {
+            return Response::new_err(
+                ErrorCode::NotFound,
+                format!("playlist `{}` not found", abs_path.to_string_lossy()),
+            );
+        }
+        if new_abs_path.exists() {
+            return Response::new_err(
+                ErrorCode::InvalidArg,
+                format!(
+                    "playlist `{}` already exists",
+                    new_abs_path.to_string_lossy()
+                ),
+            );
+        }
+        if let Err(e) = fs::rename(&abs_path, &new_abs_path) {
+            return Response::new_err(ErrorCode::File, e.to_string());
+        }
+        self.playlists.remove(&abs_path);
+        self.playlists.insert(new_abs_path);
+
+        Response::new_ok()
+    }
+
+    pub fn delete_playlist(&mut self, playlist_path: impl AsRef<Path>) -> Response {
+        let abs_path = match db_utils::playlist_path(&self.playlist_dir, playlist_path.as_ref()) {
+            Ok(abs_path) => abs_path,
+            Err(e) => return Response::new_err(ErrorCode::InvalidArg, e.to_string()),
+        };
+        if !abs_path.is_file() {
+            return Response::new_err(
+                ErrorCode::NotFound,
+                format!("playlist `{}` not found", abs_path.to_string_lossy()),
+            );
+        }
+        if let Err(e) = fs::remove_file(&abs_path) {
+            return Response::new_err(ErrorCode::File, e.to_string());
         }
-        let _ = stream.flush();
+        self.playlists.remove(&abs_path);
+
+        Response::new_ok()
+    }
+
+    // get the cover art of the song located at `path`
+    // embedded images take precedence over image files in the song's directory
+    pub fn cover_art(&mut self, CoverArtArgs(path): CoverArtArgs) -> Response {
+        let Some(abs_path) = self.try_to_abs_path(&path) else {
+            return Response::new_err(
+                ErrorCode::NotFound,
+                format!(
+                    "song `{}` not found in the database",
+                    path.to_string_lossy()
+                ),
+            );
+        };
+        let cover_art = self.cached_cover_art(abs_path);
 
         Response::new_ok()
+            .with_item("cover_art", &cover_art.map(|art| art.base64()))
+            .with_item("mime", &cover_art.and_then(|art| art.mime.as_deref()))
+    }
+
+    // the hash of the cover art of the song located at `path` (cheaper than the art itself)
+    pub fn cover_art_hash(&mut self, path: &Path) -> Option<String> {
+        let abs_path = self.try_to_abs_path(path)?;
+
+        self.cached_cover_art(abs_path).map(|art| art.hash.clone())
+    }
+
+    fn cached_cover_art(&mut self, abs_path: PathBuf) -> Option<&CoverArt> {
+        let mod_time = abs_path
+            .metadata()
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        self.cover_art_cache.get_or_load(&abs_path, mod_time, || {
+            song::cover_art(&abs_path).or_else(|| song::sidecar_cover_art(&abs_path))
+        })
+    }
+
+    // get the total size (in bytes) and number of files in the database,
+    // optionally also per every value of the `group_by` tag
+    pub fn disk_size(&self, DiskSizeArgs(group_by): DiskSizeArgs) -> Response {
+        let size: u64 = self.data_rows.par_iter().map(|row| row.song.size).sum();
+        let response = Response::new_ok()
+            .with_item("size", &size)
+            .with_item("files", &self.data_rows.len());
+        let Some(group_by) = group_by else {
+            return response;
+        };
+
+        let groups = self
+            .data_rows
+            .par_iter()
+            .fold(HashMap::new, |mut groups, row| {
+                let (size, files) = groups
+                    .entry(row.song.metadata.get(&group_by))
+                    .or_insert((0, 0));
+                *size += row.song.size;
+                *files += 1;
+
+                groups
+            })
+            .reduce(HashMap::new, |mut lhs, rhs| {
+                for (value, (size, files)) in rhs {
+                    let entry = lhs.entry(value).or_insert((0, 0));
+                    entry.0 += size;
+                    entry.1 += files;
+                }
+
+                lhs
+            });
+        let groups: Vec<_> = groups
+            .into_iter()
+            .map(|(value, (size, files))| {
+                let mut json_map = Map::new();
+                json_map.insert(group_by.to_string(), value.into());
+                json_map.insert("size".into(), size.into());
+                json_map.insert("files".into(), files.into());
+
+                json_map
+            })
+            .collect();
+
+        response.with_item("groups", &groups)
     }
 
     // get paths of songs located in `path`
     // allows to use musing with untagged music collections
-    // `path` can be relative (to the provided music dir) or absolute
+    // `path` can be relative (to one of the music dirs) or absolute
     // if `path` points to a single file, ls returns the path of that file
     pub fn ls(&self, LsArgs(path): LsArgs) -> Response {
-        let abs_path = db_utils::to_abs_path(&self.music_dir, &path);
+        let abs_path = self.resolve_path(path);
         match abs_path.metadata() {
             Ok(meta) => {
                 let paths = if meta.is_file() {
@@ -221,7 +659,7 @@ impl Database {
                         .par_iter()
                         .find_any(|&row| row.song.path == abs_path)
                     {
-                        Some(row) => vec![&row.song.path],
+                        Some(row) => vec![self.output_path(&row.song.path)],
                         None => vec![],
                     }
                 } else {
@@ -231,7 +669,7 @@ impl Database {
                             if let Some(parent) = row.song.path.parent()
                                 && parent == abs_path
                             {
-                                Some(&row.song.path)
+                                Some(self.output_path(&row.song.path))
                             } else {
                                 None
                             }
@@ -241,40 +679,64 @@ impl Database {
 
                 Response::new_ok().with_item("paths", &paths)
             }
-            Err(e) => Response::new_err(e.to_string()),
+            Err(e) => Response::new_err(ErrorCode::NotFound, e.to_string()),
         }
     }
 
-    // get values of `tags` for songs in `paths`
-    pub fn metadata(&self, MetadataArgs(paths, tags): MetadataArgs) -> Response {
-        let metadata: Vec<_> = paths
+    // get values of `tags` (and `freeform` tags) for songs in `paths`
+    pub fn metadata(&self, MetadataArgs(paths, tags, freeform): MetadataArgs) -> Response {
+        let metadata = self.metadata_values(paths, &tags, &freeform);
+
+        Response::new_ok().with_item("metadata", &metadata)
+    }
+
+    // `None` for songs not found in the database
+    pub fn metadata_values(
+        &self,
+        paths: Vec<PathBuf>,
+        tags: &[TagKey],
+        freeform: &[String],
+    ) -> Vec<Option<Map<String, Value>>> {
+        let abs_paths: Vec<_> = paths
+            .into_iter()
+            .map(|path| self.resolve_path(path))
+            .collect();
+        db_utils::search_by_paths(&self.data_rows, &abs_paths)
             .into_par_iter()
-            .map(|path| {
-                let abs_path = db_utils::to_abs_path(&self.music_dir, path);
-                db_utils::binary_search_by_path(&self.data_rows, abs_path).map(|i| {
-                    let data = tags.iter().map(|tag| {
-                        let value = self.data_rows[i].song.metadata.get(tag).into();
-                        (tag.to_string(), value)
-                    });
+            .map(|row_i| {
+                row_i.map(|i| {
+                    let metadata = &self.data_rows[i].song.metadata;
+                    let data = tags
+                        .iter()
+                        .map(|tag| (tag.to_string(), metadata.get_all(tag)))
+                        .chain(
+                            freeform
+                                .iter()
+                                .map(|key| (key.clone(), metadata.get_freeform(key))),
+                        )
+                        .map(|(key, values)| (key, db_utils::tag_value(values)));
 
                     // additional non-standard tags that clients
                     // will generally want to use
                     let mut map = Map::from_iter(data);
-                    map.insert(
-                        "duration".to_string(),
-                        self.data_rows[i]
-                            .song
-                            .duration
-                            .map(|d| d.to_string())
-                            .into(),
-                    );
+                    let song = &self.data_rows[i].song;
+                    let extra = [
+                        ("duration", song.duration),
+                        ("sample_rate", song.params.sample_rate.map(u64::from)),
+                        ("channels", song.params.channels.map(|c| c as u64)),
+                        ("bitrate", song.params.bitrate),
+                        ("filesize", Some(song.size)),
+                        ("mtime", song.mtime),
+                        ("playcount", Some(self.play_counts.get(&song.path) as u64)),
+                    ];
+                    for (key, value) in extra {
+                        map.insert(key.to_string(), value.map(|v| v.to_string()).into());
+                    }
 
-                    Some(map)
+                    map
                 })
             })
-            .collect();
-
-        Response::new_ok().with_item("metadata", &metadata)
+            .collect()
     }
 
     // get paths of songs (together with their `tags` metadata), matching `filter_expr`
@@ -308,7 +770,7 @@ impl Database {
                     .iter()
                     .map(|tag| song.metadata.get(tag).map(String::from))
                     .collect();
-                song_data.push(Some(song.path.to_string_lossy().into_owned()));
+                song_data.push(Some(self.output_path(&song.path)));
 
                 song_data
             };
@@ -336,25 +798,256 @@ impl Database {
         Response::new_ok().with_item("values", &values)
     }
 
+    // paths of songs in which any of `tags` contains `query`,
+    // along with a few tags for clients to display them with
+    pub fn search(&self, SearchArgs(query, tags): SearchArgs) -> Response {
+        let filter_expr = FilterExpr::contains_any(&tags, &query);
+        let display_tags: Vec<_> = constants::SEARCH_DISPLAY_TAGS
+            .iter()
+            .filter_map(|&name| TagKey::try_from(name).ok())
+            .collect();
+        let results: Vec<_> =
+            self.data_rows
+                .par_iter()
+                .filter(|row| filter_expr.evaluate(&row.song))
+                .map(|row| {
+                    let metadata = &row.song.metadata;
+                    let mut map =
+                        Map::from_iter(display_tags.iter().map(|tag| {
+                            (tag.to_string(), db_utils::tag_value(metadata.get_all(tag)))
+                        }));
+                    map.insert("path".into(), self.output_path(&row.song.path).into());
+
+                    map
+                })
+                .collect();
+
+        Response::new_ok().with_item("results", &results)
+    }
+
+    // absolute paths of songs matching `filter_expr`, sorted by disc and then
+    // by track number (songs without them go first, ties are sorted by path)
+    pub fn album_paths(&self, filter_expr: &FilterExpr) -> Vec<PathBuf> {
+        let sort_by: Vec<_> = ["discnumber", "tracknumber"]
+            .into_iter()
+            .filter_map(|name| TagKey::try_from(name).ok())
+            .map(Comparator::from)
+            .collect();
+        let mut filtered: Vec<_> = self
+            .data_rows
+            .par_iter()
+            .filter(|row| filter_expr.evaluate(&row.song))
+            .collect();
+        // a stable sort, `data_rows` are sorted by path
+        filtered.par_sort_by(|lhs, rhs| {
+            sort_by
+                .iter()
+                .map(|cmp| cmp.cmp(&lhs.song.metadata, &rhs.song.metadata))
+                .find(|&ord| ord != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        });
+
+        filtered
+            .into_iter()
+            .map(|row| row.song.path.clone())
+            .collect()
+    }
+
+    // up to `n` random songs matching `filter_expr` (in random order)
+    pub fn sample(&self, SampleArgs(n, filter_expr): SampleArgs) -> Response {
+        // every matching song gets a random key and the ones with the `n` smallest
+        // keys are picked, so every thread needs to keep only `n` songs
+        // and the samples of different threads are easy to merge
+        let sample = self
+            .data_rows
+            .par_iter()
+            .enumerate()
+            .filter(|(_, row)| filter_expr.evaluate(&row.song))
+            .fold(
+                || (Rng::default(), BinaryHeap::new()),
+                |(mut rng, mut sample), (i, _)| {
+                    db_utils::push_bounded(&mut sample, (rng.next_u64(), i), n);
+                    (rng, sample)
+                },
+            )
+            .map(|(_, sample)| sample)
+            .reduce(BinaryHeap::new, |mut lhs, rhs| {
+                for item in rhs {
+                    db_utils::push_bounded(&mut lhs, item, n);
+                }
+                lhs
+            });
+        let paths: Vec<_> = sample
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(_, i)| self.output_path(&self.data_rows[i].song.path))
+            .collect();
+
+        Response::new_ok().with_item("paths", &paths)
+    }
+
+    // get the number and total duration (in seconds) of songs matching `filter_expr`,
+    // grouped by tags in `group_by`
+    pub fn count(&self, CountArgs(filter_expr, group_by): CountArgs) -> Response {
+        let groups = self
+            .data_rows
+            .par_iter()
+            .filter(|row| filter_expr.evaluate(&row.song))
+            .fold(HashMap::new, |mut groups, row| {
+                let combination: Vec<_> = group_by
+                    .iter()
+                    .map(|group_tag| row.song.metadata.get(group_tag))
+                    .collect();
+                let (songs, total_duration) = groups.entry(combination).or_insert((0, 0));
+                *songs += 1;
+                *total_duration += row.song.duration.unwrap_or(0);
+
+                groups
+            })
+            .reduce(HashMap::new, |mut lhs, rhs| {
+                for (combination, (songs, total_duration)) in rhs {
+                    let entry = lhs.entry(combination).or_insert((0, 0));
+                    entry.0 += songs;
+                    entry.1 += total_duration;
+                }
+
+                lhs
+            });
+        let values: Vec<_> = groups
+            .into_iter()
+            .map(|(combination, (songs, total_duration))| {
+                let group_by_data = group_by
+                    .iter()
+                    .map(|tag_key| tag_key.to_string())
+                    .zip(combination.into_iter().map(|value| value.into()));
+                let mut json_map = Map::from_iter(group_by_data);
+                json_map.insert("songs".into(), songs.into());
+                json_map.insert("total_duration".into(), total_duration.into());
+
+                json_map
+            })
+            .collect();
+
+        Response::new_ok().with_item("values", &values)
+    }
+
+    // library-wide totals
+    pub fn stats(&self) -> Response {
+        let (artist, album) = (
+            TagKey::try_from("artist").unwrap(),
+            TagKey::try_from("album").unwrap(),
+        );
+        let (artists, albums) = self
+            .data_rows
+            .par_iter()
+            .fold(
+                || (HashSet::new(), HashSet::new()),
+                |(mut artists, mut albums), row| {
+                    if let Some(value) = row.song.metadata.get(&artist) {
+                        artists.insert(value);
+                    }
+                    if let Some(value) = row.song.metadata.get(&album) {
+                        albums.insert(value);
+                    }
+
+                    (artists, albums)
+                },
+            )
+            .reduce(
+                || (HashSet::new(), HashSet::new()),
+                |(mut artists, mut albums), (rhs_artists, rhs_albums)| {
+                    artists.extend(rhs_artists);
+                    albums.extend(rhs_albums);
+
+                    (artists, albums)
+                },
+            );
+        let total_duration: u64 = self
+            .data_rows
+            .par_iter()
+            .filter_map(|row| row.song.duration)
+            .sum();
+
+        Response::new_ok()
+            .with_item("songs", &self.data_rows.len())
+            .with_item("total_duration", &total_duration)
+            .with_item("artists", &artists.len())
+            .with_item("albums", &albums.len())
+            .with_item("playlists", &self.playlists.len())
+    }
+
+    // returns distinct values of `tag` among songs that match the `filter_expr`,
+    // grouped by tags in `group_by` with each group sorted
+    pub fn unique(&self, UniqueArgs(tag, filter_expr, group_by): UniqueArgs) -> Response {
+        let filtered: Vec<_> = self
+            .data_rows
+            .par_iter()
+            .filter(|row| filter_expr.evaluate(&row.song))
+            .collect();
+
+        let mut groups = HashMap::new();
+        for row in filtered {
+            let metadata = &row.song.metadata;
+            let Some(value) = metadata.get(&tag) else {
+                continue;
+            };
+            let combination: Vec<_> = group_by
+                .iter()
+                .map(|group_tag| metadata.get(group_tag))
+                .collect();
+            // the first spelling of a value encountered is the one that gets returned
+            groups
+                .entry(combination)
+                .or_insert_with(BTreeMap::new)
+                .entry(db_utils::unique_key(value, tag.kind))
+                .or_insert(value);
+        }
+        let values: Vec<_> = groups
+            .into_iter()
+            .map(|(combination, values)| {
+                let group_by_data = group_by
+                    .iter()
+                    .map(|tag_key| tag_key.to_string())
+                    .zip(combination.into_iter().map(|value| value.into()));
+                let mut json_map = Map::from_iter(group_by_data);
+                json_map.insert("data".into(), values.into_values().collect());
+
+                json_map
+            })
+            .collect();
+
+        Response::new_ok().with_item("values", &values)
+    }
+
     pub fn update(&mut self) -> Response {
-        // do a full rescan if the ignore file changed recently
-        if let Ok(ignore_mod_time) = self
-            .music_dir
-            .join(Path::new(constants::DEFAULT_IGNORE_FILE))
-            .metadata()
-            .and_then(|m| m.modified())
-            && ignore_mod_time >= self.last_update
-        {
-            return match Self::try_new(&self.music_dir, Some(&self.playlist_dir)) {
+        // do a full rescan if any ignore file changed recently
+        let ignore_changed = self.music_dirs.iter().any(|music_dir| {
+            music_dir
+                .join(Path::new(constants::DEFAULT_IGNORE_FILE))
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|ignore_mod_time| ignore_mod_time >= self.last_update)
+        });
+        if ignore_changed {
+            return match Self::try_new(
+                &self.music_dirs,
+                Some(&self.playlist_dir),
+                Some(&self.allowed_exts),
+                self.follow_symlinks,
+                self.exact_durations,
+            ) {
                 Ok(db) => {
                     let n_removed = self.data_rows.len();
-                    *self = db;
+                    let play_counts = mem::take(&mut self.play_counts);
+                    *self = db
+                        .with_relative_paths(self.relative_paths)
+                        .with_play_counts(play_counts);
 
                     Response::new_ok()
                         .with_item("added_songs", &self.data_rows.len())
                         .with_item("removed_songs", &n_removed)
                 }
-                Err(e) => Response::new_err(e.to_string()),
+                Err(e) => Response::new_err(ErrorCode::Database, e.to_string()),
             };
         }
 
@@ -371,20 +1064,29 @@ impl Database {
                 row.pending_delete = true;
             }
         });
+        for row in self.data_rows.iter().filter(|row| row.pending_delete) {
+            self.cover_art_cache.invalidate(&row.song.path);
+        }
         let old_len = self.data_rows.len();
         self.data_rows.retain(|row| !row.pending_delete);
         let n_removed = old_len - self.data_rows.len();
 
-        let added_songs = match db_utils::walk_dir(
-            &self.music_dir,
-            self.last_update,
-            &constants::DEFAULT_ALLOWED_EXTS,
-        ) {
-            Ok(added_songs) => added_songs,
-            Err(e) => return Response::new_err(e.to_string()),
-        };
-        let mut added_data_rows = Self::to_data_rows(&added_songs);
-        added_data_rows.par_sort_unstable_by(|lhs, rhs| lhs.song.path.cmp(&rhs.song.path));
+        let mut added_data_rows = Vec::new();
+        for (root, music_dir) in self.music_dirs.iter().enumerate() {
+            let added_songs = match db_utils::walk_dir(
+                music_dir,
+                self.last_update,
+                &self.allowed_exts,
+                self.follow_symlinks,
+            ) {
+                Ok(added_songs) => added_songs,
+                Err(e) => return Response::new_err(ErrorCode::Database, e.to_string()),
+            };
+            added_data_rows.extend(Self::to_data_rows(&added_songs, root, self.exact_durations));
+        }
+        added_data_rows.par_sort_by(|lhs, rhs| lhs.song.path.cmp(&rhs.song.path));
+        added_data_rows.dedup_by(|lhs, rhs| lhs.song.path == rhs.song.path);
+        let n_added = added_data_rows.len();
         // merge old rows with new ones while keeping the sorted order
         let mut new_data_rows = Vec::with_capacity(self.data_rows.len() + added_data_rows.len());
         {
@@ -411,15 +1113,82 @@ impl Database {
         self.last_update = SystemTime::now();
 
         Response::new_ok()
-            .with_item("added_songs", &added_songs.len())
+            .with_item("added_songs", &n_added)
             .with_item("removed_songs", &n_removed)
     }
-}
 
-mod db_utils {
-    use super::*;
-
-    pub fn to_abs_path<S, T>(root_dir: S, path: T) -> PathBuf
+    // writes the tags to the song's file and re-reads its metadata,
+    // so that the change is visible without an `update`
+    pub fn write_tags(&mut self, WriteTagsArgs(path, tags): WriteTagsArgs) -> Response {
+        let Some(abs_path) = self.try_to_abs_path(&path) else {
+            return Response::new_err(
+                ErrorCode::NotFound,
+                format!(
+                    "song `{}` not found in the database",
+                    path.to_string_lossy()
+                ),
+            );
+        };
+        if let Err(e) = tag_writer::write_tags(&abs_path, &tags) {
+            return Response::new_err(ErrorCode::File, e.to_string());
+        }
+        let song = match Song::try_new(&abs_path, self.exact_durations) {
+            Ok(song) => song,
+            Err(e) => return Response::new_err(ErrorCode::File, e.to_string()),
+        };
+        if let Some(i) = db_utils::binary_search_by_path(&self.data_rows, &abs_path) {
+            self.data_rows[i].song = song;
+        }
+
+        Response::new_ok()
+    }
+}
+
+mod db_utils {
+    use super::*;
+
+    // keeps the `n` smallest items in a max-heap
+    pub fn push_bounded<T: Ord>(heap: &mut BinaryHeap<T>, item: T, n: usize) {
+        if heap.len() < n {
+            heap.push(item);
+        } else if let Some(mut largest) = heap.peek_mut()
+            && item < *largest
+        {
+            *largest = item;
+        }
+    }
+
+    // tags with many values become arrays
+    pub fn tag_value(values: &[String]) -> Value {
+        match values {
+            [] => Value::Null,
+            [value] => value.as_str().into(),
+            values => values.into(),
+        }
+    }
+
+    // values that compare equal are considered duplicates
+    // (numbers go first, followed by values that couldn't be parsed as numbers)
+    #[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+    pub enum UniqueKey {
+        Number(i64),
+        Text(String),
+    }
+
+    pub fn unique_key(value: &str, kind: TagKeyKind) -> UniqueKey {
+        let number = match kind {
+            TagKeyKind::String => None,
+            TagKeyKind::Integer => value.trim().parse().ok(),
+            TagKeyKind::OutOf => value.split('/').next().and_then(|n| n.trim().parse().ok()),
+        };
+
+        match number {
+            Some(number) => UniqueKey::Number(number),
+            None => UniqueKey::Text(value.to_lowercase()),
+        }
+    }
+
+    pub fn to_abs_path<S, T>(root_dir: S, path: T) -> PathBuf
     where
         S: AsRef<Path>,
         T: AsRef<Path> + Into<PathBuf>,
@@ -431,6 +1200,183 @@ mod db_utils {
         }
     }
 
+    // parses `#EXTINF:<duration>[ <attributes>],<title>`,
+    // returns None if the line is malformed
+    pub fn parse_ext_inf(line: &str) -> Option<ExtInf> {
+        let (duration, title) = line
+            .strip_prefix(constants::EXTINF_PREFIX)?
+            .split_once(',')?;
+        let duration: f64 = duration.split_whitespace().next()?.parse().ok()?;
+        let title = title.trim();
+
+        Some(ExtInf {
+            // a negative duration means that it's unknown
+            duration: (duration >= 0.0).then_some(duration.round() as u64),
+            title: (!title.is_empty()).then(|| title.to_string()),
+        })
+    }
+
+    // the duration (-1 if unknown) and the title of the song located at `path`
+    // (as saved in extended m3u and PLS playlists)
+    pub fn ext_inf(path: &Path, song: Option<&Song>) -> (i64, String) {
+        let duration = song
+            .and_then(|song| song.duration)
+            .map_or(-1, |duration| duration as i64);
+        let tag = |name| song.and_then(|song| song.metadata.get(&TagKey::try_from(name).unwrap()));
+        let title = match (tag("artist"), tag("tracktitle")) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.to_string(),
+            _ => path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        // titles can't span many lines
+        let title = title.replace(['\n', '\r'], " ");
+
+        (duration, title)
+    }
+
+    // lines starting with `#` are comments in m3u files
+    // (except for `#EXTINF`, which describes the next song)
+    pub fn read_m3u(stream: impl BufRead) -> Vec<(PathBuf, Option<ExtInf>)> {
+        let mut songs = Vec::new();
+        let mut ext_inf = None;
+        for line in stream.lines().map_while(Result::ok) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if line.starts_with("#") {
+                if line.starts_with(constants::EXTINF_PREFIX) {
+                    ext_inf = parse_ext_inf(&line);
+                }
+                continue;
+            }
+            songs.push((PathBuf::from(line), ext_inf.take()));
+        }
+
+        songs
+    }
+
+    // PLS files are INI-like, the n-th song is described by the keys
+    // `Filen`, `Titlen` and `Lengthn` (other keys are ignored)
+    pub fn read_pls(stream: impl BufRead) -> Vec<(PathBuf, Option<ExtInf>)> {
+        let mut songs: BTreeMap<u32, (Option<PathBuf>, Option<ExtInf>)> = BTreeMap::new();
+        for line in stream.lines().map_while(Result::ok) {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let Some(i) = key.find(|c: char| c.is_ascii_digit()) else {
+                continue;
+            };
+            let Ok(n) = key[i..].parse() else {
+                continue;
+            };
+            let (path, ext_inf) = songs.entry(n).or_default();
+            match key[..i].to_lowercase().as_str() {
+                "file" => *path = Some(PathBuf::from(value)),
+                "title" if !value.is_empty() => {
+                    ext_inf.get_or_insert_default().title = Some(value.to_string());
+                }
+                "length" => {
+                    // a negative length means that it's unknown
+                    if let Ok(length) = value.parse::<f64>()
+                        && length >= 0.0
+                    {
+                        ext_inf.get_or_insert_default().duration = Some(length.round() as u64);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        songs
+            .into_values()
+            .filter_map(|(path, ext_inf)| path.map(|path| (path, ext_inf)))
+            .collect()
+    }
+
+    // `songs` are (path, duration, title)
+    pub fn write_m3u<'a>(
+        stream: &mut impl Write,
+        songs: impl Iterator<Item = (&'a Path, i64, String)>,
+        extended: bool,
+    ) -> std::io::Result<()> {
+        if extended {
+            stream.write_all(b"#EXTM3U\n")?;
+        }
+        for (path, duration, title) in songs {
+            if extended {
+                writeln!(stream, "{}{},{}", constants::EXTINF_PREFIX, duration, title)?;
+            }
+            stream.write_all(path.as_os_str().as_encoded_bytes())?;
+            stream.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    // `songs` are (path, duration, title)
+    pub fn write_pls<'a>(
+        stream: &mut impl Write,
+        songs: impl Iterator<Item = (&'a Path, i64, String)>,
+    ) -> std::io::Result<()> {
+        stream.write_all(b"[playlist]\n")?;
+        let mut n = 0;
+        for (path, duration, title) in songs {
+            n += 1;
+            write!(stream, "File{}=", n)?;
+            stream.write_all(path.as_os_str().as_encoded_bytes())?;
+            writeln!(stream, "\nTitle{}={}\nLength{}={}", n, title, n, duration)?;
+        }
+        writeln!(stream, "NumberOfEntries={}\nVersion=2", n)?;
+
+        Ok(())
+    }
+
+    // the absolute path of a playlist file, which has to be located
+    // inside of the playlist directory (so that clients can't touch other files)
+    pub fn playlist_path(playlist_dir: &Path, path: &Path) -> Result<PathBuf> {
+        let abs_path = to_abs_path(playlist_dir, path);
+        // symlinks are resolved, so that they can't lead outside either
+        // (the playlist itself may not exist yet, but its directory has to)
+        let canonicalize = |path: &Path| dunce::canonicalize(path).unwrap_or(path.into());
+        let canonical_path = match (abs_path.parent(), abs_path.file_name()) {
+            _ if abs_path.exists() => canonicalize(&abs_path),
+            (Some(parent), Some(file_name)) => canonicalize(parent).join(file_name),
+            _ => abs_path.clone(),
+        };
+        let escapes = abs_path
+            .components()
+            .any(|component| matches!(component, Component::ParentDir))
+            || !canonical_path.starts_with(canonicalize(playlist_dir));
+        if escapes {
+            bail!(
+                "`{}` is outside of the playlist directory",
+                path.to_string_lossy()
+            );
+        }
+        if !is_playlist(&abs_path) {
+            bail!("`{}` isn't a playlist file", path.to_string_lossy());
+        }
+
+        Ok(abs_path)
+    }
+
+    pub fn is_pls(path: impl AsRef<Path>) -> bool {
+        path.as_ref()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("pls"))
+    }
+
+    pub fn is_playlist(path: impl AsRef<Path>) -> bool {
+        path.as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| constants::DEFAULT_PLAYLIST_EXTS.contains(ext))
+    }
+
     pub fn binary_search_by_path(rows: &[DataRow], path: impl AsRef<Path>) -> Option<usize> {
         let n = rows.len();
         if n == 0 {
@@ -447,11 +1393,93 @@ mod db_utils {
         (rows[i].song.path == path.as_ref()).then_some(i)
     }
 
-    // returns absolute paths
+    // finds indices of rows with the given paths (in the same order as `paths`)
+    // instead of doing an independent binary search for every path, we sort
+    // the paths and look for each one only after the previous match
+    pub fn search_by_paths(rows: &[DataRow], paths: &[PathBuf]) -> Vec<Option<usize>> {
+        let mut order: Vec<_> = (0..paths.len()).collect();
+        order.sort_unstable_by(|&lhs, &rhs| paths[lhs].cmp(&paths[rhs]));
+        let mut found = vec![None; paths.len()];
+        let mut start = 0;
+        for i in order {
+            start += rows[start..].partition_point(|row| row.song.path < paths[i]);
+            if rows.get(start).is_some_and(|row| row.song.path == paths[i]) {
+                found[i] = Some(start);
+            }
+        }
+
+        found
+    }
+
+    // lines of the ignore file are either paths of directories
+    // or gitignore-like glob patterns (e.g. `*.tmp` or `**/demos/`)
+    #[derive(Default)]
+    pub struct IgnoreRules {
+        dirs: HashSet<PathBuf>, // absolute paths
+        globs: GlobSet,
+        // patterns ending with a `/` match only directories
+        dir_globs: GlobSet,
+    }
+
+    impl IgnoreRules {
+        pub fn from_file(root_dir: &Path) -> Result<Self> {
+            let Ok(file) = File::open(root_dir.join(constants::DEFAULT_IGNORE_FILE)) else {
+                return Ok(Self::default());
+            };
+            let mut dirs = HashSet::new();
+            let (mut globs, mut dir_globs) = (GlobSetBuilder::new(), GlobSetBuilder::new());
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                dirs.insert(to_abs_path(root_dir, Path::new(line)));
+                let (pattern, dir_only) = match line.strip_suffix('/') {
+                    Some(pattern) => (pattern, true),
+                    None => (line, false),
+                };
+                // like in .gitignore, a pattern without slashes matches at any depth
+                let pattern = if pattern.contains('/') {
+                    pattern.trim_start_matches('/').to_string()
+                } else {
+                    format!("**/{}", pattern)
+                };
+                // e.g. a plain directory name with a `{` in it, which is still ignored as a path
+                let glob = match GlobBuilder::new(&pattern).literal_separator(true).build() {
+                    Ok(glob) => glob,
+                    Err(e) => {
+                        log::warn!("`{}` isn't a valid ignore pattern ({})", line, e);
+                        continue;
+                    }
+                };
+                if dir_only {
+                    dir_globs.add(glob);
+                } else {
+                    globs.add(glob);
+                }
+            }
+
+            Ok(Self {
+                dirs,
+                globs: globs.build()?,
+                dir_globs: dir_globs.build()?,
+            })
+        }
+
+        // `rel_path` is relative to the root directory
+        pub fn is_ignored(&self, parent_path: &Path, rel_path: &Path, is_dir: bool) -> bool {
+            self.dirs.contains(parent_path)
+                || self.globs.is_match(rel_path)
+                || (is_dir && self.dir_globs.is_match(rel_path))
+        }
+    }
+
+    // returns absolute (canonical) paths, without duplicates
     pub fn walk_dir(
         root_dir: impl AsRef<Path>,
         timestamp: SystemTime,
         allowed_exts: &HashSet<String>,
+        follow_symlinks: bool,
     ) -> Result<Vec<PathBuf>> {
         let is_ok = move |path: &Path| -> bool {
             if let Some(ext) = path.extension().and_then(|ext| ext.to_str())
@@ -470,20 +1498,21 @@ mod db_utils {
                 root_dir.as_ref().to_string_lossy()
             ));
         }
-        let mut ignored = HashSet::new();
-        if let Ok(file) = File::open(root_dir.as_ref().join(constants::DEFAULT_IGNORE_FILE)) {
-            let stream = BufReader::new(file);
-            for line in stream.lines().map_while(Result::ok) {
-                let abs_path = db_utils::to_abs_path(&root_dir, Path::new(&line));
-                ignored.insert(abs_path);
-            }
-        }
-        let list = WalkDir::new(root_dir)
+        let ignore_rules = IgnoreRules::from_file(root_dir.as_ref())?;
+        let walk_root = root_dir.as_ref().to_path_buf();
+        // pruning a directory here means that it won't be descended into
+        // jwalk skips symlinks that would create a cycle
+        let mut list: Vec<_> = WalkDir::new(root_dir)
+            .follow_links(follow_symlinks)
             .process_read_dir(move |_, _, _, children| {
                 children.retain(|entry| {
                     entry
                         .as_ref()
-                        .map(|e| !ignored.contains(&*(e.parent_path)))
+                        .map(|e| {
+                            let path = e.parent_path.join(&e.file_name);
+                            let rel_path = path.strip_prefix(&walk_root).unwrap_or(&path);
+                            !ignore_rules.is_ignored(&e.parent_path, rel_path, e.file_type.is_dir())
+                        })
                         .unwrap_or(false)
                 });
             })
@@ -500,6 +1529,10 @@ mod db_utils {
             })
             .collect();
 
+        // the same file can be reached through many symlinks
+        list.par_sort_unstable();
+        list.dedup();
+
         Ok(list)
     }
 }
@@ -507,22 +1540,582 @@ mod db_utils {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::model::song::AudioParams;
+    use symphonia::core::meta::{MetadataBuilder, StandardTagKey, Tag, Value};
+
+    // a song at `path` with the given standard tags
+    fn row(path: impl Into<PathBuf>, tags: &[(StandardTagKey, &str)]) -> DataRow {
+        let mut builder = MetadataBuilder::new();
+        for &(std_key, value) in tags {
+            builder.add_tag(Tag::new(Some(std_key), "", Value::String(value.into())));
+        }
+        DataRow {
+            song: Song {
+                path: path.into(),
+                metadata: Metadata::from(&builder.metadata()),
+                duration: None,
+                size: 0,
+                mtime: None,
+                params: AudioParams::default(),
+            },
+            root: 0,
+            pending_delete: false,
+        }
+    }
+
+    // a fresh directory that no other test uses
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!(
+            "musing_test_{}_{}_{}",
+            name,
+            std::process::id(),
+            nanos
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    // a database of the given songs that never touches the disk
+    fn test_db(data_rows: Vec<DataRow>) -> Database {
+        Database {
+            music_dirs: vec!["/music".into()],
+            playlist_dir: "/music/playlists".into(),
+            data_rows,
+            playlists: HashSet::new(),
+            last_update: SystemTime::now(),
+            relative_paths: false,
+            allowed_exts: constants::DEFAULT_ALLOWED_EXTS.clone(),
+            follow_symlinks: false,
+            exact_durations: false,
+            play_counts: PlayCounts::default(),
+            cover_art_cache: CoverArtCache::default(),
+        }
+    }
 
     #[test]
-    fn walk_dir_with_ignore() {
-        use std::fs;
+    fn search_by_many_paths() {
+        let rows: Vec<_> = (0..10)
+            .map(|i| row(format!("/music/{:02}.mp3", 2 * i), &[]))
+            .collect();
+        // every other path is missing, the order is scrambled
+        let paths: Vec<PathBuf> = (0..20)
+            .map(|i| format!("/music/{:02}.mp3", (i * 7) % 20).into())
+            .collect();
+
+        let found = db_utils::search_by_paths(&rows, &paths);
+        let expected: Vec<_> = paths
+            .iter()
+            .map(|path| db_utils::binary_search_by_path(&rows, path))
+            .collect();
+        assert_eq!(found, expected);
+        assert_eq!(found.iter().flatten().count(), 10);
+    }
+
+    #[test]
+    fn unique_genres_by_albumartist() {
+        let row = |i: usize, albumartist: &str, genre: &str| {
+            row(
+                format!("/music/{}.mp3", i),
+                &[
+                    (StandardTagKey::AlbumArtist, albumartist),
+                    (StandardTagKey::Genre, genre),
+                ],
+            )
+        };
+        let db = test_db(vec![
+            row(0, "Boards of Canada", "Electronic"),
+            row(1, "Boards of Canada", "IDM"),
+            row(2, "Boards of Canada", "electronic"),
+            row(3, "Miles Davis", "Jazz"),
+            row(4, "Miles Davis", "Cool Jazz"),
+            row(5, "Miles Davis", "jazz"),
+        ]);
 
-        let tmp = std::env::temp_dir();
-        let dir = tmp.join(format!(
-            "musing_test_{}",
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
+        let args = UniqueArgs(
+            TagKey::try_from("genre").unwrap(),
+            FilterExpr::default(),
+            vec![TagKey::try_from("albumartist").unwrap()],
+        );
+        let response = db.unique(args);
+        let mut groups: Vec<_> = response.inner()["values"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|group| (group["albumartist"].clone(), group["data"].clone()))
+            .collect();
+        groups.sort_by_key(|(albumartist, _)| albumartist.to_string());
+        assert_eq!(
+            groups,
+            [
+                (
+                    serde_json::json!("Boards of Canada"),
+                    serde_json::json!(["Electronic", "IDM"])
+                ),
+                (
+                    serde_json::json!("Miles Davis"),
+                    serde_json::json!(["Cool Jazz", "Jazz"])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn search_ignoring_case_and_accents() {
+        let row = |i: usize, artist: &str, title: &str| {
+            row(
+                format!("/music/{}.mp3", i),
+                &[
+                    (StandardTagKey::Artist, artist),
+                    (StandardTagKey::TrackTitle, title),
+                ],
+            )
+        };
+        let db = test_db(vec![
+            row(0, "Beyoncé", "Halo"),
+            row(1, "Sigur Rós", "Hoppípolla"),
+            row(2, "Björk", "Jóga"),
+            row(3, "Röyksopp", "Eple"),
+        ]);
+        let search = |query: &str, tags: &[&str]| {
+            let tags = tags.iter().map(|&tag| TagKey::try_from(tag).unwrap());
+            let response = db.search(SearchArgs(query.into(), tags.collect()));
+            response.inner()["results"]
+                .as_array()
                 .unwrap()
-                .as_secs()
+                .iter()
+                .map(|result| result["path"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(search("BEYON", &["artist", "tracktitle"]), ["/music/0.mp3"]);
+        assert_eq!(
+            search("o", &["artist", "tracktitle"]),
+            [
+                "/music/0.mp3",
+                "/music/1.mp3",
+                "/music/2.mp3",
+                "/music/3.mp3"
+            ]
+        );
+        assert_eq!(search("jog", &["artist", "tracktitle"]), ["/music/2.mp3"]);
+        assert!(search("jog", &["artist"]).is_empty());
+        let response = db.search(SearchArgs(
+            "ros".into(),
+            vec![TagKey::try_from("artist").unwrap()],
         ));
+        assert_eq!(
+            response.inner()["results"][0]["tracktitle"],
+            serde_json::json!("Hoppípolla")
+        );
+    }
+
+    #[test]
+    fn random_sample() {
+        let db = test_db(
+            (0..1000)
+                .map(|i| {
+                    let genre = if i % 10 == 0 { "Jazz" } else { "Rock" };
+                    row(
+                        format!("/music/{:04}.mp3", i),
+                        &[(StandardTagKey::Genre, genre)],
+                    )
+                })
+                .collect(),
+        );
+        let sample = |n: usize| -> Vec<String> {
+            let filter_expr = FilterExpr::try_from(serde_json::json!([
+                {"kind": "regex", "tag": "genre", "regex": "^Jazz$"},
+            ]))
+            .unwrap();
+            let response = db.sample(SampleArgs(n, filter_expr));
+            serde_json::from_value(response.inner()["paths"].clone()).unwrap()
+        };
+
+        let paths = sample(20);
+        assert_eq!(paths.len(), 20);
+        assert_eq!(paths.iter().collect::<HashSet<_>>().len(), 20);
+        for path in paths.iter() {
+            let i: usize = path[7..11].parse().unwrap();
+            assert_eq!(i % 10, 0);
+        }
+        assert_ne!(paths, sample(20));
+        // there are only 100 jazz songs
+        let mut paths = sample(500);
+        paths.sort();
+        let expected: Vec<_> = (0..1000)
+            .step_by(10)
+            .map(|i| format!("/music/{:04}.mp3", i))
+            .collect();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn album_in_track_order() {
+        let row = |i: usize, album: &str, disc: &str, track: &str| {
+            row(
+                format!("/music/{}.mp3", i),
+                &[
+                    (StandardTagKey::Album, album),
+                    (StandardTagKey::DiscNumber, disc),
+                    (StandardTagKey::TrackNumber, track),
+                ],
+            )
+        };
+        let db = test_db(vec![
+            row(0, "Mellon Collie", "2/2", "1/14"),
+            row(1, "Mellon Collie", "1/2", "10/14"),
+            row(2, "Geogaddi", "1", "2"),
+            row(3, "Mellon Collie", "1/2", "2/14"),
+            row(4, "Mellon Collie", "2/2", "9/14"),
+            row(5, "Mellon Collie", "1/2", "1/14"),
+        ]);
+
+        let filter_expr = FilterExpr::try_from(serde_json::json!([
+            {"kind": "regex", "tag": "album", "regex": "^Mellon Collie$"},
+        ]))
+        .unwrap();
+        let paths = db.album_paths(&filter_expr);
+        let expected: Vec<PathBuf> = [5, 3, 1, 0, 4]
+            .into_iter()
+            .map(|i| format!("/music/{}.mp3", i).into())
+            .collect();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn unique_numeric_values() {
+        assert_eq!(
+            db_utils::unique_key("03/12", TagKeyKind::OutOf),
+            db_utils::unique_key("3", TagKeyKind::OutOf)
+        );
+        assert!(
+            db_utils::unique_key("9", TagKeyKind::Integer)
+                < db_utils::unique_key("10", TagKeyKind::Integer)
+        );
+    }
+
+    #[test]
+    fn nested_playlists() {
+        let dir = temp_dir("playlists");
+        let _ = fs::write(dir.join("inner.m3u"), "b.mp3\nc.mp3\n");
+        let _ = fs::write(dir.join("outer.m3u"), "a.mp3\ninner.m3u\nd.mp3\n");
+        let _ = fs::write(
+            dir.join("cycle.m3u"),
+            "#comment\ne.mp3\ncycle.m3u\nouter.m3u\n",
+        );
+        let db =
+            Database::try_new(std::slice::from_ref(&dir), Some(&dir), None, false, false).unwrap();
+
+        let outer = db.load_playlist("outer.m3u", true).unwrap();
+        let outer_flat = db.load_playlist("outer.m3u", false).unwrap();
+        let cycle = db.load_playlist("cycle.m3u", true).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        let to_paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(outer, to_paths(&["a.mp3", "b.mp3", "c.mp3", "d.mp3"]));
+        assert_eq!(outer_flat, to_paths(&["a.mp3", "inner.m3u", "d.mp3"]));
+        assert_eq!(
+            cycle,
+            to_paths(&["e.mp3", "a.mp3", "b.mp3", "c.mp3", "d.mp3"])
+        );
+    }
+
+    #[test]
+    fn rename_and_delete_playlists() {
+        let dir = temp_dir("playlist_files");
+        let _ = fs::write(dir.join("a.m3u"), "a.mp3\n");
+        let _ = fs::write(dir.join("b.m3u"), "b.mp3\n");
+        let mut db =
+            Database::try_new(std::slice::from_ref(&dir), Some(&dir), None, false, false).unwrap();
+        let playlists = |db: &Database| {
+            let mut names: Vec<_> = db
+                .playlists()
+                .iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(playlists(&db), ["a.m3u", "b.m3u"]);
+
+        let response = db.rename_playlist("a.m3u", "c.m3u8");
+        assert_eq!(response.inner()["status"], "ok");
+        assert_eq!(playlists(&db), ["b.m3u", "c.m3u8"]);
+        assert_eq!(db.load_playlist("c.m3u8", false).unwrap().len(), 1);
+        // the new name is taken
+        let response = db.rename_playlist("b.m3u", "c.m3u8");
+        assert_eq!(response.inner()["status"], "err");
+        let response = db.delete_playlist("b.m3u");
+        assert_eq!(response.inner()["status"], "ok");
+        assert_eq!(playlists(&db), ["c.m3u8"]);
+        let response = db.delete_playlist("b.m3u");
+        assert_eq!(response.inner()["status"], "err");
+
+        // other files are off limits
+        for path in ["../c.m3u8", "/etc/passwd.m3u", "c.txt"] {
+            let response = db.rename_playlist("c.m3u8", path);
+            assert_eq!(response.inner()["status"], "err");
+            let response = db.delete_playlist(path);
+            assert_eq!(response.inner()["status"], "err");
+        }
+        let exists = dir.join("c.m3u8").exists();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(exists);
+    }
+
+    #[test]
+    fn playlists_cant_escape() {
+        let base = temp_dir("playlist_escape");
+        let (music_dir, playlist_dir) = (base.join("music"), base.join("music/playlists"));
+        let _ = fs::create_dir_all(&playlist_dir);
+        song::write_silent_wav(music_dir.join("a.wav"), 8000, 1, 100);
+        let _ = fs::write(base.join("outside.m3u"), "a.wav\n");
+        #[cfg(unix)]
+        let _ = std::os::unix::fs::symlink(&base, playlist_dir.join("link"));
+        let mut db = Database::try_new(
+            std::slice::from_ref(&music_dir),
+            Some(&playlist_dir),
+            None,
+            false,
+            false,
+        )
+        .unwrap();
+
+        let mut statuses = Vec::new();
+        for path in [
+            "../../outside.m3u",
+            "/etc/passwd.m3u",
+            "link/outside.m3u",
+            "a.txt",
+        ] {
+            statuses.push(db.add_to_playlist(path, "a.wav"));
+            statuses.push(db.remove_from_playlist(path, 0));
+            statuses.push(db.move_in_playlist(path, 0, 0));
+            statuses.push(db.save_as_playlist(path, &[], false));
+        }
+        let loaded =
+            ["../../outside.m3u", "link/outside.m3u"].map(|path| db.load_playlist(path, false));
+        let inside = db.add_to_playlist("inside.m3u", "a.wav");
+        let outside = fs::read_to_string(base.join("outside.m3u"));
+        let _ = fs::remove_dir_all(&base);
+
+        for response in statuses {
+            assert_eq!(response.inner()["status"], "err");
+            assert_eq!(response.inner()["code"], "invalid_arg");
+        }
+        assert!(loaded.iter().all(|res| res.is_err()));
+        assert_eq!(inside.inner()["status"], "ok");
+        assert_eq!(outside.unwrap(), "a.wav\n");
+    }
+
+    #[test]
+    fn remove_last_from_playlist() {
+        let dir = temp_dir("playlist_remove");
+        let _ = fs::write(dir.join("a.m3u"), "a.mp3\n");
+        let _ = fs::write(dir.join("b.m3u"), "\nb.mp3\n\r\n\n");
+        let _ = fs::write(
+            dir.join("c.m3u"),
+            "#EXTM3U\n#EXTINF:100,A - a\na.mp3\n#EXTINF:200,B - b\nb.mp3\n",
+        );
+        let mut db =
+            Database::try_new(std::slice::from_ref(&dir), Some(&dir), None, false, false).unwrap();
+
+        let response = db.remove_from_playlist("a.m3u", 0);
+        let content = fs::read_to_string(dir.join("a.m3u"));
+        let playlist = db.load_playlist("a.m3u", false);
+        let with_blank_lines = db.load_playlist("b.m3u", false);
+        let extended_response = db.remove_from_playlist("c.m3u", 1);
+        let first_removed = fs::read_to_string(dir.join("c.m3u"));
+        let _ = db.remove_from_playlist("c.m3u", 0);
+        let all_removed = fs::read_to_string(dir.join("c.m3u"));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(response.inner()["status"], "ok");
+        assert_eq!(content.unwrap(), "");
+        assert_eq!(playlist.unwrap(), Vec::<PathBuf>::new());
+        assert_eq!(with_blank_lines.unwrap(), [PathBuf::from("b.mp3")]);
+        // positions count only songs, which take their `#EXTINF` lines with them
+        assert_eq!(extended_response.inner()["status"], "ok");
+        assert_eq!(
+            first_removed.unwrap(),
+            "#EXTM3U\n#EXTINF:100,A - a\na.mp3\n"
+        );
+        assert_eq!(all_removed.unwrap(), "#EXTM3U\n");
+    }
+
+    #[test]
+    fn move_in_playlist() {
+        let dir = temp_dir("playlist_move");
+        let _ = fs::write(
+            dir.join("p.m3u"),
+            "#EXTM3U\na.mp3\nb.mp3\n# comment\nc.mp3\n",
+        );
+        let mut db =
+            Database::try_new(std::slice::from_ref(&dir), Some(&dir), None, false, false).unwrap();
+
+        let response = db.move_in_playlist("p.m3u", 0, 2);
+        assert_eq!(response.inner()["status"], "ok");
+        let content = fs::read_to_string(dir.join("p.m3u")).unwrap();
+        assert_eq!(content, "#EXTM3U\nb.mp3\nc.mp3\n# comment\na.mp3\n");
+        // out of range positions are clamped
+        let response = db.move_in_playlist("p.m3u", 10, 0);
+        assert_eq!(response.inner()["status"], "ok");
+        let content = fs::read_to_string(dir.join("p.m3u")).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(content, "#EXTM3U\na.mp3\nb.mp3\n# comment\nc.mp3\n");
+    }
+
+    #[test]
+    fn extended_playlists() {
+        let dir = temp_dir("extended_playlist");
+        song::write_silent_wav(dir.join("a.wav"), 8000, 1, 16000);
+        song::write_silent_wav(dir.join("b.wav"), 8000, 1, 24000);
+        let db =
+            Database::try_new(std::slice::from_ref(&dir), Some(&dir), None, false, false).unwrap();
+        let entries: Vec<_> = db
+            .data_rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| Entry {
+                id: i as u32,
+                path: row.song.path.clone(),
+                priority: 0,
+            })
+            .collect();
+
+        let response = db.save_as_playlist("list.m3u", &entries, true);
+        assert_eq!(response.inner()["status"], "ok");
+        let _ = fs::write(
+            dir.join("malformed.m3u"),
+            "#EXTM3U\n#EXTINF:abc,x\na.wav\n#EXTINF:5 tvg-id=\"1\",\nb.wav\n#EXTINF:-1,c\n",
+        );
+        let playlist = db.load_playlist_with_info("list.m3u", false).unwrap();
+        let malformed = db.load_playlist_with_info("malformed.m3u", false).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(
+            playlist,
+            [
+                (
+                    "a.wav".into(),
+                    Some(ExtInf {
+                        duration: Some(2),
+                        title: Some("a".into())
+                    })
+                ),
+                (
+                    "b.wav".into(),
+                    Some(ExtInf {
+                        duration: Some(3),
+                        title: Some("b".into())
+                    })
+                ),
+            ]
+        );
+        assert_eq!(
+            malformed,
+            [
+                ("a.wav".into(), None),
+                (
+                    "b.wav".into(),
+                    Some(ExtInf {
+                        duration: Some(5),
+                        title: None
+                    })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn pls_playlists() {
+        let dir = temp_dir("pls_playlist");
+        song::write_silent_wav(dir.join("a.wav"), 8000, 1, 16000);
+        song::write_silent_wav(dir.join("b.wav"), 8000, 1, 24000);
+        let _ = fs::write(
+            dir.join("hand.pls"),
+            "[playlist]\nFile2=b.wav\nFile1=a.wav\nTitle1=Song A\nLength1=-1\n\
+             Title3=no file\nNumberOfEntries=3\nVersion=2\n",
+        );
+        let db =
+            Database::try_new(std::slice::from_ref(&dir), Some(&dir), None, false, false).unwrap();
+        assert_eq!(db.playlists().len(), 1);
+        let entries: Vec<_> = db
+            .data_rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| Entry {
+                id: i as u32,
+                path: row.song.path.clone(),
+                priority: 0,
+            })
+            .collect();
+
+        let response = db.save_as_playlist("saved.pls", &entries, false);
+        assert_eq!(response.inner()["status"], "ok");
+        let hand = db.load_playlist_with_info("hand.pls", false).unwrap();
+        let saved = db.load_playlist_with_info("saved.pls", false).unwrap();
+        let content = fs::read_to_string(dir.join("saved.pls")).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(
+            hand,
+            [
+                (
+                    "a.wav".into(),
+                    Some(ExtInf {
+                        duration: None,
+                        title: Some("Song A".into())
+                    })
+                ),
+                ("b.wav".into(), None),
+            ]
+        );
+        assert_eq!(
+            saved,
+            [
+                (
+                    "a.wav".into(),
+                    Some(ExtInf {
+                        duration: Some(2),
+                        title: Some("a".into())
+                    })
+                ),
+                (
+                    "b.wav".into(),
+                    Some(ExtInf {
+                        duration: Some(3),
+                        title: Some("b".into())
+                    })
+                ),
+            ]
+        );
+        assert!(content.starts_with("[playlist]\nFile1=a.wav\nTitle1=a\nLength1=2\n"));
+        assert!(content.ends_with("NumberOfEntries=2\nVersion=2\n"));
+    }
+
+    #[test]
+    fn walk_dir_with_configured_exts() {
+        let dir = temp_dir("exts");
+        let _ = File::create(dir.join("a.opus"));
+        let _ = File::create(dir.join("b.mp3"));
+
+        let config = crate::config::PlayerConfig::try_new("allowed_exts = [\".Opus\"]").unwrap();
+        let files =
+            db_utils::walk_dir(&dir, SystemTime::UNIX_EPOCH, &config.allowed_exts, false).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(files, [dir.join("a.opus")]);
+    }
+
+    #[test]
+    fn walk_dir_with_ignore() {
+        use std::fs;
+
+        let dir = temp_dir("ignore");
 
         let n = 10;
-        let _ = fs::create_dir(&dir);
         for i in 1..=n {
             let _ = File::create(dir.join(format!("song{}.xyz", i)));
         }
@@ -535,15 +2128,225 @@ mod test {
             let _ = File::create(dir.join(format!("bad_dir/song_bad{}.xyz", i)));
         }
 
+        for i in 1..=n {
+            let _ = File::create(dir.join(format!("ok_dir/song_ok{}.xyz.bak", i)));
+        }
+        let _ = fs::create_dir_all(dir.join("ok_dir/demos"));
+        let _ = File::create(dir.join("ok_dir/demos/demo.xyz"));
+        // not a valid glob, but still a valid path
+        let _ = fs::create_dir(dir.join("bad{dir"));
+        let _ = File::create(dir.join("bad{dir/song.xyz"));
+
         let mut ignore = File::create(dir.join(constants::DEFAULT_IGNORE_FILE)).unwrap();
-        let _ = ignore.write_all(b"bad_dir");
-        let res = db_utils::walk_dir(&dir, SystemTime::UNIX_EPOCH, &HashSet::from(["xyz".into()]))
-            .unwrap();
+        let _ = ignore.write_all(b"bad_dir\n*.bak\n**/demos/\nbad{dir\n");
+        let res = db_utils::walk_dir(
+            &dir,
+            SystemTime::UNIX_EPOCH,
+            &HashSet::from(["xyz".into(), "bak".into()]),
+            false,
+        )
+        .unwrap();
         let _ = fs::remove_dir_all(&dir);
         assert_eq!(res.len(), 20);
-        assert!(
-            res.iter()
-                .all(|path| !path.to_string_lossy().contains("bad_dir"))
+        assert!(res.iter().all(|path| {
+            let path = path.to_string_lossy();
+            !path.contains("bad") && !path.ends_with(".bak") && !path.contains("demos")
+        }));
+    }
+
+    #[test]
+    fn stats() {
+        let row = |i: usize, artist: &str, album: Option<&str>| {
+            let mut tags = vec![(StandardTagKey::Artist, artist)];
+            tags.extend(album.map(|album| (StandardTagKey::Album, album)));
+            let mut row = row(format!("/music/{}.mp3", i), &tags);
+            row.song.duration = Some(100 + i as u64);
+
+            row
+        };
+        let mut db = test_db(vec![
+            row(0, "Miles Davis", Some("Kind of Blue")),
+            row(1, "Miles Davis", Some("Kind of Blue")),
+            row(2, "Miles Davis", Some("Bitches Brew")),
+            row(3, "Boards of Canada", Some("Geogaddi")),
+            row(4, "Boards of Canada", None),
+        ]);
+        db.playlists = HashSet::from(["/music/playlists/a.m3u".into()]);
+
+        let response = db.stats();
+        let stats = response.inner();
+        assert_eq!(stats["songs"], 5);
+        assert_eq!(stats["total_duration"], 510);
+        assert_eq!(stats["artists"], 2);
+        assert_eq!(stats["albums"], 3);
+        assert_eq!(stats["playlists"], 1);
+    }
+
+    #[test]
+    fn count_by_album() {
+        let row = |i: usize, album: &str, duration: Option<u64>| {
+            let mut row = row(
+                format!("/music/{}.mp3", i),
+                &[(StandardTagKey::Album, album)],
+            );
+            row.song.duration = duration;
+
+            row
+        };
+        let db = test_db(vec![
+            row(0, "Kind of Blue", Some(545)),
+            row(1, "Kind of Blue", Some(566)),
+            row(2, "Kind of Blue", Some(337)),
+            row(3, "Geogaddi", Some(104)),
+            // unknown durations don't add up to the total
+            row(4, "Geogaddi", None),
+        ]);
+
+        let args = CountArgs(
+            FilterExpr::default(),
+            vec![TagKey::try_from("album").unwrap()],
+        );
+        let response = db.count(args);
+        let mut groups: Vec<_> = response.inner()["values"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|group| {
+                (
+                    group["album"].clone(),
+                    group["songs"].clone(),
+                    group["total_duration"].clone(),
+                )
+            })
+            .collect();
+        groups.sort_by_key(|(album, _, _)| album.to_string());
+        assert_eq!(
+            groups,
+            [
+                (
+                    serde_json::json!("Geogaddi"),
+                    serde_json::json!(2),
+                    serde_json::json!(104)
+                ),
+                (
+                    serde_json::json!("Kind of Blue"),
+                    serde_json::json!(3),
+                    serde_json::json!(1448)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn many_music_dirs() {
+        let base = temp_dir("roots");
+        let (first, second) = (base.join("first"), base.join("second"));
+        fs::create_dir_all(first.join("playlists")).unwrap();
+        fs::create_dir_all(second.join("sub")).unwrap();
+        song::write_silent_wav(first.join("a.wav"), 8000, 1, 800);
+        song::write_silent_wav(second.join("b.wav"), 8000, 1, 800);
+        song::write_silent_wav(second.join("sub").join("c.wav"), 8000, 1, 800);
+
+        let mut db = Database::try_new(&[first.clone(), second.clone()], None, None, false, false)
+            .unwrap()
+            .with_relative_paths(true);
+        let (first, second) = (
+            dunce::canonicalize(&first).unwrap(),
+            dunce::canonicalize(&second).unwrap(),
+        );
+        assert_eq!(db.data_rows.len(), 3);
+        assert_eq!(db.try_to_abs_path("a.wav"), Some(first.join("a.wav")));
+        assert_eq!(db.try_to_abs_path("b.wav"), Some(second.join("b.wav")));
+        assert_eq!(db.try_to_abs_path("c.wav"), None);
+        let c = second.join("sub").join("c.wav");
+        assert_eq!(
+            db.output_path(&c),
+            Path::new("sub").join("c.wav").to_string_lossy()
+        );
+
+        // playlists go to the first music dir, but songs are relative to their own dirs
+        let response = db.add_to_playlist("list.m3u", Path::new("sub").join("c.wav"));
+        assert_eq!(response.inner()["status"], "ok");
+        let response = db.add_to_playlist("list.m3u", "a.wav");
+        assert_eq!(response.inner()["status"], "ok");
+        let playlist = db.load_playlist("list.m3u", false).unwrap();
+        let _ = fs::remove_dir_all(&base);
+        assert_eq!(playlist, [Path::new("sub").join("c.wav"), "a.wav".into()]);
+        let abs_paths: Vec<_> = playlist
+            .iter()
+            .map(|path| db.try_to_abs_path(path))
+            .collect();
+        assert_eq!(abs_paths, [Some(c), Some(first.join("a.wav"))]);
+    }
+
+    #[test]
+    fn file_size_and_mtime() {
+        let dir = temp_dir("file_info");
+        let path = dir.join("a.wav");
+        song::write_silent_wav(&path, 8000, 1, 800);
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let mut db =
+            Database::try_new(std::slice::from_ref(&dir), None, None, false, false).unwrap();
+        let path = dunce::canonicalize(&path).unwrap();
+        let file_info = |db: &Database| {
+            let map = db
+                .metadata_values(vec![path.clone()], &[], &[])
+                .remove(0)
+                .unwrap();
+            let filesize: u64 = map["filesize"].as_str().unwrap().parse().unwrap();
+            let mtime: u64 = map["mtime"].as_str().unwrap().parse().unwrap();
+            (filesize, mtime)
+        };
+        let (filesize, mtime) = file_info(&db);
+        assert_eq!(filesize, 44 + 1600);
+        assert!(mtime.abs_diff(now) <= 5);
+
+        // the cached values are refreshed after the file changes
+        // (file timestamps come from a coarse clock, so give it a moment
+        // to make sure that the new mtime is later than the last update)
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        song::write_silent_wav(&path, 8000, 1, 1600);
+        db.update();
+        let (filesize, _) = file_info(&db);
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(filesize, 44 + 3200);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_dir_following_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let base = temp_dir("symlinks");
+        let (root, elsewhere) = (base.join("music"), base.join("elsewhere"));
+        fs::create_dir_all(root.join("album")).unwrap();
+        fs::create_dir_all(elsewhere.join("linked_album")).unwrap();
+        File::create(root.join("album/a.xyz")).unwrap();
+        File::create(elsewhere.join("linked_album/b.xyz")).unwrap();
+        symlink(elsewhere.join("linked_album"), root.join("linked_album")).unwrap();
+        // the same album reachable twice and a cycle
+        symlink(root.join("album"), root.join("album_again")).unwrap();
+        symlink(&root, root.join("album/cycle")).unwrap();
+
+        let exts = HashSet::from(["xyz".into()]);
+        let not_followed = db_utils::walk_dir(&root, SystemTime::UNIX_EPOCH, &exts, false).unwrap();
+        let followed = db_utils::walk_dir(&root, SystemTime::UNIX_EPOCH, &exts, true).unwrap();
+        let (root, elsewhere) = (
+            dunce::canonicalize(&root).unwrap(),
+            dunce::canonicalize(&elsewhere).unwrap(),
+        );
+        let _ = fs::remove_dir_all(&base);
+        assert_eq!(not_followed, [root.join("album/a.xyz")]);
+        assert_eq!(
+            followed,
+            [
+                elsewhere.join("linked_album/b.xyz"),
+                root.join("album/a.xyz")
+            ]
         );
     }
 }
@@ -0,0 +1,373 @@
+use anyhow::Result;
+use cpal::{Sample, SampleFormat};
+use std::{
+    collections::HashMap,
+    mem,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{
+        broadcast,
+        mpsc::{self as tokio_chan, error::TrySendError},
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    model::{
+        device::{BaseSample, Device, DeviceConfig, SampleSink},
+        wav_writer,
+    },
+    server,
+};
+
+const HTTP_DEVICE: &str = "http";
+// samples sent to the listener at once
+const HTTP_CHUNK_LEN: usize = 4096;
+// chunks waiting to be sent before the listener starts missing audio
+const HTTP_CHANNEL_CAPACITY: usize = 64;
+const MAX_HTTP_HEAD_LEN: usize = 8192;
+
+// the playback as heard by the HTTP stream's listener, who can get it
+// as raw PCM (at `/stream`, `audio/L16` is big-endian) or as an endless WAV file
+// (at `/stream.wav`, which browsers can play)
+//
+// only one listener at a time is supported for now
+//
+// if the server has a password, the listener has to give it too
+// (e.g. `/stream?password=hunter2`), otherwise the stream is only reachable locally
+#[derive(Default)]
+struct HttpTap {
+    listener: Option<tokio_chan::Sender<Vec<i16>>>,
+    buf: Vec<i16>,
+}
+
+impl SampleSink for HttpTap {
+    fn write(&mut self, sample: BaseSample) -> Result<()> {
+        // nobody's listening
+        if self.listener.is_none() {
+            return Ok(());
+        }
+        self.buf.push(i16::from_sample(sample));
+        if self.buf.len() >= HTTP_CHUNK_LEN {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let chunk = mem::take(&mut self.buf);
+        if let Some(listener) = &self.listener
+            && !chunk.is_empty()
+        {
+            // a listener that can't keep up misses some audio
+            if let Err(TrySendError::Closed(_)) = listener.try_send(chunk) {
+                self.listener = None;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl HttpTap {
+    fn connect(&mut self) -> Option<tokio_chan::Receiver<Vec<i16>>> {
+        if self.listener.as_ref().is_some_and(|l| !l.is_closed()) {
+            return None;
+        }
+        let (tx, rx) = tokio_chan::channel(HTTP_CHANNEL_CAPACITY);
+        self.listener = Some(tx);
+        self.buf.clear();
+
+        Some(rx)
+    }
+}
+
+struct HttpStream {
+    tap: Arc<Mutex<HttpTap>>,
+    sample_rate: u32,
+    channels: u16,
+    password: Option<String>,
+}
+
+impl HttpStream {
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let head = http_utils::read_head(&mut stream).await?;
+        let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+        let (method, target) = (request_line.next(), request_line.next());
+        let (path, query) = match target.and_then(|target| target.split_once('?')) {
+            Some((path, query)) => (Some(path), query),
+            None => (target, ""),
+        };
+        let is_authorized = self.password.as_ref().is_none_or(|expected| {
+            http_utils::query_param(query, "password").is_some_and(|password| {
+                server::constant_time_eq(expected.as_bytes(), password.as_bytes())
+            })
+        });
+        let wav = match (method, path) {
+            (Some("GET"), Some("/stream" | "/stream.wav")) if !is_authorized => {
+                return http_utils::respond(&mut stream, "401 Unauthorized").await;
+            }
+            (Some("GET"), Some("/stream")) => false,
+            (Some("GET"), Some("/stream.wav")) => true,
+            (Some("GET"), _) => return http_utils::respond(&mut stream, "404 Not Found").await,
+            _ => return http_utils::respond(&mut stream, "405 Method Not Allowed").await,
+        };
+        let rx_chunk = self.tap.lock().unwrap().connect();
+        let Some(mut rx_chunk) = rx_chunk else {
+            return http_utils::respond(&mut stream, "503 Service Unavailable").await;
+        };
+
+        let content_type = if wav {
+            "audio/wav".into()
+        } else {
+            format!(
+                "audio/L16;rate={};channels={}",
+                self.sample_rate, self.channels
+            )
+        };
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+            content_type
+        );
+        stream.write_all(head.as_bytes()).await?;
+        if wav {
+            // the length is unknown, so it's the maximum one
+            let header = wav_writer::header(
+                self.sample_rate,
+                self.channels,
+                wav_writer::PCM,
+                16,
+                u32::MAX - 36,
+            );
+            stream.write_all(&header).await?;
+        }
+        // ends once the listener disconnects
+        while let Some(chunk) = rx_chunk.recv().await {
+            let bytes: Vec<u8> = if wav {
+                chunk.iter().flat_map(|s| s.to_le_bytes()).collect()
+            } else {
+                chunk.iter().flat_map(|s| s.to_be_bytes()).collect()
+            };
+            stream.write_all(&bytes).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn run(self, listener: TcpListener) -> Result<()> {
+        let http_stream = Arc::new(self);
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let http_stream = Arc::clone(&http_stream);
+            tokio::spawn(async move {
+                if let Err(e) = http_stream.handle(stream).await {
+                    log::warn!("http stream to {} ended ({})", addr, e);
+                }
+            });
+        }
+    }
+}
+
+// the returned device feeds the stream, its sample rate and channels
+// can be set with `device_config` (like for any other device), samples are always 16-bit
+pub async fn spawn(
+    bind_addr: &str,
+    port: u16,
+    password: Option<String>,
+    device_configs: &HashMap<String, DeviceConfig>,
+    mut rx_shutdown: broadcast::Receiver<()>,
+) -> Result<(JoinHandle<()>, Device)> {
+    let config = DeviceConfig {
+        sample_format: Some(SampleFormat::I16),
+        ..device_configs.get(HTTP_DEVICE).cloned().unwrap_or_default()
+    }
+    .sink_config();
+    let tap = Arc::new(Mutex::new(HttpTap::default()));
+    let http_stream = HttpStream {
+        tap: Arc::clone(&tap),
+        sample_rate: config.sample_rate().0,
+        channels: config.channels(),
+        password: password.clone(),
+    };
+    let mut ip = bind_addr.parse::<IpAddr>()?;
+    // without a password anybody who can connect would be able to listen
+    if password.is_none() && !ip.is_loopback() {
+        log::warn!("the HTTP stream is only available locally (set a password to share it)");
+        ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    }
+    let addr = SocketAddr::new(ip, port);
+    let listener = TcpListener::bind(addr).await?;
+    log::warn!("streaming over HTTP at http://{}/stream", addr);
+    let task = tokio::spawn(async move {
+        tokio::select! {
+            res = http_stream.run(listener) => {
+                if let Err(e) = res {
+                    log::error!("http stream error ({})", e);
+                }
+            }
+            _ = rx_shutdown.recv() => (),
+        }
+    });
+
+    Ok((task, Device::with_sink(HTTP_DEVICE, tap, config)))
+}
+
+mod http_utils {
+    use super::*;
+
+    // the request line and headers (the body, if any, is ignored)
+    pub async fn read_head(stream: &mut TcpStream) -> Result<String> {
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        while !head.ends_with(b"\r\n\r\n") && head.len() < MAX_HTTP_HEAD_LEN {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            head.extend(&buf[..n]);
+        }
+
+        Ok(String::from_utf8_lossy(&head).into_owned())
+    }
+
+    // the percent-decoded value of `key` in a query string (e.g. `a=1&b=%2F`)
+    pub fn query_param(query: &str, key: &str) -> Option<String> {
+        let value = query
+            .split('&')
+            .find_map(|param| param.strip_prefix(key)?.strip_prefix('='))?;
+        let bytes = value.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match (bytes[i], hex) {
+                (b'%', Some(byte)) => {
+                    decoded.push(byte);
+                    i += 3;
+                }
+                (b'+', _) => {
+                    decoded.push(b' ');
+                    i += 1;
+                }
+                (byte, _) => {
+                    decoded.push(byte);
+                    i += 1;
+                }
+            }
+        }
+
+        String::from_utf8(decoded).ok()
+    }
+
+    pub async fn respond(stream: &mut TcpStream, status: &str) -> Result<()> {
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        );
+        stream.write_all(response.as_bytes()).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn single_listener() {
+        let tap = Arc::new(Mutex::new(HttpTap::default()));
+        let http_stream = HttpStream {
+            tap: Arc::clone(&tap),
+            sample_rate: 48000,
+            channels: 2,
+            password: None,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(http_stream.run(listener));
+        let request = |path: &str| format!("GET {} HTTP/1.1\r\nHost: musing\r\n\r\n", path);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(request("/stream").as_bytes())
+            .await
+            .unwrap();
+        let head = http_utils::read_head(&mut stream).await.unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains("Content-Type: audio/L16;rate=48000;channels=2"));
+
+        // the tap is connected before the response is sent
+        {
+            let mut tap = tap.lock().unwrap();
+            for s in [0.5, -0.5] {
+                tap.write(s).unwrap();
+            }
+            tap.flush().unwrap();
+        }
+        let mut samples = [0; 4];
+        stream.read_exact(&mut samples).await.unwrap();
+        assert_eq!(
+            [
+                i16::from_be_bytes([samples[0], samples[1]]),
+                i16::from_be_bytes([samples[2], samples[3]])
+            ],
+            [i16::from_sample(0.5), i16::from_sample(-0.5)]
+        );
+
+        let mut other_stream = TcpStream::connect(addr).await.unwrap();
+        other_stream
+            .write_all(request("/stream.wav").as_bytes())
+            .await
+            .unwrap();
+        let head = http_utils::read_head(&mut other_stream).await.unwrap();
+        assert!(head.starts_with("HTTP/1.1 503"));
+
+        let mut other_stream = TcpStream::connect(addr).await.unwrap();
+        other_stream
+            .write_all(request("/other").as_bytes())
+            .await
+            .unwrap();
+        let head = http_utils::read_head(&mut other_stream).await.unwrap();
+        assert!(head.starts_with("HTTP/1.1 404"));
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn password() {
+        let http_stream = HttpStream {
+            tap: Arc::default(),
+            sample_rate: 48000,
+            channels: 2,
+            password: Some("hunter 2".into()),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(http_stream.run(listener));
+
+        let mut heads = Vec::new();
+        for target in [
+            "/stream",
+            "/stream?password=hunter3",
+            "/stream?password=hunter%202",
+        ] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: musing\r\n\r\n", target);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            heads.push(http_utils::read_head(&mut stream).await.unwrap());
+        }
+        task.abort();
+
+        assert!(heads[0].starts_with("HTTP/1.1 401"));
+        assert!(heads[1].starts_with("HTTP/1.1 401"));
+        assert!(heads[2].starts_with("HTTP/1.1 200 OK"));
+    }
+}
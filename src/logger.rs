@@ -0,0 +1,131 @@
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    io::Write,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::constants;
+
+// the most recent errors, so that clients can learn about them
+// (with the `health` request) even if nobody watches the log file
+static RECENT_ERRORS: Mutex<VecDeque<LoggedError>> = Mutex::new(VecDeque::new());
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LoggedError {
+    pub timestamp: u64, // seconds since the Unix epoch
+    pub message: String,
+}
+
+// writes to a file (or stderr) and keeps track of errors
+struct Logger {
+    level: LevelFilter,
+    start: Instant,
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let elapsed = self.start.elapsed();
+        let secs = elapsed.as_secs();
+        if let Ok(mut sink) = self.sink.lock() {
+            let _ = writeln!(
+                sink,
+                "[{:02}:{:02}:{:02}.{:03}] {:6} {}",
+                secs / 3600,
+                (secs / 60) % 60,
+                secs % 60,
+                elapsed.subsec_millis(),
+                record.level(),
+                record.args()
+            );
+        }
+        if record.level() == Level::Error {
+            record_error(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut sink) = self.sink.lock() {
+            let _ = sink.flush();
+        }
+    }
+}
+
+fn record_error(message: String) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    if let Ok(mut errors) = RECENT_ERRORS.lock() {
+        if errors.len() >= constants::MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(LoggedError { timestamp, message });
+    }
+}
+
+// oldest first
+pub fn recent_errors() -> Vec<LoggedError> {
+    RECENT_ERRORS
+        .lock()
+        .map(|errors| errors.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+pub fn init(sink: impl Write + Send + 'static, level: LevelFilter) -> Result<(), SetLoggerError> {
+    let logger = Logger {
+        level,
+        start: Instant::now(),
+        sink: Mutex::new(Box::new(sink)),
+    };
+    // the logger lives for the rest of the program anyway
+    log::set_logger(Box::leak(Box::new(logger)))?;
+    log::set_max_level(level);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn keep_recent_errors() {
+        let logger = Logger {
+            level: LevelFilter::Warn,
+            start: Instant::now(),
+            sink: Mutex::new(Box::new(io::sink())),
+        };
+        let log = |level, message: &str| {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .args(format_args!("{}", message))
+                    .build(),
+            )
+        };
+        log(Level::Warn, "just a warning");
+        for i in 0..=constants::MAX_RECENT_ERRORS {
+            log(Level::Error, &format!("error {}", i));
+        }
+
+        let errors = recent_errors();
+        assert_eq!(errors.len(), constants::MAX_RECENT_ERRORS);
+        assert_eq!(errors[0].message, "error 1");
+        assert_eq!(
+            errors.last().unwrap().message,
+            format!("error {}", constants::MAX_RECENT_ERRORS)
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use clap::Parser;
+use std::{fs::File, io};
 use tokio::{
     signal,
     sync::{
@@ -13,21 +14,27 @@ mod audio;
 mod config;
 mod constants;
 mod database;
+#[cfg(feature = "http")]
+mod http;
+mod logger;
 mod player;
 mod server;
 mod state;
+mod watcher;
 
 mod model;
 
 fn setup_logging(cli_opts: &CliOptions) {
     if cli_opts.log_stderr {
-        simple_logging::log_to_stderr(log::LevelFilter::Warn);
+        let _ = logger::init(io::stderr(), log::LevelFilter::Warn);
     } else {
         let default_log_file = dirs::cache_dir()
             .unwrap_or(".".into())
             .join(constants::DEFAULT_LOG_FILE);
         let log_file = cli_opts.log_file.as_deref().unwrap_or(&default_log_file);
-        let _ = simple_logging::log_to_file(log_file, log::LevelFilter::Warn);
+        if let Ok(file) = File::create(log_file) {
+            let _ = logger::init(file, log::LevelFilter::Warn);
+        }
     }
 }
 
@@ -48,18 +55,50 @@ async fn main() {
     } = config;
 
     let (tx_request, rx_request) = tokio_chan::unbounded_channel();
+    // the player notifies all idling clients about possible state changes
+    let (tx_notify, _) = broadcast::channel(constants::NOTIFY_CHANNEL_CAPACITY);
     // two-way shutdown notification to ensure that state is saved no matter how the program exits
     let (tx_shutdown1, _) = broadcast::channel(1);
     let (tx_shutdown2, mut rx_shutdown2) = broadcast::channel(1);
+    // the HTTP stream gets the playback from a "device" of the player
+    #[cfg(feature = "http")]
+    let (http_task, extra_devices) = match server_config.http_port {
+        Some(port) => match http::spawn(
+            &server_config.bind_addr,
+            port,
+            server_config.password.clone(),
+            &player_config.device_configs,
+            tx_shutdown1.subscribe(),
+        )
+        .await
+        {
+            Ok((task, device)) => (Some(task), vec![device]),
+            Err(e) => {
+                log::error!("can't stream over HTTP ({})", e);
+                (None, Vec::new())
+            }
+        },
+        None => (None, Vec::new()),
+    };
+    #[cfg(not(feature = "http"))]
+    let extra_devices = {
+        if server_config.http_port.is_some() {
+            log::warn!("`http_port` ignored, musing was built without the `http` feature");
+        }
+        Vec::new()
+    };
     let server_task = server::spawn(
         server_config,
         tx_request,
+        tx_notify.clone(),
         tx_shutdown1.subscribe(),
         tx_shutdown2.clone(),
     );
     let player_task = player::spawn(
         player_config,
+        extra_devices,
         rx_request,
+        tx_notify,
         tx_shutdown1.subscribe(),
         tx_shutdown2,
     );
@@ -70,4 +109,8 @@ async fn main() {
     };
     let _ = tx_shutdown1.send(());
     let _ = tokio::join!(server_task, player_task);
+    #[cfg(feature = "http")]
+    if let Some(http_task) = http_task {
+        let _ = http_task.await;
+    }
 }
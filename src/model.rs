@@ -1,10 +1,18 @@
+pub mod bookmark;
 pub mod comparator;
+pub mod cover_art_cache;
 pub mod decoder;
 pub mod device;
+pub mod equalizer;
 pub mod filter;
+pub mod filter_parser;
+pub mod play_count;
 pub mod queue;
 pub mod request;
 pub mod resampler;
 pub mod response;
 pub mod song;
+pub mod stretcher;
 pub mod tag_key;
+pub mod tag_writer;
+pub mod wav_writer;
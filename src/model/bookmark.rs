@@ -0,0 +1,89 @@
+use bincode::{Decode, Encode};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Debug, Decode, Encode, PartialEq, Serialize)]
+pub struct Bookmark {
+    pub label: String,
+    pub seconds: u64,
+}
+
+// song path (absolute) -> bookmarks sorted by position
+#[derive(Clone, Debug, Decode, Default, Encode)]
+pub struct Bookmarks(HashMap<PathBuf, Vec<Bookmark>>);
+
+impl Bookmarks {
+    pub fn list(&self, path: impl AsRef<Path>) -> &[Bookmark] {
+        self.0
+            .get(path.as_ref())
+            .map(|bookmarks| bookmarks.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn find(&self, path: impl AsRef<Path>, label: impl AsRef<str>) -> Option<&Bookmark> {
+        self.list(path).iter().find(|b| b.label == label.as_ref())
+    }
+
+    // a bookmark with the same label gets overwritten
+    pub fn add(&mut self, path: impl Into<PathBuf>, label: impl Into<String>, seconds: u64) {
+        let label = label.into();
+        let bookmarks = self.0.entry(path.into()).or_default();
+        bookmarks.retain(|b| b.label != label);
+        let i = bookmarks.partition_point(|b| b.seconds <= seconds);
+        bookmarks.insert(i, Bookmark { label, seconds });
+    }
+
+    // returns false if there was no such bookmark
+    pub fn remove(&mut self, path: impl AsRef<Path>, label: impl AsRef<str>) -> bool {
+        let Some(bookmarks) = self.0.get_mut(path.as_ref()) else {
+            return false;
+        };
+        let old_len = bookmarks.len();
+        bookmarks.retain(|b| b.label != label.as_ref());
+        let removed = bookmarks.len() != old_len;
+        if bookmarks.is_empty() {
+            self.0.remove(path.as_ref());
+        }
+
+        removed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn add_and_remove() {
+        let mut bookmarks = Bookmarks::default();
+        bookmarks.add("a", "outro", 300);
+        bookmarks.add("a", "intro", 10);
+        bookmarks.add("a", "drop", 120);
+        bookmarks.add("a", "intro", 15);
+        let expected = &[
+            Bookmark {
+                label: "intro".into(),
+                seconds: 15,
+            },
+            Bookmark {
+                label: "drop".into(),
+                seconds: 120,
+            },
+            Bookmark {
+                label: "outro".into(),
+                seconds: 300,
+            },
+        ];
+        assert_eq!(bookmarks.list("a"), expected);
+        assert_eq!(bookmarks.find("a", "drop").map(|b| b.seconds), Some(120));
+
+        assert!(bookmarks.remove("a", "drop"));
+        assert!(!bookmarks.remove("a", "drop"));
+        assert!(!bookmarks.remove("b", "intro"));
+        assert_eq!(bookmarks.list("a").len(), 2);
+        assert!(bookmarks.list("b").is_empty());
+    }
+}
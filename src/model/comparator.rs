@@ -57,6 +57,16 @@ impl TryFrom<Value> for Comparator {
     }
 }
 
+// sorts in ascending order of `tag`
+impl From<TagKey> for Comparator {
+    fn from(tag: TagKey) -> Self {
+        Self {
+            tag,
+            order: ComparisonOrder::Ascending,
+        }
+    }
+}
+
 impl Comparator {
     fn cmp_values<S, T>(&self, lhs: S, rhs: T) -> Ordering
     where
@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{constants, model::song::CoverArt};
+
+#[derive(Debug)]
+struct CacheEntry {
+    mod_time: SystemTime, // of the song's file
+    cover_art: Option<CoverArt>,
+    last_used: u64,
+}
+
+// cover art of the most recently used songs, so that repeated requests
+// (e.g. every `state` poll) don't read the same files over and over again
+#[derive(Debug)]
+pub struct CoverArtCache {
+    entries: HashMap<PathBuf, CacheEntry>,
+    capacity: usize,
+    clock: u64, // incremented on every access
+}
+
+impl Default for CoverArtCache {
+    fn default() -> Self {
+        Self::new(constants::COVER_ART_CACHE_SIZE)
+    }
+}
+
+impl CoverArtCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    // `load` is called only if the song isn't cached
+    // or its file has been modified since it was cached
+    pub fn get_or_load(
+        &mut self,
+        path: &Path,
+        mod_time: SystemTime,
+        load: impl FnOnce() -> Option<CoverArt>,
+    ) -> Option<&CoverArt> {
+        self.clock += 1;
+        let is_stale = self
+            .entries
+            .get(path)
+            .is_none_or(|entry| entry.mod_time != mod_time);
+        if is_stale {
+            if !self.entries.contains_key(path) && self.entries.len() >= self.capacity {
+                self.evict();
+            }
+            let entry = CacheEntry {
+                mod_time,
+                cover_art: load(),
+                last_used: 0,
+            };
+            self.entries.insert(path.to_path_buf(), entry);
+        }
+        let entry = self.entries.get_mut(path)?;
+        entry.last_used = self.clock;
+
+        entry.cover_art.as_ref()
+    }
+
+    pub fn invalidate(&mut self, path: &Path) {
+        self.entries.remove(path);
+    }
+
+    // removes the least recently used entry
+    fn evict(&mut self) {
+        let lru_path = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(path, _)| path.clone());
+        if let Some(path) = lru_path {
+            self.entries.remove(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{cell::Cell, time::Duration};
+
+    #[test]
+    fn load_once() {
+        let mut cache = CoverArtCache::default();
+        let n_loads = Cell::new(0);
+        let load = || {
+            n_loads.set(n_loads.get() + 1);
+            Some(CoverArt::new(vec![1, 2, 3], None))
+        };
+        let t0 = SystemTime::UNIX_EPOCH;
+        let t1 = t0 + Duration::from_secs(1);
+
+        let hash = cache
+            .get_or_load(Path::new("a"), t0, load)
+            .unwrap()
+            .hash
+            .clone();
+        let cached = cache.get_or_load(Path::new("a"), t0, load).unwrap();
+        assert_eq!(cached.hash, hash);
+        assert_eq!(n_loads.get(), 1);
+        // the file has been modified
+        cache.get_or_load(Path::new("a"), t1, load);
+        assert_eq!(n_loads.get(), 2);
+        cache.invalidate(Path::new("a"));
+        cache.get_or_load(Path::new("a"), t1, load);
+        assert_eq!(n_loads.get(), 3);
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = CoverArtCache::new(2);
+        let n_loads = Cell::new(0);
+        let load = || {
+            n_loads.set(n_loads.get() + 1);
+            None
+        };
+        let t0 = SystemTime::UNIX_EPOCH;
+
+        cache.get_or_load(Path::new("a"), t0, load);
+        cache.get_or_load(Path::new("b"), t0, load);
+        cache.get_or_load(Path::new("a"), t0, load);
+        // evicts "b"
+        cache.get_or_load(Path::new("c"), t0, load);
+        assert_eq!(n_loads.get(), 3);
+        cache.get_or_load(Path::new("a"), t0, load);
+        assert_eq!(n_loads.get(), 3);
+        cache.get_or_load(Path::new("b"), t0, load);
+        assert_eq!(n_loads.get(), 4);
+    }
+}
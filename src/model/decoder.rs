@@ -1,10 +1,11 @@
 use anyhow::{Result, anyhow, bail};
 use bincode::{Decode, Encode};
-use crossbeam_channel::{self as cbeam_chan, TryRecvError};
+use crossbeam_channel::{self as cbeam_chan, RecvTimeoutError, TryRecvError};
 use std::{
     io,
-    path::Path,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
 use symphonia::core::{
     audio::{AudioBuffer, SampleBuffer, Signal},
@@ -13,24 +14,44 @@ use symphonia::core::{
     formats::{FormatReader, SeekMode, SeekTo},
     units::{Time, TimeBase},
 };
-use tokio::sync::oneshot;
+use tokio::sync::{
+    mpsc::{self as tokio_chan},
+    oneshot,
+};
 
-use crate::model::{
-    device::{BaseSample, DeviceProxy},
-    resampler::Resampler,
-    song,
+use crate::{
+    constants,
+    model::{
+        device::{BaseSample, DeviceProxy},
+        equalizer::{Band, Equalizer},
+        resampler::Resampler,
+        song::{self, SongEvent},
+    },
 };
 
 const BASE_SAMPLE_MIN: BaseSample = -1.0;
 const BASE_SAMPLE_MAX: BaseSample = 1.0;
-const MAX_VOLUME: u8 = 100;
+pub const MAX_VOLUME: u8 = 100;
 const MIN_SPEED: u16 = 25; // x0.25
 const MAX_SPEED: u16 = 400; // x4
+// how long to wait for more seek requests before actually seeking
+const SEEK_DEBOUNCE: Duration = Duration::from_millis(100);
+// the next song is opened this many seconds before the current one ends
+const PRELOAD_SECS: u64 = 5;
+// how often to check whether the devices played what they have buffered
+const DRAIN_POLL: Duration = Duration::from_millis(5);
+// a device that stopped taking samples (e.g. because it got unplugged)
+// can't hold up a pause for longer than this
+const MAX_PAUSE_DRAIN: Duration = Duration::from_millis(constants::MAX_BUFFER_MS as u64);
 
 #[derive(Clone, Copy, Debug, Default)]
 pub struct PlaybackTimer {
     pub elapsed: u64,
     pub duration: u64,
+    // the (zero-indexed) chapter that's playing, `None` if there are none
+    // or if the song hasn't reached the first one yet
+    pub chapter: Option<usize>,
+    pub n_chapters: usize,
     time_base: TimeBase,
 }
 
@@ -80,15 +101,25 @@ impl Default for Speed {
 pub enum Seek {
     Forwards(u64),
     Backwards(u64),
+    Absolute(u64),
+    // that many seconds before the end
+    FromEnd(u64),
 }
 
 #[derive(Debug)]
 pub enum DecoderRequest {
     Disable(String),
     Enable(DeviceProxy),
+    // send samples to the next song's decoder instead of to the devices
+    FadeOut(Vec<DeviceProxy>),
     Pause(oneshot::Sender<()>),
     Resume,
     Seek(Seek),
+    // relative to the current chapter
+    SeekChapter(i32),
+    // the song to continue with (without a gap) once the current one ends
+    // (and its duration, if the database knows it)
+    SetNext(Option<(PathBuf, Option<u64>)>),
     Stop,
     Timer(oneshot::Sender<PlaybackTimer>),
 }
@@ -100,19 +131,142 @@ enum DecoderState {
     Active,
 }
 
+// the next song starts fading in when this one has `secs` seconds left
+struct Crossfade {
+    secs: u16,
+    tx_event: tokio_chan::UnboundedSender<SongEvent>,
+    ending_sent: bool,
+}
+
+// a short gain ramp on pause (down to silence) and on resume (back up),
+// so that the playback doesn't stop or start abruptly (which causes clicks)
+struct PauseFade {
+    ms: u16,
+    gain: BaseSample,
+    target: BaseSample,
+}
+
+// samples of the previous song (meant for the device `name`)
+// which get mixed into the first `secs` seconds of this one
+pub struct FadeIn {
+    name: String,
+    rx_sample: cbeam_chan::Receiver<BaseSample>,
+    secs: u16,
+    n_mixed: u64,
+    done: bool,
+}
+
+// an opened song, ready to be decoded
+struct Source {
+    demuxer: Box<dyn FormatReader>,
+    decoder: Box<dyn SymphoniaDecoder>,
+    track_id: u32,
+    duration: Option<u64>,
+    chapters: Vec<u64>,
+    time_base: TimeBase,
+}
+
 pub struct Decoder {
     demuxer: Box<dyn FormatReader>,
     decoder: Box<dyn SymphoniaDecoder>,
     device_proxies: Vec<(DeviceProxy, Option<Resampler>)>,
     track_id: u32,
+    // `None` if unknown
+    duration: Option<u64>,
+    // start times (in seconds) of the chapters, in ascending order
+    chapters: Vec<u64>,
     timer: PlaybackTimer,
     state: DecoderState,
+    // a seek to `timer.elapsed` will be done at this instant
+    pending_seek: Option<Instant>,
+    crossfade: Option<Crossfade>,
+    fade_in: Vec<FadeIn>,
+    pause_fade: PauseFade,
+    // acknowledges the pause once the fade out is over and the devices played it
+    // (otherwise they'd get paused with the end of the fade still in their buffers)
+    pause_ack: Option<oneshot::Sender<()>>,
+    // when to acknowledge the pause even if some device hasn't played everything yet
+    pause_deadline: Option<Instant>,
+    gapless: bool,
+    // whether songs of unknown duration are read in full to find it out
+    exact_durations: bool,
+    next: Option<(PathBuf, Option<u64>)>,
+    // the next song, opened in advance when this one is about to end
+    preloaded: Option<(PathBuf, Source)>,
+    // notifies the player when the decoder moves on to the next song
+    tx_event: Option<tokio_chan::UnboundedSender<SongEvent>>,
 }
 
-impl Decoder {
-    pub fn try_new(
+impl Default for PauseFade {
+    fn default() -> Self {
+        Self {
+            ms: 0,
+            gain: 1.0,
+            target: 1.0,
+        }
+    }
+}
+
+impl PauseFade {
+    fn apply(&mut self, data: &mut AudioBuffer<BaseSample>) {
+        if self.gain == 1.0 && self.target == 1.0 {
+            return;
+        }
+        let spec = *data.spec();
+        let step = decoder_utils::ramp_step(self.ms, spec.rate);
+        // every channel of a frame gets the same gain
+        let gains: Vec<_> = (0..data.frames())
+            .map(|_| {
+                self.gain = decoder_utils::ramp(self.gain, self.target, step);
+                self.gain
+            })
+            .collect();
+        for i in 0..spec.channels.count() {
+            for (s, gain) in data.chan_mut(i).iter_mut().zip(gains.iter()) {
+                *s *= gain;
+            }
+        }
+    }
+}
+
+impl FadeIn {
+    pub fn new(name: String, rx_sample: cbeam_chan::Receiver<BaseSample>, secs: u16) -> Self {
+        Self {
+            name,
+            rx_sample,
+            secs,
+            n_mixed: 0,
+            done: false,
+        }
+    }
+
+    fn mix(&mut self, sample: BaseSample, sample_rate: u32, channels: usize) -> BaseSample {
+        if self.done {
+            return sample;
+        }
+        // the previous decoder sends a NAN (or goes out of scope) when its song ends
+        let prev_sample = match self.rx_sample.recv() {
+            Ok(s) if !s.is_nan() => s,
+            _ => {
+                self.done = true;
+                return sample;
+            }
+        };
+        let total = self.secs as u64 * sample_rate as u64 * channels as u64;
+        let (gain_out, gain_in) = decoder_utils::crossfade_gains(self.n_mixed, total);
+        self.n_mixed += 1;
+        self.done = self.n_mixed >= total;
+
+        (prev_sample * gain_out + sample * gain_in).clamp(BASE_SAMPLE_MIN, BASE_SAMPLE_MAX)
+    }
+}
+
+impl Source {
+    // the duration is scanned for only if neither the file nor the caller knows it
+    fn try_new(
         path: impl AsRef<Path>,
-        device_proxies: Vec<DeviceProxy>,
+        duration: Option<u64>,
+        exact_duration: bool,
         gapless: bool,
     ) -> Result<Self> {
         let demuxer = song::demuxer(&path, gapless)?;
@@ -127,34 +281,160 @@ impl Decoder {
             .codec_params()
             .time_base
             .unwrap_or(TimeBase::default());
+        let duration = match (
+            decoder.codec_params().time_base,
+            decoder.codec_params().n_frames,
+        ) {
+            (Some(tb), Some(n)) => Some(tb.calc_time(n).seconds),
+            _ if duration.is_some() => duration,
+            _ => {
+                let size = path
+                    .as_ref()
+                    .metadata()
+                    .map(|m| m.len())
+                    .unwrap_or_default();
+                song::demuxer(&path, gapless)
+                    .ok()
+                    .and_then(|demuxer| song::scanned_duration(demuxer, size, exact_duration))
+                    .map(|secs| secs as u64)
+            }
+        };
+        // some formats (e.g. audiobooks in MKV or FLAC with a cuesheet) mark chapters with cues
+        let mut chapters: Vec<_> = demuxer
+            .cues()
+            .iter()
+            .map(|cue| time_base.calc_time(cue.start_ts).seconds)
+            .collect();
+        chapters.sort_unstable();
+        chapters.dedup();
+
+        Ok(Self {
+            demuxer,
+            decoder,
+            track_id,
+            duration,
+            chapters,
+            time_base,
+        })
+    }
+}
+
+impl Decoder {
+    // `duration` is the one known from the database (if any)
+    pub fn try_new(
+        path: impl AsRef<Path>,
+        duration: Option<u64>,
+        device_proxies: Vec<DeviceProxy>,
+        gapless: bool,
+        exact_durations: bool,
+    ) -> Result<Self> {
+        let source = Source::try_new(path, duration, exact_durations, gapless)?;
         let device_proxies = device_proxies.into_iter().map(|d| (d, None)).collect();
         let timer = PlaybackTimer {
-            time_base,
+            time_base: source.time_base,
             ..Default::default()
         };
         let state = DecoderState::default();
 
         Ok(Self {
-            demuxer,
-            decoder,
+            demuxer: source.demuxer,
+            decoder: source.decoder,
             device_proxies,
-            track_id,
+            track_id: source.track_id,
+            duration: source.duration,
+            chapters: source.chapters,
             timer,
             state,
+            pending_seek: None,
+            crossfade: None,
+            fade_in: Vec::new(),
+            pause_fade: PauseFade::default(),
+            pause_ack: None,
+            pause_deadline: None,
+            gapless,
+            exact_durations,
+            next: None,
+            preloaded: None,
+            tx_event: None,
         })
     }
 
-    fn seek(&mut self, seek: Seek) {
-        let target_elapsed = match seek {
-            Seek::Forwards(secs) => self
-                .timer
-                .elapsed
-                .saturating_add(secs)
-                .min(self.duration().unwrap_or(u64::MAX)),
-            Seek::Backwards(secs) => self.timer.elapsed.saturating_sub(secs),
+    // makes the decoder send `SongEvent::Chained` when it moves on to the next song
+    // and `SongEvent::Over` when there's no next song
+    pub fn with_chaining(mut self, tx_event: tokio_chan::UnboundedSender<SongEvent>) -> Self {
+        self.tx_event = Some(tx_event);
+        self
+    }
+
+    // makes the decoder send `SongEvent::Ending` when there are `secs` seconds left
+    pub fn with_crossfade(
+        mut self,
+        secs: u16,
+        tx_event: tokio_chan::UnboundedSender<SongEvent>,
+    ) -> Self {
+        if secs > 0 {
+            self.crossfade = Some(Crossfade {
+                secs,
+                tx_event,
+                ending_sent: false,
+            });
+        }
+
+        self
+    }
+
+    pub fn with_fade_in(mut self, fade_in: Vec<FadeIn>) -> Self {
+        self.fade_in = fade_in;
+        self
+    }
+
+    // 0 disables fading on pause and resume
+    pub fn with_pause_fade(mut self, ms: u16) -> Self {
+        self.pause_fade.ms = ms;
+        self
+    }
+
+    // stops decoding, the pause is acknowledged by `ack_pause` later on
+    fn finish_pause(&mut self) {
+        if self.pause_ack.is_some() && self.pause_deadline.is_none() {
+            self.state = DecoderState::Idle;
+            self.pause_deadline = Some(Instant::now() + MAX_PAUSE_DRAIN);
+        }
+    }
+
+    fn ack_pause(&mut self) {
+        let Some(deadline) = self.pause_deadline else {
+            return;
         };
+        let played = self
+            .device_proxies
+            .iter()
+            .all(|(proxy, _)| proxy.tx_sample.is_empty());
+        if played || Instant::now() >= deadline {
+            self.pause_deadline = None;
+            if let Some(tx) = self.pause_ack.take() {
+                let _ = tx.send(());
+            }
+        }
+    }
+
+    // rapid seeks (e.g. from dragging a scrubber) would each reset the decoder,
+    // so we only record the target and do the actual seek after a quiet period
+    fn seek(&mut self, seek: Seek) {
+        self.timer.elapsed = decoder_utils::seek_target(seek, self.timer.elapsed, self.duration());
+        self.pending_seek = Some(Instant::now() + SEEK_DEBOUNCE);
+    }
+
+    fn seek_chapter(&mut self, delta: i32) {
+        self.timer.elapsed =
+            decoder_utils::chapter_target(&self.chapters, self.timer.elapsed, delta);
+        self.pending_seek = Some(Instant::now() + SEEK_DEBOUNCE);
+    }
+
+    fn apply_seek(&mut self) {
+        let _ = self.pending_seek.take();
         let target_time = Time {
-            seconds: target_elapsed,
+            seconds: self.timer.elapsed,
             frac: 0.0,
         };
         let seek_to = SeekTo::Time {
@@ -167,35 +447,135 @@ impl Decoder {
         self.decoder.reset();
     }
 
+    fn preload_next(&mut self) {
+        if let Some((path, duration)) = self.next.take() {
+            match Source::try_new(&path, duration, self.exact_durations, self.gapless) {
+                Ok(source) => self.preloaded = Some((path, source)),
+                // the player will try (and skip) it once this song is over
+                Err(e) => log::warn!("can't preload `{}` ({})", path.to_string_lossy(), e),
+            }
+        }
+    }
+
+    // continues with the next song in place of this one (sending its samples
+    // to the same devices, so that there's no gap between the songs)
+    // returns false if there's no next song
+    fn chain_next(&mut self) -> bool {
+        self.preload_next();
+        let Some((path, source)) = self.preloaded.take() else {
+            return false;
+        };
+        self.demuxer = source.demuxer;
+        self.decoder = source.decoder;
+        self.track_id = source.track_id;
+        self.duration = source.duration;
+        self.chapters = source.chapters;
+        self.timer = PlaybackTimer {
+            duration: self.duration.unwrap_or_default(),
+            time_base: source.time_base,
+            ..Default::default()
+        };
+        // the next song might have a different sample rate
+        for (_, resampler) in self.device_proxies.iter_mut() {
+            let _ = resampler.take();
+        }
+        if let Some(crossfade) = &mut self.crossfade {
+            crossfade.ending_sent = false;
+        }
+        if let Some(tx_event) = &self.tx_event {
+            let _ = tx_event.send(SongEvent::Chained(path));
+        }
+
+        true
+    }
+
     fn stop(&mut self) {
         for proxy in self.device_proxies.iter() {
             let _ = proxy.0.tx_sample.send(BaseSample::NAN);
         }
         self.timer.elapsed = 0;
         self.timer.duration = 0;
+        let _ = self.pending_seek.take();
+    }
+
+    // waits until every device took all samples of the song, so that the player
+    // doesn't start the next one (or stop) before this one is heard in full
+    // returns false if the decoder was stopped in the meantime
+    fn drain(&mut self, rx_request: &cbeam_chan::Receiver<DecoderRequest>) -> bool {
+        loop {
+            self.ack_pause();
+            if self
+                .device_proxies
+                .iter()
+                .all(|(proxy, _)| proxy.tx_sample.is_empty())
+            {
+                return true;
+            }
+            match rx_request.recv_timeout(DRAIN_POLL) {
+                Ok(request) => {
+                    if self.handle_request(request) {
+                        return false;
+                    }
+                    // there's nothing left to fade out
+                    self.finish_pause();
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return false,
+            }
+        }
     }
 
     // true -> stop the decoder
     fn handle_request(&mut self, req: DecoderRequest) -> bool {
         match req {
             DecoderRequest::Disable(device_name) => {
-                self.device_proxies.retain(|p| p.0.name != device_name)
+                self.device_proxies.retain(|p| p.0.name != device_name);
+                // dropping the receiver unblocks the previous decoder
+                self.fade_in.retain(|f| f.name != device_name);
             }
             DecoderRequest::Enable(proxy) => {
                 self.device_proxies.push((proxy, None));
             }
+            DecoderRequest::FadeOut(proxies) => {
+                self.device_proxies = proxies.into_iter().map(|p| (p, None)).collect();
+                // the player already moved on, so the end of this song isn't reported
+                self.tx_event = None;
+            }
             DecoderRequest::Pause(tx) => {
-                self.state = DecoderState::Idle;
-                let _ = tx.send(());
+                self.pause_ack = Some(tx);
+                // (nothing is decoded while waiting to seek, so there'd be nothing to fade out)
+                if self.pause_fade.ms == 0 || self.pending_seek.is_some() {
+                    self.finish_pause();
+                } else {
+                    self.pause_fade.target = 0.0;
+                }
+            }
+            DecoderRequest::Resume => {
+                self.state = DecoderState::Active;
+                self.pause_fade.target = 1.0;
             }
-            DecoderRequest::Resume => self.state = DecoderState::Active,
             DecoderRequest::Seek(seek) => self.seek(seek),
+            DecoderRequest::SeekChapter(delta) => self.seek_chapter(delta),
+            // a song that's already preloaded doesn't have to be opened again
+            DecoderRequest::SetNext(next) => match self.preloaded.take() {
+                Some((preloaded, source))
+                    if next.as_ref().is_some_and(|(path, _)| *path == preloaded) =>
+                {
+                    self.preloaded = Some((preloaded, source));
+                }
+                _ => self.next = next,
+            },
             DecoderRequest::Stop => {
                 self.stop();
                 return true;
             }
             DecoderRequest::Timer(tx) => {
-                let _ = tx.send(self.timer);
+                let timer = PlaybackTimer {
+                    chapter: decoder_utils::current_chapter(&self.chapters, self.timer.elapsed),
+                    n_chapters: self.chapters.len(),
+                    ..self.timer
+                };
+                let _ = tx.send(timer);
             }
         }
 
@@ -207,16 +587,18 @@ impl Decoder {
         rx_request: cbeam_chan::Receiver<DecoderRequest>,
         volume: Arc<RwLock<Volume>>,
         speed: Arc<RwLock<Speed>>,
+        preserve_pitch: Arc<RwLock<bool>>,
+        eq_bands: Arc<RwLock<Vec<Band>>>,
     ) -> Result<()> {
         fn send_decoded_packet(
             proxies: &mut [(DeviceProxy, Option<Resampler>)],
+            fade_in: &mut Vec<FadeIn>,
             data: AudioBuffer<BaseSample>,
             volume: Volume,
         ) {
             if data.frames() == 0 {
                 return;
             }
-            let mult = decoder_utils::volume_to_mult(volume);
             let spec = data.spec();
             let duration = data.capacity() as u64;
             let mut buf = SampleBuffer::new(duration, *spec);
@@ -231,68 +613,144 @@ impl Decoder {
                     },
                     None => unchanged_samples,
                 };
+                let mult = decoder_utils::combined_mult(volume, *proxy.volume.read().unwrap());
+                let mut fade = fade_in.iter_mut().find(|f| f.name == proxy.name);
                 for s in samples
                     .iter()
                     .map(|&s| (s * mult).clamp(BASE_SAMPLE_MIN, BASE_SAMPLE_MAX))
                 {
+                    let s = match fade.as_mut() {
+                        Some(fade) => fade.mix(s, proxy.sample_rate, spec.channels.count()),
+                        None => s,
+                    };
                     let _ = proxy.tx_sample.send(s);
                 }
             }
+            fade_in.retain(|f| !f.done);
         }
 
         self.timer.elapsed = 0;
         self.timer.duration = self.duration().unwrap_or_default();
         self.state = DecoderState::Active;
         let mut prev_speed = { *speed.read().unwrap() };
+        let mut prev_preserve_pitch = { *preserve_pitch.read().unwrap() };
+        let mut equalizer: Option<Equalizer> = None;
         loop {
-            // block if idle to avoid busy waiting
-            let request = match self.state {
-                DecoderState::Idle => rx_request.recv().map_err(|_| TryRecvError::Disconnected),
-                DecoderState::Active => rx_request.try_recv(),
-            };
-            match request {
-                Ok(request) => {
-                    if self.handle_request(request) {
-                        break;
-                    }
+            // block if idle (or waiting to seek) to avoid busy waiting,
+            // but keep checking whether a pause can be acknowledged
+            let wake_up = [
+                self.pending_seek,
+                self.pause_deadline.map(|_| Instant::now() + DRAIN_POLL),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            let request = match (&self.state, wake_up) {
+                (_, Some(deadline)) => rx_request.recv_deadline(deadline).map_err(|e| match e {
+                    RecvTimeoutError::Timeout => TryRecvError::Empty,
+                    RecvTimeoutError::Disconnected => TryRecvError::Disconnected,
+                }),
+                (DecoderState::Idle, None) => {
+                    rx_request.recv().map_err(|_| TryRecvError::Disconnected)
                 }
+                (DecoderState::Active, None) => rx_request.try_recv(),
+            };
+            let should_stop = match request {
+                Ok(request) => self.handle_request(request),
                 // the player went out of scope (due to an error or a Ctrl+C)
                 Err(TryRecvError::Disconnected) => return Ok(()),
-                _ => (),
+                _ => false,
+            };
+            if should_stop {
+                break;
             }
-            if let DecoderState::Active = self.state {
+            if let Some(deadline) = self.pending_seek
+                && Instant::now() >= deadline
+            {
+                self.apply_seek();
+            }
+            if self.pending_seek.is_some() {
+                self.finish_pause();
+            }
+            self.ack_pause();
+            if self.pending_seek.is_none()
+                && let DecoderState::Active = self.state
+            {
                 match self.demuxer.next_packet() {
                     Ok(packet) if packet.track_id() == self.track_id => {
                         match self.decoder.decode(&packet) {
                             Ok(data) => {
                                 let speed = { *speed.read().unwrap() };
+                                let preserve_pitch = { *preserve_pitch.read().unwrap() };
                                 let spec = data.spec();
                                 let duration = data.capacity() as u64;
+                                let changed =
+                                    prev_speed != speed || prev_preserve_pitch != preserve_pitch;
                                 for (proxy, resampler) in self.device_proxies.iter_mut() {
-                                    if (resampler.is_none() && proxy.sample_rate != spec.rate)
-                                        || prev_speed != speed
+                                    if (resampler.is_none()
+                                        && (proxy.sample_rate != spec.rate
+                                            || speed != Speed::default()))
+                                        || changed
                                     {
                                         *resampler = Some(Resampler::new(
                                             *spec,
                                             proxy.sample_rate,
                                             duration,
                                             speed.into(),
+                                            preserve_pitch,
                                         ));
                                     }
                                 }
                                 prev_speed = speed;
+                                prev_preserve_pitch = preserve_pitch;
 
                                 let mut typed_data = data.make_equivalent::<BaseSample>();
                                 data.convert(&mut typed_data);
+                                {
+                                    let bands = eq_bands.read().unwrap();
+                                    let n_channels = spec.channels.count();
+                                    if bands.is_empty() {
+                                        equalizer = None;
+                                    } else if equalizer.as_ref().is_none_or(|eq| {
+                                        eq.is_outdated(&bands, spec.rate, n_channels)
+                                    }) {
+                                        equalizer = Some(Equalizer::new(
+                                            bands.clone(),
+                                            spec.rate,
+                                            n_channels,
+                                        ));
+                                    }
+                                }
+                                if let Some(equalizer) = &mut equalizer {
+                                    equalizer.process(&mut typed_data);
+                                }
+                                self.pause_fade.apply(&mut typed_data);
                                 send_decoded_packet(
                                     &mut self.device_proxies,
+                                    &mut self.fade_in,
                                     typed_data,
                                     *volume.read().unwrap(),
                                 );
+                                // the rest of the packet (after the fade out) is lost
+                                if self.pause_fade.gain == 0.0 {
+                                    self.finish_pause();
+                                }
                                 let new_elapsed = self.timer.time_base.calc_time(packet.ts).seconds;
                                 if new_elapsed != self.timer.elapsed {
                                     self.timer.elapsed = new_elapsed;
                                 }
+                                if let Some(crossfade) = &mut self.crossfade
+                                    && !crossfade.ending_sent
+                                    && self.timer.duration > crossfade.secs as u64
+                                    && self.timer.elapsed + crossfade.secs as u64
+                                        >= self.timer.duration
+                                {
+                                    crossfade.ending_sent = true;
+                                    let _ = crossfade.tx_event.send(SongEvent::Ending);
+                                }
+                                if self.timer.elapsed + PRELOAD_SECS >= self.timer.duration {
+                                    self.preload_next();
+                                }
                             }
                             Err(e) => match e {
                                 SymphoniaError::ResetRequired
@@ -310,8 +768,15 @@ impl Decoder {
                             if matches!(e.kind(), io::ErrorKind::UnexpectedEof) =>
                         {
                             // the entire song has been processed
-                            self.stop();
-                            break;
+                            if !self.chain_next() {
+                                self.stop();
+                                if self.drain(&rx_request)
+                                    && let Some(tx_event) = &self.tx_event
+                                {
+                                    let _ = tx_event.send(SongEvent::Over);
+                                }
+                                break;
+                            }
                         }
                         _ => bail!(e),
                     },
@@ -324,13 +789,7 @@ impl Decoder {
     }
 
     pub fn duration(&self) -> Option<u64> {
-        match (
-            self.decoder.codec_params().time_base,
-            self.decoder.codec_params().n_frames,
-        ) {
-            (Some(tb), Some(n)) => Some(tb.calc_time(n).seconds),
-            _ => None,
-        }
+        self.duration
     }
 }
 
@@ -343,4 +802,270 @@ mod decoder_utils {
         let v: u8 = v.into();
         (((0.07 * (v as BaseSample)).exp() - 1.0) / 1000.0).max(0.0)
     }
+
+    // the global volume is the "master" one, the device's volume
+    // only attenuates it (a device at full volume leaves it unchanged)
+    pub fn combined_mult(volume: Volume, device_volume: Volume) -> BaseSample {
+        volume_to_mult(volume) * volume_to_mult(device_volume) / volume_to_mult(MAX_VOLUME.into())
+    }
+
+    // (gain of the previous song, gain of the next song) after `n_mixed` out of `total` samples
+    pub fn crossfade_gains(n_mixed: u64, total: u64) -> (BaseSample, BaseSample) {
+        if total == 0 {
+            return (0.0, 1.0);
+        }
+        let progress = (n_mixed as BaseSample / total as BaseSample).min(1.0);
+
+        (1.0 - progress, progress)
+    }
+
+    // how much the gain changes per frame when fading on pause or resume
+    pub fn ramp_step(ms: u16, sample_rate: u32) -> BaseSample {
+        if ms == 0 {
+            return 1.0;
+        }
+
+        1000.0 / (ms as BaseSample * sample_rate as BaseSample)
+    }
+
+    // the gain of the next frame, moving linearly towards `target`
+    pub fn ramp(gain: BaseSample, target: BaseSample, step: BaseSample) -> BaseSample {
+        if gain < target {
+            (gain + step).min(target)
+        } else {
+            (gain - step).max(target)
+        }
+    }
+
+    // the position (in seconds) after the seek, never past the end of the song
+    // (seeking from the end of a song of unknown length doesn't move it)
+    pub fn seek_target(seek: Seek, elapsed: u64, duration: Option<u64>) -> u64 {
+        let max = duration.unwrap_or(u64::MAX);
+        match seek {
+            Seek::Forwards(secs) => elapsed.saturating_add(secs).min(max),
+            Seek::Backwards(secs) => elapsed.saturating_sub(secs),
+            Seek::Absolute(secs) => secs.min(max),
+            Seek::FromEnd(secs) => duration.map_or(elapsed, |d| d.saturating_sub(secs)),
+        }
+    }
+
+    // the last chapter which started at or before `elapsed`
+    pub fn current_chapter(chapters: &[u64], elapsed: u64) -> Option<usize> {
+        chapters.iter().rposition(|&start| start <= elapsed)
+    }
+
+    // the start of the chapter `delta` chapters away from the current one
+    // (clamped to the first and the last chapter)
+    pub fn chapter_target(chapters: &[u64], elapsed: u64, delta: i32) -> u64 {
+        if chapters.is_empty() {
+            return elapsed;
+        }
+        let target = match current_chapter(chapters, elapsed) {
+            Some(i) => i as i64 + delta as i64,
+            // before the first chapter, so moving forwards by one gets to it
+            None => delta as i64 - 1,
+        };
+
+        chapters[target.clamp(0, chapters.len() as i64 - 1) as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // decodes the whole song (and the ones chained after it) without any devices
+    fn run_to_end(decoder: &mut Decoder, requests: Vec<DecoderRequest>) -> Vec<SongEvent> {
+        let (tx_event, mut rx_event) = tokio_chan::unbounded_channel();
+        let (tx_request, rx_request) = cbeam_chan::unbounded();
+        decoder.tx_event = Some(tx_event);
+        for request in requests {
+            tx_request.send(request).unwrap();
+        }
+        decoder
+            .run(
+                rx_request,
+                Arc::default(),
+                Arc::default(),
+                Arc::default(),
+                Arc::default(),
+            )
+            .unwrap();
+        let mut events = Vec::new();
+        while let Ok(event) = rx_event.try_recv() {
+            events.push(event);
+        }
+
+        events
+    }
+
+    #[test]
+    fn chain_songs() {
+        let dir = std::env::temp_dir().join(format!("musing_test_chain_{}", std::process::id()));
+        let _ = std::fs::create_dir(&dir);
+        let (a, b, c) = (dir.join("a.wav"), dir.join("b.wav"), dir.join("c.wav"));
+        song::write_silent_wav(&a, 8000, 1, 8000);
+        song::write_silent_wav(&b, 8000, 2, 2 * 8000);
+        song::write_silent_wav(&c, 8000, 1, 3 * 8000);
+
+        let mut decoder = Decoder::try_new(&a, None, Vec::new(), false, false).unwrap();
+        let chained = run_to_end(
+            &mut decoder,
+            vec![DecoderRequest::SetNext(Some((b.clone(), None)))],
+        );
+        let chained_duration = decoder.duration();
+        // the next song was replaced, and then unset
+        let mut decoder = Decoder::try_new(&a, None, Vec::new(), false, false).unwrap();
+        let replaced = run_to_end(
+            &mut decoder,
+            vec![
+                DecoderRequest::SetNext(Some((b.clone(), None))),
+                DecoderRequest::SetNext(Some((c.clone(), None))),
+            ],
+        );
+        let mut decoder = Decoder::try_new(&a, None, Vec::new(), false, false).unwrap();
+        let unset = run_to_end(
+            &mut decoder,
+            vec![
+                DecoderRequest::SetNext(Some((b.clone(), None))),
+                DecoderRequest::SetNext(None),
+            ],
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(matches!(&chained[..], [SongEvent::Chained(path), SongEvent::Over] if *path == b));
+        assert_eq!(chained_duration, Some(2));
+        assert!(matches!(&replaced[..], [SongEvent::Chained(path), SongEvent::Over] if *path == c));
+        assert!(matches!(&unset[..], [SongEvent::Over]));
+    }
+
+    #[test]
+    fn known_duration() {
+        let path = std::env::temp_dir().join(format!(
+            "musing_test_known_duration_{}.flac",
+            std::process::id()
+        ));
+        song::write_minimal_flac(&path, &[]);
+        // zero out the number of samples in STREAMINFO, i.e. the length is unknown
+        let mut flac = std::fs::read(&path).unwrap();
+        flac[25] = 0;
+        std::fs::write(&path, flac).unwrap();
+
+        let known = Decoder::try_new(&path, Some(42), Vec::new(), false, false)
+            .unwrap()
+            .duration();
+        let scanned = Decoder::try_new(&path, None, Vec::new(), false, false)
+            .unwrap()
+            .duration();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(known, Some(42));
+        assert_ne!(scanned, Some(42));
+    }
+
+    #[test]
+    fn pause_fade_ramp() {
+        use symphonia::core::audio::{Channels, SignalSpec};
+
+        // 4 ms at 1 kHz == a ramp over 4 frames
+        let spec = SignalSpec::new(1000, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let buffer = || {
+            let mut data = AudioBuffer::<BaseSample>::new(6, spec);
+            data.render_reserved(Some(6));
+            for i in 0..2 {
+                data.chan_mut(i).fill(1.0);
+            }
+            data
+        };
+        let mut fade = PauseFade {
+            ms: 4,
+            ..Default::default()
+        };
+
+        let mut data = buffer();
+        fade.apply(&mut data);
+        assert_eq!(data.chan(0), [1.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+        fade.target = 0.0;
+        fade.apply(&mut data);
+        assert_eq!(data.chan(0), [0.75, 0.5, 0.25, 0.0, 0.0, 0.0]);
+        assert_eq!(data.chan(1), data.chan(0));
+        fade.target = 1.0;
+        let mut data = buffer();
+        fade.apply(&mut data);
+        assert_eq!(data.chan(1), [0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn seek_clamping() {
+        use decoder_utils::seek_target;
+
+        assert_eq!(seek_target(Seek::Absolute(100), 10, Some(200)), 100);
+        assert_eq!(seek_target(Seek::Absolute(250), 10, Some(200)), 200);
+        assert_eq!(seek_target(Seek::Absolute(250), 10, None), 250);
+        assert_eq!(seek_target(Seek::Forwards(195), 10, Some(200)), 200);
+        assert_eq!(seek_target(Seek::Backwards(15), 10, Some(200)), 0);
+    }
+
+    #[test]
+    fn seek_from_end() {
+        use decoder_utils::seek_target;
+
+        assert_eq!(seek_target(Seek::FromEnd(30), 10, Some(200)), 170);
+        assert_eq!(seek_target(Seek::FromEnd(0), 10, Some(200)), 200);
+        assert_eq!(seek_target(Seek::FromEnd(250), 10, Some(200)), 0);
+        assert_eq!(seek_target(Seek::FromEnd(30), 10, None), 10);
+    }
+
+    #[test]
+    fn chapter_clamping() {
+        use decoder_utils::{chapter_target, current_chapter};
+
+        let chapters = [10, 100, 200];
+        assert_eq!(current_chapter(&chapters, 5), None);
+        assert_eq!(current_chapter(&chapters, 100), Some(1));
+        assert_eq!(current_chapter(&chapters, 500), Some(2));
+        assert_eq!(chapter_target(&chapters, 150, 1), 200);
+        assert_eq!(chapter_target(&chapters, 150, -1), 10);
+        assert_eq!(chapter_target(&chapters, 150, 0), 100);
+        assert_eq!(chapter_target(&chapters, 150, 5), 200);
+        assert_eq!(chapter_target(&chapters, 150, -5), 10);
+        assert_eq!(chapter_target(&chapters, 5, 1), 10);
+        assert_eq!(chapter_target(&chapters, 5, -1), 10);
+        assert_eq!(chapter_target(&[], 42, 1), 42);
+    }
+
+    #[test]
+    fn device_volume() {
+        use decoder_utils::{combined_mult, volume_to_mult};
+
+        let max = Volume::from(MAX_VOLUME);
+        for v in [0, 20, 50, 100] {
+            let mult = combined_mult(v.into(), max);
+            assert!((mult - volume_to_mult(v.into())).abs() < 1e-9);
+            assert_eq!(combined_mult(v.into(), 0.into()), 0.0);
+        }
+        // the order doesn't matter and a quieter device means a quieter output
+        assert!(
+            (combined_mult(30.into(), 70.into()) - combined_mult(70.into(), 30.into())).abs()
+                < 1e-9
+        );
+        assert!(combined_mult(70.into(), 50.into()) < combined_mult(70.into(), 60.into()));
+        assert!(combined_mult(70.into(), 50.into()) < volume_to_mult(70.into()));
+    }
+
+    #[test]
+    fn crossfade_ramp() {
+        use decoder_utils::crossfade_gains;
+
+        assert_eq!(crossfade_gains(0, 100), (1.0, 0.0));
+        assert_eq!(crossfade_gains(25, 100), (0.75, 0.25));
+        assert_eq!(crossfade_gains(50, 100), (0.5, 0.5));
+        assert_eq!(crossfade_gains(100, 100), (0.0, 1.0));
+        assert_eq!(crossfade_gains(150, 100), (0.0, 1.0));
+        assert_eq!(crossfade_gains(0, 0), (0.0, 1.0));
+        for n_mixed in 0..=100 {
+            let (gain_out, gain_in) = crossfade_gains(n_mixed, 100);
+            assert!((gain_out + gain_in - 1.0).abs() < 1e-9);
+        }
+    }
 }
@@ -1,20 +1,38 @@
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
 use cpal::{
-    Device as CpalDevice, FromSample, OutputCallbackInfo, SampleFormat, SizedSample,
-    SupportedStreamConfig,
+    Device as CpalDevice, FromSample, I24, OutputCallbackInfo, SampleFormat, SampleRate,
+    SizedSample, StreamError, SupportedBufferSize, SupportedStreamConfig,
+    SupportedStreamConfigRange,
     platform::Stream as CpalStream,
     traits::{DeviceTrait, StreamTrait},
 };
 use crossbeam_channel::{self as cbeam_chan};
+use std::{
+    cmp::Reverse,
+    fmt::{self, Display},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc::{self as tokio_chan};
 
-use crate::{constants, model::song::SongEvent};
+use crate::{
+    constants,
+    model::{
+        decoder::{MAX_VOLUME, Volume},
+        song::SongEvent,
+    },
+};
 
 pub type BaseSample = f64;
 trait Sample: FromSample<BaseSample> + SizedSample + Send + 'static {}
 
 impl Sample for i8 {}
 impl Sample for i16 {}
+impl Sample for I24 {}
 impl Sample for i32 {}
 impl Sample for i64 {}
 impl Sample for u8 {}
@@ -24,11 +42,41 @@ impl Sample for u64 {}
 impl Sample for f32 {}
 impl Sample for f64 {}
 
+// where the samples of devices not backed by the system's audio go
+// (e.g. a WAV file or the HTTP stream)
+pub trait SampleSink: Send {
+    fn write(&mut self, sample: BaseSample) -> Result<()>;
+
+    // called when a song ends and when the device stops
+    fn flush(&mut self) -> Result<()>;
+}
+
+pub type SharedSink = Arc<Mutex<dyn SampleSink>>;
+
+// stands in for the audio callback of a real device, so it takes
+// samples at the same pace (and passes them to the sink)
+struct SinkStream {
+    paused: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+enum OutputStream {
+    Cpal(CpalStream),
+    Sink(SinkStream),
+}
+
 struct Stream {
-    cpal_stream: CpalStream,
+    output_stream: OutputStream,
     tx_sample: cbeam_chan::Sender<BaseSample>,
 }
 
+enum Output {
+    Cpal(CpalDevice),
+    // (name, sink)
+    Sink(String, SharedSink),
+}
+
 #[derive(Default)]
 enum DeviceState {
     #[default]
@@ -38,9 +86,21 @@ enum DeviceState {
 }
 
 pub struct Device {
-    cpal_device: CpalDevice,
+    output: Output,
     config: SupportedStreamConfig,
     state: DeviceState,
+    // applied on top of the global volume
+    volume: Arc<RwLock<Volume>>,
+    buffer_ms: u16,
+}
+
+// overrides of the device's default output config,
+// the fields that aren't set are taken from the default one if possible
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceConfig {
+    pub sample_rate: Option<u32>,
+    pub sample_format: Option<SampleFormat>,
+    pub channels: Option<u16>,
 }
 
 #[derive(Debug)]
@@ -48,6 +108,7 @@ pub struct DeviceProxy {
     pub name: String,
     pub sample_rate: u32,
     pub tx_sample: cbeam_chan::Sender<BaseSample>,
+    pub volume: Arc<RwLock<Volume>>,
 }
 
 impl TryFrom<CpalDevice> for Device {
@@ -55,19 +116,172 @@ impl TryFrom<CpalDevice> for Device {
 
     fn try_from(cpal_device: CpalDevice) -> Result<Self> {
         let config = cpal_device.default_output_config()?;
-        Ok(Self {
-            cpal_device,
-            config,
-            state: DeviceState::default(),
-        })
+        Ok(Self::new(Output::Cpal(cpal_device), config))
+    }
+}
+
+impl Drop for SinkStream {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl OutputStream {
+    fn play(&self) -> Result<()> {
+        match self {
+            OutputStream::Cpal(cpal_stream) => cpal_stream.play().map_err(|e| e.into()),
+            OutputStream::Sink(sink_stream) => {
+                sink_stream.paused.store(false, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    fn pause(&self) -> Result<()> {
+        match self {
+            OutputStream::Cpal(cpal_stream) => cpal_stream.pause().map_err(|e| e.into()),
+            OutputStream::Sink(sink_stream) => {
+                sink_stream.paused.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Display for DeviceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(sample_rate) = self.sample_rate {
+            parts.push(format!("{} Hz", sample_rate));
+        }
+        if let Some(sample_format) = self.sample_format {
+            parts.push(sample_format.to_string());
+        }
+        if let Some(channels) = self.channels {
+            parts.push(format!("{} channels", channels));
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl DeviceConfig {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // the config of devices not backed by the system's audio,
+    // 44.1 kHz, 16-bit stereo unless set otherwise
+    pub fn sink_config(&self) -> SupportedStreamConfig {
+        SupportedStreamConfig::new(
+            self.channels.unwrap_or(2),
+            SampleRate(self.sample_rate.unwrap_or(44100)),
+            SupportedBufferSize::Unknown,
+            self.sample_format.unwrap_or(SampleFormat::I16),
+        )
+    }
+
+    // the inverse of `SampleFormat`'s `Display`, e.g. "i24"
+    pub fn parse_sample_format(s: &str) -> Result<SampleFormat> {
+        use SampleFormat::*;
+        [I8, I16, I24, I32, I64, U8, U16, U32, U64, F32, F64]
+            .into_iter()
+            .find(|format| format.to_string() == s.to_lowercase())
+            .ok_or(anyhow!("invalid sample format `{}`", s))
+    }
+
+    // picks one of the `supported` configs that matches every set field,
+    // preferring the ones closest to the `default` config
+    pub fn select(
+        &self,
+        supported: impl IntoIterator<Item = SupportedStreamConfigRange>,
+        default: Option<&SupportedStreamConfig>,
+    ) -> Result<SupportedStreamConfig> {
+        let mut matching: Vec<_> = supported
+            .into_iter()
+            .filter(|range| {
+                self.channels.is_none_or(|c| range.channels() == c)
+                    && self
+                        .sample_format
+                        .is_none_or(|f| range.sample_format() == f)
+                    && self.sample_rate.is_none_or(|r| {
+                        (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&r)
+                    })
+            })
+            .collect();
+        // (stable, so the order of equally close configs is kept)
+        matching.sort_by_key(|range| {
+            Reverse(default.map(|d| {
+                (
+                    range.channels() == d.channels(),
+                    range.sample_format() == d.sample_format(),
+                )
+            }))
+        });
+        let Some(range) = matching.into_iter().next() else {
+            bail!("no supported output config matches `{}`", self);
+        };
+        let sample_rate = self.sample_rate.or(default.map(|d| d.sample_rate().0));
+
+        match sample_rate.and_then(|r| range.try_with_sample_rate(SampleRate(r))) {
+            Some(config) => Ok(config),
+            None => Ok(range.with_max_sample_rate()),
+        }
     }
 }
 
 impl Device {
+    fn new(output: Output, config: SupportedStreamConfig) -> Self {
+        Self {
+            output,
+            config,
+            state: DeviceState::default(),
+            volume: Arc::new(RwLock::new(MAX_VOLUME.into())),
+            buffer_ms: constants::DEFAULT_BUFFER_MS,
+        }
+    }
+
+    // uses the config that matches `device_config` instead of the default one
+    pub fn try_with_config(cpal_device: CpalDevice, device_config: &DeviceConfig) -> Result<Self> {
+        if device_config.is_empty() {
+            return Self::try_from(cpal_device);
+        }
+        let default = cpal_device.default_output_config().ok();
+        let config = device_config
+            .select(cpal_device.supported_output_configs()?, default.as_ref())
+            .map_err(|e| {
+                anyhow!(
+                    "can't configure `{}` ({})",
+                    cpal_device
+                        .name()
+                        .unwrap_or(constants::UNKNOWN_DEVICE.into()),
+                    e
+                )
+            })?;
+
+        Ok(Self::new(Output::Cpal(cpal_device), config))
+    }
+
+    // the sink must expect samples in the format described by `config`
+    pub fn with_sink(
+        name: impl Into<String>,
+        sink: SharedSink,
+        config: SupportedStreamConfig,
+    ) -> Self {
+        Self::new(Output::Sink(name.into(), sink), config)
+    }
+
+    pub fn with_buffer_ms(mut self, buffer_ms: u16) -> Self {
+        self.buffer_ms = buffer_ms;
+        self
+    }
+
     fn create_data_callback<T>(
         &self,
         rx_sample: cbeam_chan::Receiver<BaseSample>,
-        tx_event: tokio_chan::UnboundedSender<SongEvent>,
     ) -> Result<impl FnMut(&mut [T], &OutputCallbackInfo) + Send + 'static>
     where
         T: Sample,
@@ -76,8 +290,8 @@ impl Device {
             let mut i = 0;
             while let Ok(s) = rx_sample.try_recv() {
                 // NAN == the end of this song
+                // (the decoder tells the player about it once every device got here)
                 if s.is_nan() {
-                    let _ = tx_event.send(SongEvent::Over);
                     break;
                 }
                 data[i] = T::from_sample(s);
@@ -93,23 +307,47 @@ impl Device {
     }
 
     fn build_stream(&self, tx_event: tokio_chan::UnboundedSender<SongEvent>) -> Result<Stream> {
-        // buffer 100 ms of audio
-        // too little buffering forces the decoder to pause frequently,
-        // and too much causes considerable delays on volume changes and seeks
-        // 100 ms seems to be a decent middle ground
+        // buffer `buffer_ms` of audio (100 ms by default)
+        // too little buffering forces the decoder to pause frequently (and may cause
+        // underruns on some hardware), and too much causes considerable delays
+        // on volume changes and seeks
         let (tx_sample, rx_sample) = cbeam_chan::bounded(
-            self.config.channels() as usize * self.config.sample_rate().0 as usize / 10,
+            self.config.channels() as usize
+                * self.config.sample_rate().0 as usize
+                * self.buffer_ms as usize
+                / 1000,
         );
+        // only an unavailable device means that it stopped working for good
+        // (e.g. headphones got unplugged), other errors may be transient
+        let name = self.name().unwrap_or(constants::UNKNOWN_DEVICE.into());
+        let tx_event_err = tx_event.clone();
+        let error_callback = move |e: StreamError| match e {
+            StreamError::DeviceNotAvailable => {
+                let _ = tx_event_err.send(SongEvent::DeviceLost(name.clone()));
+            }
+            e => log::error!("playback error ({})", e),
+        };
+
+        let cpal_device = match &self.output {
+            Output::Cpal(cpal_device) => cpal_device,
+            Output::Sink(_, sink) => {
+                let sink_stream = self.build_sink_stream(Arc::clone(sink), rx_sample, tx_event);
+                return Ok(Stream {
+                    output_stream: OutputStream::Sink(sink_stream),
+                    tx_sample,
+                });
+            }
+        };
 
         macro_rules! build_output_stream {
             ($type:ty) => {
                 Ok(Stream {
-                    cpal_stream: self.cpal_device.build_output_stream(
+                    output_stream: OutputStream::Cpal(cpal_device.build_output_stream(
                         &self.config.clone().into(),
-                        self.create_data_callback::<$type>(rx_sample, tx_event)?,
-                        |e| log::error!("playback error ({})", e),
+                        self.create_data_callback::<$type>(rx_sample)?,
+                        error_callback,
                         None,
-                    )?,
+                    )?),
                     tx_sample,
                 })
             };
@@ -119,6 +357,7 @@ impl Device {
         match self.config.sample_format() {
             I8 => build_output_stream!(i8),
             I16 => build_output_stream!(i16),
+            I24 => build_output_stream!(cpal::I24),
             I32 => build_output_stream!(i32),
             I64 => build_output_stream!(i64),
             U8 => build_output_stream!(u8),
@@ -131,12 +370,67 @@ impl Device {
         }
     }
 
+    // the stream starts paused (like a cpal one)
+    fn build_sink_stream(
+        &self,
+        sink: SharedSink,
+        rx_sample: cbeam_chan::Receiver<BaseSample>,
+        tx_event: tokio_chan::UnboundedSender<SongEvent>,
+    ) -> SinkStream {
+        let paused = Arc::new(AtomicBool::new(true));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let name = self.name().unwrap_or(constants::UNKNOWN_DEVICE.into());
+        let samples_per_tick = self.config.channels() as usize
+            * self.config.sample_rate().0 as usize
+            * constants::SINK_OUTPUT_TICK_MS as usize
+            / 1000;
+        let thread = {
+            let paused = Arc::clone(&paused);
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || {
+                let res = device_utils::run_sink_output(
+                    &sink,
+                    rx_sample,
+                    samples_per_tick,
+                    &paused,
+                    &stopped,
+                )
+                .and_then(|_| sink.lock().unwrap().flush());
+                if let Err(e) = res {
+                    log::error!("playback error ({})", e);
+                    let _ = tx_event.send(SongEvent::DeviceLost(name));
+                }
+            })
+        };
+
+        SinkStream {
+            paused,
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
     pub fn is_enabled(&self) -> bool {
         !matches!(self.state, DeviceState::Disabled)
     }
 
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, DeviceState::Active(_))
+    }
+
     pub fn name(&self) -> Result<String> {
-        self.cpal_device.name().map_err(|e| e.into())
+        match &self.output {
+            Output::Cpal(cpal_device) => cpal_device.name().map_err(|e| e.into()),
+            Output::Sink(name, _) => Ok(name.clone()),
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        (*self.volume.read().unwrap()).into()
+    }
+
+    pub fn set_volume(&mut self, volume: u8) {
+        *self.volume.write().unwrap() = volume.into();
     }
 
     pub fn disable(&mut self) {
@@ -162,25 +456,22 @@ impl Device {
 
     pub fn play(&mut self, tx_event: tokio_chan::UnboundedSender<SongEvent>) -> Result<()> {
         let stream = self.build_stream(tx_event)?;
-        match stream.cpal_stream.play() {
-            Ok(_) => {
-                self.state = DeviceState::Active(stream);
-                Ok(())
-            }
-            Err(e) => Err(e.into()),
-        }
+        stream.output_stream.play()?;
+        self.state = DeviceState::Active(stream);
+
+        Ok(())
     }
 
     pub fn pause(&mut self) -> Result<()> {
         match &self.state {
-            DeviceState::Active(stream) => stream.cpal_stream.pause().map_err(|e| e.into()),
+            DeviceState::Active(stream) => stream.output_stream.pause(),
             _ => Ok(()),
         }
     }
 
     pub fn resume(&mut self) -> Result<()> {
         match &self.state {
-            DeviceState::Active(stream) => stream.cpal_stream.play().map_err(|e| e.into()),
+            DeviceState::Active(stream) => stream.output_stream.play(),
             _ => Ok(()),
         }
     }
@@ -195,14 +486,114 @@ impl DeviceProxy {
     pub fn try_new(device: &Device) -> Option<Self> {
         match &device.state {
             DeviceState::Active(stream) => Some(Self {
-                name: device
-                    .cpal_device
-                    .name()
-                    .unwrap_or(constants::UNKNOWN_DEVICE.into()),
+                name: device.name().unwrap_or(constants::UNKNOWN_DEVICE.into()),
                 sample_rate: device.config.sample_rate().0,
                 tx_sample: stream.tx_sample.clone(),
+                volume: Arc::clone(&device.volume),
             }),
             _ => None,
         }
     }
 }
+
+mod device_utils {
+    use super::*;
+
+    // writes whatever the decoder sent in the meantime once every tick,
+    // until the stream gets dropped
+    pub fn run_sink_output(
+        sink: &Mutex<dyn SampleSink>,
+        rx_sample: cbeam_chan::Receiver<BaseSample>,
+        samples_per_tick: usize,
+        paused: &AtomicBool,
+        stopped: &AtomicBool,
+    ) -> Result<()> {
+        let tick = Duration::from_millis(constants::SINK_OUTPUT_TICK_MS);
+        let mut next_tick = Instant::now();
+        while !stopped.load(Ordering::Relaxed) {
+            next_tick += tick;
+            if !paused.load(Ordering::Relaxed) {
+                let mut sink = sink.lock().unwrap();
+                for s in rx_sample.try_iter().take(samples_per_tick) {
+                    // NAN == the end of this song
+                    if s.is_nan() {
+                        sink.flush()?;
+                        break;
+                    }
+                    sink.write(s)?;
+                }
+            }
+            thread::sleep(next_tick.saturating_duration_since(Instant::now()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpal::SupportedBufferSize;
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    #[test]
+    fn select_config() {
+        let supported = [
+            range(2, 44100, 48000, SampleFormat::I16),
+            range(6, 44100, 192000, SampleFormat::I24),
+            range(2, 44100, 192000, SampleFormat::I24),
+            range(2, 44100, 192000, SampleFormat::F32),
+        ];
+        let default = range(2, 44100, 48000, SampleFormat::F32).with_sample_rate(SampleRate(44100));
+
+        let config = DeviceConfig {
+            sample_rate: Some(96000),
+            sample_format: Some(SampleFormat::I24),
+            channels: None,
+        }
+        .select(supported, Some(&default))
+        .unwrap();
+        assert_eq!(config.channels(), 2);
+        assert_eq!(config.sample_rate(), SampleRate(96000));
+        assert_eq!(config.sample_format(), SampleFormat::I24);
+
+        // unset fields are taken from the default config
+        let config = DeviceConfig {
+            sample_rate: Some(48000),
+            ..Default::default()
+        }
+        .select(supported, Some(&default))
+        .unwrap();
+        assert_eq!(config.sample_format(), SampleFormat::F32);
+        let config = DeviceConfig {
+            channels: Some(6),
+            ..Default::default()
+        }
+        .select(supported, Some(&default))
+        .unwrap();
+        assert_eq!(config.sample_rate(), SampleRate(44100));
+        assert_eq!(config.sample_format(), SampleFormat::I24);
+
+        let res = DeviceConfig {
+            sample_rate: Some(96000),
+            sample_format: Some(SampleFormat::I16),
+            channels: None,
+        }
+        .select(supported, Some(&default));
+        assert!(res.is_err_and(|e| e.to_string().contains("96000 Hz, i16")));
+    }
+}
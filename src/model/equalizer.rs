@@ -0,0 +1,219 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use symphonia::core::audio::{AudioBuffer, Signal};
+
+use crate::{
+    constants::{MAX_EQ_FREQ, MAX_EQ_GAIN, MAX_EQ_Q, MIN_EQ_Q},
+    model::device::BaseSample,
+};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Band {
+    pub freq: f64, // center frequency in Hz
+    pub gain: f64, // in dB
+    pub q: f64,
+}
+
+// normalized (a0 = 1) coefficients of a biquad filter
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Biquad {
+    coeffs: Coefficients,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+pub struct Equalizer {
+    bands: Vec<Band>,
+    sample_rate: u32,
+    // one chain of filters per channel
+    filters: Vec<Vec<Biquad>>,
+}
+
+impl Band {
+    pub fn validate(&self) -> Result<()> {
+        // NaNs fail all of these checks
+        if !(self.freq > 0.0 && self.freq <= MAX_EQ_FREQ) {
+            bail!("`freq` must be positive and at most {} Hz", MAX_EQ_FREQ);
+        }
+        if !(MIN_EQ_Q..=MAX_EQ_Q).contains(&self.q) {
+            bail!("`q` must be between {} and {}", MIN_EQ_Q, MAX_EQ_Q);
+        }
+        if !(-MAX_EQ_GAIN..=MAX_EQ_GAIN).contains(&self.gain) {
+            bail!(
+                "`gain` must be between -{} and {} dB",
+                MAX_EQ_GAIN,
+                MAX_EQ_GAIN
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Coefficients {
+    // a peaking filter from the Audio EQ Cookbook
+    // https://www.w3.org/TR/audio-eq-cookbook/
+    fn peaking(band: &Band, sample_rate: u32) -> Self {
+        let a = 10.0_f64.powf(band.gain / 40.0);
+        let w0 = 2.0 * PI * band.freq / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * band.q);
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha / a;
+
+        Self {
+            b0: (1.0 + alpha * a) / a0,
+            b1: (-2.0 * cos_w0) / a0,
+            b2: (1.0 - alpha * a) / a0,
+            a1: (-2.0 * cos_w0) / a0,
+            a2: (1.0 - alpha / a) / a0,
+        }
+    }
+}
+
+impl Biquad {
+    fn new(coeffs: Coefficients) -> Self {
+        Self {
+            coeffs,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let Coefficients { b0, b1, b2, a1, a2 } = self.coeffs;
+        let y = b0 * x + b1 * self.x1 + b2 * self.x2 - a1 * self.y1 - a2 * self.y2;
+        (self.x2, self.x1) = (self.x1, x);
+        (self.y2, self.y1) = (self.y1, y);
+
+        y
+    }
+}
+
+impl Equalizer {
+    pub fn new(bands: Vec<Band>, sample_rate: u32, n_channels: usize) -> Self {
+        let chain: Vec<_> = bands
+            .iter()
+            // bands at or above the Nyquist frequency (e.g. 16 kHz in a song sampled at 22.05 kHz)
+            // can't be represented, so they're skipped
+            .filter(|band| band.freq < sample_rate as f64 / 2.0)
+            .map(|band| Biquad::new(Coefficients::peaking(band, sample_rate)))
+            .collect();
+
+        Self {
+            bands,
+            sample_rate,
+            filters: vec![chain; n_channels],
+        }
+    }
+
+    // true if the filters have to be rebuilt
+    pub fn is_outdated(&self, bands: &[Band], sample_rate: u32, n_channels: usize) -> bool {
+        self.bands != bands || self.sample_rate != sample_rate || self.filters.len() != n_channels
+    }
+
+    pub fn process(&mut self, data: &mut AudioBuffer<BaseSample>) {
+        for (i, chain) in self.filters.iter_mut().enumerate() {
+            if chain.is_empty() {
+                continue;
+            }
+            for s in data.chan_mut(i) {
+                *s = chain.iter_mut().fold(*s, |x, biquad| biquad.process(x));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the magnitude of the filter's frequency response at `freq`
+    fn magnitude(c: Coefficients, freq: f64, sample_rate: u32) -> f64 {
+        let w = 2.0 * PI * freq / sample_rate as f64;
+        let (cos1, sin1, cos2, sin2) = (w.cos(), w.sin(), (2.0 * w).cos(), (2.0 * w).sin());
+        let num = (c.b0 + c.b1 * cos1 + c.b2 * cos2).hypot(c.b1 * sin1 + c.b2 * sin2);
+        let den = (1.0 + c.a1 * cos1 + c.a2 * cos2).hypot(c.a1 * sin1 + c.a2 * sin2);
+
+        num / den
+    }
+
+    #[test]
+    fn peaking_coefficients() {
+        let close = |lhs: f64, rhs: f64| (lhs - rhs).abs() < 1e-6;
+
+        let band = Band {
+            freq: 1000.0,
+            gain: 6.0,
+            q: 1.0,
+        };
+        let c = Coefficients::peaking(&band, 48000);
+        assert!(close(c.a1, c.b1));
+        assert!(close(
+            magnitude(c, 1000.0, 48000),
+            10.0_f64.powf(6.0 / 20.0)
+        ));
+        // far away from the center frequency the signal is (almost) unchanged
+        assert!((magnitude(c, 20.0, 48000) - 1.0).abs() < 0.01);
+        assert!((magnitude(c, 20000.0, 48000) - 1.0).abs() < 0.01);
+
+        let band = Band {
+            freq: 100.0,
+            gain: -12.0,
+            q: 0.7,
+        };
+        let c = Coefficients::peaking(&band, 44100);
+        assert!(close(
+            magnitude(c, 100.0, 44100),
+            10.0_f64.powf(-12.0 / 20.0)
+        ));
+
+        // no gain == no change
+        let band = Band {
+            freq: 5000.0,
+            gain: 0.0,
+            q: 2.0,
+        };
+        let c = Coefficients::peaking(&band, 44100);
+        assert_eq!((c.b1, c.b2), (c.a1, c.a2));
+        assert!(close(c.b0, 1.0));
+    }
+
+    #[test]
+    fn band_limits() {
+        let band = |freq, gain, q| Band { freq, gain, q };
+
+        assert!(band(1000.0, 6.0, 1.0).validate().is_ok());
+        assert!(band(MAX_EQ_FREQ, -MAX_EQ_GAIN, MIN_EQ_Q).validate().is_ok());
+        assert!(band(0.0, 6.0, 1.0).validate().is_err());
+        assert!(band(MAX_EQ_FREQ + 1.0, 6.0, 1.0).validate().is_err());
+        assert!(band(f64::NAN, 6.0, 1.0).validate().is_err());
+        assert!(band(1000.0, 30.0, 1.0).validate().is_err());
+        assert!(band(1000.0, -30.0, 1.0).validate().is_err());
+        assert!(band(1000.0, f64::INFINITY, 1.0).validate().is_err());
+        assert!(band(1000.0, 6.0, 0.01).validate().is_err());
+        assert!(band(1000.0, 6.0, 50.0).validate().is_err());
+        assert!(band(1000.0, 6.0, f64::NAN).validate().is_err());
+
+        // a valid band can still be out of range of a song with a low sample rate
+        let eq = Equalizer::new(
+            vec![band(1000.0, 6.0, 1.0), band(8000.0, 6.0, 1.0)],
+            16000,
+            2,
+        );
+        assert!(eq.filters.iter().all(|chain| chain.len() == 1));
+    }
+}
@@ -1,16 +1,30 @@
 use anyhow::{Result, anyhow, bail};
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
 use serde_json::Value;
 use unidecode::unidecode;
 
-use crate::model::{song::Song, tag_key::TagKey};
+use crate::model::{
+    song::Song,
+    tag_key::{TagKey, TagKeyKind},
+};
+
+// regexes come from clients, so they can't be allowed to take up arbitrarily
+// much memory (the defaults are 10 MiB for the compiled regex and 2 MiB for the DFA cache)
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+const REGEX_DFA_SIZE_LIMIT: usize = 1 << 20;
 
 pub trait Filter: Send + Sync {
     fn matches(&self, song: &Song) -> bool;
 }
 
-// filters inside of one expression are joined by a logical "and"
-pub struct FilterExpr(pub Vec<Box<dyn Filter>>);
+// a tree of filters joined by logical operators
+// (a plain array of filters is the same as an "and" of them)
+pub enum FilterExpr {
+    Filter(Box<dyn Filter>),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+    Not(Box<FilterExpr>),
+}
 
 // matches iff the tag value matches the regex
 #[derive(Debug)]
@@ -19,21 +33,214 @@ struct RegexFilter {
     regex: Regex,
 }
 
+// matches iff the tag value contains `value`, ignoring the letter case
+// (much cheaper than a regex)
+#[derive(Debug)]
+struct ContainsFilter {
+    tag: TagKey,
+    value: String, // transliterated and lowercase
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CompareOp {
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PathPart {
+    Full,
+    FileName,
+}
+
+// like RegexFilter, but matches the song's path instead of a tag value
+// (usable with the pseudo-tags `path` and `filename`)
+#[derive(Debug)]
+struct PathFilter {
+    part: PathPart,
+    regex: Regex,
+}
+
+// matches iff the (numeric) tag value compared with `value` satisfies `op`
+#[derive(Debug)]
+struct CompareFilter {
+    tag: TagKey,
+    op: CompareOp,
+    value: f64,
+}
+
+// matches iff the song's duration (in seconds) is within [min, max]
+// a missing bound doesn't limit the range
+#[derive(Debug)]
+struct DurationFilter {
+    min: Option<u64>,
+    max: Option<u64>,
+}
+
+// matches iff the song has a value of `tag` (or doesn't, if `present` is false)
+#[derive(Debug)]
+struct ExistsFilter {
+    tag: TagKey,
+    present: bool,
+}
+
+impl TryFrom<&str> for CompareOp {
+    type Error = anyhow::Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        match s {
+            ">" => Ok(CompareOp::Greater),
+            ">=" => Ok(CompareOp::GreaterEqual),
+            "<" => Ok(CompareOp::Less),
+            "<=" => Ok(CompareOp::LessEqual),
+            _ => bail!("`op` must be one of '>', '>=', '<', '<='"),
+        }
+    }
+}
+
 impl FilterExpr {
+    // matches iff any of the values of `tags` contains `value`
+    // (ignoring the letter case and accents, like ContainsFilter)
+    pub fn contains_any(tags: &[TagKey], value: &str) -> Self {
+        let value = unidecode(value).to_lowercase();
+        let nodes = tags
+            .iter()
+            .map(|&tag| {
+                FilterExpr::Filter(Box::new(ContainsFilter {
+                    tag,
+                    value: value.clone(),
+                }))
+            })
+            .collect();
+
+        FilterExpr::Or(nodes)
+    }
+
     pub fn evaluate(&self, song: &Song) -> bool {
-        self.0.iter().all(|filter| filter.matches(song))
+        match self {
+            FilterExpr::Filter(filter) => filter.matches(song),
+            FilterExpr::And(nodes) => nodes.iter().all(|node| node.evaluate(song)),
+            FilterExpr::Or(nodes) => nodes.iter().any(|node| node.evaluate(song)),
+            FilterExpr::Not(node) => !node.evaluate(song),
+        }
+    }
+}
+
+// every song matches an empty expression
+impl Default for FilterExpr {
+    fn default() -> Self {
+        FilterExpr::And(Vec::new())
+    }
+}
+
+impl TryFrom<Value> for FilterExpr {
+    type Error = anyhow::Error;
+
+    fn try_from(mut v: Value) -> Result<Self> {
+        if let Value::Array(nodes) = v {
+            return Ok(FilterExpr::And(filter_utils::nodes(nodes)?));
+        }
+        let map = v
+            .as_object_mut()
+            .ok_or(anyhow!("a filter must be a JSON object or an array"))?;
+        // compare filters have an `op` too, but only filters have a `kind`
+        if map.contains_key("kind") {
+            return Ok(FilterExpr::Filter(v.try_into()?));
+        }
+        let op = map
+            .remove("op")
+            .ok_or(anyhow!("key `kind` or `op` not found"))?;
+        let nodes = serde_json::from_value::<Vec<Value>>(
+            map.remove("nodes")
+                .ok_or(anyhow!("key `nodes` not found"))?,
+        )?;
+        let mut nodes = filter_utils::nodes(nodes)?;
+        let expr = match op.as_str().ok_or(anyhow!("`op` must be a string"))? {
+            "and" => FilterExpr::And(nodes),
+            "or" => FilterExpr::Or(nodes),
+            "not" => match (nodes.pop(), nodes.is_empty()) {
+                (Some(node), true) => FilterExpr::Not(Box::new(node)),
+                _ => bail!("`not` must have exactly one node"),
+            },
+            other => bail!(
+                "invalid value of key `op`: `{}` (valid ones are `and`, `or`, `not`)",
+                other
+            ),
+        };
+
+        Ok(expr)
     }
 }
 
 impl Filter for RegexFilter {
     fn matches(&self, song: &Song) -> bool {
-        match song.metadata.get(&self.tag) {
-            Some(value) => self.regex.is_match(&unidecode(value)),
+        song.metadata
+            .get_all(&self.tag)
+            .iter()
+            .any(|value| self.regex.is_match(&unidecode(value)))
+    }
+}
+
+impl Filter for ContainsFilter {
+    fn matches(&self, song: &Song) -> bool {
+        song.metadata
+            .get_all(&self.tag)
+            .iter()
+            .any(|value| unidecode(value).to_lowercase().contains(&self.value))
+    }
+}
+
+impl Filter for PathFilter {
+    fn matches(&self, song: &Song) -> bool {
+        let path = match self.part {
+            PathPart::Full => Some(song.path.as_os_str()),
+            PathPart::FileName => song.path.file_name(),
+        };
+        match path {
+            Some(path) => self.regex.is_match(&unidecode(&path.to_string_lossy())),
+            None => false,
+        }
+    }
+}
+
+impl Filter for CompareFilter {
+    fn matches(&self, song: &Song) -> bool {
+        song.metadata
+            .get_all(&self.tag)
+            .iter()
+            .filter_map(|value| filter_utils::numeric_value(value, self.tag.kind))
+            .any(|value| match self.op {
+                CompareOp::Greater => value > self.value,
+                CompareOp::GreaterEqual => value >= self.value,
+                CompareOp::Less => value < self.value,
+                CompareOp::LessEqual => value <= self.value,
+            })
+    }
+}
+
+impl Filter for DurationFilter {
+    fn matches(&self, song: &Song) -> bool {
+        if self.min.is_none() && self.max.is_none() {
+            return true;
+        }
+        match song.duration {
+            Some(duration) => {
+                self.min.is_none_or(|min| duration >= min)
+                    && self.max.is_none_or(|max| duration <= max)
+            }
             None => false,
         }
     }
 }
 
+impl Filter for ExistsFilter {
+    fn matches(&self, song: &Song) -> bool {
+        song.metadata.get(&self.tag).is_some() == self.present
+    }
+}
+
 impl TryFrom<Value> for Box<dyn Filter> {
     type Error = anyhow::Error;
 
@@ -42,26 +249,331 @@ impl TryFrom<Value> for Box<dyn Filter> {
             .as_object_mut()
             .ok_or(anyhow!("a filter must be a JSON object"))?;
         let kind = map.remove("kind").ok_or(anyhow!("key `kind` not found"))?;
-        let tag: TagKey = map
-            .remove("tag")
+        let filter: Box<dyn Filter> =
+            match kind.as_str().ok_or(anyhow!("`kind` must be a string"))? {
+                "regex" => {
+                    let regex = filter_utils::regex(
+                        map.remove("regex")
+                            .ok_or(anyhow!("key `regex` not found"))?
+                            .as_str()
+                            .ok_or(anyhow!("`regex` must be a string"))?,
+                    )?;
+                    let part = match map.get("tag").and_then(|v| v.as_str()) {
+                        Some("path") => Some(PathPart::Full),
+                        Some("filename") => Some(PathPart::FileName),
+                        _ => None,
+                    };
+
+                    match part {
+                        Some(part) => Box::new(PathFilter { part, regex }),
+                        None => Box::new(RegexFilter {
+                            tag: filter_utils::tag(map)?,
+                            regex,
+                        }),
+                    }
+                }
+                "contains" => {
+                    let tag = filter_utils::tag(map)?;
+                    let value = map
+                        .remove("value")
+                        .ok_or(anyhow!("key `value` not found"))?
+                        .as_str()
+                        .ok_or(anyhow!("`value` must be a string"))?
+                        .to_owned();
+
+                    Box::new(ContainsFilter {
+                        tag,
+                        value: unidecode(&value).to_lowercase(),
+                    })
+                }
+                "compare" => {
+                    let tag = filter_utils::tag(map)?;
+                    let op: CompareOp = map
+                        .remove("op")
+                        .ok_or(anyhow!("key `op` not found"))?
+                        .as_str()
+                        .ok_or(anyhow!("`op` must be a string"))?
+                        .try_into()?;
+                    let value = map
+                        .remove("value")
+                        .ok_or(anyhow!("key `value` not found"))?
+                        .as_f64()
+                        .ok_or(anyhow!("`value` must be a number"))?;
+
+                    Box::new(CompareFilter { tag, op, value })
+                }
+                "duration" => {
+                    let bound = |v: Option<Value>, key: &str| -> Result<Option<u64>> {
+                        // `null` is the same as a missing bound
+                        v.filter(|v| !v.is_null())
+                            .map(|v| {
+                                v.as_u64()
+                                    .ok_or(anyhow!("`{}` must be a non-negative integer", key))
+                            })
+                            .transpose()
+                    };
+                    let min = bound(map.remove("min"), "min")?;
+                    let max = bound(map.remove("max"), "max")?;
+
+                    Box::new(DurationFilter { min, max })
+                }
+                "exists" => {
+                    let tag = filter_utils::tag(map)?;
+                    let present = match map.remove("present") {
+                        Some(v) => v.as_bool().ok_or(anyhow!("`present` must be a boolean"))?,
+                        None => true,
+                    };
+
+                    Box::new(ExistsFilter { tag, present })
+                }
+                other => bail!("invalid value of key `kind`: `{}`", other),
+            };
+
+        Ok(filter)
+    }
+}
+
+mod filter_utils {
+    use super::*;
+    use serde_json::Map;
+
+    pub fn regex(pattern: &str) -> Result<Regex> {
+        RegexBuilder::new(pattern)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .dfa_size_limit(REGEX_DFA_SIZE_LIMIT)
+            .build()
+            .map_err(|e| match e {
+                regex::Error::CompiledTooBig(limit) => {
+                    anyhow!("`regex` is too complex (over {} bytes compiled)", limit)
+                }
+                e => e.into(),
+            })
+    }
+
+    pub fn nodes(nodes: Vec<Value>) -> Result<Vec<FilterExpr>> {
+        nodes.into_iter().map(FilterExpr::try_from).collect()
+    }
+
+    pub fn tag(map: &mut Map<String, Value>) -> Result<TagKey> {
+        map.remove("tag")
             .ok_or(anyhow!("key `tag` not found"))?
             .as_str()
             .ok_or(anyhow!("`tag` must be a string"))?
-            .try_into()?;
-        let filter = match kind.as_str().ok_or(anyhow!("`kind` must be a string"))? {
-            "regex" => {
-                let regex = Regex::new(
-                    map.remove("regex")
-                        .ok_or(anyhow!("key `regex` not found"))?
-                        .as_str()
-                        .ok_or(anyhow!("`regex` must be a string"))?,
-                )?;
+            .try_into()
+    }
 
-                Box::new(RegexFilter { tag, regex })
-            }
-            other => bail!("invalid value of key `kind`: `{}`", other),
+    // tags of kind `OutOf` (e.g. "3/12") are compared by their first number
+    pub fn numeric_value(value: &str, kind: TagKeyKind) -> Option<f64> {
+        let value = match kind {
+            TagKeyKind::String | TagKeyKind::Integer => value,
+            TagKeyKind::OutOf => value.split('/').next()?,
         };
 
-        Ok(filter)
+        value.trim().parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use symphonia::core::meta::StandardTagKey;
+
+    use crate::model::song::tagged_song;
+
+    fn with_path(path: &str) -> Song {
+        Song {
+            path: path.into(),
+            ..tagged_song(&[])
+        }
+    }
+
+    fn with_duration(duration: Option<u64>) -> Song {
+        Song {
+            duration,
+            ..tagged_song(&[])
+        }
+    }
+
+    fn compare(tag: &str, op: &str, value: f64) -> Box<dyn Filter> {
+        json!({"kind": "compare", "tag": tag, "op": op, "value": value})
+            .try_into()
+            .unwrap()
+    }
+
+    #[test]
+    fn compare_bpm() {
+        let fast = tagged_song(&[(StandardTagKey::Bpm, "128")]);
+        assert!(compare("bpm", ">=", 120.0).matches(&fast));
+        assert!(compare("bpm", ">=", 128.0).matches(&fast));
+        assert!(!compare("bpm", ">", 128.0).matches(&fast));
+        assert!(compare("bpm", "<", 140.0).matches(&fast));
+        assert!(!compare("bpm", "<=", 100.0).matches(&fast));
+
+        let not_a_number = tagged_song(&[(StandardTagKey::Bpm, "fast")]);
+        assert!(!compare("bpm", ">=", 0.0).matches(&not_a_number));
+        assert!(!compare("bpm", "<=", 0.0).matches(&not_a_number));
+        let missing = tagged_song(&[]);
+        assert!(!compare("bpm", ">=", 0.0).matches(&missing));
+
+        let res: Result<Box<dyn Filter>> =
+            json!({"kind": "compare", "tag": "bpm", "op": "==", "value": 1}).try_into();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn match_any_value() {
+        let duo = tagged_song(&[
+            (StandardTagKey::Artist, "Simon"),
+            (StandardTagKey::Artist, "Garfunkel"),
+        ]);
+        let regex: Box<dyn Filter> = json!({"kind": "regex", "tag": "artist", "regex": "^Gar"})
+            .try_into()
+            .unwrap();
+        assert!(regex.matches(&duo));
+        let regex: Box<dyn Filter> = json!({"kind": "regex", "tag": "artist", "regex": "^Art"})
+            .try_into()
+            .unwrap();
+        assert!(!regex.matches(&duo));
+    }
+
+    #[test]
+    fn compare_track_number() {
+        let third = tagged_song(&[(StandardTagKey::TrackNumber, "3/12")]);
+        assert!(compare("tracknumber", ">", 2.0).matches(&third));
+        assert!(compare("tracknumber", "<=", 3.0).matches(&third));
+        assert!(!compare("tracknumber", ">", 3.0).matches(&third));
+        assert!(!compare("tracknumber", ">=", 12.0).matches(&third));
+    }
+
+    #[test]
+    fn duration_range() {
+        let between = |min: Option<u64>, max: Option<u64>| -> Box<dyn Filter> {
+            json!({"kind": "duration", "min": min, "max": max})
+                .try_into()
+                .unwrap()
+        };
+        let (short, long) = (with_duration(Some(90)), with_duration(Some(400)));
+
+        let filter = between(Some(120), Some(300));
+        assert!(filter.matches(&with_duration(Some(120))));
+        assert!(filter.matches(&with_duration(Some(300))));
+        assert!(!filter.matches(&short));
+        assert!(!filter.matches(&long));
+
+        let filter = between(Some(120), None);
+        assert!(!filter.matches(&short));
+        assert!(filter.matches(&long));
+
+        let filter = between(None, Some(120));
+        assert!(filter.matches(&short));
+        assert!(!filter.matches(&long));
+
+        let unknown = with_duration(None);
+        assert!(!between(Some(0), None).matches(&unknown));
+        assert!(!between(None, Some(1000)).matches(&unknown));
+        assert!(between(None, None).matches(&unknown));
+    }
+
+    #[test]
+    fn exists_and_missing() {
+        let exists = |present: bool| -> Box<dyn Filter> {
+            json!({"kind": "exists", "tag": "genre", "present": present})
+                .try_into()
+                .unwrap()
+        };
+        let tagged = tagged_song(&[(StandardTagKey::Genre, "Jazz")]);
+        let untagged = tagged_song(&[(StandardTagKey::Artist, "Miles Davis")]);
+
+        assert!(exists(true).matches(&tagged));
+        assert!(!exists(true).matches(&untagged));
+        assert!(!exists(false).matches(&tagged));
+        assert!(exists(false).matches(&untagged));
+    }
+
+    #[test]
+    fn path_pseudo_tags() {
+        let regex = |tag: &str, regex: &str| -> Box<dyn Filter> {
+            json!({"kind": "regex", "tag": tag, "regex": regex})
+                .try_into()
+                .unwrap()
+        };
+        let live = with_path("/music/Live/Köln Concert/01 Part I.flac");
+        let studio = with_path("/music/Studio/Kind of Blue/01 So What.flac");
+
+        let filter = regex("path", "/Live/");
+        assert!(filter.matches(&live));
+        assert!(!filter.matches(&studio));
+        // non-ASCII characters are transliterated, like in tag values
+        assert!(regex("path", "Koln").matches(&live));
+        // the file name alone doesn't contain the directories
+        assert!(!regex("filename", "Live").matches(&live));
+        assert!(regex("filename", r"^01 So What\.flac$").matches(&studio));
+    }
+
+    #[test]
+    fn or_and_not() {
+        let expr = |v: Value| -> FilterExpr { v.try_into().unwrap() };
+        let jazz = json!({"kind": "regex", "tag": "genre", "regex": "Jazz"});
+        let rock = json!({"kind": "regex", "tag": "genre", "regex": "Rock"});
+        let fast = json!({"kind": "compare", "tag": "bpm", "op": ">", "value": 120});
+        let slow_jazz =
+            tagged_song(&[(StandardTagKey::Genre, "Jazz"), (StandardTagKey::Bpm, "90")]);
+        let fast_rock = tagged_song(&[
+            (StandardTagKey::Genre, "Rock"),
+            (StandardTagKey::Bpm, "140"),
+        ]);
+        let pop = tagged_song(&[(StandardTagKey::Genre, "Pop")]);
+
+        let jazz_or_rock = expr(json!({"op": "or", "nodes": [jazz, rock]}));
+        assert!(jazz_or_rock.evaluate(&slow_jazz));
+        assert!(jazz_or_rock.evaluate(&fast_rock));
+        assert!(!jazz_or_rock.evaluate(&pop));
+
+        let not_fast = expr(json!({"op": "not", "nodes": [fast]}));
+        assert!(not_fast.evaluate(&slow_jazz));
+        assert!(!not_fast.evaluate(&fast_rock));
+
+        // the flat form is an "and", and it can contain trees too
+        let rock_but_not_fast = expr(json!([rock, {"op": "not", "nodes": [fast]}]));
+        assert!(!rock_but_not_fast.evaluate(&fast_rock));
+        assert!(!rock_but_not_fast.evaluate(&slow_jazz));
+        assert!(FilterExpr::default().evaluate(&pop));
+
+        let res: Result<FilterExpr> = json!({"op": "not", "nodes": [jazz, rock]}).try_into();
+        assert!(res.is_err());
+        let res: Result<FilterExpr> = json!({"op": "xor", "nodes": []}).try_into();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn contains_substring() {
+        let contains = |value: &str| -> Box<dyn Filter> {
+            json!({"kind": "contains", "tag": "tracktitle", "value": value})
+                .try_into()
+                .unwrap()
+        };
+        let song = tagged_song(&[(StandardTagKey::TrackTitle, "Beyoncé – Crazy in Love")]);
+
+        assert!(contains("love").matches(&song));
+        assert!(contains("CRAZY IN").matches(&song));
+        // accents are ignored on both sides
+        assert!(contains("beyonce").matches(&song));
+        assert!(contains("Beyoncé").matches(&song));
+        // no regex syntax
+        assert!(!contains("Crazy.*Love").matches(&song));
+        assert!(!contains("love").matches(&with_path("/music/love.mp3")));
+    }
+
+    #[test]
+    fn reject_huge_regex() {
+        let res: Result<Box<dyn Filter>> =
+            json!({"kind": "regex", "tag": "tracktitle", "regex": "(a{1000}){1000}"}).try_into();
+        let e = res.err().unwrap();
+        assert!(e.to_string().contains("too complex"));
+
+        let res: Result<Box<dyn Filter>> =
+            json!({"kind": "regex", "tag": "tracktitle", "regex": "^(Love|Hate)+ Song"}).try_into();
+        assert!(res.is_ok());
     }
 }
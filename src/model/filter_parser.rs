@@ -0,0 +1,254 @@
+use anyhow::{Result, anyhow, bail};
+use serde_json::{Value, json};
+use std::{iter::Peekable, vec::IntoIter};
+use unidecode::unidecode;
+
+use crate::{constants, model::filter::FilterExpr};
+
+// a filter expression written as text, e.g. `artist == "ABBA" & (genre ~ "^Pop" | bpm >= 120)`
+// is parsed into the same tree that the JSON form of filters describes
+//
+// expr := and ("|" and)*
+// and  := not ("&" not)*
+// not  := "!" not | "(" expr ")" | tag op value
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(&'static str), // "==", "!=", "~", ">", ">=", "<", "<="
+    And,
+    Or,
+    Not,
+    LeftParen,
+    RightParen,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '&' => Token::And,
+            '|' => Token::Or,
+            '~' => Token::Op("~"),
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Op("!="),
+            '!' => Token::Not,
+            '=' if chars.next_if_eq(&'=').is_some() => Token::Op("=="),
+            '>' if chars.next_if_eq(&'=').is_some() => Token::Op(">="),
+            '>' => Token::Op(">"),
+            '<' if chars.next_if_eq(&'=').is_some() => Token::Op("<="),
+            '<' => Token::Op("<"),
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => value.push(c),
+                            None => bail!("unterminated string `\"{}`", value),
+                        },
+                        Some(c) => value.push(c),
+                        None => bail!("unterminated string `\"{}`", value),
+                    }
+                }
+                Token::Quoted(value)
+            }
+            '=' => bail!("unexpected `=` (did you mean `==`?)"),
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|&c| !c.is_whitespace() && !"()&|!=~<>\"".contains(c))
+                {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<Token>>,
+    // how many `!` and `(` enclose the current token
+    depth: usize,
+}
+
+impl Parser {
+    fn expr(&mut self) -> Result<Value> {
+        let mut nodes = vec![self.and()?];
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            nodes.push(self.and()?);
+        }
+
+        Ok(parser_utils::join("or", nodes))
+    }
+
+    fn and(&mut self) -> Result<Value> {
+        let mut nodes = vec![self.not()?];
+        while self.tokens.next_if_eq(&Token::And).is_some() {
+            nodes.push(self.not()?);
+        }
+
+        Ok(parser_utils::join("and", nodes))
+    }
+
+    fn not(&mut self) -> Result<Value> {
+        match self.tokens.next() {
+            Some(Token::Not) => Ok(json!({"op": "not", "nodes": [self.nested(Self::not)?]})),
+            Some(Token::LeftParen) => {
+                let expr = self.nested(Self::expr)?;
+                match self.tokens.next() {
+                    Some(Token::RightParen) => Ok(expr),
+                    _ => bail!("missing `)`"),
+                }
+            }
+            Some(Token::Word(tag)) => self.condition(tag),
+            Some(token) => bail!("unexpected {:?}, expected a tag, `!` or `(`", token),
+            None => bail!("unexpected end of the expression"),
+        }
+    }
+
+    // the parser recurses once per level, so without a limit
+    // a long enough run of `!` or `(` would overflow the stack
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Value>) -> Result<Value> {
+        if self.depth >= constants::MAX_FILTER_DEPTH {
+            bail!(
+                "the expression is nested too deeply (the limit is {} levels)",
+                constants::MAX_FILTER_DEPTH
+            );
+        }
+        self.depth += 1;
+        let res = parse(self);
+        self.depth -= 1;
+
+        res
+    }
+
+    fn condition(&mut self, tag: String) -> Result<Value> {
+        let op = match self.tokens.next() {
+            Some(Token::Op(op)) => op,
+            _ => bail!("expected an operator after `{}`", tag),
+        };
+        let value = match self.tokens.next() {
+            Some(Token::Word(value) | Token::Quoted(value)) => value,
+            _ => bail!("expected a value after `{} {}`", tag, op),
+        };
+        // tag values are transliterated to ASCII before matching
+        let equals = || format!("(?i)^{}$", regex::escape(&unidecode(&value)));
+        let condition = match op {
+            "==" => json!({"kind": "regex", "tag": tag, "regex": equals()}),
+            "!=" => json!({
+                "op": "not",
+                "nodes": [{"kind": "regex", "tag": tag, "regex": equals()}],
+            }),
+            "~" => json!({"kind": "regex", "tag": tag, "regex": value}),
+            op => {
+                let value: f64 = value
+                    .parse()
+                    .map_err(|_| anyhow!("`{}` must be compared with a number", tag))?;
+                json!({"kind": "compare", "tag": tag, "op": op, "value": value})
+            }
+        };
+
+        Ok(condition)
+    }
+}
+
+pub fn parse(s: &str) -> Result<FilterExpr> {
+    let mut parser = Parser {
+        tokens: tokenize(s)?.into_iter().peekable(),
+        depth: 0,
+    };
+    let tree = parser.expr()?;
+    if let Some(token) = parser.tokens.next() {
+        bail!("unexpected {:?} after the end of the expression", token);
+    }
+
+    FilterExpr::try_from(tree)
+}
+
+mod parser_utils {
+    use super::*;
+
+    pub fn join(op: &str, mut nodes: Vec<Value>) -> Value {
+        if nodes.len() == 1 {
+            nodes.pop().unwrap()
+        } else {
+            json!({"op": op, "nodes": nodes})
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use symphonia::core::meta::StandardTagKey;
+
+    use crate::model::song::tagged_song;
+
+    #[test]
+    fn same_as_structured() {
+        let parsed = parse(r#"artist == "ABBA" & (genre == pop | !bpm < 120)"#).unwrap();
+        let structured = FilterExpr::try_from(json!([
+            {"kind": "regex", "tag": "artist", "regex": "(?i)^ABBA$"},
+            {"op": "or", "nodes": [
+                {"kind": "regex", "tag": "genre", "regex": "(?i)^pop$"},
+                {"op": "not", "nodes": [{"kind": "compare", "tag": "bpm", "op": "<", "value": 120}]},
+            ]},
+        ]))
+        .unwrap();
+        let songs = [
+            tagged_song(&[
+                (StandardTagKey::Artist, "ABBA"),
+                (StandardTagKey::Genre, "Pop"),
+            ]),
+            tagged_song(&[
+                (StandardTagKey::Artist, "ABBA"),
+                (StandardTagKey::Genre, "Disco"),
+            ]),
+            tagged_song(&[
+                (StandardTagKey::Artist, "ABBA"),
+                (StandardTagKey::Bpm, "130"),
+            ]),
+            tagged_song(&[
+                (StandardTagKey::Artist, "ABBA"),
+                (StandardTagKey::Bpm, "100"),
+            ]),
+            tagged_song(&[
+                (StandardTagKey::Artist, "Abbath"),
+                (StandardTagKey::Genre, "Pop"),
+            ]),
+        ];
+
+        let results: Vec<_> = songs.iter().map(|song| parsed.evaluate(song)).collect();
+        assert_eq!(results, [true, true, true, false, false]);
+        for song in songs.iter() {
+            assert_eq!(parsed.evaluate(song), structured.evaluate(song));
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("artist = ABBA").is_err());
+        assert!(parse("artist == \"ABBA").is_err());
+        assert!(parse("(artist == ABBA").is_err());
+        assert!(parse("artist == ABBA genre == pop").is_err());
+        assert!(parse("bpm > fast").is_err());
+        assert!(parse("nosuchtag == x").is_err());
+        assert!(parse(r#"tracktitle ~ "^Dancing" & artist != "Queen""#).is_ok());
+        // deep nesting is an error, not a stack overflow
+        assert!(parse(&format!("{}artist == x", "!".repeat(1_000_000))).is_err());
+        assert!(parse(&format!("{}artist == x", "(".repeat(1_000_000))).is_err());
+        let nested = constants::MAX_FILTER_DEPTH / 2;
+        let s = format!("{}artist == x{}", "!(".repeat(nested), ")".repeat(nested));
+        assert!(parse(&s).is_ok());
+    }
+}
@@ -0,0 +1,60 @@
+use bincode::{Decode, Encode};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+// long songs count as played after this many seconds even if
+// less than half of them elapsed (as with most scrobblers)
+const PLAYED_SECS: u64 = 240;
+
+// song path (absolute) -> how many times the song was played
+#[derive(Clone, Debug, Decode, Default, Encode)]
+pub struct PlayCounts(HashMap<PathBuf, u32>);
+
+impl PlayCounts {
+    pub fn get(&self, path: impl AsRef<Path>) -> u32 {
+        self.0.get(path.as_ref()).copied().unwrap_or_default()
+    }
+
+    pub fn record(&mut self, path: impl Into<PathBuf>) {
+        let count = self.0.entry(path.into()).or_default();
+        *count = count.saturating_add(1);
+    }
+}
+
+// true if enough of the song elapsed for it to count as played
+// (so that skipping through songs doesn't inflate their counts)
+pub fn is_played(elapsed: u64, duration: u64) -> bool {
+    elapsed >= PLAYED_SECS || (duration > 0 && 2 * elapsed > duration)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn played_threshold() {
+        assert!(!is_played(0, 200));
+        assert!(!is_played(100, 200));
+        assert!(is_played(101, 200));
+        assert!(is_played(200, 200));
+        // long songs
+        assert!(!is_played(239, 3600));
+        assert!(is_played(240, 3600));
+        // songs of unknown duration
+        assert!(!is_played(100, 0));
+        assert!(is_played(240, 0));
+    }
+
+    #[test]
+    fn record_plays() {
+        let mut play_counts = PlayCounts::default();
+        play_counts.record("a");
+        play_counts.record("b");
+        play_counts.record("a");
+        assert_eq!(play_counts.get("a"), 2);
+        assert_eq!(play_counts.get("b"), 1);
+        assert_eq!(play_counts.get("c"), 0);
+    }
+}
@@ -1,23 +1,36 @@
+use anyhow::{Result, bail};
 use bincode::{self, Decode, Encode};
 use std::{
-    collections::HashSet,
+    collections::{
+        HashMap, HashSet, VecDeque,
+        hash_map::{DefaultHasher, RandomState},
+    },
+    fmt::{self, Display, Formatter},
+    hash::{BuildHasher, Hash, Hasher},
     mem,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-// https://www.ams.org/journals/mcom/1999-68-225/S0025-5718-99-00996-5/S0025-5718-99-00996-5.pdf
+use crate::{constants, model::play_count::PlayCounts};
+
+// xorshift64* (https://arxiv.org/abs/1402.6246)
 // not using an rng from the rand crate makes (de)serialization easier
-const RNG_A: usize = 279_470_273;
-const RNG_MOD: usize = 4_294_967_291;
+const RNG_MULTIPLIER: u64 = 0x2545_f491_4f6c_dd1d;
+// in weighted random mode, songs already played from the queue
+// are this many times less likely to be picked
+const HISTORY_PENALTY: f64 = 8.0;
 
 #[derive(Clone, Debug, Decode, Encode, PartialEq)]
 pub struct Entry {
     pub id: u32,
     pub path: PathBuf,
+    // in random mode, songs with higher priority tend to be played sooner
+    pub priority: u8,
 }
 
 #[derive(Clone, Debug, Decode, Encode)]
-struct Rng(usize);
+pub struct Rng(u64); // never 0
 
 #[derive(Clone, Debug, Decode, Encode)]
 struct Random {
@@ -25,12 +38,30 @@ struct Random {
     ids: Vec<u32>,
 }
 
+#[derive(Clone, Debug, Decode, Encode)]
+struct WeightedRandom {
+    // id -> play count of the song when the mode was started
+    play_counts: HashMap<u32, u32>,
+}
+
 #[derive(Clone, Debug, Decode, Default, Encode)]
 enum QueueMode {
     #[default]
     Sequential,
     Single,
     Random(Random),
+    RepeatAll,
+    RepeatOne,
+    WeightedRandom(WeightedRandom),
+}
+
+// what to do after the last song of the queue finishes
+#[derive(Clone, Copy, Debug, Default)]
+pub enum QueueEndAction {
+    #[default]
+    Stop,
+    Repeat,
+    Random,
 }
 
 #[derive(Clone, Debug, Decode, Default, Encode)]
@@ -39,37 +70,172 @@ pub struct Queue {
     pos: Option<usize>,
     mode: QueueMode,
     history: HashSet<u32>,
+    // the same songs in the order in which they were played (oldest first),
+    // kept even when they're removed from the queue
+    played: VecDeque<Entry>,
     next_id: u32,
+    // seeds the shuffles, so that every one of them is different
+    rng: Rng,
 }
 
 impl From<(u32, PathBuf)> for Entry {
     fn from((id, path): (u32, PathBuf)) -> Self {
-        Self { id, path }
+        Self {
+            id,
+            path,
+            priority: 0,
+        }
+    }
+}
+
+impl FromStr for QueueEndAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stop" => Ok(Self::Stop),
+            "repeat" => Ok(Self::Repeat),
+            "random" => Ok(Self::Random),
+            other => bail!(
+                "invalid queue end action `{}` (valid ones are `stop`, `repeat`, `random`)",
+                other
+            ),
+        }
+    }
+}
+
+impl Display for QueueEndAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Stop => "stop",
+            Self::Repeat => "repeat",
+            Self::Random => "random",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// seeded with the randomness that std uses for hash maps
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(RandomState::new().build_hasher().finish())
     }
 }
 
 impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at 0
+        Self(if seed == 0 { RNG_MULTIPLIER } else { seed })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(RNG_MULTIPLIER)
+    }
+
+    // the modulo bias is negligible for queue-sized ranges
     pub fn next_usize(&mut self, l: usize, r: usize) -> usize {
-        self.0 = (self.0 * RNG_A) % RNG_MOD;
-        self.0 % (r - l + 1) + l
+        (self.next_u64() % (r - l + 1) as u64) as usize + l
+    }
+
+    // a number in (0, 1]
+    pub fn next_f64(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
     }
 }
 
 impl Random {
-    // k is there to prevent seeding with the same value
-    // when starting from an empty vector
-    pub fn new(mut ids: Vec<u32>, k: usize) -> Self {
-        let mut rng = Rng(ids.len() + k);
-        // Fisher-Yates shuffle
-        for i in 0..(ids.len().saturating_sub(1)) {
-            let j = rng.next_usize(i, ids.len().saturating_sub(1));
-            ids.swap(i, j);
-        }
+    // the seed is mixed with the ids, so that the same seed
+    // doesn't give the same order for different queues
+    pub fn new(ids: Vec<(u32, u8)>, seed: u64) -> Self {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        ids.hash(&mut hasher);
+        let mut rng = Rng::new(hasher.finish());
+        let ids = weighted_shuffle(ids, &mut rng);
 
         Self { rng, ids }
     }
 }
 
+impl WeightedRandom {
+    pub fn new(list: &[Entry], play_counts: &PlayCounts) -> Self {
+        let play_counts = list
+            .iter()
+            .map(|entry| (entry.id, play_counts.get(&entry.path)))
+            .collect();
+
+        Self { play_counts }
+    }
+
+    // weight = (priority + 1) / ((play count + 1) * penalty),
+    // where the penalty is `HISTORY_PENALTY` if the song has already been played
+    // from the queue and 1 otherwise
+    fn weight(&self, entry: &Entry, history: &HashSet<u32>) -> f64 {
+        let play_count = self.play_counts.get(&entry.id).copied().unwrap_or_default();
+        let penalty = if history.contains(&entry.id) {
+            HISTORY_PENALTY
+        } else {
+            1.0
+        };
+
+        (entry.priority as f64 + 1.0) / ((play_count as f64 + 1.0) * penalty)
+    }
+
+    // returns the position of the next entry
+    // (the current one is picked only if there's nothing else)
+    pub fn pick(
+        &self,
+        list: &[Entry],
+        history: &mut HashSet<u32>,
+        current: Option<usize>,
+        rng: &mut Rng,
+    ) -> Option<usize> {
+        // every song has been played, so the weights start over
+        if list.iter().all(|entry| history.contains(&entry.id)) {
+            history.clear();
+        }
+        let candidates: Vec<_> = (0..list.len())
+            .filter(|&pos| Some(pos) != current)
+            .map(|pos| (pos, self.weight(&list[pos], history)))
+            .collect();
+        if candidates.is_empty() {
+            return current;
+        }
+        let total: f64 = candidates.iter().map(|(_, weight)| weight).sum();
+        let mut target = rng.next_f64() * total;
+        for &(pos, weight) in candidates.iter() {
+            if target <= weight {
+                return Some(pos);
+            }
+            target -= weight;
+        }
+
+        // only reachable through rounding errors
+        candidates.last().map(|&(pos, _)| pos)
+    }
+}
+
+// ids are taken from the back of the pool, so the higher the priority
+// of an id, the closer to the back it's likely to be placed
+//
+// https://doi.org/10.1016/j.ipl.2005.11.003 (Efraimidis-Spirakis)
+// with equal priorities this is just a uniformly random shuffle
+fn weighted_shuffle(ids: Vec<(u32, u8)>, rng: &mut Rng) -> Vec<u32> {
+    let mut keyed: Vec<_> = ids
+        .into_iter()
+        .map(|(id, priority)| {
+            let weight = priority as f64 + 1.0;
+            (rng.next_f64().powf(1.0 / weight), id)
+        })
+        .collect();
+    keyed.sort_unstable_by(|lhs, rhs| lhs.0.total_cmp(&rhs.0));
+
+    keyed.into_iter().map(|(_, id)| id).collect()
+}
+
 impl Queue {
     pub fn find_by_id(&self, id: u32) -> Option<usize> {
         self.list.iter().position(|entry| entry.id == id)
@@ -80,6 +246,9 @@ impl Queue {
             QueueMode::Sequential => "sequential",
             QueueMode::Single => "single",
             QueueMode::Random(_) => "random",
+            QueueMode::RepeatAll => "repeat_all",
+            QueueMode::RepeatOne => "repeat_one",
+            QueueMode::WeightedRandom(_) => "weighted_random",
         }
         .into()
     }
@@ -92,14 +261,56 @@ impl Queue {
         &self.list
     }
 
+    // at most `n` entries that will be played after the current one
+    // in random mode, only the entries remaining in the current pool are considered
+    pub fn upcoming(&self, n: usize) -> Vec<&Entry> {
+        match &self.mode {
+            QueueMode::Sequential => {
+                let start = self.pos.map(|pos| pos + 1).unwrap_or(0);
+                self.list.iter().skip(start).take(n).collect()
+            }
+            // the next entry isn't known until it's picked
+            QueueMode::Single | QueueMode::WeightedRandom(_) => Vec::new(),
+            QueueMode::RepeatOne => self.current().filter(|_| n > 0).into_iter().collect(),
+            QueueMode::RepeatAll => {
+                let start = self.pos.map(|pos| pos + 1).unwrap_or(0);
+                self.list
+                    .iter()
+                    .cycle()
+                    .skip(start)
+                    .take(n.min(self.list.len()))
+                    .collect()
+            }
+            QueueMode::Random(Random { rng: _, ids }) => ids
+                .iter()
+                .rev()
+                .filter_map(|&id| self.find_by_id(id).map(|pos| &self.list[pos]))
+                .take(n)
+                .collect(),
+        }
+    }
+
     pub fn reset_pos(&mut self) {
         let _ = self.pos.take();
     }
 
     pub fn add_current_to_history(&mut self) {
-        if let Some(current) = self.current() {
-            self.history.insert(current.id);
+        let Some(current) = self.current().cloned() else {
+            return;
+        };
+        self.history.insert(current.id);
+        if self.played.back().is_some_and(|last| last.id == current.id) {
+            return;
+        }
+        if self.played.len() >= constants::MAX_PLAYED_HISTORY {
+            self.played.pop_front();
         }
+        self.played.push_back(current);
+    }
+
+    // at most `n` most recently played songs, oldest first
+    pub fn played(&self, n: usize) -> impl Iterator<Item = &Entry> {
+        self.played.iter().skip(self.played.len().saturating_sub(n))
     }
 
     pub fn move_next(&mut self) -> Option<&Entry> {
@@ -112,6 +323,19 @@ impl Queue {
             QueueMode::Single => {
                 let _ = self.pos.take();
             }
+            QueueMode::RepeatAll => {
+                self.pos = match self.pos {
+                    _ if self.list.is_empty() => None,
+                    Some(pos) => Some((pos + 1) % self.list.len()),
+                    None => Some(0),
+                }
+            }
+            // the current song stays (and if there's none, nothing happens)
+            QueueMode::RepeatOne => (),
+            QueueMode::WeightedRandom(weighted_random) => {
+                self.pos =
+                    weighted_random.pick(&self.list, &mut self.history, self.pos, &mut self.rng);
+            }
             QueueMode::Random(random) => match random.ids.pop() {
                 // Some(id) => self.pos = self.find_by_id(id),
                 Some(id) => match self.find_by_id(id) {
@@ -124,11 +348,15 @@ impl Queue {
                 },
                 None => {
                     // random pool exhausted
-                    let ids: Vec<_> = self.list.iter().map(|entry| entry.id).collect();
+                    let ids: Vec<_> = self
+                        .list
+                        .iter()
+                        .map(|entry| (entry.id, entry.priority))
+                        .collect();
                     if ids.is_empty() {
                         self.pos = None;
                     } else {
-                        self.mode = QueueMode::Random(Random::new(ids, 0));
+                        self.mode = QueueMode::Random(Random::new(ids, self.rng.next_u64()));
                         // this won't recurse more because
                         // the Some(id) branch will be taken
                         self.move_next();
@@ -141,8 +369,10 @@ impl Queue {
     }
 
     pub fn move_prev(&mut self) -> Option<&Entry> {
+        let wrap = matches!(self.mode, QueueMode::RepeatAll);
         match &mut self.pos {
             Some(pos) if *pos > 0 => *pos -= 1,
+            Some(_) if wrap => self.pos = Some(self.list.len().saturating_sub(1)),
             None if !self.list.is_empty() => self.pos = Some(self.list.len().saturating_sub(1)),
             _ => self.pos = None,
         };
@@ -150,6 +380,31 @@ impl Queue {
         self.current()
     }
 
+    // whether `move_next` would land on a song (without actually moving)
+    pub fn has_next(&self) -> bool {
+        match &self.mode {
+            QueueMode::Sequential => match self.pos {
+                Some(pos) => pos + 1 < self.list.len(),
+                None => !self.list.is_empty(),
+            },
+            QueueMode::Single => false,
+            QueueMode::RepeatOne => self.pos.is_some(),
+            // an exhausted random pool is refilled with the whole queue
+            QueueMode::RepeatAll | QueueMode::Random(_) | QueueMode::WeightedRandom(_) => {
+                !self.list.is_empty()
+            }
+        }
+    }
+
+    // whether `move_prev` would land on a song (without actually moving)
+    pub fn has_previous(&self) -> bool {
+        let wrap = matches!(self.mode, QueueMode::RepeatAll);
+        match self.pos {
+            Some(pos) => pos > 0 || wrap,
+            None => !self.list.is_empty(),
+        }
+    }
+
     pub fn move_to(&mut self, id: u32) -> Option<&Entry> {
         // without this check, you could manually play song X and then
         // still get song X from the random pool later
@@ -165,17 +420,28 @@ impl Queue {
         }
     }
 
+    // inserts the song at `pos` (a `pos` equal to or past the length of the queue
+    // means appending), the current song stays current even if `pos` is before it
     pub fn add(&mut self, path: impl AsRef<Path> + Into<PathBuf>, pos: Option<usize>) {
         self.next_id += 1;
         let id = self.next_id;
         let entry = Entry {
             id,
             path: path.into(),
+            priority: 0,
         };
 
         match pos {
-            Some(pos) if pos < self.list.len() => self.list.insert(pos, entry),
-            _ => self.list.push(entry),
+            Some(pos) => {
+                let pos = pos.min(self.list.len());
+                self.list.insert(pos, entry);
+                if let Some(current) = &mut self.pos
+                    && pos <= *current
+                {
+                    *current += 1;
+                }
+            }
+            None => self.list.push(entry),
         }
         if let QueueMode::Random(Random { rng, ids }) = &mut self.mode {
             if ids.is_empty() {
@@ -189,6 +455,15 @@ impl Queue {
         }
     }
 
+    // inserts the songs (keeping their order) right after the current one,
+    // or at the front if there's no current song
+    pub fn add_after_current(&mut self, paths: Vec<PathBuf>) {
+        let start = self.pos.map(|pos| pos + 1).unwrap_or(0);
+        for (offset, path) in paths.into_iter().enumerate() {
+            self.add(path, Some(start + offset));
+        }
+    }
+
     // does nothing if the id is invalid
     // returns true if the currently playing song was removed
     pub fn remove(&mut self, id: u32) -> bool {
@@ -213,13 +488,107 @@ impl Queue {
         false
     }
 
+    // returns false if the id is invalid
+    // `to` is clamped to the queue's length
+    pub fn move_entry(&mut self, id: u32, to: usize) -> bool {
+        let Some(from) = self.find_by_id(id) else {
+            return false;
+        };
+        let to = to.min(self.list.len() - 1);
+        let entry = self.list.remove(from);
+        self.list.insert(to, entry);
+        // the current song has to stay the same
+        if let Some(cur_pos) = self.pos {
+            self.pos = Some(if cur_pos == from {
+                to
+            } else if from < cur_pos && cur_pos <= to {
+                cur_pos - 1
+            } else if to <= cur_pos && cur_pos < from {
+                cur_pos + 1
+            } else {
+                cur_pos
+            });
+        }
+
+        true
+    }
+
+    // returns false if the id is invalid
+    pub fn set_priority(&mut self, id: u32, priority: u8) -> bool {
+        let Some(pos) = self.find_by_id(id) else {
+            return false;
+        };
+        self.list[pos].priority = priority;
+        // reshuffle the ids that are yet to be played
+        if let QueueMode::Random(Random { rng, ids }) = &mut self.mode {
+            let pool: Vec<_> = ids
+                .iter()
+                .filter_map(|&id| self.list.iter().find(|entry| entry.id == id))
+                .map(|entry| (entry.id, entry.priority))
+                .collect();
+            *ids = weighted_shuffle(pool, rng);
+        }
+
+        true
+    }
+
+    // permutes the entries themselves (unlike random mode, which only changes
+    // the order of playback), the current song stays the same
+    pub fn shuffle(&mut self) {
+        let current_id = self.current().map(|entry| entry.id);
+        // Fisher-Yates
+        for i in (1..self.list.len()).rev() {
+            let j = self.rng.next_usize(0, i);
+            self.list.swap(i, j);
+        }
+        self.pos = current_id.and_then(|id| self.find_by_id(id));
+    }
+
     pub fn clear(&mut self) {
         self.list.clear();
         self.history.clear();
         let _ = self.pos.take();
         self.next_id = 0;
-        if let QueueMode::Random(rng) = &mut self.mode {
-            self.mode = QueueMode::Random(Random::new(Vec::new(), rng.rng.next_usize(1, 100)));
+        if matches!(self.mode, QueueMode::Random(_)) {
+            self.mode = QueueMode::Random(Random::new(Vec::new(), self.rng.next_u64()));
+        }
+    }
+
+    // removes entries whose paths already appeared earlier in the queue,
+    // except for the current one (which is kept instead of its first occurrence)
+    // returns the number of removed entries
+    pub fn dedup(&mut self) -> usize {
+        let current = self.current().map(|entry| (entry.id, entry.path.clone()));
+        let mut seen = HashSet::new();
+        let n_before = self.list.len();
+        self.list.retain(|entry| match &current {
+            Some((id, path)) if *path == entry.path => entry.id == *id,
+            _ => seen.insert(entry.path.clone()),
+        });
+        self.pos = current.and_then(|(id, _)| self.find_by_id(id));
+        let kept_ids: HashSet<_> = self.list.iter().map(|entry| entry.id).collect();
+        self.history.retain(|id| kept_ids.contains(id));
+        if let QueueMode::Random(Random { rng: _, ids }) = &mut self.mode {
+            ids.retain(|id| kept_ids.contains(id));
+        }
+
+        n_before - self.list.len()
+    }
+
+    // removes every entry except for the current one
+    // (if there's no current song, this is the same as `clear`)
+    pub fn crop(&mut self) {
+        let Some(pos) = self.pos else {
+            self.clear();
+            return;
+        };
+        let current = self.list.swap_remove(pos);
+        self.list = vec![current];
+        self.pos = Some(0);
+        self.history.clear();
+        // `next_id` stays, so that new ids don't collide with the current one's
+        if matches!(self.mode, QueueMode::Random(_)) {
+            self.mode = QueueMode::Random(Random::new(Vec::new(), self.rng.next_u64()));
         }
     }
 
@@ -234,22 +603,43 @@ impl Queue {
                         .map(|cur_entry| entry.id != cur_entry.id)
                         .unwrap_or(true)
             })
-            .map(|entry| entry.id)
+            .map(|entry| (entry.id, entry.priority))
             .collect();
         // if we've already played every song, start again
         if not_played_ids.is_empty() {
-            not_played_ids = self.list.iter().map(|entry| entry.id).collect();
+            not_played_ids = self
+                .list
+                .iter()
+                .map(|entry| (entry.id, entry.priority))
+                .collect();
         }
-        self.mode = QueueMode::Random(Random::new(not_played_ids, 0));
+        self.mode = QueueMode::Random(Random::new(not_played_ids, self.rng.next_u64()));
+    }
+
+    // play counts are looked up only once, when the mode is started
+    pub fn start_weighted_random(&mut self, play_counts: &PlayCounts) {
+        self.mode = QueueMode::WeightedRandom(WeightedRandom::new(&self.list, play_counts));
     }
 
     pub fn start_sequential(&mut self) {
         self.mode = QueueMode::Sequential;
     }
 
+    pub fn is_single(&self) -> bool {
+        matches!(self.mode, QueueMode::Single)
+    }
+
     pub fn start_single(&mut self) {
         self.mode = QueueMode::Single;
     }
+
+    pub fn start_repeat_all(&mut self) {
+        self.mode = QueueMode::RepeatAll;
+    }
+
+    pub fn start_repeat_one(&mut self) {
+        self.mode = QueueMode::RepeatOne;
+    }
 }
 
 #[cfg(test)]
@@ -285,6 +675,126 @@ mod test {
         assert_eq!(queue.inner(), expected);
     }
 
+    #[test]
+    fn add_at_boundary() {
+        let mut queue = Queue::default();
+        queue.add("a", None);
+        queue.add("b", None);
+        queue.move_to(2);
+        queue.add("c", Some(2));
+        queue.add("d", Some(100));
+        queue.add("e", Some(0));
+        let paths: Vec<_> = queue.inner().iter().map(|entry| &entry.path).collect();
+        assert_eq!(
+            paths,
+            [
+                &PathBuf::from("e"),
+                &"a".into(),
+                &"b".into(),
+                &"c".into(),
+                &"d".into()
+            ]
+        );
+        assert_eq!(queue.current().map(|entry| entry.id), Some(2));
+
+        // every song (including the ones added at the end) is played once per round
+        let mut queue = Queue::default();
+        queue.add("a", None);
+        queue.start_random();
+        queue.add("b", Some(1));
+        queue.add("c", Some(100));
+        let mut ids: Vec<_> = (0..3)
+            .map(|_| queue.move_next().map(|entry| entry.id).unwrap())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, [1, 2, 3]);
+    }
+
+    #[test]
+    fn moving() {
+        let mut queue = Queue::default();
+        for i in 1..=5 {
+            queue.add(format!("song{}", i), None);
+        }
+        queue.move_to(3);
+
+        assert!(queue.move_entry(3, 0));
+        assert_eq!(queue.current().map(|entry| entry.id), Some(3));
+        assert!(queue.move_entry(1, 100));
+        assert!(queue.move_entry(5, 0));
+        assert!(!queue.move_entry(2137, 0));
+        let ids: Vec<_> = queue.inner().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [5, 3, 2, 4, 1]);
+        assert_eq!(queue.current().map(|entry| entry.id), Some(3));
+    }
+
+    #[test]
+    fn dedup() {
+        let mut queue = Queue::default();
+        for path in ["a", "b", "a", "c", "b", "d", "c"] {
+            queue.add(path, None);
+        }
+        // the current song is a later duplicate
+        queue.move_to(5);
+
+        assert_eq!(queue.dedup(), 3);
+        let ids: Vec<_> = queue.inner().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [1, 4, 5, 6]);
+        assert_eq!(queue.current().map(|entry| entry.id), Some(5));
+        assert_eq!(queue.dedup(), 0);
+    }
+
+    #[test]
+    fn crop() {
+        let mut queue = Queue::default();
+        for i in 1..=5 {
+            queue.add(format!("song{}", i), None);
+        }
+        queue.move_to(3);
+        queue.crop();
+        assert_eq!(queue.inner(), &[(3, "song3".into()).into()]);
+        assert_eq!(queue.current().map(|entry| entry.id), Some(3));
+        queue.add("song6", None);
+        let ids: Vec<_> = queue.inner().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [3, 6]);
+
+        queue.reset_pos();
+        queue.crop();
+        assert!(queue.inner().is_empty());
+    }
+
+    #[test]
+    fn play_next() {
+        let mut queue = Queue::default();
+        for i in 1..=4 {
+            queue.add(format!("song{}", i), None);
+        }
+        queue.move_to(2);
+        queue.add_after_current(vec!["song5".into(), "song6".into()]);
+
+        let ids: Vec<_> = queue.inner().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [1, 2, 5, 6, 3, 4]);
+        assert_eq!(queue.current().map(|entry| entry.id), Some(2));
+        queue.move_next();
+        assert_eq!(queue.current().map(|entry| entry.id), Some(5));
+    }
+
+    #[test]
+    fn shuffle() {
+        let mut queue = Queue::default();
+        for i in 1..=10 {
+            queue.add(format!("song{}", i), None);
+        }
+        queue.move_to(4);
+        queue.shuffle();
+
+        let mut ids: Vec<_> = queue.inner().iter().map(|entry| entry.id).collect();
+        ids.sort();
+        assert_eq!(ids, (1..=10).collect::<Vec<_>>());
+        assert_eq!(queue.current().map(|entry| entry.id), Some(4));
+        assert_eq!(queue.mode(), "sequential");
+    }
+
     #[test]
     fn traversing() {
         let mut queue = Queue::default();
@@ -312,6 +822,108 @@ mod test {
         assert_eq!(queue.current(), Some((1, "song1".into()).into()).as_ref());
     }
 
+    #[test]
+    fn has_next_and_previous() {
+        let mut queue = Queue::default();
+        assert!(!queue.has_next() && !queue.has_previous());
+        queue.add("a", None);
+        queue.add("b", None);
+        // nothing's playing, so `next` starts from the first song and `previous` from the last one
+        assert!(queue.has_next() && queue.has_previous());
+
+        queue.move_to(1);
+        assert!(queue.has_next() && !queue.has_previous());
+        queue.move_to(2);
+        assert!(!queue.has_next() && queue.has_previous());
+        queue.start_repeat_all();
+        assert!(queue.has_next() && queue.has_previous());
+        queue.move_to(1);
+        assert!(queue.has_next() && queue.has_previous());
+        queue.start_single();
+        assert!(!queue.has_next() && !queue.has_previous());
+        queue.start_repeat_one();
+        assert!(queue.has_next());
+
+        // the random pool is refilled, so there's always a next song
+        queue.start_random();
+        for _ in 0..5 {
+            assert!(queue.has_next());
+            assert_eq!(queue.has_next(), queue.move_next().is_some());
+        }
+        queue.start_weighted_random(&PlayCounts::default());
+        assert_eq!(queue.has_next(), queue.move_next().is_some());
+        for start_mode in [
+            Queue::start_sequential,
+            Queue::start_single,
+            Queue::start_repeat_one,
+            Queue::start_repeat_all,
+        ] {
+            start_mode(&mut queue);
+            for id in [1, 2] {
+                queue.move_to(id);
+                assert_eq!(queue.has_next(), queue.clone().move_next().is_some());
+                assert_eq!(queue.has_previous(), queue.clone().move_prev().is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn played_in_order() {
+        let mut queue = Queue::default();
+        for i in 1..=5 {
+            queue.add(format!("song{}", i), None);
+        }
+        queue.move_to(2);
+        for _ in 0..3 {
+            queue.add_current_to_history();
+            queue.move_next();
+        }
+        // adding the same song twice in a row doesn't duplicate it
+        queue.add_current_to_history();
+        queue.add_current_to_history();
+        queue.clear();
+
+        let ids: Vec<_> = queue.played(10).map(|entry| entry.id).collect();
+        assert_eq!(ids, [2, 3, 4, 5]);
+        let ids: Vec<_> = queue.played(2).map(|entry| entry.id).collect();
+        assert_eq!(ids, [4, 5]);
+    }
+
+    #[test]
+    fn repeat_all() {
+        let mut queue = Queue::default();
+        for i in 1..=3 {
+            queue.add(format!("song{}", i), None);
+        }
+        queue.start_repeat_all();
+
+        let ids: Vec<_> = (0..7)
+            .map(|_| queue.move_next().map(|entry| entry.id))
+            .collect();
+        assert_eq!(ids, [1, 2, 3, 1, 2, 3, 1].map(Some));
+        let ids: Vec<_> = (0..4)
+            .map(|_| queue.move_prev().map(|entry| entry.id))
+            .collect();
+        assert_eq!(ids, [3, 2, 1, 3].map(Some));
+        assert_eq!(queue.mode(), "repeat_all");
+    }
+
+    #[test]
+    fn repeat_one() {
+        let mut queue = Queue::default();
+        for i in 1..=3 {
+            queue.add(format!("song{}", i), None);
+        }
+        queue.start_repeat_one();
+        assert_eq!(queue.move_next(), None);
+
+        queue.move_to(2);
+        for _ in 0..5 {
+            assert_eq!(queue.move_next().map(|entry| entry.id), Some(2));
+        }
+        assert_eq!(queue.mode(), "repeat_one");
+    }
+
     #[test]
     fn random() {
         let mut queue = Queue::default();
@@ -332,4 +944,90 @@ mod test {
             assert_eq!(ids, (1..=n).collect::<Vec<_>>());
         }
     }
+
+    #[test]
+    fn random_with_priority() {
+        let n = 10;
+        let high_priority_id = 7;
+        let (mut avg_pos_high, mut avg_pos_other) = (0.0, 0.0);
+        let n_tries = 1000;
+        for k in 0..n_tries {
+            let ids: Vec<_> = (1..=n)
+                .map(|id| (id, if id == high_priority_id { 100 } else { 0 }))
+                .collect();
+            let random = Random::new(ids, k);
+            // ids are taken from the back
+            for (pos, &id) in random.ids.iter().rev().enumerate() {
+                if id == high_priority_id {
+                    avg_pos_high += pos as f64 / n_tries as f64;
+                } else {
+                    avg_pos_other += pos as f64 / ((n - 1) * n_tries as u32) as f64;
+                }
+            }
+        }
+
+        assert!(avg_pos_high < 1.0);
+        assert!(avg_pos_other > 4.0);
+    }
+
+    #[test]
+    fn random_large_queue() {
+        let n = 1000;
+        let play_through = |queue: &mut Queue| -> Vec<u32> {
+            (0..n)
+                .map(|_| queue.move_next().map(|entry| entry.id).unwrap())
+                .collect()
+        };
+        let mut queue = Queue::default();
+        for i in 1..=n {
+            queue.add(format!("song{}", i), None);
+        }
+        queue.start_random();
+        let order = play_through(&mut queue);
+
+        // a uniformly random permutation moves an entry by n/3 on average
+        let avg_displacement = order
+            .iter()
+            .enumerate()
+            .map(|(pos, &id)| (pos as f64 - (id - 1) as f64).abs())
+            .sum::<f64>()
+            / n as f64;
+        assert!((300.0..366.0).contains(&avg_displacement));
+        // and leaves very few consecutive entries next to each other
+        let n_consecutive = order.windows(2).filter(|w| w[1] == w[0] + 1).count();
+        assert!(n_consecutive < 10);
+
+        // the next round (and a queue of the same length) is shuffled differently
+        assert_ne!(play_through(&mut queue), order);
+        let mut other_queue = Queue::default();
+        for i in 1..=n {
+            other_queue.add(format!("song{}", i), None);
+        }
+        other_queue.start_random();
+        assert_ne!(play_through(&mut other_queue), order);
+    }
+
+    #[test]
+    fn weighted_random() {
+        let mut queue = Queue::default();
+        for i in 1..=3 {
+            queue.add(format!("song{}", i), None);
+        }
+        queue.start_weighted_random(&PlayCounts::default());
+        queue.move_to(2);
+        queue.add_current_to_history();
+
+        let (mut n_played, mut n_fresh) = (0, 0);
+        for _ in 0..1000 {
+            queue.move_to(1);
+            match queue.move_next().map(|entry| entry.id) {
+                Some(2) => n_played += 1,
+                Some(3) => n_fresh += 1,
+                other => panic!("unexpected entry {:?}", other),
+            }
+        }
+        // the expected ratio is `HISTORY_PENALTY`
+        assert!(n_played * 3 < n_fresh);
+        assert_eq!(queue.mode(), "weighted_random");
+    }
 }
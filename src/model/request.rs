@@ -1,87 +1,181 @@
 use anyhow::{Result, anyhow, bail};
 use serde_json::Value;
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+};
 use tokio::sync::oneshot;
 
-use crate::model::{
-    comparator::Comparator,
-    filter::{Filter, FilterExpr},
-    response::{JsonObject, Response},
-    tag_key::{self, TagKey},
+use crate::{
+    constants,
+    model::{
+        comparator::Comparator,
+        equalizer::Band,
+        filter::FilterExpr,
+        filter_parser,
+        queue::QueueEndAction,
+        response::{JsonObject, Response},
+        tag_key::{self, TagKey},
+    },
 };
 
+pub struct AddBookmarkArgs(pub PathBuf, pub String, pub u64); // song, label, seconds
+pub struct DeleteBookmarkArgs(pub PathBuf, pub String); // song, label
+pub struct ListBookmarksArgs(pub PathBuf);
+pub struct SeekBookmarkArgs(pub String); // label (of a bookmark in the current song)
+pub enum BookmarkRequestKind {
+    Add(AddBookmarkArgs),
+    Delete(DeleteBookmarkArgs),
+    List(ListBookmarksArgs),
+    Seek(SeekBookmarkArgs),
+}
+
+pub struct CountArgs(pub FilterExpr, pub Vec<TagKey>); // filters, group by
+pub struct CoverArtArgs(pub PathBuf);
+pub struct DiskSizeArgs(pub Option<TagKey>); // group by
 pub struct LsArgs(pub PathBuf);
-pub struct MetadataArgs(pub Vec<PathBuf>, pub Vec<TagKey>);
+pub struct MetadataArgs(pub Vec<PathBuf>, pub Vec<TagKey>, pub Vec<String>); // paths, tags, freeform tags
 pub struct SelectArgs(
     pub Vec<TagKey>,
     pub FilterExpr,
     pub Vec<TagKey>,
     pub Vec<Comparator>,
 );
+pub struct SampleArgs(pub usize, pub FilterExpr); // how many songs, filters
+pub struct SearchArgs(pub String, pub Vec<TagKey>); // query, tags to search
+pub struct UniqueArgs(pub TagKey, pub FilterExpr, pub Vec<TagKey>); // tag, filters, group by
+pub struct WriteTagsArgs(pub PathBuf, pub Vec<(String, Vec<String>)>); // song, (tag name, values)
 pub enum DbRequestKind {
+    Count(CountArgs),
+    CoverArt(CoverArtArgs),
+    DiskSize(DiskSizeArgs),
     Ls(LsArgs),
     Metadata(MetadataArgs),
+    Sample(SampleArgs),
+    Search(SearchArgs),
     Select(SelectArgs),
+    Stats,
+    Unique(UniqueArgs),
     Update,
+    WriteTags(WriteTagsArgs),
 }
 
 pub struct DisableArgs(pub String);
 pub struct EnableArgs(pub String);
+pub struct DeviceVolumeArgs(pub String, pub u8);
 pub enum DeviceRequestKind {
     Disable(DisableArgs),
     Enable(EnableArgs),
+    Volume(DeviceVolumeArgs),
 }
 
+pub struct CrossfadeArgs(pub u16); // in seconds
+pub struct EqArgs(pub Vec<Band>);
 pub struct VolumeArgs(pub i8);
 pub struct SeekArgs(pub i64); // in seconds
+pub struct SeekToArgs(pub i64); // in seconds, from the end if negative
+pub struct SeekChapterArgs(pub i32); // relative to the current chapter
 pub struct SpeedArgs(pub i16);
 pub enum PlaybackRequestKind {
+    Crossfade(CrossfadeArgs),
+    Eq(EqArgs),
     Gapless,
+    PreservePitch,
+    Mute,
     Pause,
     Resume,
     Seek(SeekArgs),
+    SeekTo(SeekToArgs),
+    SeekChapter(SeekChapterArgs),
     Speed(SpeedArgs),
     Stop,
+    StopAfterCurrent,
     Toggle,
     Volume(VolumeArgs),
 }
 
+pub struct AddCurrentToPlaylistArgs(pub PathBuf); // playlist
 pub struct AddToPlaylistArgs(pub PathBuf, pub PathBuf); // playlist, song
-pub struct ListSongsArgs(pub PathBuf);
-// playlist, range (inclusive), position
-pub struct LoadArgs(pub PathBuf, pub Option<(usize, usize)>, pub Option<usize>);
+pub struct ListSongsArgs(pub PathBuf, pub bool); // playlist, recursive
+// playlist, range (inclusive), position, recursive
+pub struct LoadArgs(
+    pub PathBuf,
+    pub Option<(usize, usize)>,
+    pub Option<usize>,
+    pub bool,
+);
 pub struct RemoveFromPlaylistArgs(pub PathBuf, pub usize); // playlist, position
-pub struct SaveArgs(pub PathBuf);
+pub struct MovePlaylistArgs(pub PathBuf, pub usize, pub usize); // playlist, from, to
+pub struct SaveArgs(pub PathBuf, pub bool); // path, extended
+pub struct RenameArgs(pub PathBuf, pub PathBuf); // playlist, new path
+pub struct DeleteArgs(pub PathBuf); // playlist
 pub enum PlaylistRequestKind {
+    AddCurrentToPlaylist(AddCurrentToPlaylistArgs),
     AddToPlaylist(AddToPlaylistArgs),
+    Delete(DeleteArgs),
     ListSongs(ListSongsArgs),
     Load(LoadArgs),
+    MoveInPlaylist(MovePlaylistArgs),
     RemoveFromPlaylist(RemoveFromPlaylistArgs),
+    Rename(RenameArgs),
     Save(SaveArgs),
 }
 
 pub struct AddToQueueArgs(pub Vec<PathBuf>, pub Option<usize>); // relative or absolute paths
+pub struct MoveArgs(pub u32, pub usize); // queue id, new position
 pub struct PlayArgs(pub u32); // queue id
+pub struct PlayNextArgs(pub Vec<PathBuf>); // relative or absolute paths
+pub struct PlayAlbumArgs(pub FilterExpr); // filters selecting the album
+pub struct HistoryArgs(pub Option<usize>); // how many songs (all remembered ones if `None`)
+pub struct ReplaceArgs(pub Vec<PathBuf>, pub Option<usize>); // paths, position to start from
+pub struct UpNextArgs(pub usize, pub Vec<TagKey>); // count, tags
+pub struct PriorityArgs(pub Vec<u32>, pub u8); // queue ids, priority
+pub struct QueueEndActionArgs(pub QueueEndAction);
 pub struct RemoveFromQueueArgs(pub Vec<u32>); // queue ids
 pub enum QueueRequestKind {
     AddToQueue(AddToQueueArgs),
     Clear,
+    Crop,
+    Dedup,
+    History(HistoryArgs),
+    Move(MoveArgs),
     Next,
     Play(PlayArgs),
+    PlayAlbum(PlayAlbumArgs),
+    PlayNext(PlayNextArgs),
     Previous,
+    Priority(PriorityArgs),
+    QueueEndAction(QueueEndActionArgs),
     Random,
     RemoveFromQueue(RemoveFromQueueArgs),
+    RepeatAll,
+    RepeatOne,
+    Replace(ReplaceArgs),
     Sequential,
+    Shuffle,
     Single,
+    UpNext(UpNextArgs),
+    WeightedRandom,
 }
 
+pub struct StateArgs(pub Option<HashSet<String>>); // only these keys (all of them if `None`)
+pub struct AuthArgs(pub String); // the password
 pub enum RequestKind {
+    Bookmark(BookmarkRequestKind),
+    CommandList(Vec<RequestKind>),
     Db(DbRequestKind),
     Device(DeviceRequestKind),
     Playback(PlaybackRequestKind),
     Playlist(PlaylistRequestKind),
     Queue(QueueRequestKind),
-    State,
+    // handled by the client handler, never sent to the player
+    Auth(AuthArgs),
+    Idle,
+    NoIdle,
+    Ping,
+    State(StateArgs),
+    Timer,
+    Health,
 }
 
 pub struct Request {
@@ -89,6 +183,90 @@ pub struct Request {
     pub tx_response: oneshot::Sender<Response>,
 }
 
+impl TryFrom<&mut JsonObject> for AddBookmarkArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let song: PathBuf =
+            serde_json::from_value(args.remove("song").ok_or(anyhow!("key `song` not found"))?)?;
+        let label: String = serde_json::from_value(
+            args.remove("label")
+                .ok_or(anyhow!("key `label` not found"))?,
+        )?;
+        let seconds: u64 = serde_json::from_value(
+            args.remove("seconds")
+                .ok_or(anyhow!("key `seconds` not found"))?,
+        )?;
+
+        Ok(Self(song, label, seconds))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for DeleteBookmarkArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let song: PathBuf =
+            serde_json::from_value(args.remove("song").ok_or(anyhow!("key `song` not found"))?)?;
+        let label: String = serde_json::from_value(
+            args.remove("label")
+                .ok_or(anyhow!("key `label` not found"))?,
+        )?;
+
+        Ok(Self(song, label))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for ListBookmarksArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let song: PathBuf =
+            serde_json::from_value(args.remove("song").ok_or(anyhow!("key `song` not found"))?)?;
+
+        Ok(Self(song))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for SeekBookmarkArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let label: String = serde_json::from_value(
+            args.remove("label")
+                .ok_or(anyhow!("key `label` not found"))?,
+        )?;
+
+        Ok(Self(label))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for CoverArtArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let path: PathBuf =
+            serde_json::from_value(args.remove("path").ok_or(anyhow!("key `path` not found"))?)?;
+
+        Ok(Self(path))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for DiskSizeArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let group_by = args
+            .remove("group_by")
+            .map(serde_json::from_value::<String>)
+            .transpose()?
+            .map(|s| TagKey::try_from(s.as_str()))
+            .transpose()?;
+
+        Ok(Self(group_by))
+    }
+}
+
 impl TryFrom<&mut JsonObject> for LsArgs {
     type Error = anyhow::Error;
 
@@ -108,48 +286,92 @@ impl TryFrom<&mut JsonObject> for MetadataArgs {
             args.remove("paths")
                 .ok_or(anyhow!("key `paths` not found"))?,
         )?;
-        let tags: Vec<TagKey> = match args.remove("all_tags").and_then(|v| v.as_bool()) {
-            Some(all_tags) if all_tags => tag_key::all_tags(),
-            _ => serde_json::from_value::<Vec<String>>(
-                args.remove("tags").ok_or(anyhow!("key `tags` not found"))?,
-            )?
-            .into_iter()
-            .map(|s| TagKey::try_from(s.as_str()))
-            .collect::<Result<_>>()?,
-        };
+        let tags = request_utils::tags(args)?;
+        // raw names of non-standard tags (e.g. `musicbrainz_trackid`)
+        let freeform = serde_json::from_value::<Vec<String>>(
+            args.remove("freeform_tags")
+                .unwrap_or(Value::Array(Vec::new())),
+        )?;
 
-        Ok(Self(paths, tags))
+        Ok(Self(paths, tags, freeform))
     }
 }
 
-impl TryFrom<&mut JsonObject> for SelectArgs {
+impl TryFrom<&mut JsonObject> for WriteTagsArgs {
     type Error = anyhow::Error;
 
     fn try_from(args: &mut JsonObject) -> Result<Self> {
-        let tags: Vec<TagKey> = match args.remove("all_tags").and_then(|v| v.as_bool()) {
-            Some(all_tags) if all_tags => tag_key::all_tags(),
-            _ => serde_json::from_value::<Vec<String>>(
-                args.remove("tags").ok_or(anyhow!("key `tags` not found"))?,
-            )?
+        let path: PathBuf =
+            serde_json::from_value(args.remove("path").ok_or(anyhow!("key `path` not found"))?)?;
+        let tags: BTreeMap<String, Value> =
+            serde_json::from_value(args.remove("tags").ok_or(anyhow!("key `tags` not found"))?)?;
+        // a tag can have many values (e.g. many artists)
+        let tags = tags
             .into_iter()
-            .map(|s| TagKey::try_from(s.as_str()))
-            .collect::<Result<_>>()?,
-        };
+            .map(|(name, value)| match value {
+                Value::String(s) => Ok((name, vec![s])),
+                value => match serde_json::from_value::<Vec<String>>(value) {
+                    Ok(values) => Ok((name, values)),
+                    Err(_) => Err(anyhow!(
+                        "the value of `{}` must be a string or an array of strings",
+                        name
+                    )),
+                },
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self(path, tags))
+    }
+}
 
-        let filters: Vec<Box<dyn Filter>> = serde_json::from_value::<Vec<Value>>(
-            args.remove("filters").unwrap_or(Value::Array(Vec::new())),
-        )?
-        .into_iter()
-        .map(|v| v.try_into())
-        .collect::<Result<_>>()?;
+impl TryFrom<&mut JsonObject> for SampleArgs {
+    type Error = anyhow::Error;
 
-        let group_by: Vec<TagKey> = serde_json::from_value::<Vec<String>>(
-            args.remove("group_by").unwrap_or(Value::Array(Vec::new())),
-        )?
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let count: usize = serde_json::from_value(
+            args.remove("count")
+                .ok_or(anyhow!("key `count` not found"))?,
+        )?;
+        if count == 0 {
+            bail!("`count` must be positive");
+        }
+        let filters = request_utils::filters(args)?;
+
+        Ok(Self(count, filters))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for SearchArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let query: String = serde_json::from_value(
+            args.remove("query")
+                .ok_or(anyhow!("key `query` not found"))?,
+        )?;
+        if query.trim().is_empty() {
+            bail!("`query` can't be empty");
+        }
+        let tags = match args.remove("tags") {
+            Some(tags) => serde_json::from_value::<Vec<String>>(tags)?,
+            None => constants::DEFAULT_SEARCH_TAGS.map(String::from).to_vec(),
+        }
         .into_iter()
         .map(|s| TagKey::try_from(s.as_str()))
         .collect::<Result<_>>()?;
 
+        Ok(Self(query, tags))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for SelectArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let tags = request_utils::tags(args)?;
+        let filters = request_utils::filters(args)?;
+        let group_by = request_utils::group_by(args)?;
+
         let comparators: Vec<Comparator> = serde_json::from_value::<Vec<Value>>(
             args.remove("comparators")
                 .unwrap_or(Value::Array(Vec::new())),
@@ -158,7 +380,32 @@ impl TryFrom<&mut JsonObject> for SelectArgs {
         .map(|v| v.try_into())
         .collect::<Result<_>>()?;
 
-        Ok(Self(tags, FilterExpr(filters), group_by, comparators))
+        Ok(Self(tags, filters, group_by, comparators))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for CountArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let filters = request_utils::filters(args)?;
+        let group_by = request_utils::group_by(args)?;
+
+        Ok(Self(filters, group_by))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for UniqueArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let tag: String =
+            serde_json::from_value(args.remove("tag").ok_or(anyhow!("key `tag` not found"))?)?;
+        let tag = TagKey::try_from(tag.as_str())?;
+        let filters = request_utils::filters(args)?;
+        let group_by = request_utils::group_by(args)?;
+
+        Ok(Self(tag, filters, group_by))
     }
 }
 
@@ -188,6 +435,23 @@ impl TryFrom<&mut JsonObject> for EnableArgs {
     }
 }
 
+impl TryFrom<&mut JsonObject> for DeviceVolumeArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let device: String = serde_json::from_value(
+            args.remove("device")
+                .ok_or(anyhow!("key `device` not found"))?,
+        )?;
+        let volume: u8 = serde_json::from_value(
+            args.remove("volume")
+                .ok_or(anyhow!("key `volume` not found"))?,
+        )?;
+
+        Ok(Self(device, volume))
+    }
+}
+
 impl TryFrom<&mut JsonObject> for SeekArgs {
     type Error = anyhow::Error;
 
@@ -201,6 +465,58 @@ impl TryFrom<&mut JsonObject> for SeekArgs {
     }
 }
 
+impl TryFrom<&mut JsonObject> for SeekToArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let seconds: i64 = serde_json::from_value(
+            args.remove("seconds")
+                .ok_or(anyhow!("key `seconds` not found"))?,
+        )?;
+
+        Ok(Self(seconds))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for StateArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let only = args
+            .remove("only")
+            .map(serde_json::from_value)
+            .transpose()?;
+
+        Ok(Self(only))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for AuthArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let password: String = serde_json::from_value(
+            args.remove("password")
+                .ok_or(anyhow!("key `password` not found"))?,
+        )?;
+
+        Ok(Self(password))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for SeekChapterArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let delta: i32 = serde_json::from_value(
+            args.remove("delta")
+                .ok_or(anyhow!("key `delta` not found"))?,
+        )?;
+
+        Ok(Self(delta))
+    }
+}
+
 impl TryFrom<&mut JsonObject> for VolumeArgs {
     type Error = anyhow::Error;
 
@@ -214,6 +530,35 @@ impl TryFrom<&mut JsonObject> for VolumeArgs {
     }
 }
 
+impl TryFrom<&mut JsonObject> for CrossfadeArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let seconds: u16 = serde_json::from_value(
+            args.remove("seconds")
+                .ok_or(anyhow!("key `seconds` not found"))?,
+        )?;
+
+        Ok(Self(seconds))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for EqArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let bands: Vec<Band> = serde_json::from_value(
+            args.remove("bands")
+                .ok_or(anyhow!("key `bands` not found"))?,
+        )?;
+        for band in bands.iter() {
+            band.validate()?;
+        }
+
+        Ok(Self(bands))
+    }
+}
+
 impl TryFrom<&mut JsonObject> for SpeedArgs {
     type Error = anyhow::Error;
 
@@ -227,6 +572,19 @@ impl TryFrom<&mut JsonObject> for SpeedArgs {
     }
 }
 
+impl TryFrom<&mut JsonObject> for AddCurrentToPlaylistArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let playlist: PathBuf = serde_json::from_value(
+            args.remove("playlist")
+                .ok_or(anyhow!("key `playlist` not found"))?,
+        )?;
+
+        Ok(Self(playlist))
+    }
+}
+
 impl TryFrom<&mut JsonObject> for AddToPlaylistArgs {
     type Error = anyhow::Error;
 
@@ -250,8 +608,12 @@ impl TryFrom<&mut JsonObject> for ListSongsArgs {
             args.remove("playlist")
                 .ok_or(anyhow!("key `playlist` not found"))?,
         )?;
+        let recursive = args
+            .remove("recursive")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-        Ok(Self(playlist))
+        Ok(Self(playlist, recursive))
     }
 }
 
@@ -268,8 +630,12 @@ impl TryFrom<&mut JsonObject> for LoadArgs {
             .map(serde_json::from_value)
             .transpose()?;
         let pos = args.remove("pos").map(serde_json::from_value).transpose()?;
+        let recursive = args
+            .remove("recursive")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-        Ok(Self(playlist, range, pos))
+        Ok(Self(playlist, range, pos, recursive))
     }
 }
 
@@ -279,8 +645,42 @@ impl TryFrom<&mut JsonObject> for SaveArgs {
     fn try_from(args: &mut JsonObject) -> Result<Self> {
         let path: PathBuf =
             serde_json::from_value(args.remove("path").ok_or(anyhow!("key `path` not found"))?)?;
+        let extended = args
+            .remove("extended")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-        Ok(Self(path))
+        Ok(Self(path, extended))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for RenameArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let playlist: PathBuf = serde_json::from_value(
+            args.remove("playlist")
+                .ok_or(anyhow!("key `playlist` not found"))?,
+        )?;
+        let new_name: PathBuf = serde_json::from_value(
+            args.remove("new_name")
+                .ok_or(anyhow!("key `new_name` not found"))?,
+        )?;
+
+        Ok(Self(playlist, new_name))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for DeleteArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let playlist: PathBuf = serde_json::from_value(
+            args.remove("playlist")
+                .ok_or(anyhow!("key `playlist` not found"))?,
+        )?;
+
+        Ok(Self(playlist))
     }
 }
 
@@ -299,6 +699,23 @@ impl TryFrom<&mut JsonObject> for RemoveFromPlaylistArgs {
     }
 }
 
+impl TryFrom<&mut JsonObject> for MovePlaylistArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let playlist: PathBuf = serde_json::from_value(
+            args.remove("playlist")
+                .ok_or(anyhow!("key `playlist` not found"))?,
+        )?;
+        let from: usize =
+            serde_json::from_value(args.remove("from").ok_or(anyhow!("key `from` not found"))?)?;
+        let to: usize =
+            serde_json::from_value(args.remove("to").ok_or(anyhow!("key `to` not found"))?)?;
+
+        Ok(Self(playlist, from, to))
+    }
+}
+
 impl TryFrom<&mut JsonObject> for AddToQueueArgs {
     type Error = anyhow::Error;
 
@@ -313,6 +730,59 @@ impl TryFrom<&mut JsonObject> for AddToQueueArgs {
     }
 }
 
+impl TryFrom<&mut JsonObject> for HistoryArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let count = args
+            .remove("count")
+            .map(serde_json::from_value)
+            .transpose()?;
+
+        Ok(Self(count))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for PlayAlbumArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let filters = request_utils::filters(args)?;
+
+        Ok(Self(filters))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for ReplaceArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let paths: Vec<PathBuf> = serde_json::from_value(
+            args.remove("paths")
+                .ok_or(anyhow!("key `paths` not found"))?,
+        )?;
+        let start = args
+            .remove("start")
+            .map(serde_json::from_value)
+            .transpose()?;
+
+        Ok(Self(paths, start))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for MoveArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let id: u32 =
+            serde_json::from_value(args.remove("id").ok_or(anyhow!("key `id` not found"))?)?;
+        let to: usize =
+            serde_json::from_value(args.remove("to").ok_or(anyhow!("key `to` not found"))?)?;
+
+        Ok(Self(id, to))
+    }
+}
+
 impl TryFrom<&mut JsonObject> for PlayArgs {
     type Error = anyhow::Error;
 
@@ -324,6 +794,47 @@ impl TryFrom<&mut JsonObject> for PlayArgs {
     }
 }
 
+impl TryFrom<&mut JsonObject> for PlayNextArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let paths: Vec<PathBuf> = serde_json::from_value(
+            args.remove("paths")
+                .ok_or(anyhow!("key `paths` not found"))?,
+        )?;
+
+        Ok(Self(paths))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for PriorityArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let ids: Vec<u32> =
+            serde_json::from_value(args.remove("ids").ok_or(anyhow!("key `ids` not found"))?)?;
+        let priority: u8 = serde_json::from_value(
+            args.remove("priority")
+                .ok_or(anyhow!("key `priority` not found"))?,
+        )?;
+
+        Ok(Self(ids, priority))
+    }
+}
+
+impl TryFrom<&mut JsonObject> for QueueEndActionArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let action: String = serde_json::from_value(
+            args.remove("action")
+                .ok_or(anyhow!("key `action` not found"))?,
+        )?;
+
+        Ok(Self(action.parse()?))
+    }
+}
+
 impl TryFrom<&mut JsonObject> for RemoveFromQueueArgs {
     type Error = anyhow::Error;
 
@@ -335,10 +846,72 @@ impl TryFrom<&mut JsonObject> for RemoveFromQueueArgs {
     }
 }
 
+impl TryFrom<&mut JsonObject> for UpNextArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &mut JsonObject) -> Result<Self> {
+        let count: usize = serde_json::from_value(
+            args.remove("count")
+                .ok_or(anyhow!("key `count` not found"))?,
+        )?;
+        let tags = request_utils::tags(args)?;
+
+        Ok(Self(count, tags))
+    }
+}
+
+impl RequestKind {
+    // true if handling this request can change the player's state
+    // (and so the clients waiting in `idle` should be notified)
+    pub fn changes_state(&self) -> bool {
+        match self {
+            RequestKind::CommandList(kinds) => kinds.iter().any(|kind| kind.changes_state()),
+            RequestKind::Db(
+                DbRequestKind::Count(_)
+                | DbRequestKind::CoverArt(_)
+                | DbRequestKind::DiskSize(_)
+                | DbRequestKind::Ls(_)
+                | DbRequestKind::Metadata(_)
+                | DbRequestKind::Sample(_)
+                | DbRequestKind::Search(_)
+                | DbRequestKind::Select(_)
+                | DbRequestKind::Stats
+                | DbRequestKind::Unique(_),
+            )
+            | RequestKind::Queue(QueueRequestKind::History(_))
+            | RequestKind::Auth(_)
+            | RequestKind::Idle
+            | RequestKind::NoIdle
+            | RequestKind::Ping
+            | RequestKind::State(_)
+            | RequestKind::Timer
+            | RequestKind::Health => false,
+            _ => true,
+        }
+    }
+
+    // true if handling this request can change the current song
+    // (commands of a command list are checked one by one)
+    pub fn changes_song(&self) -> bool {
+        matches!(
+            self,
+            RequestKind::Playback(PlaybackRequestKind::Stop)
+                | RequestKind::Queue(
+                    QueueRequestKind::Next
+                        | QueueRequestKind::Play(_)
+                        | QueueRequestKind::PlayAlbum(_)
+                        | QueueRequestKind::Previous
+                        | QueueRequestKind::Replace(_)
+                )
+        )
+    }
+}
+
 impl TryFrom<&str> for RequestKind {
     type Error = anyhow::Error;
 
     fn try_from(s: &str) -> Result<Self> {
+        use BookmarkRequestKind as Bookmark;
         use DbRequestKind as Db;
         use DeviceRequestKind as Device;
         use PlaybackRequestKind as Playback;
@@ -352,41 +925,90 @@ impl TryFrom<&str> for RequestKind {
         let kind: String =
             serde_json::from_value(map.remove("kind").ok_or(anyhow!("key `kind` not found"))?)?;
         let kind = match kind.as_str() {
+            "addbookmark" => RequestKind::Bookmark(Bookmark::Add(map.try_into()?)),
+            "deletebookmark" => RequestKind::Bookmark(Bookmark::Delete(map.try_into()?)),
+            "listbookmarks" => RequestKind::Bookmark(Bookmark::List(map.try_into()?)),
+            "seekbookmark" => RequestKind::Bookmark(Bookmark::Seek(map.try_into()?)),
+
+            "coverart" => RequestKind::Db(Db::CoverArt(map.try_into()?)),
+            "count" => RequestKind::Db(Db::Count(map.try_into()?)),
+            "disksize" => RequestKind::Db(Db::DiskSize(map.try_into()?)),
             "ls" => RequestKind::Db(Db::Ls(map.try_into()?)),
             "metadata" => RequestKind::Db(Db::Metadata(map.try_into()?)),
+            "sample" => RequestKind::Db(Db::Sample(map.try_into()?)),
+            "search" => RequestKind::Db(Db::Search(map.try_into()?)),
             "select" => RequestKind::Db(Db::Select(map.try_into()?)),
+            "stats" => RequestKind::Db(Db::Stats),
+            "unique" => RequestKind::Db(Db::Unique(map.try_into()?)),
             "update" => RequestKind::Db(Db::Update),
+            "writetags" => RequestKind::Db(Db::WriteTags(map.try_into()?)),
 
             "disable" => RequestKind::Device(Device::Disable(map.try_into()?)),
             "enable" => RequestKind::Device(Device::Enable(map.try_into()?)),
+            "devicevol" => RequestKind::Device(Device::Volume(map.try_into()?)),
             "volume" => RequestKind::Playback(Playback::Volume(map.try_into()?)),
+            "crossfade" => RequestKind::Playback(Playback::Crossfade(map.try_into()?)),
+            "eq" => RequestKind::Playback(Playback::Eq(map.try_into()?)),
             "modegapless" => RequestKind::Playback(Playback::Gapless),
+            "preservepitch" => RequestKind::Playback(Playback::PreservePitch),
+            "mute" => RequestKind::Playback(Playback::Mute),
             "pause" => RequestKind::Playback(Playback::Pause),
             "resume" => RequestKind::Playback(Playback::Resume),
             "seek" => RequestKind::Playback(Playback::Seek(map.try_into()?)),
+            "seekto" => RequestKind::Playback(Playback::SeekTo(map.try_into()?)),
+            "seekchapter" => RequestKind::Playback(Playback::SeekChapter(map.try_into()?)),
             "speed" => RequestKind::Playback(Playback::Speed(map.try_into()?)),
             "stop" => RequestKind::Playback(Playback::Stop),
+            "stopafter" => RequestKind::Playback(Playback::StopAfterCurrent),
             "toggle" => RequestKind::Playback(Playback::Toggle),
 
+            "addcurrenttoplaylist" => {
+                RequestKind::Playlist(Playlist::AddCurrentToPlaylist(map.try_into()?))
+            }
             "addplaylist" => RequestKind::Playlist(Playlist::AddToPlaylist(map.try_into()?)),
             "listsongs" => RequestKind::Playlist(Playlist::ListSongs(map.try_into()?)),
             "load" => RequestKind::Playlist(Playlist::Load(map.try_into()?)),
             "removeplaylist" => {
                 RequestKind::Playlist(Playlist::RemoveFromPlaylist(map.try_into()?))
             }
+            "moveplaylist" => RequestKind::Playlist(Playlist::MoveInPlaylist(map.try_into()?)),
             "save" => RequestKind::Playlist(Playlist::Save(map.try_into()?)),
+            "renameplaylist" => RequestKind::Playlist(Playlist::Rename(map.try_into()?)),
+            "deleteplaylist" => RequestKind::Playlist(Playlist::Delete(map.try_into()?)),
 
             "addqueue" => RequestKind::Queue(Queue::AddToQueue(map.try_into()?)),
             "clearqueue" => RequestKind::Queue(Queue::Clear),
+            "crop" => RequestKind::Queue(Queue::Crop),
+            "replace" => RequestKind::Queue(Queue::Replace(map.try_into()?)),
+            "dedup" => RequestKind::Queue(Queue::Dedup),
             "moderandom" => RequestKind::Queue(Queue::Random),
             "modesequential" => RequestKind::Queue(Queue::Sequential),
             "modesingle" => RequestKind::Queue(Queue::Single),
+            "move" => RequestKind::Queue(Queue::Move(map.try_into()?)),
             "next" => RequestKind::Queue(Queue::Next),
             "play" => RequestKind::Queue(Queue::Play(map.try_into()?)),
+            "playalbum" => RequestKind::Queue(Queue::PlayAlbum(map.try_into()?)),
+            "playnext" => RequestKind::Queue(Queue::PlayNext(map.try_into()?)),
             "previous" => RequestKind::Queue(Queue::Previous),
+            "prio" => RequestKind::Queue(Queue::Priority(map.try_into()?)),
+            "queueend" => RequestKind::Queue(Queue::QueueEndAction(map.try_into()?)),
+            "repeatall" => RequestKind::Queue(Queue::RepeatAll),
+            "repeatone" => RequestKind::Queue(Queue::RepeatOne),
+            "shuffle" => RequestKind::Queue(Queue::Shuffle),
             "removequeue" => RequestKind::Queue(Queue::RemoveFromQueue(map.try_into()?)),
+            "upnext" => RequestKind::Queue(Queue::UpNext(map.try_into()?)),
+            "history" => RequestKind::Queue(Queue::History(map.try_into()?)),
+            "weightedrandom" => RequestKind::Queue(Queue::WeightedRandom),
 
-            "state" => RequestKind::State,
+            "commandlist" => RequestKind::CommandList(request_utils::command_list(map)?),
+
+            "auth" => RequestKind::Auth(map.try_into()?),
+            "idle" => RequestKind::Idle,
+            "noidle" => RequestKind::NoIdle,
+            "ping" => RequestKind::Ping,
+            "state" => RequestKind::State(map.try_into()?),
+            "timer" => RequestKind::Timer,
+            "health" => RequestKind::Health,
 
             other => bail!("invalid value of key `kind`: `{}`", other),
         };
@@ -394,3 +1016,126 @@ impl TryFrom<&str> for RequestKind {
         Ok(kind)
     }
 }
+
+mod request_utils {
+    use super::*;
+
+    pub fn command_list(args: &mut JsonObject) -> Result<Vec<RequestKind>> {
+        let commands = serde_json::from_value::<Vec<Value>>(
+            args.remove("commands")
+                .ok_or(anyhow!("key `commands` not found"))?,
+        )?;
+        commands
+            .into_iter()
+            .enumerate()
+            .map(|(i, command)| {
+                let kind = RequestKind::try_from(command.to_string().as_str())
+                    .map_err(|e| anyhow!("invalid command at index {} ({})", i, e))?;
+                match kind {
+                    RequestKind::CommandList(_)
+                    | RequestKind::Auth(_)
+                    | RequestKind::Idle
+                    | RequestKind::NoIdle
+                    | RequestKind::Ping => {
+                        bail!(
+                            "invalid command at index {} (not allowed in a command list)",
+                            i
+                        )
+                    }
+                    kind => Ok(kind),
+                }
+            })
+            .collect()
+    }
+
+    // either the `tags` array or all tags if `all_tags` is true
+    pub fn tags(args: &mut JsonObject) -> Result<Vec<TagKey>> {
+        match args.remove("all_tags").and_then(|v| v.as_bool()) {
+            Some(all_tags) if all_tags => Ok(tag_key::all_tags()),
+            _ => serde_json::from_value::<Vec<String>>(
+                args.remove("tags").ok_or(anyhow!("key `tags` not found"))?,
+            )?
+            .into_iter()
+            .map(|s| TagKey::try_from(s.as_str()))
+            .collect(),
+        }
+    }
+
+    // either an array of filters, a tree of them or a text expression
+    pub fn filters(args: &mut JsonObject) -> Result<FilterExpr> {
+        let filter_expr = args
+            .remove("filter_expr")
+            .map(serde_json::from_value::<String>)
+            .transpose()?;
+        match (args.remove("filters"), filter_expr) {
+            (Some(_), Some(_)) => bail!("only one of `filters` and `filter_expr` can be given"),
+            (Some(filters), None) => FilterExpr::try_from(filters),
+            (None, Some(filter_expr)) => filter_parser::parse(&filter_expr)
+                .map_err(|e| anyhow!("invalid `filter_expr` ({})", e)),
+            (None, None) => Ok(FilterExpr::default()),
+        }
+    }
+
+    pub fn group_by(args: &mut JsonObject) -> Result<Vec<TagKey>> {
+        serde_json::from_value::<Vec<String>>(
+            args.remove("group_by").unwrap_or(Value::Array(Vec::new())),
+        )?
+        .into_iter()
+        .map(|s| TagKey::try_from(s.as_str()))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metadata_tags() {
+        let s = r#"{"kind": "metadata", "paths": ["a.mp3"], "tags": ["artist"],
+            "freeform_tags": ["musicbrainz_trackid"]}"#;
+        let Ok(RequestKind::Db(DbRequestKind::Metadata(MetadataArgs(_, tags, freeform)))) =
+            RequestKind::try_from(s)
+        else {
+            panic!("expected a metadata request");
+        };
+        assert_eq!(tags, vec![TagKey::try_from("artist").unwrap()]);
+        assert_eq!(freeform, vec!["musicbrainz_trackid".to_string()]);
+
+        // unknown names are rejected, just like in `select`
+        let s = r#"{"kind": "metadata", "paths": ["a.mp3"], "tags": ["musicbrainz_trackid"]}"#;
+        assert!(RequestKind::try_from(s).is_err());
+    }
+
+    #[test]
+    fn command_list() {
+        let s = r#"{"kind": "commandlist", "commands": [
+            {"kind": "pause"},
+            {"kind": "volume", "delta": -10}
+        ]}"#;
+        let Ok(RequestKind::CommandList(kinds)) = RequestKind::try_from(s) else {
+            panic!("expected a command list");
+        };
+        assert_eq!(kinds.len(), 2);
+        assert!(matches!(
+            kinds[0],
+            RequestKind::Playback(PlaybackRequestKind::Pause)
+        ));
+        assert!(matches!(
+            kinds[1],
+            RequestKind::Playback(PlaybackRequestKind::Volume(VolumeArgs(-10)))
+        ));
+
+        let s = r#"{"kind": "commandlist", "commands": [
+            {"kind": "pause"},
+            {"kind": "volume"}
+        ]}"#;
+        let Err(e) = RequestKind::try_from(s) else {
+            panic!("expected an error");
+        };
+        assert!(e.to_string().contains("index 1"));
+
+        let s = r#"{"kind": "commandlist", "commands": [{"kind": "idle"}]}"#;
+        assert!(RequestKind::try_from(s).is_err());
+    }
+}
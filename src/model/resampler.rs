@@ -5,10 +5,12 @@ use symphonia::core::{
     sample::Sample,
 };
 
-use crate::model::device::BaseSample;
+use crate::model::{device::BaseSample, stretcher::Stretcher};
 
 pub struct Resampler {
     resampler: FftFixedIn<BaseSample>,
+    // changes the speed after resampling (if the pitch is to be preserved)
+    stretcher: Option<Stretcher>,
     input: Vec<Vec<BaseSample>>,
     output: Vec<Vec<BaseSample>>,
     interleaved: Vec<BaseSample>,
@@ -16,11 +18,25 @@ pub struct Resampler {
 }
 
 impl Resampler {
-    pub fn new(spec: SignalSpec, out_rate: u32, duration: u64, speed: u16) -> Self {
+    pub fn new(
+        spec: SignalSpec,
+        out_rate: u32,
+        duration: u64,
+        speed: u16,
+        preserve_pitch: bool,
+    ) -> Self {
         let duration = duration as usize;
         let n_channels = spec.channels.count();
+        // without preserving the pitch, the speed is changed by pretending
+        // that the sample rate of the song is higher (or lower) than it is
+        let (rate_speed, stretcher) = if preserve_pitch {
+            let stretcher = (speed != 100).then(|| Stretcher::new(speed, out_rate, n_channels));
+            (100, stretcher)
+        } else {
+            (speed, None)
+        };
         let (in_rate, out_rate) = (
-            (spec.rate as f32 * (speed as f32) / 100.0) as usize,
+            (spec.rate as f32 * (rate_speed as f32) / 100.0) as usize,
             out_rate as usize,
         );
         let resampler =
@@ -31,6 +47,7 @@ impl Resampler {
 
         Self {
             resampler,
+            stretcher,
             input,
             output,
             interleaved,
@@ -69,6 +86,9 @@ impl Resampler {
             }
         }
 
-        Some(&self.interleaved)
+        match &mut self.stretcher {
+            Some(stretcher) => Some(stretcher.process(&self.interleaved)),
+            None => Some(&self.interleaved),
+        }
     }
 }
@@ -1,10 +1,27 @@
 use anyhow::Result;
 use erased_serde::Serialize as ErasedSerialize;
+use serde::Serialize;
 use serde_json::{self, Map, Value, json};
 use std::fmt::{self, Display, Formatter};
 
 pub type JsonObject = Map<String, Value>;
 
+// lets clients tell errors apart without matching on their messages
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Audio,        // playback or audio devices
+    Database,     // scanning the music directories
+    File,         // reading or writing files (e.g. playlists)
+    Syntax,       // malformed requests
+    NotFound,     // no such song, playlist, bookmark, etc.
+    InvalidArg,   // the request is well-formed, but it can't be done with these arguments
+    Busy,         // too many clients are connected
+    Timeout,      // the player didn't respond in time
+    Unauthorized, // the client hasn't authenticated yet
+    Other,
+}
+
 // invariant: this Value is always a JsonObject
 // is there a way to enforce this using the type system?
 #[derive(Debug)]
@@ -20,7 +37,7 @@ impl<T> From<Result<T>> for Response {
     fn from(result: Result<T>) -> Self {
         match result {
             Ok(_) => Self::new_ok(),
-            Err(e) => Self::new_err(e.to_string()),
+            Err(e) => Self::new_err(ErrorCode::Other, e.to_string()),
         }
     }
 }
@@ -36,16 +53,41 @@ impl Response {
         self.0.as_object().unwrap()
     }
 
+    pub fn into_inner(self) -> JsonObject {
+        match self.0 {
+            Value::Object(object) => object,
+            _ => unreachable!(),
+        }
+    }
+
     pub fn inner_mut(&mut self) -> &'_ mut JsonObject {
         self.0.as_object_mut().unwrap()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.inner().is_empty()
+    }
+
+    pub fn is_err(&self) -> bool {
+        self.inner()
+            .get("status")
+            .is_some_and(|status| status == "err")
+    }
+
     pub fn new_ok() -> Self {
         Self(json!({"status": "ok"}))
     }
 
-    pub fn new_err(reason: impl Into<String>) -> Self {
-        Self(json!({"status": "err", "reason": reason.into()}))
+    pub fn new_err(code: ErrorCode, reason: impl Into<String>) -> Self {
+        Self(json!({"status": "err", "code": code, "reason": reason.into()}))
+    }
+
+    // like `From<Result<T>>`, but with a more specific error code
+    pub fn from_result<T>(result: Result<T>, code: ErrorCode) -> Self {
+        match result {
+            Ok(_) => Self::new_ok(),
+            Err(e) => Self::new_err(code, e.to_string()),
+        }
     }
 
     pub fn with_item(mut self, key: impl Into<String>, value: &dyn ErasedSerialize) -> Self {
@@ -70,4 +112,31 @@ impl Response {
 
         Self(Value::Object(diff))
     }
+
+    // overwrites the keys present in `newer`, keeps the rest
+    pub fn merge(&mut self, newer: Self) {
+        self.inner_mut().extend(newer.into_inner());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn error_codes() {
+        let response = Response::new_err(ErrorCode::NotFound, "song `a` not found");
+        assert_eq!(response.inner()["status"], "err");
+        assert_eq!(response.inner()["code"], "not_found");
+        assert_eq!(response.inner()["reason"], "song `a` not found");
+
+        let response: Response = Err::<(), _>(anyhow!("oops")).into();
+        assert_eq!(response.inner()["code"], "other");
+        let response = Response::from_result(Err::<(), _>(anyhow!("oops")), ErrorCode::Audio);
+        assert_eq!(response.inner()["code"], "audio");
+        let response = Response::from_result(Ok(()), ErrorCode::Audio);
+        assert_eq!(response.inner()["status"], "ok");
+        assert!(!response.inner().contains_key("code"));
+    }
 }
@@ -2,21 +2,42 @@ use anyhow::{Result, anyhow};
 use base64::prelude::*;
 use std::{
     collections::HashMap,
-    fs::File,
+    fs::{self, File},
+    hash::{DefaultHasher, Hash, Hasher},
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 use symphonia::core::{
     formats::{FormatOptions, FormatReader},
-    io::MediaSourceStream,
-    meta::{MetadataOptions, MetadataRevision},
+    io::{MediaSourceStream, ReadBytes},
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey},
     probe::{Hint, ProbeResult},
+    units::TimeBase,
 };
 
-use crate::model::tag_key::TagKey;
+use crate::{constants, model::tag_key::TagKey};
 
 #[derive(Clone, Debug, Default)]
 pub struct Metadata {
-    data: HashMap<TagKey, String>,
+    // a tag can have many values (e.g. a song by many artists), never zero
+    data: HashMap<TagKey, Vec<String>>,
+    // tags without a `TagKey` (e.g. `musicbrainz_trackid`), by their lowercase raw keys
+    freeform: HashMap<String, Vec<String>>,
+}
+
+// technical parameters of the audio track, `None` if unknown
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AudioParams {
+    pub sample_rate: Option<u32>, // in Hz
+    pub channels: Option<usize>,
+    pub bitrate: Option<u64>, // average, in kbit/s
+}
+
+#[derive(Clone, Debug)]
+pub struct CoverArt {
+    pub data: Vec<u8>,
+    pub mime: Option<String>, // e.g. "image/jpeg"
+    pub hash: String,         // changes whenever the image does
 }
 
 #[derive(Clone, Debug)]
@@ -24,40 +45,88 @@ pub struct Song {
     pub path: PathBuf, // absolute path
     pub metadata: Metadata,
     pub duration: Option<u64>, // in seconds
+    pub size: u64,             // in bytes
+    pub mtime: Option<u64>,    // last modification time, in unix seconds
+    pub params: AudioParams,
 }
 
 #[derive(Debug)]
 pub enum SongEvent {
     Over,
+    Ending,             // the song is about to end, so the next one can start fading in
+    DeviceLost(String), // device name
+    Error(String),      // the decoder failed (or panicked) in the middle of the song
+    Chained(PathBuf),   // the decoder moved on to the next song (with no gap)
 }
 
 impl From<&MetadataRevision> for Metadata {
     fn from(revision: &MetadataRevision) -> Self {
-        let mut data = HashMap::new();
+        let mut data: HashMap<_, Vec<_>> = HashMap::new();
+        let mut freeform: HashMap<_, Vec<_>> = HashMap::new();
         for tag in revision.tags() {
-            if let Some(tag_key) = tag.std_key.and_then(|key| TagKey::try_from(key).ok()) {
-                data.entry(tag_key).or_insert_with(|| tag.value.to_string());
+            let values = match tag.std_key.and_then(|key| TagKey::try_from(key).ok()) {
+                Some(tag_key) => data.entry(tag_key).or_default(),
+                None => freeform.entry(tag.key.to_lowercase()).or_default(),
+            };
+            let value = tag.value.to_string();
+            if !values.contains(&value) {
+                values.push(value);
             }
         }
 
-        Self { data }
+        Self { data, freeform }.with_year()
     }
 }
 
 impl Metadata {
+    // the first value
     pub fn get(&self, tag: &TagKey) -> Option<&str> {
-        self.data.get(tag).map(|s| s.as_str())
+        self.get_all(tag).first().map(|s| s.as_str())
+    }
+
+    pub fn get_all(&self, tag: &TagKey) -> &[String] {
+        self.data
+            .get(tag)
+            .map(|values| values.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn get_freeform(&self, key: &str) -> &[String] {
+        self.freeform
+            .get(&key.to_lowercase())
+            .map(|values| values.as_slice())
+            .unwrap_or_default()
     }
 
     pub fn merge(self, other: Metadata) -> Self {
         Self {
             data: self.data.into_iter().chain(other.data).collect(),
+            freeform: self.freeform.into_iter().chain(other.freeform).collect(),
         }
+        .with_year()
+    }
+
+    // derives the year from the leading 4 digits of `date` (e.g. "2021-03-15")
+    fn with_year(mut self) -> Self {
+        let year = TagKey::try_from(StandardTagKey::Date)
+            .ok()
+            .and_then(|date| self.get(&date))
+            .and_then(|date| date.trim().get(..4))
+            .filter(|year| year.chars().all(|c| c.is_ascii_digit()))
+            .map(String::from);
+        match year {
+            Some(year) => self.data.insert(TagKey::year(), vec![year]),
+            None => self.data.remove(&TagKey::year()),
+        };
+
+        self
     }
 }
 
 impl Song {
-    pub fn try_new(path: impl AsRef<Path> + Into<PathBuf>) -> Result<Self> {
+    // if `exact_duration` is true, songs of unknown length are read
+    // in full to find out their duration, otherwise it's estimated
+    pub fn try_new(path: impl AsRef<Path> + Into<PathBuf>, exact_duration: bool) -> Result<Self> {
         let mut probe_res = song_utils::get_probe_result(&path, false)?;
         let metadata_container = probe_res
             .format
@@ -75,15 +144,37 @@ impl Song {
             "no audio track found in `{}`",
             path.as_ref().to_string_lossy()
         ))?;
-        let duration = match (&track.codec_params.time_base, &track.codec_params.n_frames) {
-            (Some(tb), Some(n)) => Some(tb.calc_time(*n).seconds),
-            _ => None,
+        let file_metadata = path.as_ref().metadata().ok();
+        let size = file_metadata.as_ref().map(|m| m.len()).unwrap_or_default();
+        let secs = match (&track.codec_params.time_base, &track.codec_params.n_frames) {
+            (Some(tb), Some(n)) => {
+                let time = tb.calc_time(*n);
+                Some(time.seconds as f64 + time.frac)
+            }
+            _ => self::demuxer(&path, false)
+                .ok()
+                .and_then(|demuxer| scanned_duration(demuxer, size, exact_duration)),
+        };
+        let duration = secs.map(|secs| secs as u64);
+        let mtime = file_metadata
+            .and_then(|m| m.modified().ok())
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|time| time.as_secs());
+        let params = AudioParams {
+            sample_rate: track.codec_params.sample_rate,
+            channels: track.codec_params.channels.map(|channels| channels.count()),
+            bitrate: secs
+                .filter(|&secs| secs > 0.0)
+                .map(|secs| (size as f64 * 8.0 / secs / 1000.0) as u64),
         };
 
         let song = Self {
             path: path.into(),
             metadata: metadata_container.merge(metadata_probe),
             duration,
+            size,
+            mtime,
+            params,
         };
 
         Ok(song)
@@ -95,7 +186,21 @@ pub fn demuxer(path: impl AsRef<Path>, gapless: bool) -> Result<Box<dyn FormatRe
     Ok(probe_res.format)
 }
 
-pub fn cover_art(path: impl AsRef<Path>) -> Option<String> {
+impl CoverArt {
+    pub fn new(data: Vec<u8>, mime: Option<String>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let hash = format!("{:016x}", hasher.finish());
+
+        Self { data, mime, hash }
+    }
+
+    pub fn base64(&self) -> String {
+        BASE64_STANDARD.encode(&self.data)
+    }
+}
+
+pub fn cover_art(path: impl AsRef<Path>) -> Option<CoverArt> {
     let mut probe_res = song_utils::get_probe_result(path, false).ok()?;
     let metadata_container = probe_res.format.metadata();
     let metadata_probe = probe_res.metadata.get();
@@ -106,7 +211,144 @@ pub fn cover_art(path: impl AsRef<Path>) -> Option<String> {
         .iter()
         .next();
 
-    image.map(|image| BASE64_STANDARD.encode(&image.data))
+    image.map(|image| {
+        let mime = (!image.media_type.is_empty()).then(|| image.media_type.clone());
+        CoverArt::new(image.data.to_vec(), mime)
+    })
+}
+
+// looks for an image file (e.g. `cover.jpg`) in the directory of the song
+pub fn sidecar_cover_art(path: impl AsRef<Path>) -> Option<CoverArt> {
+    let dir = path.as_ref().parent()?;
+    constants::COVER_ART_FILES.iter().find_map(|name| {
+        let data = fs::read(dir.join(name)).ok()?;
+        let mime = if name.ends_with(".png") {
+            "image/png"
+        } else {
+            "image/jpeg"
+        };
+
+        Some(CoverArt::new(data, Some(mime.into())))
+    })
+}
+
+// for formats which don't store the number of frames (e.g. ADTS AAC)
+// the duration (in seconds) is computed from the timestamps of packets,
+// either of all of them (exact, but it requires reading the whole file)
+// or of the first few ones (and extrapolated to the size of the file)
+pub fn scanned_duration(mut demuxer: Box<dyn FormatReader>, size: u64, exact: bool) -> Option<f64> {
+    let track = demuxer.default_track()?;
+    let track_id = track.id;
+    let time_base = track.codec_params.time_base.or(track
+        .codec_params
+        .sample_rate
+        .map(|rate| TimeBase::new(1, rate)))?;
+    let (mut n_packets, mut n_bytes, mut end_ts) = (0, 0, 0);
+    while exact || n_packets < constants::DURATION_SCAN_PACKETS {
+        match demuxer.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => {
+                n_packets += 1;
+                n_bytes += packet.buf().len() as u64;
+                end_ts = packet.ts + packet.dur;
+            }
+            Ok(_) => (),
+            // the end of the file (or a broken one)
+            Err(_) => break,
+        }
+    }
+    if n_bytes == 0 {
+        return None;
+    }
+    let time = time_base.calc_time(end_ts);
+    let secs = time.seconds as f64 + time.frac;
+    if exact || n_packets < constants::DURATION_SCAN_PACKETS {
+        return Some(secs);
+    }
+    // whatever comes before the first packet (e.g. tags) isn't audio
+    let header_size = demuxer.into_inner().pos().saturating_sub(n_bytes);
+
+    Some(secs * size.saturating_sub(header_size) as f64 / n_bytes as f64)
+}
+
+// writes a silent 16-bit PCM WAV file
+#[cfg(test)]
+pub fn write_silent_wav(path: impl AsRef<Path>, sample_rate: u32, channels: u16, n_frames: u32) {
+    let block_align = 2 * channels as u32;
+    let data_len = n_frames * block_align;
+    let mut wav = Vec::new();
+    wav.extend(b"RIFF");
+    wav.extend((36 + data_len).to_le_bytes());
+    wav.extend(b"WAVEfmt ");
+    wav.extend(16_u32.to_le_bytes());
+    wav.extend(1_u16.to_le_bytes()); // PCM
+    wav.extend(channels.to_le_bytes());
+    wav.extend(sample_rate.to_le_bytes());
+    wav.extend((sample_rate * block_align).to_le_bytes());
+    wav.extend((block_align as u16).to_le_bytes());
+    wav.extend(16_u16.to_le_bytes());
+    wav.extend(b"data");
+    wav.extend(data_len.to_le_bytes());
+    wav.extend(vec![0; data_len as usize]);
+    fs::write(path, wav).unwrap();
+}
+
+// writes a FLAC file with a single (silent) frame and the given metadata blocks,
+// which are pairs of (block type, block data)
+#[cfg(test)]
+pub fn write_minimal_flac(path: impl AsRef<Path>, blocks: &[(u8, &[u8])]) {
+    let mut flac = b"fLaC".to_vec();
+    // STREAMINFO: 192-sample blocks, 44.1 kHz, mono, 16 bits
+    flac.push(if blocks.is_empty() { 0x80 } else { 0 });
+    flac.extend([0, 0, 34]);
+    flac.extend(192_u16.to_be_bytes());
+    flac.extend(192_u16.to_be_bytes());
+    flac.extend([0; 6]);
+    flac.extend(((44100_u64 << 44) | (15 << 36) | 192).to_be_bytes());
+    flac.extend([0; 16]);
+    for (i, (kind, data)) in blocks.iter().enumerate() {
+        flac.push(if i == blocks.len() - 1 {
+            0x80 | kind
+        } else {
+            *kind
+        });
+        flac.extend(&(data.len() as u32).to_be_bytes()[1..]);
+        flac.extend(*data);
+    }
+    // the header of a single frame, which the demuxer syncs to
+    let header = [0xff, 0xf8, 0x10, 0x08, 0x00];
+    let crc8 = header.iter().fold(0_u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    });
+    flac.extend(header);
+    flac.push(crc8);
+    flac.extend([0; 5]);
+    fs::write(path, flac).unwrap();
+}
+
+// a song (that doesn't exist on disk) with the given tags
+#[cfg(test)]
+pub fn tagged_song(tags: &[(StandardTagKey, &str)]) -> Song {
+    use symphonia::core::meta::{MetadataBuilder, Tag, Value};
+
+    let mut builder = MetadataBuilder::new();
+    for &(std_key, value) in tags {
+        builder.add_tag(Tag::new(Some(std_key), "", Value::String(value.into())));
+    }
+
+    Song {
+        path: "/music/song.mp3".into(),
+        metadata: Metadata::from(&builder.metadata()),
+        duration: None,
+        size: 0,
+        mtime: None,
+        params: AudioParams::default(),
+    }
 }
 
 mod song_utils {
@@ -132,3 +374,166 @@ mod song_utils {
         Ok(probe_res)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use symphonia::core::meta::{MetadataBuilder, Tag, Value};
+
+    #[test]
+    fn classical_tags() {
+        let mut builder = MetadataBuilder::new();
+        for (std_key, key, value) in [
+            (StandardTagKey::ContentGroup, "TIT1", "Cello Suite No. 1"),
+            (StandardTagKey::Opus, "OPUS", "BWV 1007"),
+            (StandardTagKey::MovementName, "MVNM", "Prélude"),
+            (StandardTagKey::MovementNumber, "MVIN", "1/6"),
+            (StandardTagKey::TrackSubtitle, "TIT3", "Allegro moderato"),
+        ] {
+            builder.add_tag(Tag::new(Some(std_key), key, Value::String(value.into())));
+        }
+        let metadata = Metadata::from(&builder.metadata());
+
+        let get = |tag: &str| metadata.get(&TagKey::try_from(tag).unwrap());
+        assert_eq!(get("contentgroup"), Some("Cello Suite No. 1"));
+        assert_eq!(get("opus"), Some("BWV 1007"));
+        assert_eq!(get("movementname"), Some("Prélude"));
+        assert_eq!(get("movementnumber"), Some("1/6"));
+        assert_eq!(get("tracksubtitle"), Some("Allegro moderato"));
+    }
+
+    #[test]
+    fn multiple_artists() {
+        let mut builder = MetadataBuilder::new();
+        for artist in ["Simon", "Garfunkel", "Simon"] {
+            builder.add_tag(Tag::new(
+                Some(StandardTagKey::Artist),
+                "TPE1",
+                Value::String(artist.into()),
+            ));
+        }
+        let metadata = Metadata::from(&builder.metadata());
+
+        let artist = TagKey::try_from("artist").unwrap();
+        assert_eq!(metadata.get(&artist), Some("Simon"));
+        assert_eq!(metadata.get_all(&artist), ["Simon", "Garfunkel"]);
+        assert!(metadata.get_all(&TagKey::year()).is_empty());
+    }
+
+    #[test]
+    fn freeform_tags() {
+        let mut builder = MetadataBuilder::new();
+        builder.add_tag(Tag::new(
+            None,
+            "MUSICBRAINZ_TRACKID",
+            Value::String("b1a9c0e9-d987-4042-ae91-78d6a3267d69".into()),
+        ));
+        builder.add_tag(Tag::new(
+            Some(StandardTagKey::Artist),
+            "ARTIST",
+            Value::String("Queen".into()),
+        ));
+        let metadata = Metadata::from(&builder.metadata());
+
+        assert_eq!(
+            metadata.get_freeform("musicbrainz_trackid"),
+            ["b1a9c0e9-d987-4042-ae91-78d6a3267d69"]
+        );
+        assert_eq!(
+            metadata.get_freeform("MusicBrainz_TrackId"),
+            metadata.get_freeform("musicbrainz_trackid")
+        );
+        // tags with a `TagKey` aren't freeform
+        assert!(metadata.get_freeform("artist").is_empty());
+    }
+
+    #[test]
+    fn year_from_date() {
+        let year = |date: &str| {
+            let mut builder = MetadataBuilder::new();
+            builder.add_tag(Tag::new(
+                Some(StandardTagKey::Date),
+                "DATE",
+                Value::String(date.into()),
+            ));
+            Metadata::from(&builder.metadata())
+                .get(&TagKey::year())
+                .map(String::from)
+        };
+
+        assert_eq!(year("2021-03-15").as_deref(), Some("2021"));
+        assert_eq!(year("1999").as_deref(), Some("1999"));
+        assert_eq!(year(" 2021-03").as_deref(), Some("2021"));
+        assert_eq!(year("March 2021"), None);
+        assert_eq!(year("21"), None);
+        assert_eq!(year(""), None);
+    }
+
+    #[test]
+    fn audio_params() {
+        let path = std::env::temp_dir().join(format!("musing_test_{}.wav", std::process::id()));
+        // 2 seconds of stereo audio
+        write_silent_wav(&path, 22050, 2, 2 * 22050);
+        let song = Song::try_new(&path, false);
+        let _ = fs::remove_file(&path);
+        let song = song.unwrap();
+
+        assert_eq!(song.duration, Some(2));
+        assert_eq!(
+            song.params,
+            AudioParams {
+                sample_rate: Some(22050),
+                channels: Some(2),
+                // 22050 * 2 * 16 bits per second (+ the header)
+                bitrate: Some(705),
+            }
+        );
+    }
+
+    #[test]
+    fn embedded_cover_art() {
+        let path = std::env::temp_dir().join(format!("musing_test_{}.flac", std::process::id()));
+        let image = b"not really a png".to_vec();
+        let mime = b"image/png";
+        let mut picture = Vec::new();
+        picture.extend(3_u32.to_be_bytes()); // front cover
+        picture.extend((mime.len() as u32).to_be_bytes());
+        picture.extend(mime);
+        picture.extend(0_u32.to_be_bytes()); // no description
+        picture.extend([0; 16]); // dimensions and colors
+        picture.extend((image.len() as u32).to_be_bytes());
+        picture.extend(&image);
+        write_minimal_flac(&path, &[(6, &picture)]);
+        let art = cover_art(&path);
+        let _ = fs::remove_file(&path);
+        let art = art.unwrap();
+
+        assert_eq!(art.data, image);
+        assert_eq!(art.mime.as_deref(), Some("image/png"));
+        assert_eq!(art.hash, CoverArt::new(image, None).hash);
+    }
+
+    #[test]
+    fn duration_without_n_frames() {
+        let path = std::env::temp_dir().join(format!("musing_test_{}.aac", std::process::id()));
+        // 250 ADTS frames of varying sizes (as in VBR files), 1024 samples each,
+        // at 8 kHz that's 32 seconds
+        let mut adts = Vec::new();
+        for i in 0..250 {
+            let frame_len: u64 = 7 + 50 + (i * 37) % 200;
+            // LC profile, 8 kHz, mono, no CRC
+            let header: u64 = (1 << 38) | (11 << 34) | (1 << 30) | (frame_len << 13) | (0x7ff << 2);
+            adts.extend([0xff, 0xf1]);
+            adts.extend(&header.to_be_bytes()[3..]);
+            adts.extend(vec![0; frame_len as usize - 7]);
+        }
+        fs::write(&path, adts).unwrap();
+        let exact = Song::try_new(&path, true);
+        let estimated = Song::try_new(&path, false);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(exact.unwrap().duration, Some(32));
+        let estimated = estimated.unwrap().duration.unwrap();
+        assert!(estimated.abs_diff(32) <= 2);
+    }
+}
@@ -0,0 +1,151 @@
+use std::f64::consts::PI;
+
+use crate::model::device::BaseSample;
+
+// length of a frame and how far a frame can be shifted (in seconds)
+const FRAME_SECS: f64 = 0.04;
+const TOLERANCE_SECS: f64 = 0.005;
+
+// changes the tempo of audio without changing its pitch (WSOLA)
+// the input is cut into overlapping frames, which are `speed` times further
+// apart than in the output, and each frame is shifted a bit to line up
+// with the previous one (so that there are no audible phase jumps)
+pub struct Stretcher {
+    speed: f64,
+    n_channels: usize,
+    // half of a frame (in samples per channel)
+    hop: usize,
+    tolerance: usize,
+    window: Vec<BaseSample>,
+    // interleaved samples
+    input: Vec<BaseSample>,
+    // where the next frame would start if it wasn't shifted
+    next_pos: f64,
+    // where the previous frame would continue
+    // (the next frame should be as similar to it as possible)
+    continuation: Option<usize>,
+    // the second (windowed) half of the previous frame
+    tail: Vec<BaseSample>,
+    output: Vec<BaseSample>,
+}
+
+impl Stretcher {
+    pub fn new(speed: u16, sample_rate: u32, n_channels: usize) -> Self {
+        let hop = ((sample_rate as f64 * FRAME_SECS) as usize / 2).max(1);
+        let tolerance = (sample_rate as f64 * TOLERANCE_SECS) as usize;
+        // a periodic Hann window, its overlapping halves add up to 1
+        let window = (0..2 * hop)
+            .map(|i| 0.5 - 0.5 * (PI * i as f64 / hop as f64).cos())
+            .collect();
+
+        Self {
+            speed: speed as f64 / 100.0,
+            n_channels,
+            hop,
+            tolerance,
+            window,
+            input: Vec::new(),
+            next_pos: 0.0,
+            continuation: None,
+            tail: vec![0.0; hop * n_channels],
+            output: Vec::new(),
+        }
+    }
+
+    pub fn process(&mut self, samples: &[BaseSample]) -> &[BaseSample] {
+        let (n_channels, hop) = (self.n_channels, self.hop);
+        self.input.extend_from_slice(samples);
+        self.output.clear();
+        let n_frames = self.input.len() / n_channels;
+        loop {
+            let nominal = self.next_pos.round() as usize;
+            let (lo, hi) = (
+                nominal.saturating_sub(self.tolerance),
+                nominal + self.tolerance,
+            );
+            if hi + 2 * hop > n_frames {
+                break;
+            }
+            let start = match self.continuation {
+                Some(continuation) => self.best_start(continuation, lo, hi),
+                None => nominal,
+            };
+            for i in 0..hop {
+                for chan in 0..n_channels {
+                    let j = (start + i) * n_channels + chan;
+                    let tail = &mut self.tail[i * n_channels + chan];
+                    self.output.push(*tail + self.input[j] * self.window[i]);
+                    *tail = self.input[j + hop * n_channels] * self.window[i + hop];
+                }
+            }
+            self.continuation = Some(start + hop);
+            self.next_pos += hop as f64 * self.speed;
+        }
+
+        // drop the samples that won't be needed anymore
+        let consumed = (self.next_pos as usize)
+            .saturating_sub(self.tolerance)
+            .min(self.continuation.unwrap_or(n_frames))
+            .min(n_frames);
+        self.input.drain(..consumed * n_channels);
+        self.next_pos -= consumed as f64;
+        if let Some(continuation) = &mut self.continuation {
+            *continuation -= consumed;
+        }
+
+        &self.output
+    }
+
+    // the start (between `lo` and `hi`) of the frame most similar
+    // to the one starting at `target`
+    fn best_start(&self, target: usize, lo: usize, hi: usize) -> usize {
+        let len = self.hop * self.n_channels;
+        let target = &self.input[target * self.n_channels..][..len];
+        let similarity = |start: usize| -> BaseSample {
+            self.input[start * self.n_channels..][..len]
+                .iter()
+                .zip(target)
+                .map(|(a, b)| a * b)
+                .sum()
+        };
+
+        (lo..=hi)
+            .map(|start| (start, similarity(start)))
+            .max_by(|lhs, rhs| lhs.1.total_cmp(&rhs.1))
+            .map_or(lo, |(start, _)| start)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stretched_length() {
+        let (sample_rate, n_channels) = (8000, 2);
+        let n_frames = 4 * sample_rate as usize;
+        let samples: Vec<_> = (0..n_frames)
+            .flat_map(|i| {
+                let s = (2.0 * PI * 440.0 * i as f64 / sample_rate as f64).sin() / 2.0;
+                [s; 2]
+            })
+            .collect();
+
+        for speed in [50, 100, 150, 200] {
+            let mut stretcher = Stretcher::new(speed, sample_rate, n_channels);
+            let mut n_out = 0;
+            for chunk in samples.chunks(512 * n_channels) {
+                let output = stretcher.process(chunk);
+                assert_eq!(output.len() % n_channels, 0);
+                n_out += output.len() / n_channels;
+            }
+            let expected = n_frames as f64 * 100.0 / speed as f64;
+            // at most a frame (plus the search range and a hop) is left buffered
+            let max_buffered = (2 * stretcher.hop + 2 * stretcher.tolerance) as f64
+                / (speed as f64 / 100.0)
+                + stretcher.hop as f64;
+            assert!(n_out as f64 <= expected + 1.0);
+            assert!(n_out as f64 >= expected - max_buffered);
+        }
+    }
+}
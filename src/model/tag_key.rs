@@ -3,6 +3,7 @@ use lazy_static::lazy_static;
 use std::{
     collections::HashMap,
     fmt::{self, Display, Formatter},
+    str::FromStr,
 };
 use symphonia::core::meta::StandardTagKey;
 
@@ -13,7 +14,8 @@ macro_rules! enum_stringify {
     }};
 }
 
-static TAG_NAMES: [&str; 30] = [
+static YEAR_TAG_NAME: &str = "year";
+static TAG_NAMES: [&str; 34] = [
     "album",
     "albumartist",
     "arranger",
@@ -21,8 +23,10 @@ static TAG_NAMES: [&str; 30] = [
     "bpm",
     "composer",
     "conductor",
+    "contentgroup",
     "date",
     "discnumber",
+    "discsubtitle",
     "disctotal",
     "ensemble",
     "genre",
@@ -32,6 +36,7 @@ static TAG_NAMES: [&str; 30] = [
     "mood",
     "movementname",
     "movementnumber",
+    "opus",
     "part",
     "parttotal",
     "performer",
@@ -43,9 +48,10 @@ static TAG_NAMES: [&str; 30] = [
     "sortcomposer",
     "sorttracktitle",
     "tracknumber",
+    "tracksubtitle",
     "tracktitle",
 ];
-static TAG_KEYS: [StandardTagKey; 30] = [
+static TAG_KEYS: [StandardTagKey; 34] = [
     StandardTagKey::Album,
     StandardTagKey::AlbumArtist,
     StandardTagKey::Arranger,
@@ -53,8 +59,10 @@ static TAG_KEYS: [StandardTagKey; 30] = [
     StandardTagKey::Bpm,
     StandardTagKey::Composer,
     StandardTagKey::Conductor,
+    StandardTagKey::ContentGroup,
     StandardTagKey::Date,
     StandardTagKey::DiscNumber,
+    StandardTagKey::DiscSubtitle,
     StandardTagKey::DiscTotal,
     StandardTagKey::Ensemble,
     StandardTagKey::Genre,
@@ -64,6 +72,7 @@ static TAG_KEYS: [StandardTagKey; 30] = [
     StandardTagKey::Mood,
     StandardTagKey::MovementName,
     StandardTagKey::MovementNumber,
+    StandardTagKey::Opus,
     StandardTagKey::Part,
     StandardTagKey::PartTotal,
     StandardTagKey::Performer,
@@ -75,6 +84,7 @@ static TAG_KEYS: [StandardTagKey; 30] = [
     StandardTagKey::SortComposer,
     StandardTagKey::SortTrackTitle,
     StandardTagKey::TrackNumber,
+    StandardTagKey::TrackSubtitle,
     StandardTagKey::TrackTitle,
 ];
 
@@ -85,9 +95,16 @@ pub enum TagKeyKind {
     OutOf, // e.g. track 3 out of 12, written in metadata as "3/12"
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+    Standard(StandardTagKey),
+    // not present in files, derived from the leading digits of `date`
+    Year,
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct TagKey {
-    pub key: StandardTagKey,
+    pub key: Key,
     pub kind: TagKeyKind,
 }
 
@@ -103,7 +120,10 @@ lazy_static! {
 
 impl Display for TagKey {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", enum_stringify!(self.key).to_lowercase())
+        match self.key {
+            Key::Standard(key) => write!(f, "{}", enum_stringify!(key).to_lowercase()),
+            Key::Year => write!(f, "{}", YEAR_TAG_NAME),
+        }
     }
 }
 
@@ -113,6 +133,9 @@ impl TryFrom<&str> for TagKey {
     fn try_from(s: &str) -> Result<Self> {
         use StandardTagKey as STKey;
 
+        if s == YEAR_TAG_NAME {
+            return Ok(Self::year());
+        }
         let Some(key) = TAG_MAP.get(&s).cloned() else {
             bail!("invalid tag `{}`", s);
         };
@@ -122,7 +145,18 @@ impl TryFrom<&str> for TagKey {
             _ => TagKeyKind::String,
         };
 
-        Ok(Self { key, kind })
+        Ok(Self {
+            key: Key::Standard(key),
+            kind,
+        })
+    }
+}
+
+impl FromStr for TagKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::try_from(s)
     }
 }
 
@@ -134,9 +168,40 @@ impl TryFrom<StandardTagKey> for TagKey {
     }
 }
 
+impl TagKey {
+    pub fn year() -> Self {
+        Self {
+            key: Key::Year,
+            kind: TagKeyKind::Integer,
+        }
+    }
+}
+
 pub fn all_tags() -> Vec<TagKey> {
     TAG_MAP
         .values()
         .filter_map(|std_key| TagKey::try_from(*std_key).ok())
+        .chain([TagKey::year()])
         .collect()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_tag_keys() {
+        let bpm: TagKey = "bpm".parse().unwrap();
+        assert_eq!(bpm.key, Key::Standard(StandardTagKey::Bpm));
+        assert_eq!(bpm.kind, TagKeyKind::Integer);
+        let track: TagKey = "tracknumber".parse().unwrap();
+        assert_eq!(track.kind, TagKeyKind::OutOf);
+        assert_eq!(TagKey::try_from("year").unwrap(), TagKey::year());
+        assert_eq!(
+            TagKey::try_from(StandardTagKey::Album).unwrap().to_string(),
+            "album"
+        );
+        assert!("nosuchtag".parse::<TagKey>().is_err());
+        assert!(TagKey::try_from("Album").is_err());
+    }
+}
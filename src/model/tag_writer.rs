@@ -0,0 +1,295 @@
+use anyhow::{Result, anyhow, bail};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::model::tag_key::TagKey;
+
+// only FLAC is supported for now, its tags (Vorbis comments) live in a single
+// metadata block, which can be rewritten without touching the audio
+// (it's copied as it is, without being loaded into memory)
+//
+// https://www.rfc-editor.org/rfc/rfc9639.html#name-vorbis-comment
+const FLAC_MARKER: &[u8] = b"fLaC";
+const VORBIS_COMMENT: u8 = 4;
+const MAX_BLOCK_LEN: usize = (1 << 24) - 1;
+const VENDOR: &str = "musing";
+
+struct Block {
+    kind: u8,
+    data: Vec<u8>,
+}
+
+struct VorbisComment {
+    vendor: String,
+    comments: Vec<String>, // "KEY=value"
+}
+
+impl TryFrom<&[u8]> for VorbisComment {
+    type Error = anyhow::Error;
+
+    fn try_from(mut data: &[u8]) -> Result<Self> {
+        let vendor = writer_utils::next_string(&mut data)?;
+        let n_comments = writer_utils::next_u32(&mut data)?;
+        let comments = (0..n_comments)
+            .map(|_| writer_utils::next_string(&mut data))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { vendor, comments })
+    }
+}
+
+impl VorbisComment {
+    // replaces all values of the tag (in place of the first one),
+    // no values remove the tag
+    fn set(&mut self, key: &str, values: &[String]) {
+        let has_key = |comment: &String| {
+            comment
+                .split_once('=')
+                .is_some_and(|(comment_key, _)| comment_key.eq_ignore_ascii_case(key))
+        };
+        let pos = self
+            .comments
+            .iter()
+            .position(has_key)
+            .unwrap_or(self.comments.len());
+        self.comments.retain(|comment| !has_key(comment));
+        let new_comments = values
+            .iter()
+            .filter(|value| !value.is_empty())
+            .map(|value| format!("{}={}", key, value));
+        self.comments.splice(pos..pos, new_comments);
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let push_string = |data: &mut Vec<u8>, s: &str| {
+            data.extend((s.len() as u32).to_le_bytes());
+            data.extend(s.as_bytes());
+        };
+        push_string(&mut data, &self.vendor);
+        data.extend((self.comments.len() as u32).to_le_bytes());
+        for comment in self.comments.iter() {
+            push_string(&mut data, comment);
+        }
+
+        data
+    }
+}
+
+// names of tags are the same as in requests, but a few of them
+// are called differently in Vorbis comments
+fn vorbis_key(name: &str) -> Result<String> {
+    let name = name.to_lowercase();
+    if name == TagKey::year().to_string() {
+        bail!("`year` can't be written (it's derived from `date`)");
+    }
+    if name.is_empty()
+        || !name
+            .bytes()
+            .all(|b| (0x20..=0x7d).contains(&b) && b != b'=')
+    {
+        bail!("invalid tag name `{}`", name);
+    }
+    let key = match name.as_str() {
+        "contentgroup" => "grouping",
+        "movementnumber" => "movement",
+        "sortalbum" => "albumsort",
+        "sortalbumartist" => "albumartistsort",
+        "sortartist" => "artistsort",
+        "sortcomposer" => "composersort",
+        "sorttracktitle" => "titlesort",
+        "tracksubtitle" => "subtitle",
+        "tracktitle" => "title",
+        other => other,
+    };
+
+    Ok(key.to_uppercase())
+}
+
+// reads the metadata blocks, leaving the reader at the start of the audio
+fn read_blocks(reader: &mut impl Read) -> Result<Vec<Block>> {
+    let mut blocks = Vec::new();
+    loop {
+        let mut header = [0; 4];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| anyhow!("truncated FLAC metadata"))?;
+        let is_last = header[0] & 0x80 != 0;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut data = vec![0; len];
+        reader
+            .read_exact(&mut data)
+            .map_err(|_| anyhow!("truncated FLAC metadata"))?;
+        blocks.push(Block {
+            kind: header[0] & 0x7f,
+            data,
+        });
+        if is_last {
+            break;
+        }
+    }
+
+    Ok(blocks)
+}
+
+// `tags` are pairs of (tag name, values), no values remove the tag
+pub fn write_tags(path: &Path, tags: &[(String, Vec<String>)]) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut marker = [0; FLAC_MARKER.len()];
+    if reader.read_exact(&mut marker).is_err() || marker != FLAC_MARKER {
+        bail!(
+            "can't write tags to `{}` (only FLAC files are supported)",
+            path.to_string_lossy()
+        );
+    }
+    let mut blocks = read_blocks(&mut reader)?;
+    let i = match blocks.iter().position(|block| block.kind == VORBIS_COMMENT) {
+        Some(i) => i,
+        None => {
+            blocks.push(Block {
+                kind: VORBIS_COMMENT,
+                data: VorbisComment {
+                    vendor: VENDOR.into(),
+                    comments: Vec::new(),
+                }
+                .to_bytes(),
+            });
+            blocks.len() - 1
+        }
+    };
+    let mut vorbis_comment = VorbisComment::try_from(blocks[i].data.as_slice())?;
+    for (name, values) in tags {
+        vorbis_comment.set(&vorbis_key(name)?, values);
+    }
+    blocks[i].data = vorbis_comment.to_bytes();
+    if blocks[i].data.len() > MAX_BLOCK_LEN {
+        bail!("the tags are too long");
+    }
+
+    // write to a temporary file first, so that a failure can't leave a corrupted song behind
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow!("invalid path `{}`", path.to_string_lossy()))?;
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    let res = writer_utils::write_flac(&temp_path, &blocks, &mut reader)
+        .and_then(|_| fs::set_permissions(&temp_path, fs::metadata(path)?.permissions()))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    Ok(res?)
+}
+
+mod writer_utils {
+    use super::*;
+
+    // the metadata blocks followed by the rest of `audio`
+    pub fn write_flac(path: &Path, blocks: &[Block], audio: &mut impl Read) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(FLAC_MARKER)?;
+        for (i, block) in blocks.iter().enumerate() {
+            let is_last = i == blocks.len() - 1;
+            writer.write_all(&[block.kind | if is_last { 0x80 } else { 0 }])?;
+            writer.write_all(&(block.data.len() as u32).to_be_bytes()[1..])?;
+            writer.write_all(&block.data)?;
+        }
+        io::copy(audio, &mut writer)?;
+
+        writer.flush()
+    }
+
+    // Vorbis comments use little-endian lengths, unlike FLAC itself
+    pub fn next_u32(data: &mut &[u8]) -> Result<u32> {
+        let (n, rest) = data
+            .split_first_chunk::<4>()
+            .ok_or(anyhow!("truncated Vorbis comment"))?;
+        *data = rest;
+
+        Ok(u32::from_le_bytes(*n))
+    }
+
+    pub fn next_string(data: &mut &[u8]) -> Result<String> {
+        let len = next_u32(data)? as usize;
+        if data.len() < len {
+            bail!("truncated Vorbis comment");
+        }
+        let (s, rest) = data.split_at(len);
+        *data = rest;
+
+        Ok(String::from_utf8_lossy(s).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::song::{self, Song};
+
+    #[test]
+    fn write_and_read_back() {
+        let path =
+            std::env::temp_dir().join(format!("musing_test_tags_{}.flac", std::process::id()));
+        song::write_minimal_flac(&path, &[]);
+        let title = TagKey::try_from("tracktitle").unwrap();
+        let artist = TagKey::try_from("artist").unwrap();
+
+        let tags = [
+            ("tracktitle".into(), vec!["Bohemian Rhapsody".into()]),
+            ("artist".into(), vec!["Queen".into()]),
+        ];
+        write_tags(&path, &tags).unwrap();
+        let song = Song::try_new(&path, false);
+        write_tags(&path, &[("artist".into(), Vec::new())]).unwrap();
+        let song_without_artist = Song::try_new(&path, false);
+        let res = write_tags(&path, &[("year".into(), vec!["1975".into()])]);
+        let _ = fs::remove_file(&path);
+
+        let song = song.unwrap();
+        assert_eq!(song.metadata.get(&title), Some("Bohemian Rhapsody"));
+        assert_eq!(song.metadata.get(&artist), Some("Queen"));
+        let song = song_without_artist.unwrap();
+        assert_eq!(song.metadata.get(&title), Some("Bohemian Rhapsody"));
+        assert_eq!(song.metadata.get(&artist), None);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn only_flac() {
+        let path =
+            std::env::temp_dir().join(format!("musing_test_tags_{}.wav", std::process::id()));
+        song::write_silent_wav(&path, 8000, 1, 100);
+        let res = write_tags(&path, &[("artist".into(), vec!["Queen".into()])]);
+        let _ = fs::remove_file(&path);
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn repeated_tags() {
+        let path = std::env::temp_dir().join(format!(
+            "musing_test_repeated_tags_{}.flac",
+            std::process::id()
+        ));
+        song::write_minimal_flac(&path, &[]);
+        let audio_before = fs::read(&path).unwrap().split_off(42);
+        let artist = TagKey::try_from("artist").unwrap();
+        let title = TagKey::try_from("tracktitle").unwrap();
+
+        let artists = vec!["Simon".to_string(), "Garfunkel".to_string()];
+        write_tags(&path, &[("artist".into(), artists.clone())]).unwrap();
+        // other tags are left alone, including the ones with many values
+        write_tags(&path, &[("tracktitle".into(), vec!["The Boxer".into()])]).unwrap();
+        let song = Song::try_new(&path, false);
+        let bytes = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let song = song.unwrap();
+        assert_eq!(song.metadata.get_all(&artist), artists.as_slice());
+        assert_eq!(song.metadata.get(&title), Some("The Boxer"));
+        assert!(bytes.ends_with(&audio_before));
+    }
+}
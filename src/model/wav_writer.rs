@@ -0,0 +1,142 @@
+use anyhow::{Result, bail};
+use cpal::{I24, Sample, SampleFormat};
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::model::device::{BaseSample, SampleSink};
+
+// http://soundfile.sapp.org/doc/WaveFormat/
+pub const PCM: u16 = 1;
+const IEEE_FLOAT: u16 = 3;
+// the lengths in the header are 32-bit, and the RIFF one covers the rest of the header too
+const MAX_DATA_LEN: u32 = u32::MAX - 36;
+
+// the 44-byte header of a WAV file with `data_len` bytes of samples
+pub fn header(
+    sample_rate: u32,
+    channels: u16,
+    format_tag: u16,
+    bits: u16,
+    data_len: u32,
+) -> Vec<u8> {
+    let block_align = channels * bits / 8;
+    let mut header = Vec::with_capacity(44);
+    header.extend(b"RIFF");
+    header.extend(data_len.saturating_add(36).to_le_bytes());
+    header.extend(b"WAVEfmt ");
+    header.extend(16_u32.to_le_bytes());
+    header.extend(format_tag.to_le_bytes());
+    header.extend(channels.to_le_bytes());
+    header.extend(sample_rate.to_le_bytes());
+    header.extend((sample_rate * block_align as u32).to_le_bytes());
+    header.extend(block_align.to_le_bytes());
+    header.extend(bits.to_le_bytes());
+    header.extend(b"data");
+    header.extend(data_len.to_le_bytes());
+
+    header
+}
+
+// a WAV file that grows as samples are written to it, the lengths in its header
+// are updated on every `flush`, so that it can be read while it's still being written
+pub struct WavWriter {
+    file: BufWriter<File>,
+    sample_format: SampleFormat,
+    data_len: u32,
+}
+
+impl WavWriter {
+    pub fn try_new(
+        path: impl AsRef<Path>,
+        sample_rate: u32,
+        channels: u16,
+        sample_format: SampleFormat,
+    ) -> Result<Self> {
+        let (format_tag, bits): (u16, u16) = match sample_format {
+            SampleFormat::I16 => (PCM, 16),
+            SampleFormat::I24 => (PCM, 24),
+            SampleFormat::I32 => (PCM, 32),
+            SampleFormat::F32 => (IEEE_FLOAT, 32),
+            x => bail!(
+                "unsupported sample format `{}` (WAV files can be i16, i24, i32 or f32)",
+                x
+            ),
+        };
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&header(sample_rate, channels, format_tag, bits, 0))?;
+
+        Ok(Self {
+            file,
+            sample_format,
+            data_len: 0,
+        })
+    }
+}
+
+impl SampleSink for WavWriter {
+    fn write(&mut self, sample: BaseSample) -> Result<()> {
+        let (bytes, len) = match self.sample_format {
+            SampleFormat::I16 => {
+                let [a, b] = i16::from_sample(sample).to_le_bytes();
+                ([a, b, 0, 0], 2)
+            }
+            SampleFormat::I24 => (I24::from_sample(sample).inner().to_le_bytes(), 3),
+            SampleFormat::I32 => (i32::from_sample(sample).to_le_bytes(), 4),
+            _ => (f32::from_sample(sample).to_le_bytes(), 4),
+        };
+        // the format can't describe more than 4 GiB of data, so the recording ends there
+        // (the error makes the device stop, as if it got unplugged)
+        let Some(data_len) = self
+            .data_len
+            .checked_add(len)
+            .filter(|&data_len| data_len <= MAX_DATA_LEN)
+        else {
+            self.flush()?;
+            bail!("the recording reached the size limit of WAV files (4 GiB)");
+        };
+        self.file.write_all(&bytes[..len as usize])?;
+        self.data_len = data_len;
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(self.data_len + 36).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn size_limit() {
+        let path =
+            std::env::temp_dir().join(format!("musing_test_wav_limit_{}.wav", std::process::id()));
+        let mut writer = WavWriter::try_new(&path, 8000, 1, SampleFormat::I24).unwrap();
+        writer.write(0.5).unwrap();
+        writer.flush().unwrap();
+        let written = std::fs::read(&path).unwrap();
+        // pretend that the file is almost full
+        writer.data_len = MAX_DATA_LEN - 4;
+        let last = writer.write(0.5);
+        let over = writer.write(0.5);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(written.len(), 44 + 3);
+        assert_eq!(&written[40..44], &3_u32.to_le_bytes());
+        assert!(last.is_ok());
+        assert!(over.is_err());
+        assert_eq!(writer.data_len, MAX_DATA_LEN - 1);
+    }
+}
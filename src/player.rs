@@ -1,5 +1,5 @@
-use anyhow::Result;
-use std::path::PathBuf;
+use anyhow::{Result, anyhow};
+use std::{collections::HashSet, path::PathBuf, time::Instant};
 use tokio::{
     sync::{
         broadcast,
@@ -13,25 +13,108 @@ use crate::{
     audio::Audio,
     config::PlayerConfig,
     database::Database,
+    logger,
     model::{
+        bookmark::Bookmarks,
         decoder::{Speed, Volume},
-        queue::Queue,
-        request::{self, Request, RequestKind},
-        response::{JsonObject, Response},
-        song::{self, SongEvent},
+        device::Device,
+        play_count,
+        queue::{Queue, QueueEndAction},
+        request::{self, Request, RequestKind, StateArgs},
+        response::{ErrorCode, JsonObject, Response},
+        song::SongEvent,
     },
-    state::{AudioState, PlayerState, State},
+    state::{AudioState, PlayerState, State, VersionMismatch},
+    watcher::Watcher,
 };
 
 struct Player {
     audio: Audio,
+    bookmarks: Bookmarks,
     database: Database,
     queue: Queue,
+    queue_end_action: QueueEndAction,
+    // a one-shot flag, cleared once the current song ends
+    stop_after_current: bool,
+    pause_on_device_loss: bool,
+    // why the playback got paused, if it wasn't a client's request
+    pause_reason: Option<String>,
     rx_event: tokio_chan::UnboundedReceiver<SongEvent>,
     rx_request: tokio_chan::UnboundedReceiver<Request>,
+    started_at: Instant,
+    // notifies the idling clients that the state might have changed
+    tx_notify: broadcast::Sender<()>,
+    watcher: Option<Watcher>,
 }
 
 impl Player {
+    fn bookmark_request(&mut self, req: request::BookmarkRequestKind) -> Response {
+        use request::{
+            AddBookmarkArgs, BookmarkRequestKind, DeleteBookmarkArgs, ListBookmarksArgs,
+            SeekBookmarkArgs,
+        };
+
+        let song_not_found = |path: &PathBuf| {
+            Response::new_err(
+                ErrorCode::NotFound,
+                format!(
+                    "song `{}` not found in the database",
+                    path.to_string_lossy()
+                ),
+            )
+        };
+        match req {
+            BookmarkRequestKind::Add(args) => {
+                let AddBookmarkArgs(path, label, seconds) = args;
+                match self.database.try_to_abs_path(&path) {
+                    Some(abs_path) => {
+                        self.bookmarks.add(abs_path, label, seconds);
+                        Response::new_ok()
+                    }
+                    None => song_not_found(&path),
+                }
+            }
+            BookmarkRequestKind::Delete(args) => {
+                let DeleteBookmarkArgs(path, label) = args;
+                match self.database.try_to_abs_path(&path) {
+                    Some(abs_path) if self.bookmarks.remove(&abs_path, &label) => {
+                        Response::new_ok()
+                    }
+                    Some(_) => Response::new_err(
+                        ErrorCode::NotFound,
+                        format!("bookmark `{}` not found", label),
+                    ),
+                    None => song_not_found(&path),
+                }
+            }
+            BookmarkRequestKind::List(args) => {
+                let ListBookmarksArgs(path) = args;
+                match self.database.try_to_abs_path(&path) {
+                    Some(abs_path) => {
+                        Response::new_ok().with_item("bookmarks", &self.bookmarks.list(abs_path))
+                    }
+                    None => song_not_found(&path),
+                }
+            }
+            BookmarkRequestKind::Seek(args) => {
+                let SeekBookmarkArgs(label) = args;
+                let Some(current) = self.queue.current() else {
+                    return Response::new_err(ErrorCode::Audio, "playback is stopped");
+                };
+                match self.bookmarks.find(&current.path, &label) {
+                    Some(bookmark) => {
+                        self.audio.seek_to(bookmark.seconds);
+                        Response::new_ok()
+                    }
+                    None => Response::new_err(
+                        ErrorCode::NotFound,
+                        format!("bookmark `{}` not found", label),
+                    ),
+                }
+            }
+        }
+    }
+
     // database requests are blocking and (mostly) parallelizable,
     // so we send them to rayon's thread pool
     async fn db_request(&mut self, req: request::DbRequestKind) -> Response {
@@ -41,10 +124,18 @@ impl Player {
                 use request::DbRequestKind;
 
                 let response = match req {
+                    DbRequestKind::CoverArt(args) => self.database.cover_art(args),
+                    DbRequestKind::Count(args) => self.database.count(args),
+                    DbRequestKind::DiskSize(args) => self.database.disk_size(args),
                     DbRequestKind::Ls(args) => self.database.ls(args),
                     DbRequestKind::Metadata(args) => self.database.metadata(args),
+                    DbRequestKind::Sample(args) => self.database.sample(args),
+                    DbRequestKind::Search(args) => self.database.search(args),
                     DbRequestKind::Select(args) => self.database.select(args),
+                    DbRequestKind::Stats => self.database.stats(),
+                    DbRequestKind::Unique(args) => self.database.unique(args),
                     DbRequestKind::Update => self.database.update(),
+                    DbRequestKind::WriteTags(args) => self.database.write_tags(args),
                 };
                 let _ = tx.send(response);
             });
@@ -54,129 +145,213 @@ impl Player {
     }
 
     fn device_request(&mut self, req: request::DeviceRequestKind) -> Response {
-        use request::{DeviceRequestKind, DisableArgs, EnableArgs};
+        use request::{DeviceRequestKind, DeviceVolumeArgs, DisableArgs, EnableArgs};
 
         match req {
             DeviceRequestKind::Disable(args) => {
                 let DisableArgs(device) = args;
-                self.audio.disable_device(device).into()
+                Response::from_result(self.audio.disable_device(device), ErrorCode::Audio)
             }
             DeviceRequestKind::Enable(args) => {
                 let EnableArgs(device) = args;
-                self.audio.enable_device(&device).into()
+                Response::from_result(self.audio.enable_device(&device), ErrorCode::Audio)
+            }
+            DeviceRequestKind::Volume(args) => {
+                let DeviceVolumeArgs(device, volume) = args;
+                Response::from_result(
+                    self.audio.set_device_volume(&device, volume),
+                    ErrorCode::Audio,
+                )
             }
         }
     }
 
     async fn playback_request(&mut self, req: request::PlaybackRequestKind) -> Response {
-        use request::{PlaybackRequestKind, SeekArgs, SpeedArgs, VolumeArgs};
+        use request::{
+            CrossfadeArgs, EqArgs, PlaybackRequestKind, SeekArgs, SeekChapterArgs, SeekToArgs,
+            SpeedArgs, VolumeArgs,
+        };
 
         match req {
             PlaybackRequestKind::Volume(args) => {
                 let VolumeArgs(volume) = args;
                 self.audio.change_volume(volume);
 
+                Response::new_ok().with_item("volume", &self.audio.volume())
+            }
+            PlaybackRequestKind::Crossfade(args) => {
+                let CrossfadeArgs(secs) = args;
+                self.audio.set_crossfade(secs);
+
+                Response::new_ok()
+            }
+            PlaybackRequestKind::Eq(args) => {
+                let EqArgs(bands) = args;
+                self.audio.set_eq(bands);
+
+                Response::new_ok()
+            }
+            PlaybackRequestKind::PreservePitch => {
+                self.audio.toggle_preserve_pitch();
+
                 Response::new_ok()
             }
             PlaybackRequestKind::Gapless => {
                 self.audio.toggle_gapless();
                 Response::new_ok()
             }
-            PlaybackRequestKind::Pause => self.audio.pause().await.into(),
-            PlaybackRequestKind::Resume => self.audio.resume().into(),
+            PlaybackRequestKind::Mute => {
+                self.audio.toggle_mute();
+                Response::new_ok()
+            }
+            PlaybackRequestKind::Pause => {
+                Response::from_result(self.audio.pause().await, ErrorCode::Audio)
+            }
+            PlaybackRequestKind::Resume => {
+                Response::from_result(self.audio.resume(), ErrorCode::Audio)
+            }
             PlaybackRequestKind::Seek(args) => {
                 let SeekArgs(secs) = args;
                 self.audio.seek(secs);
 
                 Response::new_ok()
             }
+            PlaybackRequestKind::SeekChapter(args) => {
+                let SeekChapterArgs(delta) = args;
+                Response::from_result(self.audio.seek_chapter(delta).await, ErrorCode::Audio)
+            }
+            PlaybackRequestKind::SeekTo(args) => {
+                let SeekToArgs(secs) = args;
+                if secs < 0 {
+                    self.audio.seek_from_end(secs.unsigned_abs());
+                } else {
+                    self.audio.seek_to(secs.unsigned_abs());
+                }
+
+                Response::new_ok()
+            }
             PlaybackRequestKind::Speed(args) => {
                 let SpeedArgs(delta) = args;
                 self.audio.change_speed(delta);
 
-                Response::new_ok()
+                Response::new_ok().with_item("speed", &self.audio.speed())
             }
             PlaybackRequestKind::Stop => {
+                self.stop_after_current = false;
                 self.queue.reset_pos();
                 self.audio.stop();
 
                 Response::new_ok()
             }
-            PlaybackRequestKind::Toggle => self.audio.toggle().await.into(),
+            PlaybackRequestKind::StopAfterCurrent => {
+                self.stop_after_current ^= true;
+                Response::new_ok()
+            }
+            PlaybackRequestKind::Toggle => {
+                Response::from_result(self.audio.toggle().await, ErrorCode::Audio)
+            }
         }
     }
 
     fn playlist_request(&mut self, req: request::PlaylistRequestKind) -> Response {
         use request::{
-            AddToPlaylistArgs, ListSongsArgs, LoadArgs, PlaylistRequestKind,
-            RemoveFromPlaylistArgs, SaveArgs,
+            AddCurrentToPlaylistArgs, AddToPlaylistArgs, DeleteArgs, ListSongsArgs, LoadArgs,
+            MovePlaylistArgs, PlaylistRequestKind, RemoveFromPlaylistArgs, RenameArgs, SaveArgs,
         };
 
         match req {
+            PlaylistRequestKind::AddCurrentToPlaylist(args) => {
+                let AddCurrentToPlaylistArgs(playlist_path) = args;
+                match self.queue.current() {
+                    Some(current) => self
+                        .database
+                        .add_to_playlist(playlist_path, current.path.clone()),
+                    None => Response::new_err(ErrorCode::Audio, "playback is stopped"),
+                }
+            }
             PlaylistRequestKind::AddToPlaylist(args) => {
                 let AddToPlaylistArgs(playlist_path, song_path) = args;
                 self.database.add_to_playlist(playlist_path, song_path)
             }
             PlaylistRequestKind::ListSongs(args) => {
-                let ListSongsArgs(path) = args;
-                match self.database.load_playlist(&path) {
-                    Ok(playlist) => Response::new_ok().with_item("songs", &playlist),
-                    Err(e) => Response::new_err(e.to_string()),
+                let ListSongsArgs(path, recursive) = args;
+                match self.database.load_playlist_with_info(&path, recursive) {
+                    Ok(playlist) => {
+                        let (songs, info): (Vec<_>, Vec<_>) = playlist.into_iter().unzip();
+                        Response::new_ok()
+                            .with_item("songs", &songs)
+                            .with_item("info", &info)
+                    }
+                    Err(e) => Response::new_err(ErrorCode::File, e.to_string()),
                 }
             }
             PlaylistRequestKind::Load(args) => {
-                let LoadArgs(path, range, pos) = args;
-                match self.database.load_playlist(&path) {
+                let LoadArgs(path, range, pos, recursive) = args;
+                match self.database.load_playlist(&path, recursive) {
                     Ok(playlist) => {
-                        let not_found =
-                            add_to_queue(&self.database, &mut self.queue, &playlist, range, pos);
+                        let songs = match playlist_range(&playlist, range) {
+                            Ok(songs) => songs,
+                            Err(e) => {
+                                return Response::new_err(ErrorCode::InvalidArg, e.to_string());
+                            }
+                        };
+                        let not_found = add_to_queue(&self.database, &mut self.queue, songs, pos);
                         if not_found.is_empty() {
                             Response::new_ok()
                         } else {
-                            Response::new_err(format!(
-                                "song(s) `{}` not found in the database",
-                                not_found
-                                    .into_iter()
-                                    .map(|p| p.to_string_lossy().into_owned())
-                                    .collect::<Vec<_>>()
-                                    .join(",")
-                            ))
+                            Response::new_err(
+                                ErrorCode::NotFound,
+                                format!(
+                                    "song(s) `{}` not found in the database",
+                                    not_found
+                                        .into_iter()
+                                        .map(|p| p.to_string_lossy().into_owned())
+                                        .collect::<Vec<_>>()
+                                        .join(",")
+                                ),
+                            )
                         }
                     }
-                    Err(e) => Response::new_err(e.to_string()),
+                    Err(e) => Response::new_err(ErrorCode::File, e.to_string()),
                 }
             }
+            PlaylistRequestKind::MoveInPlaylist(args) => {
+                let MovePlaylistArgs(path, from, to) = args;
+                self.database.move_in_playlist(path, from, to)
+            }
             PlaylistRequestKind::RemoveFromPlaylist(args) => {
                 let RemoveFromPlaylistArgs(path, pos) = args;
                 self.database.remove_from_playlist(path, pos)
             }
             PlaylistRequestKind::Save(args) => {
-                let SaveArgs(path) = args;
-                self.database.save_as_playlist(path, self.queue.inner())
+                let SaveArgs(path, extended) = args;
+                self.database
+                    .save_as_playlist(path, self.queue.inner(), extended)
+            }
+            PlaylistRequestKind::Rename(args) => {
+                let RenameArgs(path, new_path) = args;
+                self.database.rename_playlist(path, new_path)
+            }
+            PlaylistRequestKind::Delete(args) => {
+                let DeleteArgs(path) = args;
+                self.database.delete_playlist(path)
             }
         }
     }
 
     fn queue_request(&mut self, req: request::QueueRequestKind) -> Response {
-        use request::{AddToQueueArgs, PlayArgs, QueueRequestKind, RemoveFromQueueArgs};
+        use request::{
+            AddToQueueArgs, HistoryArgs, MoveArgs, PlayAlbumArgs, PlayArgs, PlayNextArgs,
+            PriorityArgs, QueueEndActionArgs, QueueRequestKind, RemoveFromQueueArgs, ReplaceArgs,
+            UpNextArgs,
+        };
 
         match req {
             QueueRequestKind::AddToQueue(args) => {
                 let AddToQueueArgs(paths, pos) = args;
-                let not_found = add_to_queue(&self.database, &mut self.queue, &paths, None, pos);
+                let not_found = add_to_queue(&self.database, &mut self.queue, &paths, pos);
 
-                if not_found.is_empty() {
-                    Response::new_ok()
-                } else {
-                    Response::new_err(format!(
-                        "file(s) `{}` not found in the database",
-                        not_found
-                            .into_iter()
-                            .map(|p| p.to_string_lossy().into_owned())
-                            .collect::<Vec<_>>()
-                            .join(",")
-                    ))
-                }
+                files_not_found(not_found)
             }
             QueueRequestKind::Clear => {
                 self.queue.clear();
@@ -184,8 +359,32 @@ impl Player {
 
                 Response::new_ok()
             }
+            QueueRequestKind::Dedup => {
+                let removed = self.queue.dedup();
+
+                Response::new_ok().with_item("removed", &removed)
+            }
+            QueueRequestKind::Crop => {
+                self.queue.crop();
+                if self.queue.current().is_none() {
+                    self.audio.stop();
+                }
+
+                Response::new_ok()
+            }
+            QueueRequestKind::Move(args) => {
+                let MoveArgs(queue_id, to) = args;
+                if self.queue.move_entry(queue_id, to) {
+                    Response::new_ok()
+                } else {
+                    Response::new_err(
+                        ErrorCode::NotFound,
+                        format!("song with queue id `{}` not found", queue_id),
+                    )
+                }
+            }
             QueueRequestKind::Next => {
-                move_next_until_playable(&mut self.queue, &mut self.audio);
+                move_next_until_playable(&mut self.queue, &mut self.audio, &self.database);
                 if self.queue.current().is_none() {
                     self.audio.stop();
                 }
@@ -196,28 +395,123 @@ impl Player {
                 let PlayArgs(id) = args;
                 match self.queue.move_to(id) {
                     Some(entry) => {
-                        let res = self.audio.play(&entry.path);
+                        let res = self
+                            .audio
+                            .play(&entry.path, self.database.duration(&entry.path));
                         if res.is_err() {
                             self.queue.reset_pos();
                             self.audio.stop();
                         }
-                        res.into()
+                        Response::from_result(res, ErrorCode::Audio)
                     }
-                    None => Response::new_err(format!("song with queue id `{}` not found", id)),
+                    None => Response::new_err(
+                        ErrorCode::NotFound,
+                        format!("song with queue id `{}` not found", id),
+                    ),
+                }
+            }
+            QueueRequestKind::PlayAlbum(args) => {
+                let PlayAlbumArgs(filter_expr) = args;
+                let paths = self.database.album_paths(&filter_expr);
+                if paths.is_empty() {
+                    return Response::new_err(ErrorCode::NotFound, "no songs match the filters");
+                }
+                self.audio.stop();
+                replace_queue(&self.database, &mut self.queue, &paths, 0);
+                if let Some(entry) = self.queue.current()
+                    && let Err(e) = self
+                        .audio
+                        .play(&entry.path, self.database.duration(&entry.path))
+                {
+                    self.queue.reset_pos();
+                    self.audio.stop();
+                    return Response::new_err(ErrorCode::Audio, e.to_string());
                 }
+
+                Response::new_ok()
+            }
+            QueueRequestKind::Replace(args) => {
+                let ReplaceArgs(paths, start) = args;
+                self.audio.stop();
+                let not_found = replace_queue(
+                    &self.database,
+                    &mut self.queue,
+                    &paths,
+                    start.unwrap_or_default(),
+                );
+                if let Some(entry) = self.queue.current()
+                    && let Err(e) = self
+                        .audio
+                        .play(&entry.path, self.database.duration(&entry.path))
+                {
+                    self.queue.reset_pos();
+                    self.audio.stop();
+                    return Response::new_err(ErrorCode::Audio, e.to_string());
+                }
+
+                files_not_found(not_found)
+            }
+            QueueRequestKind::PlayNext(args) => {
+                let PlayNextArgs(paths) = args;
+                let mut not_found = Vec::new();
+                let abs_paths = paths
+                    .iter()
+                    .filter_map(|path| {
+                        let abs_path = self.database.try_to_abs_path(path);
+                        if abs_path.is_none() {
+                            not_found.push(path);
+                        }
+
+                        abs_path
+                    })
+                    .collect();
+                self.queue.add_after_current(abs_paths);
+
+                files_not_found(not_found)
             }
             QueueRequestKind::Previous => {
-                move_prev_until_playable(&mut self.queue, &mut self.audio);
+                move_prev_until_playable(&mut self.queue, &mut self.audio, &self.database);
                 if self.queue.current().is_none() {
                     self.audio.stop();
                 }
 
                 Response::new_ok()
             }
+            QueueRequestKind::Priority(args) => {
+                let PriorityArgs(queue_ids, priority) = args;
+                let not_found: Vec<_> = queue_ids
+                    .into_iter()
+                    .filter(|&queue_id| !self.queue.set_priority(queue_id, priority))
+                    .map(|queue_id| queue_id.to_string())
+                    .collect();
+
+                if not_found.is_empty() {
+                    Response::new_ok()
+                } else {
+                    Response::new_err(
+                        ErrorCode::NotFound,
+                        format!(
+                            "song(s) with queue id(s) `{}` not found",
+                            not_found.join(",")
+                        ),
+                    )
+                }
+            }
+            QueueRequestKind::QueueEndAction(args) => {
+                let QueueEndActionArgs(action) = args;
+                self.queue_end_action = action;
+
+                Response::new_ok()
+            }
             QueueRequestKind::Random => {
                 self.queue.start_random();
                 Response::new_ok()
             }
+            QueueRequestKind::WeightedRandom => {
+                self.queue
+                    .start_weighted_random(self.database.play_counts());
+                Response::new_ok()
+            }
             QueueRequestKind::RemoveFromQueue(args) => {
                 let RemoveFromQueueArgs(queue_ids) = args;
                 for queue_id in queue_ids {
@@ -229,142 +523,519 @@ impl Player {
 
                 Response::new_ok()
             }
+            QueueRequestKind::RepeatAll => {
+                self.queue.start_repeat_all();
+                Response::new_ok()
+            }
+            QueueRequestKind::RepeatOne => {
+                self.queue.start_repeat_one();
+                Response::new_ok()
+            }
             QueueRequestKind::Sequential => {
                 self.queue.start_sequential();
                 Response::new_ok()
             }
+            QueueRequestKind::Shuffle => {
+                self.queue.shuffle();
+                Response::new_ok()
+            }
             QueueRequestKind::Single => {
                 self.queue.start_single();
                 Response::new_ok()
             }
+            QueueRequestKind::History(args) => {
+                let HistoryArgs(count) = args;
+                let songs: Vec<_> = self
+                    .queue
+                    .played(count.unwrap_or(usize::MAX))
+                    .map(|entry| {
+                        let mut object = JsonObject::new();
+                        object.insert("id".into(), entry.id.into());
+                        object.insert("path".into(), self.database.output_path(&entry.path).into());
+
+                        object
+                    })
+                    .collect();
+
+                Response::new_ok().with_item("songs", &songs)
+            }
+            QueueRequestKind::UpNext(args) => {
+                let UpNextArgs(count, tags) = args;
+                let upcoming = self.queue.upcoming(count);
+                let paths = upcoming.iter().map(|entry| entry.path.clone()).collect();
+                let metadata = self.database.metadata_values(paths, &tags, &[]);
+                let songs: Vec<_> = upcoming
+                    .into_iter()
+                    .zip(metadata)
+                    .map(|(entry, metadata)| {
+                        let mut object = JsonObject::new();
+                        object.insert("id".into(), entry.id.into());
+                        object.insert("path".into(), self.database.output_path(&entry.path).into());
+                        object.insert("metadata".into(), metadata.into());
+
+                        object
+                    })
+                    .collect();
+
+                Response::new_ok().with_item("songs", &songs)
+            }
         }
     }
 
-    async fn state_request(&self) -> Response {
-        let playlists = self.database.playlists();
-        let queue: Vec<_> = self
-            .queue
-            .inner()
-            .iter()
-            .map(|entry| {
-                let mut object = JsonObject::new();
-                object.insert("id".into(), entry.id.into());
-                object.insert("path".into(), entry.path.to_string_lossy().into());
+    // with `only`, the expensive parts of the state are computed only if requested
+    async fn state_request(&mut self, only: Option<&HashSet<String>>) -> Response {
+        let wants = |key: &str| only.is_none_or(|only| only.contains(key));
+        let mut response = Response::new_ok();
+        if wants("cover_art_hash") {
+            // clients fetch the art itself with `coverart` (only when its hash changes)
+            let cover_art_hash = self
+                .queue
+                .current()
+                .and_then(|cur| self.database.cover_art_hash(&cur.path));
+            response = response.with_item("cover_art_hash", &cover_art_hash);
+        }
+        if wants("playlists") {
+            response = response.with_item("playlists", &self.database.playlists());
+        }
+        if wants("queue") {
+            let queue: Vec<_> = self
+                .queue
+                .inner()
+                .iter()
+                .map(|entry| {
+                    let mut object = JsonObject::new();
+                    object.insert("id".into(), entry.id.into());
+                    object.insert("path".into(), self.database.output_path(&entry.path).into());
+                    object.insert("priority".into(), entry.priority.into());
 
-                object
-            })
-            .collect();
-        let devices: Vec<_> = self
-            .audio
-            .list_devices()
-            .into_iter()
-            .map(|(d, enabled)| {
-                let mut object = JsonObject::new();
-                object.insert("device".into(), d.into());
-                object.insert("enabled".into(), enabled.into());
+                    object
+                })
+                .collect();
+            response = response.with_item("queue", &queue);
+        }
+        if wants("devices") {
+            let devices: Vec<_> = self
+                .audio
+                .list_devices()
+                .into_iter()
+                .map(|(d, enabled, volume)| {
+                    let mut object = JsonObject::new();
+                    object.insert("device".into(), d.into());
+                    object.insert("enabled".into(), enabled.into());
+                    object.insert("volume".into(), volume.into());
 
-                object
-            })
-            .collect();
-        let (elapsed, duration) = match self.audio.playback_timer().await {
-            Some(t) => (t.elapsed, t.duration),
-            None => (0, 0),
+                    object
+                })
+                .collect();
+            response = response.with_item("devices", &devices);
+        }
+        if wants("available_devices") {
+            response = response.with_item("available_devices", &self.audio.list_system_devices());
+        }
+        let timer = if wants("timer") || wants("queue_remaining") {
+            self.timer().await
+        } else {
+            JsonObject::new()
         };
-        let mut timer = JsonObject::new();
-        timer.insert("elapsed".into(), elapsed.into());
-        timer.insert("duration".into(), duration.into());
-
-        Response::new_ok()
-            .with_item("devices", &devices)
+        if wants("queue_duration") || wants("queue_remaining") {
+            let durations: Vec<_> = self
+                .queue
+                .inner()
+                .iter()
+                .map(|entry| self.database.duration(&entry.path))
+                .collect();
+            let elapsed = timer
+                .get("elapsed")
+                .and_then(|elapsed| elapsed.as_u64())
+                .unwrap_or_default();
+            let current = self
+                .queue
+                .current()
+                .and_then(|cur| self.queue.find_by_id(cur.id));
+            let (queue_duration, queue_remaining) = queue_durations(&durations, current, elapsed);
+            response = response
+                .with_item("queue_duration", &queue_duration)
+                .with_item("queue_remaining", &queue_remaining);
+        }
+        let mut response = response
+            .with_item("timer", &timer)
             .with_item("gapless", &self.audio.gapless())
+            .with_item("preserve_pitch", &self.audio.preserve_pitch())
+            .with_item("crossfade", &self.audio.crossfade())
+            .with_item("eq", &self.audio.eq())
+            .with_item("music_dir", &self.database.music_dirs()[0])
+            .with_item("music_dirs", &self.database.music_dirs())
             .with_item("playback_mode", &self.queue.mode())
-            .with_item("playlists", &playlists)
-            .with_item("queue", &queue)
+            .with_item("queue_end_action", &self.queue_end_action.to_string())
+            .with_item("stop_after_current", &self.stop_after_current)
+            .with_item("has_next", &self.queue.has_next())
+            .with_item("has_previous", &self.queue.has_previous())
             .with_item("playback_state", &self.audio.playback_state())
+            .with_item("pause_reason", &self.pause_reason)
             .with_item("speed", &self.audio.speed())
             .with_item("volume", &self.audio.volume())
-            .with_item("timer", &timer)
+            .with_item("muted", &self.audio.muted())
             .with_item(
                 "current",
                 &self
                     .queue
                     .current()
                     .map(|cur| self.queue.find_by_id(cur.id)),
-            )
-            .with_item(
-                "cover_art",
-                &self.queue.current().map(|cur| song::cover_art(&cur.path)),
-            )
+            );
+        // the cheap parts are just filtered out
+        response
+            .inner_mut()
+            .retain(|key, _| key == "status" || wants(key));
+
+        response
+    }
+
+    // a cheaper alternative to `state` for clients that only need the progress
+    async fn timer_request(&self) -> Response {
+        let timer = self.timer().await;
+
+        Response::new_ok()
+            .with_item("elapsed", &timer["elapsed"])
+            .with_item("duration", &timer["duration"])
+            .with_item("playback_state", &self.audio.playback_state())
+    }
+
+    fn health_request(&self) -> Response {
+        Response::new_ok()
+            .with_item("uptime", &self.started_at.elapsed().as_secs())
+            .with_item("errors", &logger::recent_errors())
+            .with_item("audio_alive", &self.audio.has_enabled_device())
+    }
+
+    async fn timer(&self) -> JsonObject {
+        let (elapsed, duration, chapter, n_chapters) = match self.audio.playback_timer().await {
+            Some(t) => (t.elapsed, t.duration, t.chapter, t.n_chapters),
+            None => (0, 0, None, 0),
+        };
+        let mut timer = JsonObject::new();
+        timer.insert("elapsed".into(), elapsed.into());
+        timer.insert("duration".into(), duration.into());
+        timer.insert("chapter".into(), chapter.into());
+        timer.insert("chapters".into(), n_chapters.into());
+
+        timer
+    }
+
+    // counts the current song as played if enough of it elapsed
+    async fn count_play(&mut self) {
+        if let Some(entry) = self.queue.current()
+            && let Some(timer) = self.audio.playback_timer().await
+            && play_count::is_played(timer.elapsed, timer.duration)
+        {
+            self.database.record_play(entry.path.clone());
+        }
+    }
+
+    // the current song reached its end
+    fn count_finished(&mut self) {
+        if let Some(entry) = self.queue.current() {
+            self.database.record_play(entry.path.clone());
+        }
     }
 
     async fn handle_request(&mut self, req: RequestKind) -> Response {
-        match req {
+        if req.changes_song() {
+            self.count_play().await;
+        }
+        let response = match req {
+            RequestKind::Bookmark(req) => self.bookmark_request(req),
+            RequestKind::CommandList(kinds) => {
+                let mut responses = Vec::with_capacity(kinds.len());
+                for kind in kinds {
+                    let response = Box::pin(self.handle_request(kind)).await;
+                    responses.push(response.into_inner());
+                }
+
+                // the client handler sends only the array to the client
+                Response::new_ok().with_item("responses", &responses)
+            }
             RequestKind::Db(req) => self.db_request(req).await,
             RequestKind::Device(req) => self.device_request(req),
             RequestKind::Playback(req) => self.playback_request(req).await,
             RequestKind::Playlist(req) => self.playlist_request(req),
             RequestKind::Queue(req) => self.queue_request(req),
-            RequestKind::State => self.state_request().await,
+            // the client handler responds to these on its own
+            RequestKind::Auth(_) | RequestKind::Idle | RequestKind::NoIdle | RequestKind::Ping => {
+                Response::new_ok()
+            }
+            RequestKind::State(StateArgs(only)) => self.state_request(only.as_ref()).await,
+            RequestKind::Timer => self.timer_request().await,
+            RequestKind::Health => self.health_request(),
+        };
+        // the reason only applies to the pause it explains
+        if !self.audio.is_paused() {
+            self.pause_reason = None;
         }
+
+        response
     }
 
     pub fn new(
         state: Option<PlayerState>,
         audio: Audio,
         database: Database,
+        queue_end_action: QueueEndAction,
+        pause_on_device_loss: bool,
         rx_event: tokio_chan::UnboundedReceiver<SongEvent>,
         rx_request: tokio_chan::UnboundedReceiver<Request>,
     ) -> Self {
-        let queue = state.map(|s| s.queue).unwrap_or_default();
+        let (queue, bookmarks, play_counts) = state
+            .map(|s| (s.queue, s.bookmarks, s.play_counts))
+            .unwrap_or_default();
 
         Self {
             audio,
-            database,
+            bookmarks,
+            database: database.with_play_counts(play_counts),
             queue,
+            queue_end_action,
+            stop_after_current: false,
+            pause_on_device_loss,
+            pause_reason: None,
             rx_event,
             rx_request,
+            started_at: Instant::now(),
+            // no one listens to the notifications by default
+            tx_notify: broadcast::channel(1).0,
+            watcher: None,
+        }
+    }
+
+    // continues (paused) the song that was playing when musing was shut down
+    pub async fn restore_song(&mut self, id: u32, elapsed: u64) {
+        let Some(entry) = self.queue.move_to(id) else {
+            return;
+        };
+        if !entry.path.exists() {
+            log::warn!(
+                "file `{}` doesn't exist anymore, not restoring it",
+                entry.path.to_string_lossy()
+            );
+            self.queue.reset_pos();
+            return;
+        }
+        if let Err(e) = self
+            .audio
+            .play(&entry.path, self.database.duration(&entry.path))
+        {
+            log::error!("playback error ({})", e);
+            self.queue.reset_pos();
+            self.audio.stop();
+            return;
+        }
+        self.audio.seek_to(elapsed);
+        if let Err(e) = self.audio.pause().await {
+            log::error!("playback error ({})", e);
         }
     }
 
+    pub fn with_notify(self, tx_notify: broadcast::Sender<()>) -> Self {
+        Self { tx_notify, ..self }
+    }
+
+    pub fn with_watcher(self, watcher: Option<Watcher>) -> Self {
+        Self { watcher, ..self }
+    }
+
     pub async fn run(&mut self) -> Result<()> {
         loop {
             tokio::select! {
                 res = self.rx_request.recv() => match res {
                     Some(request) => {
                         let Request { kind, tx_response } = request;
+                        let changes_state = kind.changes_state();
                         let response = self.handle_request(kind).await;
+                        self.sync_next();
                         let _ = tx_response.send(response);
+                        if changes_state {
+                            // fails only if no client is connected
+                            let _ = self.tx_notify.send(());
+                        }
                     }
                     // breaks when all client handlers go out of scope
                     None => break Ok(()),
                 },
-                Some(event) = self.rx_event.recv() => match event {
-                    SongEvent::Over => {
-                        move_next_until_playable(&mut self.queue, &mut self.audio);
-                        if self.queue.current().is_none() {
-                            self.queue.reset_pos();
-                            self.audio.stop();
-                        }
-                    }
-                },
+                Some(event) = self.rx_event.recv() => {
+                    self.handle_event(event).await;
+                    self.sync_next();
+                    let _ = self.tx_notify.send(());
+                }
+                _ = watched_change(&mut self.watcher) => {
+                    self.handle_watched_change();
+                    let _ = self.tx_notify.send(());
+                }
                 else => break Ok(())
             }
         }
     }
 
-    pub fn state(&self) -> State {
-        let volume = Volume::from(self.audio.volume());
+    // lets the decoder know which song comes next, so that it can continue
+    // with it right away (crossfading takes care of the transition on its own)
+    fn sync_next(&mut self) {
+        let next = match self.queue.current() {
+            Some(_)
+                if self.audio.gapless()
+                    && self.audio.crossfade() == 0
+                    && !self.stop_after_current =>
+            {
+                self.queue
+                    .upcoming(1)
+                    .first()
+                    .map(|entry| entry.path.clone())
+            }
+            _ => None,
+        };
+        let duration = next.as_ref().and_then(|path| self.database.duration(path));
+        self.audio.set_next(next.as_deref(), duration);
+    }
+
+    // the current song stopped playing, so the next one starts
+    // (unless the playback should stop after it)
+    fn song_over(&mut self) {
+        if self.stop_after_current {
+            self.stop_after_current = false;
+            self.queue.add_current_to_history();
+            self.queue.reset_pos();
+            self.audio.stop();
+            return;
+        }
+        move_next_until_playable(&mut self.queue, &mut self.audio, &self.database);
+        // in single mode every song is the end of the queue, which just stops
+        if self.queue.current().is_none() && !self.queue.is_single() {
+            // the end of the queue has been reached
+            match self.queue_end_action {
+                QueueEndAction::Stop => (),
+                QueueEndAction::Repeat => {
+                    move_next_until_playable(&mut self.queue, &mut self.audio, &self.database);
+                }
+                QueueEndAction::Random => {
+                    self.queue.start_random();
+                    move_next_until_playable(&mut self.queue, &mut self.audio, &self.database);
+                }
+            }
+        }
+        if self.queue.current().is_none() {
+            self.queue.reset_pos();
+            self.audio.stop();
+        }
+    }
+
+    async fn handle_event(&mut self, event: SongEvent) {
+        match event {
+            SongEvent::Over => {
+                self.count_finished();
+                self.song_over();
+            }
+            // the song can't be played any further, so it's skipped
+            // (it's not counted as played though)
+            SongEvent::Error(reason) => {
+                log::error!("playback error ({}), skipping the song", reason);
+                self.song_over();
+            }
+            // the decoder already continues with the next song,
+            // so only the queue has to catch up
+            SongEvent::Chained(path) => {
+                self.count_finished();
+                self.audio.next_started();
+                let in_sync = !self.stop_after_current
+                    && self
+                        .queue
+                        .upcoming(1)
+                        .first()
+                        .is_some_and(|entry| entry.path == path);
+                if in_sync {
+                    self.queue.add_current_to_history();
+                    self.queue.move_next();
+                } else {
+                    // the queue changed in the meantime
+                    self.song_over();
+                }
+            }
+            // start the next song early, so that it can be mixed with this one
+            // (if there's no next song, the current one just ends normally)
+            SongEvent::Ending => {
+                if !self.stop_after_current && !self.queue.upcoming(1).is_empty() {
+                    // the song keeps playing while fading out, but it's as good as finished
+                    self.count_finished();
+                    self.audio.fade_next();
+                    move_next_until_playable(&mut self.queue, &mut self.audio, &self.database);
+                    if self.queue.current().is_none() {
+                        self.audio.stop();
+                    }
+                }
+            }
+            SongEvent::DeviceLost(device) => {
+                log::error!("audio device `{}` lost", device);
+                let was_playing = self.audio.is_playing();
+                let any_active = self.audio.handle_device_loss(device);
+                if any_active && !self.pause_on_device_loss {
+                    return;
+                }
+                // the decoder keeps its position while paused, so the song
+                // can be resumed on another device from where it stopped
+                if let Err(e) = self.audio.pause().await {
+                    log::error!("pause error ({})", e);
+                } else if was_playing && self.pause_on_device_loss {
+                    self.pause_reason = Some("output device lost".into());
+                }
+                if let Err(e) = self.audio.ensure_enabled_device() {
+                    log::error!("playback stopped ({})", e);
+                    self.pause_reason = None;
+                    self.queue.reset_pos();
+                    self.audio.stop();
+                } else if was_playing
+                    && !self.pause_on_device_loss
+                    && let Err(e) = self.audio.resume()
+                {
+                    log::error!("resume error ({})", e);
+                }
+            }
+        }
+    }
+
+    fn handle_watched_change(&mut self) {
+        // updating now would remove every song from the database
+        if self.database.music_dirs().iter().any(|dir| !dir.exists()) {
+            log::warn!("a music directory doesn't exist, skipping the update");
+            return;
+        }
+        let response = self.database.update();
+        if let Some(reason) = response.inner().get("reason") {
+            log::error!("automatic update error ({})", reason);
+        }
+    }
+
+    pub async fn state(&self) -> State {
+        let volume = Volume::from(self.audio.unmuted_volume());
         let speed = Speed::from(self.audio.speed());
         let gapless = self.audio.gapless();
         let mut queue = self.queue.clone();
         queue.reset_pos();
+        let current = self.queue.current().map(|entry| entry.id);
+        let elapsed = match self.audio.playback_timer().await {
+            Some(timer) if current.is_some() => timer.elapsed,
+            _ => 0,
+        };
 
         let audio_state = AudioState {
             volume,
             speed,
             gapless,
         };
-        let player_state = PlayerState { queue };
+        let bookmarks = self.bookmarks.clone();
+        let play_counts = self.database.play_counts().clone();
+        let player_state = PlayerState {
+            queue,
+            bookmarks,
+            play_counts,
+            current,
+            elapsed,
+        };
 
         State {
             audio_state,
@@ -373,23 +1044,45 @@ impl Player {
     }
 }
 
-fn move_next_until_playable(queue: &mut Queue, audio: &mut Audio) {
+// never resolves if there's no watcher
+async fn watched_change(watcher: &mut Option<Watcher>) {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+// in modes that never run out of songs (e.g. repeat all), we give up
+// after trying every song in the queue once
+fn move_next_until_playable(queue: &mut Queue, audio: &mut Audio, database: &Database) {
     queue.add_current_to_history();
+    let mut attempts_left = queue.inner().len();
     while let Some(entry) = queue.move_next() {
-        match audio.play(&entry.path) {
-            Ok(_) => break,
+        match audio.play(&entry.path, database.duration(&entry.path)) {
+            Ok(_) => return,
             Err(e) => log::error!("playback error ({})", e),
         }
+        attempts_left = attempts_left.saturating_sub(1);
+        if attempts_left == 0 {
+            break;
+        }
     }
+    queue.reset_pos();
 }
 
-fn move_prev_until_playable(queue: &mut Queue, audio: &mut Audio) {
+fn move_prev_until_playable(queue: &mut Queue, audio: &mut Audio, database: &Database) {
+    let mut attempts_left = queue.inner().len();
     while let Some(entry) = queue.move_prev() {
-        match audio.play(&entry.path) {
-            Ok(_) => break,
+        match audio.play(&entry.path, database.duration(&entry.path)) {
+            Ok(_) => return,
             Err(e) => log::error!("playback error ({})", e),
         }
+        attempts_left = attempts_left.saturating_sub(1);
+        if attempts_left == 0 {
+            break;
+        }
     }
+    queue.reset_pos();
 }
 
 // returns the songs which weren't be found
@@ -397,25 +1090,17 @@ fn add_to_queue<'a>(
     database: &Database,
     queue: &mut Queue,
     paths: &'a [PathBuf],
-    range: Option<(usize, usize)>,
     pos: Option<usize>,
 ) -> Vec<&'a PathBuf> {
     let mut not_found = Vec::new();
-    let range = match range {
-        Some((start, end)) => {
-            let start = start.min(paths.len().saturating_sub(1));
-            let end = end.clamp(start, paths.len().saturating_sub(1));
-
-            start..=end
-        }
-        None => 0..=(paths.len().saturating_sub(1)),
-    };
-    for (offset, path) in paths[range].iter().enumerate() {
+    let mut pos = pos;
+    for path in paths.iter() {
         match database.try_to_abs_path(path) {
-            Some(abs_path) => match pos {
-                Some(pos) => queue.add(&abs_path, Some(pos + offset)),
-                None => queue.add(&abs_path, None),
-            },
+            Some(abs_path) => {
+                queue.add(&abs_path, pos);
+                // the next song goes right after this one
+                pos = pos.map(|pos| pos + 1);
+            }
             None => {
                 not_found.push(path);
             }
@@ -425,19 +1110,115 @@ fn add_to_queue<'a>(
     not_found
 }
 
+// the songs of a playlist from the `start`-th to the `end`-th one (inclusive)
+fn playlist_range(playlist: &[PathBuf], range: Option<(usize, usize)>) -> Result<&[PathBuf]> {
+    match range {
+        Some((start, end)) if start > end => Err(anyhow!(
+            "invalid range `{}-{}` (its start is greater than its end)",
+            start,
+            end
+        )),
+        Some((start, end)) if end >= playlist.len() => Err(anyhow!(
+            "range `{}-{}` out of bounds for a playlist of {} song(s)",
+            start,
+            end,
+            playlist.len()
+        )),
+        Some((start, end)) => Ok(&playlist[start..=end]),
+        None => Ok(playlist),
+    }
+}
+
+// replaces the queue with the songs and moves to the one at position `start`
+// (clamped to the length of the new queue)
+// returns the songs which weren't found
+fn replace_queue<'a>(
+    database: &Database,
+    queue: &mut Queue,
+    paths: &'a [PathBuf],
+    start: usize,
+) -> Vec<&'a PathBuf> {
+    queue.clear();
+    let not_found = add_to_queue(database, queue, paths, None);
+    let start = start.min(queue.inner().len().saturating_sub(1));
+    if let Some(id) = queue.inner().get(start).map(|entry| entry.id) {
+        queue.move_to(id);
+    }
+
+    not_found
+}
+
+// the total duration of the queue and the time left until its end
+// (None if nothing is playing), songs of unknown duration are skipped
+fn queue_durations(
+    durations: &[Option<u64>],
+    current: Option<usize>,
+    elapsed: u64,
+) -> (u64, Option<u64>) {
+    let total = durations.iter().flatten().sum();
+    let remaining = current.map(|pos| {
+        let current = durations[pos].unwrap_or_default().saturating_sub(elapsed);
+        current + durations[pos + 1..].iter().flatten().sum::<u64>()
+    });
+
+    (total, remaining)
+}
+
+fn files_not_found(not_found: Vec<&PathBuf>) -> Response {
+    if not_found.is_empty() {
+        Response::new_ok()
+    } else {
+        Response::new_err(
+            ErrorCode::NotFound,
+            format!(
+                "file(s) `{}` not found in the database",
+                not_found
+                    .into_iter()
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        )
+    }
+}
+
+// `extra_devices` are ones not backed by the system's audio (e.g. the HTTP stream),
+// they're all enabled at startup
 pub async fn run(
     config: PlayerConfig,
+    extra_devices: Vec<Device>,
     rx_request: tokio_chan::UnboundedReceiver<Request>,
+    tx_notify: broadcast::Sender<()>,
     mut rx_shutdown: broadcast::Receiver<()>,
 ) -> Result<()> {
     let PlayerConfig {
-        music_dir,
+        music_dirs,
         state_file,
         audio_device,
+        output_devices,
+        device_configs,
         playlist_dir,
+        recording_dir,
+        relative_paths,
+        pause_on_device_loss,
+        queue_end_action,
+        watch,
+        allowed_exts,
+        follow_symlinks,
+        exact_durations,
+        preserve_pitch,
+        buffer_ms,
+        pause_fade_ms,
     } = config;
     let (player_state, audio_state) = match State::try_from_file(&state_file) {
         Ok(s) => (Some(s.player_state), Some(s.audio_state)),
+        Err(e) if e.is::<VersionMismatch>() => {
+            log::warn!(
+                "state file from incompatible version, starting fresh ({})",
+                e
+            );
+            (None, None)
+        }
         Err(e) => {
             log::error!("state file error ({})", e);
             (None, None)
@@ -445,23 +1226,67 @@ pub async fn run(
     };
 
     let (tx_event, rx_event) = tokio_chan::unbounded_channel();
-    let audio = Audio::new(audio_state, tx_event).try_with_default(audio_device.as_ref())?;
+    let audio = Audio::new(audio_state, tx_event)
+        .with_preserve_pitch(preserve_pitch)
+        .with_exact_durations(exact_durations)
+        .with_recording_dir(recording_dir)
+        .with_buffer_ms(buffer_ms)
+        .with_pause_fade_ms(pause_fade_ms)
+        .with_device_configs(device_configs);
+    let audio = extra_devices
+        .into_iter()
+        .try_fold(audio, |audio, device| audio.try_with_device(device))?;
+    let audio = if output_devices.is_empty() {
+        audio.try_with_default(audio_device.as_ref())?
+    } else {
+        audio.try_with_devices(&output_devices)?
+    };
     // creating the db is blocking and parallelizable,
     // so we delegate it to rayon's thread pool
     let database = {
         let (tx, rx) = oneshot::channel();
         rayon::spawn(move || {
-            let _ = tx.send(Database::try_new(music_dir, playlist_dir.as_ref()));
+            let _ = tx.send(
+                Database::try_new(
+                    &music_dirs,
+                    playlist_dir.as_ref(),
+                    Some(&allowed_exts),
+                    follow_symlinks,
+                    exact_durations,
+                )
+                .map(|db| db.with_relative_paths(relative_paths)),
+            );
         });
         rx.await?
     }?;
-    let mut player = Player::new(player_state, audio, database, rx_event, rx_request);
+    let restored = player_state
+        .as_ref()
+        .and_then(|s| s.current.map(|id| (id, s.elapsed)));
+    let mut player = Player::new(
+        player_state,
+        audio,
+        database,
+        queue_end_action,
+        pause_on_device_loss,
+        rx_event,
+        rx_request,
+    )
+    .with_notify(tx_notify);
+    if watch {
+        match Watcher::try_new(player.database.music_dirs(), player.database.playlist_dir()) {
+            Ok(watcher) => player = player.with_watcher(Some(watcher)),
+            Err(e) => log::error!("watcher error ({})", e),
+        }
+    }
+    if let Some((id, elapsed)) = restored {
+        player.restore_song(id, elapsed).await;
+    }
 
     let res = tokio::select! {
         res = player.run() => res,
         _ = rx_shutdown.recv() => Ok(()),
     };
-    let state = player.state();
+    let state = player.state().await;
     state.save(state_file)?;
 
     res
@@ -469,15 +1294,364 @@ pub async fn run(
 
 pub fn spawn(
     config: PlayerConfig,
+    extra_devices: Vec<Device>,
     rx_request: tokio_chan::UnboundedReceiver<Request>,
+    tx_notify: broadcast::Sender<()>,
     rx_shutdown: broadcast::Receiver<()>,
     tx_shutdown: broadcast::Sender<()>,
 ) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let res = run(config, rx_request, rx_shutdown).await;
+        let res = run(config, extra_devices, rx_request, tx_notify, rx_shutdown).await;
         if let Err(e) = res {
             log::error!("fatal error ({})", e);
         }
         let _ = tx_shutdown.send(());
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::song;
+
+    // a player with an empty database and no audio devices
+    fn test_player(name: &str) -> Player {
+        let dir = std::env::temp_dir().join(format!("musing_test_{}_{}", name, std::process::id()));
+        let _ = std::fs::create_dir(&dir);
+        let (tx_event, rx_event) = tokio_chan::unbounded_channel();
+        let (_, rx_request) = tokio_chan::unbounded_channel();
+        let audio = Audio::new(None, tx_event).with_recording_dir(Some(std::env::temp_dir()));
+        let database =
+            Database::try_new(std::slice::from_ref(&dir), None, None, false, false).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        Player::new(
+            None,
+            audio,
+            database,
+            QueueEndAction::Stop,
+            false,
+            rx_event,
+            rx_request,
+        )
+    }
+
+    #[test]
+    fn replace() {
+        let dir = std::env::temp_dir().join(format!("musing_test_replace_{}", std::process::id()));
+        let _ = std::fs::create_dir(&dir);
+        for name in ["a.wav", "b.wav", "c.wav"] {
+            song::write_silent_wav(dir.join(name), 8000, 1, 800);
+        }
+        let database =
+            Database::try_new(std::slice::from_ref(&dir), None, None, false, false).unwrap();
+        let dir = dunce::canonicalize(&dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut queue = Queue::default();
+        queue.add(dir.join("a.wav"), None);
+        queue.move_to(1);
+
+        let paths: Vec<PathBuf> = vec!["c.wav".into(), "x.wav".into(), "b.wav".into()];
+        let not_found = replace_queue(&database, &mut queue, &paths, 1);
+        assert_eq!(not_found, [&PathBuf::from("x.wav")]);
+        let paths: Vec<_> = queue.inner().iter().map(|entry| &entry.path).collect();
+        assert_eq!(paths, [&dir.join("c.wav"), &dir.join("b.wav")]);
+        assert_eq!(
+            queue.current().map(|entry| &entry.path),
+            Some(&dir.join("b.wav"))
+        );
+
+        // out of range
+        let paths: Vec<PathBuf> = vec!["a.wav".into()];
+        replace_queue(&database, &mut queue, &paths, 5);
+        assert_eq!(
+            queue.current().map(|entry| &entry.path),
+            Some(&dir.join("a.wav"))
+        );
+    }
+
+    #[test]
+    fn load_range() {
+        let playlist: Vec<PathBuf> = vec!["a".into(), "b".into(), "c".into()];
+        assert_eq!(playlist_range(&playlist, None).unwrap(), &playlist[..]);
+        assert_eq!(
+            playlist_range(&playlist, Some((1, 2))).unwrap(),
+            &playlist[1..]
+        );
+        assert_eq!(
+            playlist_range(&playlist, Some((0, 0))).unwrap(),
+            &playlist[..1]
+        );
+        // inverted
+        assert!(playlist_range(&playlist, Some((2, 1))).is_err());
+        // out of bounds
+        assert!(playlist_range(&playlist, Some((1, 3))).is_err());
+        assert!(playlist_range(&playlist, Some((5, 7))).is_err());
+        assert!(playlist_range(&[], Some((0, 0))).is_err());
+        assert!(playlist_range(&[], None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn queue_duration() {
+        let durations = [Some(100), None, Some(200), Some(50)];
+        assert_eq!(queue_durations(&durations, None, 0), (350, None));
+        assert_eq!(queue_durations(&durations, Some(0), 30), (350, Some(320)));
+        assert_eq!(queue_durations(&durations, Some(1), 30), (350, Some(250)));
+        assert_eq!(queue_durations(&durations, Some(3), 80), (350, Some(0)));
+        assert_eq!(queue_durations(&[], None, 0), (0, None));
+    }
+
+    #[tokio::test]
+    async fn stop_after_current() {
+        let mut player = test_player("stop_after");
+        player.queue_end_action = QueueEndAction::Repeat;
+        player.queue.add("a", None);
+        player.queue.add("b", None);
+        player.queue.move_to(1);
+
+        for _ in 0..3 {
+            let _ = player
+                .playback_request(request::PlaybackRequestKind::StopAfterCurrent)
+                .await;
+        }
+        assert!(player.stop_after_current);
+        player.handle_event(SongEvent::Over).await;
+        assert!(player.queue.current().is_none());
+        assert!(!player.stop_after_current);
+        assert_eq!(player.audio.playback_state(), "stopped");
+
+        // stopping by hand clears the flag too
+        let _ = player
+            .playback_request(request::PlaybackRequestKind::StopAfterCurrent)
+            .await;
+        let _ = player
+            .playback_request(request::PlaybackRequestKind::Stop)
+            .await;
+        assert!(!player.stop_after_current);
+    }
+
+    #[tokio::test]
+    async fn volume_in_response() {
+        let mut player = test_player("volume");
+
+        for _ in 0..2 {
+            let _ = player
+                .playback_request(request::PlaybackRequestKind::Volume(request::VolumeArgs(
+                    100,
+                )))
+                .await;
+        }
+        let response = player
+            .playback_request(request::PlaybackRequestKind::Volume(request::VolumeArgs(
+                10,
+            )))
+            .await;
+        assert_eq!(
+            response.inner().get("volume"),
+            Some(&serde_json::json!(100))
+        );
+        let response = player
+            .playback_request(request::PlaybackRequestKind::Volume(request::VolumeArgs(
+                -30,
+            )))
+            .await;
+        assert_eq!(response.inner().get("volume"), Some(&serde_json::json!(70)));
+    }
+
+    #[tokio::test]
+    async fn state_subset() {
+        let mut player = test_player("subset");
+
+        let only = HashSet::from(["timer".into(), "volume".into(), "queue_remaining".into()]);
+        let subset = player.state_request(Some(&only)).await;
+        let mut keys: Vec<_> = subset.inner().keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["queue_remaining", "status", "timer", "volume"]);
+        let full = player.state_request(None).await;
+        assert!(full.inner().contains_key("devices"));
+        assert!(full.inner().contains_key("queue_duration"));
+    }
+
+    #[tokio::test]
+    async fn decoder_error() {
+        let song_path = |name: &str| {
+            std::env::temp_dir().join(format!(
+                "musing_test_error_{}_{}.wav",
+                name,
+                std::process::id()
+            ))
+        };
+        song::write_silent_wav(song_path("a"), 8000, 2, 8000);
+        let _ = std::fs::write(song_path("b"), b"not a song");
+        song::write_silent_wav(song_path("c"), 8000, 2, 8000);
+        let mut player = test_player("error");
+        player
+            .audio
+            .enable_device(format!("file:{}", song_path("out").to_string_lossy()))
+            .unwrap();
+        for name in ["a", "b", "c"] {
+            player.queue.add(song_path(name), None);
+        }
+        player.queue.move_to(1);
+        player.audio.play(song_path("a"), None).unwrap();
+
+        // "b" can't be played either, so the playback moves on to "c"
+        player
+            .handle_event(SongEvent::Error("corrupt packet".into()))
+            .await;
+        let current = player.queue.current().map(|entry| entry.path.clone());
+        let playback_state = player.audio.playback_state();
+        player.audio.stop();
+        for name in ["a", "b", "c", "out"] {
+            let _ = std::fs::remove_file(song_path(name));
+        }
+        assert_eq!(current, Some(song_path("c")));
+        assert_eq!(playback_state, "playing");
+        // skipped songs aren't counted as played
+        assert_eq!(player.database.play_counts().get(song_path("a")), 0);
+    }
+
+    #[tokio::test]
+    async fn back_to_back_on_two_devices() {
+        let song_path = |name: &str| {
+            std::env::temp_dir().join(format!(
+                "musing_test_two_devices_{}_{}.wav",
+                name,
+                std::process::id()
+            ))
+        };
+        // at the devices' sample rate, so that nothing gets lost in resampling
+        for name in ["a", "b"] {
+            song::write_silent_wav(song_path(name), 44100, 2, 4410);
+        }
+        let mut player = test_player("two_devices");
+        for out in ["out1", "out2"] {
+            let device = format!("file:{}", song_path(out).to_string_lossy());
+            player.audio.enable_device(device).unwrap();
+        }
+        player.queue.add(song_path("a"), None);
+        player.queue.add(song_path("b"), None);
+        player.queue.move_to(1);
+        player.audio.play(song_path("a"), None).unwrap();
+
+        let mut n_over = 0;
+        let finished = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while player.audio.playback_state() != "stopped" {
+                let event = player.rx_event.recv().await.unwrap();
+                if let SongEvent::Over = event {
+                    n_over += 1;
+                }
+                player.handle_event(event).await;
+            }
+        })
+        .await;
+        player.audio.stop();
+        let recordings: Vec<_> = ["out1", "out2"]
+            .into_iter()
+            .map(|out| std::fs::read(song_path(out)).unwrap())
+            .collect();
+        for name in ["a", "b", "out1", "out2"] {
+            let _ = std::fs::remove_file(song_path(name));
+        }
+
+        assert!(finished.is_ok());
+        // one event per song, not per device
+        assert_eq!(n_over, 2);
+        // both songs were recorded in full (0.1 s each, 16-bit stereo at 44.1 kHz)
+        for wav in recordings {
+            let data_len = u32::from_le_bytes(wav[40..44].try_into().unwrap());
+            assert_eq!(data_len, 2 * 4410 * 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn pause_on_device_loss() {
+        let song_path =
+            std::env::temp_dir().join(format!("musing_test_loss_{}.wav", std::process::id()));
+        song::write_silent_wav(&song_path, 8000, 2, 8000);
+        let out_path = |i: usize| {
+            std::env::temp_dir().join(format!("musing_test_loss_{}_{}.wav", i, std::process::id()))
+        };
+        let device = |i: usize| format!("file:{}", out_path(i).to_string_lossy());
+
+        for pause_on_device_loss in [false, true] {
+            let mut player = test_player("loss");
+            player.pause_on_device_loss = pause_on_device_loss;
+            player.audio.enable_device(device(0)).unwrap();
+            player.audio.enable_device(device(1)).unwrap();
+            player.audio.play(&song_path, None).unwrap();
+            player.handle_event(SongEvent::DeviceLost(device(0))).await;
+            let state = player.state_request(None).await;
+            if pause_on_device_loss {
+                assert_eq!(player.audio.playback_state(), "paused");
+                assert_eq!(
+                    state.inner().get("pause_reason"),
+                    Some(&serde_json::json!("output device lost"))
+                );
+                // resuming makes the reason obsolete
+                let _ = player
+                    .handle_request(RequestKind::Playback(request::PlaybackRequestKind::Resume))
+                    .await;
+                assert_eq!(player.audio.playback_state(), "playing");
+                assert!(player.pause_reason.is_none());
+            } else {
+                // the other device keeps playing
+                assert_eq!(player.audio.playback_state(), "playing");
+                assert_eq!(
+                    state.inner().get("pause_reason"),
+                    Some(&serde_json::Value::Null)
+                );
+            }
+            player.audio.stop();
+        }
+        let _ = std::fs::remove_file(&song_path);
+        for i in 0..2 {
+            let _ = std::fs::remove_file(out_path(i));
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_end_actions() {
+        let song_path = |name: &str| {
+            std::env::temp_dir().join(format!(
+                "musing_test_end_{}_{}.wav",
+                name,
+                std::process::id()
+            ))
+        };
+        for name in ["a", "b"] {
+            song::write_silent_wav(song_path(name), 8000, 2, 800);
+        }
+        let device = format!("file:{}", song_path("out").to_string_lossy());
+
+        let cases = [
+            (QueueEndAction::Stop, false, None, "sequential"),
+            (QueueEndAction::Repeat, false, Some("a"), "sequential"),
+            (QueueEndAction::Random, false, Some("a"), "random"),
+            (QueueEndAction::Random, true, None, "single"),
+            (QueueEndAction::Repeat, true, None, "single"),
+        ];
+        for (action, single, expected, mode) in cases {
+            let mut player = test_player("end");
+            player.queue_end_action = action;
+            player.audio.enable_device(&device).unwrap();
+            player.queue.add(song_path("a"), None);
+            player.queue.add(song_path("b"), None);
+            if single {
+                player.queue.start_single();
+            }
+            player.queue.move_to(2);
+            player.audio.play(song_path("b"), None).unwrap();
+            player.song_over();
+            assert_eq!(
+                player.queue.current().map(|entry| &entry.path),
+                expected.map(song_path).as_ref()
+            );
+            assert_eq!(player.queue.mode(), mode);
+            player.audio.stop();
+        }
+        for name in ["a", "b", "out"] {
+            let _ = std::fs::remove_file(song_path(name));
+        }
+    }
+}
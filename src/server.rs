@@ -1,101 +1,345 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use serde_json::json;
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
     sync::{
-        broadcast,
+        Semaphore, broadcast,
         mpsc::{self as tokio_chan},
         oneshot,
     },
     task::JoinHandle,
+    time,
 };
 
 use crate::{
     config::ServerConfig,
+    constants,
     model::{
-        request::{Request, RequestKind},
-        response::Response,
+        request::{AuthArgs, Request, RequestKind, StateArgs},
+        response::{ErrorCode, JsonObject, Response},
     },
 };
 
+// how messages are delimited
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Framing {
+    // a 4-byte length before every message (binary safe)
+    #[default]
+    LengthPrefixed,
+    // every message is one line (handy for scripts and `nc`)
+    Line,
+}
+
+// the stream is either a TCP socket or a Unix domain socket
 #[derive(Debug)]
-struct ClientHandler {
-    stream: BufReader<TcpStream>,
+struct ClientHandler<S> {
+    stream: BufReader<S>,
+    // how long to wait for the player to respond
+    request_timeout: Duration,
+    framing: Framing,
+    password: Option<String>,
+    // when the server started (reported by `ping`)
+    started_at: Instant,
 }
 
 #[derive(Debug)]
 struct Server {
     port: u16,
+    bind_addr: String,
+    socket_path: Option<PathBuf>,
+    // one permit per connected client
+    connections: Arc<Semaphore>,
+    request_timeout: Duration,
+    framing: Framing,
+    password: Option<String>,
+    started_at: Instant,
 }
 
-impl ClientHandler {
-    pub fn new(stream: TcpStream) -> Self {
+impl FromStr for Framing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "length" => Ok(Self::LengthPrefixed),
+            "line" => Ok(Self::Line),
+            other => bail!(
+                "invalid framing `{}` (valid ones are `length`, `line`)",
+                other
+            ),
+        }
+    }
+}
+
+impl<S> ClientHandler<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S) -> Self {
         Self {
             stream: BufReader::new(stream),
+            request_timeout: Duration::from_secs(constants::DEFAULT_REQUEST_TIMEOUT_SECS),
+            framing: Framing::default(),
+            password: None,
+            started_at: Instant::now(),
         }
     }
 
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+
+        self
+    }
+
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+
+        self
+    }
+
+    pub fn with_password(mut self, password: Option<String>) -> Self {
+        self.password = password;
+
+        self
+    }
+
+    pub fn with_started_at(mut self, started_at: Instant) -> Self {
+        self.started_at = started_at;
+
+        self
+    }
+
     pub async fn run(
         &mut self,
         tx_request: tokio_chan::UnboundedSender<Request>,
+        mut rx_notify: broadcast::Receiver<()>,
         mut rx_shutdown: broadcast::Receiver<()>,
     ) -> Result<()> {
         let welcome = json!({"version": env!("CARGO_PKG_VERSION")}).to_string();
-        let bytes = welcome.as_bytes();
-        self.stream.write_u32(bytes.len() as u32).await?;
-        self.stream.write_all(bytes).await?;
+        self.write_frame(&welcome).await?;
 
         let mut prev_state = Response::default();
+        // the keys requested in the last `state` (`idle` reports changes of only these too)
+        let mut state_keys = None;
+        // if a password is set, every request other than `auth` is rejected until it's given
+        let mut authenticated = self.password.is_none();
         loop {
-            // read the length (4 bytes, big endian)
+            let max_len = if authenticated {
+                constants::MAX_FRAME_LEN
+            } else {
+                constants::MAX_AUTH_FRAME_LEN
+            };
             let res = tokio::select! {
-                res = self.stream.read_u32() => res,
+                res = self.read_frame(max_len) => res,
                 _ = rx_shutdown.recv() => break,
             };
-            let Ok(len) = res else {
-                let _ = self.stream.shutdown().await;
-                break;
+            let s = match res {
+                Ok(Some(s)) => s,
+                Ok(None) => {
+                    let _ = self.stream.shutdown().await;
+                    break;
+                }
+                // the rest of the stream can't be made sense of
+                Err(e) => {
+                    let response = Response::new_err(ErrorCode::Syntax, e.to_string());
+                    let _ = self.write_frame(&response.to_string()).await;
+                    let _ = self.stream.shutdown().await;
+                    break;
+                }
             };
 
-            // read the message
-            let mut buf = vec![0; len as usize];
-            let res = tokio::select! {
-                res = self.stream.read_exact(&mut buf) => res,
-                _ = rx_shutdown.recv() => break,
-            };
-            if res.is_err() {
-                let _ = self.stream.shutdown().await;
-                break;
+            // before authenticating, no other request is even parsed
+            // (e.g. filters compile regexes)
+            if !authenticated && !server_utils::is_auth(&s) {
+                let response =
+                    Response::new_err(ErrorCode::Unauthorized, "authenticate with `auth` first");
+                self.write_frame(&response.to_string()).await?;
+                continue;
             }
-            let s = String::from_utf8(buf)?;
 
             // respond
-            let response = match RequestKind::try_from(s.as_str()) {
+            let request = RequestKind::try_from(s.as_str());
+            let is_command_list = matches!(request, Ok(RequestKind::CommandList(_)));
+            let response = match request {
+                Ok(RequestKind::Auth(AuthArgs(password))) => {
+                    let is_correct = self.password.as_ref().is_none_or(|expected| {
+                        constant_time_eq(expected.as_bytes(), password.as_bytes())
+                    });
+                    if is_correct {
+                        authenticated = true;
+                        Response::new_ok()
+                    } else {
+                        log::warn!("failed authentication attempt");
+                        Response::new_err(ErrorCode::Unauthorized, "wrong password")
+                    }
+                }
+                Ok(RequestKind::Idle) => {
+                    match self
+                        .idle(
+                            &tx_request,
+                            &mut rx_notify,
+                            &mut rx_shutdown,
+                            &mut prev_state,
+                            &state_keys,
+                        )
+                        .await?
+                    {
+                        Some(response) => response,
+                        None => break,
+                    }
+                }
+                // there's nothing to cancel
+                Ok(RequestKind::NoIdle) => Response::new_ok(),
+                // answered even if the player is busy
+                Ok(RequestKind::Ping) => Response::new_ok()
+                    .with_item("version", &env!("CARGO_PKG_VERSION"))
+                    .with_item("uptime", &self.started_at.elapsed().as_secs()),
                 Ok(kind) => {
-                    let is_state = matches!(kind, RequestKind::State);
-                    let (tx_response, rx_response) = oneshot::channel();
-                    let _ = tx_request.send(Request { kind, tx_response });
-                    let response = rx_response.await?;
+                    let is_state = if let RequestKind::State(StateArgs(only)) = &kind {
+                        state_keys = only.clone();
+                        true
+                    } else {
+                        false
+                    };
+                    let response =
+                        server_utils::send_request(&tx_request, kind, self.request_timeout).await?;
 
                     // respond to a "state" request with a diff -
                     // we respond only with the keys whose values have changed since
                     // the last time this client requested to get the state
-                    if is_state {
+                    if is_state && !response.is_err() {
                         let diff = response.diff_with(&prev_state);
-                        prev_state = response;
+                        prev_state.merge(response);
 
                         diff
                     } else {
                         response
                     }
-                    .to_string()
                 }
-                Err(e) => Response::new_err(e.to_string()).to_string(),
+                Err(e) => Response::new_err(ErrorCode::Syntax, e.to_string()),
+            };
+            // a command list is answered with the array of its responses (in order)
+            let frame = match response.inner().get("responses") {
+                Some(responses) if is_command_list && !response.is_err() => responses.to_string(),
+                _ => response.to_string(),
             };
-            let bytes = response.as_bytes();
-            self.stream.write_u32(bytes.len() as u32).await?;
-            self.stream.write_all(bytes).await?;
+            self.write_frame(&frame).await?;
+        }
+
+        Ok(())
+    }
+
+    // waits until the state changes and responds with a diff (like to a "state" request)
+    // any message received in the meantime (normally a "noidle") cancels the waiting
+    // returns None if the client disconnected or the server is shutting down
+    async fn idle(
+        &mut self,
+        tx_request: &tokio_chan::UnboundedSender<Request>,
+        rx_notify: &mut broadcast::Receiver<()>,
+        rx_shutdown: &mut broadcast::Receiver<()>,
+        prev_state: &mut Response,
+        state_keys: &Option<HashSet<String>>,
+    ) -> Result<Option<Response>> {
+        loop {
+            // `fill_buf` is cancellation safe, so no part of the next message gets lost
+            let cancelled = tokio::select! {
+                res = rx_notify.recv() => match res {
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => false,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(None),
+                },
+                res = self.stream.fill_buf() => match res {
+                    Ok(buf) if !buf.is_empty() => true,
+                    _ => return Ok(None),
+                },
+                _ = rx_shutdown.recv() => return Ok(None),
+            };
+            if cancelled {
+                let Some(s) = self.read_frame(constants::MAX_FRAME_LEN).await? else {
+                    return Ok(None);
+                };
+                if !matches!(RequestKind::try_from(s.as_str()), Ok(RequestKind::NoIdle)) {
+                    return Ok(Some(Response::new_err(
+                        ErrorCode::InvalidArg,
+                        "only `noidle` can be sent while idling",
+                    )));
+                }
+            }
+
+            let kind = RequestKind::State(StateArgs(state_keys.clone()));
+            let state = server_utils::send_request(tx_request, kind, self.request_timeout).await?;
+            if state.is_err() {
+                return Ok(Some(state));
+            }
+            let diff = state.diff_with(prev_state);
+            prev_state.merge(state);
+            // a notification doesn't guarantee that anything has changed
+            if cancelled || !diff.is_empty() {
+                return Ok(Some(diff));
+            }
+        }
+    }
+
+    // returns None if the client disconnected
+    // and an error if the message is longer than `max_len` bytes
+    async fn read_frame(&mut self, max_len: usize) -> Result<Option<String>> {
+        let buf = match self.framing {
+            Framing::LengthPrefixed => {
+                // the length (4 bytes, big endian), then the message
+                let Ok(len) = self.stream.read_u32().await else {
+                    return Ok(None);
+                };
+                // checked before allocating anything
+                if len as usize > max_len {
+                    bail!("the message is longer than {} bytes", max_len);
+                }
+                let mut buf = vec![0; len as usize];
+                if self.stream.read_exact(&mut buf).await.is_err() {
+                    return Ok(None);
+                }
+                buf
+            }
+            Framing::Line => loop {
+                let mut buf = Vec::new();
+                // a client that never sends a newline can't make the buffer grow forever
+                let mut stream = (&mut self.stream).take(max_len as u64 + 1);
+                match stream.read_until(b'\n', &mut buf).await {
+                    Ok(0) | Err(_) => return Ok(None),
+                    Ok(n) if n > max_len && !buf.ends_with(b"\n") => {
+                        bail!("the message is longer than {} bytes", max_len);
+                    }
+                    // skip empty lines
+                    Ok(_) if buf.trim_ascii().is_empty() => continue,
+                    Ok(_) => break buf,
+                }
+            },
+        };
+
+        Ok(Some(String::from_utf8(buf)?))
+    }
+
+    async fn write_frame(&mut self, s: &str) -> Result<()> {
+        let bytes = s.as_bytes();
+        match self.framing {
+            Framing::LengthPrefixed => {
+                self.stream.write_u32(bytes.len() as u32).await?;
+                self.stream.write_all(bytes).await?;
+            }
+            // serialized JSON never contains a raw newline
+            Framing::Line => {
+                self.stream.write_all(bytes).await?;
+                self.stream.write_all(b"\n").await?;
+            }
         }
 
         Ok(())
@@ -104,29 +348,191 @@ impl ClientHandler {
 
 impl Server {
     pub fn new(config: ServerConfig) -> Self {
-        let ServerConfig { port } = config;
-        Self { port }
+        let ServerConfig {
+            port,
+            bind_addr,
+            socket_path,
+            // the HTTP stream runs on its own
+            http_port: _,
+            max_connections,
+            request_timeout_secs,
+            framing,
+            password,
+        } = config;
+        Self {
+            port,
+            bind_addr,
+            socket_path,
+            connections: Arc::new(Semaphore::new(max_connections)),
+            request_timeout: Duration::from_secs(request_timeout_secs),
+            framing,
+            password,
+            started_at: Instant::now(),
+        }
     }
 
     pub async fn run(
         &self,
         tx_request: tokio_chan::UnboundedSender<Request>,
+        tx_notify: broadcast::Sender<()>,
         tx_shutdown: broadcast::Sender<()>,
     ) -> Result<()> {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", self.port)).await?;
-        log::warn!("server listening on port {}", self.port);
+        if let Some(socket_path) = &self.socket_path {
+            return self
+                .run_unix(socket_path, tx_request, tx_notify, tx_shutdown)
+                .await;
+        }
+        let addr = SocketAddr::new(self.bind_addr.parse::<IpAddr>()?, self.port);
+        let listener = TcpListener::bind(addr).await?;
+        log::warn!("server listening on {}", addr);
         loop {
             let (stream, addr) = listener.accept().await?;
-            log::warn!("new client: {}", addr);
-            let tx_request_ = tx_request.clone();
-            let rx_shutdown = tx_shutdown.subscribe();
+            self.spawn_client_handler(stream, addr, &tx_request, &tx_notify, &tx_shutdown);
+        }
+    }
+
+    #[cfg(unix)]
+    async fn run_unix(
+        &self,
+        socket_path: &PathBuf,
+        tx_request: tokio_chan::UnboundedSender<Request>,
+        tx_notify: broadcast::Sender<()>,
+        tx_shutdown: broadcast::Sender<()>,
+    ) -> Result<()> {
+        server_utils::remove_stale_socket(socket_path)?;
+        let listener = UnixListener::bind(socket_path)?;
+        log::warn!("server listening on `{}`", socket_path.to_string_lossy());
+        loop {
+            let (stream, _) = listener.accept().await?;
+            self.spawn_client_handler(
+                stream,
+                socket_path.to_string_lossy().into_owned(),
+                &tx_request,
+                &tx_notify,
+                &tx_shutdown,
+            );
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn run_unix(
+        &self,
+        _socket_path: &PathBuf,
+        _tx_request: tokio_chan::UnboundedSender<Request>,
+        _tx_notify: broadcast::Sender<()>,
+        _tx_shutdown: broadcast::Sender<()>,
+    ) -> Result<()> {
+        anyhow::bail!("Unix domain sockets aren't supported on this platform")
+    }
+
+    // a client over the limit gets an error frame instead of the welcome message
+    fn spawn_client_handler<S>(
+        &self,
+        stream: S,
+        addr: impl Display + Send + 'static,
+        tx_request: &tokio_chan::UnboundedSender<Request>,
+        tx_notify: &broadcast::Sender<()>,
+        tx_shutdown: &broadcast::Sender<()>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (request_timeout, framing, started_at) =
+            (self.request_timeout, self.framing, self.started_at);
+        let Ok(permit) = Arc::clone(&self.connections).try_acquire_owned() else {
+            log::warn!("rejected client: {} (too many connections)", addr);
             tokio::spawn(async move {
-                let mut client_handler = ClientHandler::new(stream);
-                if let Err(e) = client_handler.run(tx_request_, rx_shutdown).await {
-                    log::error!("client handler error ({})", e);
-                }
-                log::warn!("{} disconnected", addr);
+                let mut client_handler = ClientHandler::new(stream).with_framing(framing);
+                let response = Response::new_err(
+                    ErrorCode::Busy,
+                    "server busy (too many connections), try again later",
+                );
+                let _ = client_handler.write_frame(&response.to_string()).await;
+                let _ = client_handler.stream.shutdown().await;
             });
+            return;
+        };
+        log::warn!("new client: {}", addr);
+        let password = self.password.clone();
+        let tx_request = tx_request.clone();
+        let rx_notify = tx_notify.subscribe();
+        let rx_shutdown = tx_shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut client_handler = ClientHandler::new(stream)
+                .with_request_timeout(request_timeout)
+                .with_framing(framing)
+                .with_password(password)
+                .with_started_at(started_at);
+            if let Err(e) = client_handler.run(tx_request, rx_notify, rx_shutdown).await {
+                log::error!("client handler error ({})", e);
+            }
+            log::warn!("{} disconnected", addr);
+            drop(permit);
+        });
+    }
+}
+
+mod server_utils {
+    use super::*;
+
+    // whether the message is an `auth` request (without parsing its arguments)
+    pub fn is_auth(s: &str) -> bool {
+        serde_json::from_str::<JsonObject>(s)
+            .is_ok_and(|object| object.get("kind").is_some_and(|kind| kind == "auth"))
+    }
+
+    // a socket file left over from a previous run would make binding fail, but anything else
+    // at that path (a regular file, a socket some other process listens on) is left alone
+    #[cfg(unix)]
+    pub fn remove_stale_socket(socket_path: &std::path::Path) -> Result<()> {
+        use std::{io::ErrorKind, os::unix::fs::FileTypeExt, os::unix::net::UnixStream};
+
+        let metadata = match std::fs::symlink_metadata(socket_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if !metadata.file_type().is_socket() {
+            bail!(
+                "`{}` already exists and isn't a socket",
+                socket_path.to_string_lossy()
+            );
+        }
+        match UnixStream::connect(socket_path) {
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(socket_path)?;
+                Ok(())
+            }
+            Ok(_) => bail!(
+                "`{}` is in use by another process",
+                socket_path.to_string_lossy()
+            ),
+            Err(e) => bail!(
+                "couldn't check whether `{}` is in use ({})",
+                socket_path.to_string_lossy(),
+                e
+            ),
+        }
+    }
+
+    // every request gets its own oneshot channel, so if the player doesn't respond in time
+    // the receiver is simply dropped (and the late response with it)
+    pub async fn send_request(
+        tx_request: &tokio_chan::UnboundedSender<Request>,
+        kind: RequestKind,
+        request_timeout: Duration,
+    ) -> Result<Response> {
+        let (tx_response, rx_response) = oneshot::channel();
+        let _ = tx_request.send(Request { kind, tx_response });
+
+        match time::timeout(request_timeout, rx_response).await {
+            Ok(res) => Ok(res?),
+            Err(_) => {
+                log::error!("the player didn't respond within {:?}", request_timeout);
+                Ok(Response::new_err(
+                    ErrorCode::Timeout,
+                    "the player didn't respond in time",
+                ))
+            }
         }
     }
 }
@@ -134,6 +540,7 @@ impl Server {
 pub async fn run(
     config: ServerConfig,
     tx_request: tokio_chan::UnboundedSender<Request>,
+    tx_notify: broadcast::Sender<()>,
     mut rx_shutdown: broadcast::Receiver<()>,
 ) -> Result<()> {
     // the "shutdown" channel keeps one sender and many receivers
@@ -146,22 +553,411 @@ pub async fn run(
     let server = Server::new(config);
 
     tokio::select! {
-        res = server.run(tx_request, tx_shutdown) => res,
+        res = server.run(tx_request, tx_notify, tx_shutdown) => res,
         _ = rx_shutdown.recv() => Ok(()),
     }
 }
 
+// takes the same time no matter where the first difference is
+// (only the length can be learned from the timing)
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub fn spawn(
     config: ServerConfig,
     tx_request: tokio_chan::UnboundedSender<Request>,
+    tx_notify: broadcast::Sender<()>,
     rx_shutdown: broadcast::Receiver<()>,
     tx_shutdown: broadcast::Sender<()>,
 ) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let res = run(config, tx_request, rx_shutdown).await;
+        let res = run(config, tx_request, tx_notify, rx_shutdown).await;
         if let Err(e) = res {
             log::error!("fatal error ({})", e);
         }
         let _ = tx_shutdown.send(());
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(unix)]
+    use tokio::net::UnixStream;
+
+    #[cfg(unix)]
+    async fn send(stream: &mut UnixStream, s: &str) {
+        stream.write_u32(s.len() as u32).await.unwrap();
+        stream.write_all(s.as_bytes()).await.unwrap();
+    }
+
+    #[cfg(unix)]
+    async fn recv(stream: &mut UnixStream) -> serde_json::Value {
+        let len = stream.read_u32().await.unwrap();
+        let mut buf = vec![0; len as usize];
+        stream.read_exact(&mut buf).await.unwrap();
+        serde_json::from_slice(&buf).unwrap()
+    }
+
+    // retries until the server starts listening
+    #[cfg(unix)]
+    async fn connect(socket_path: &std::path::Path) -> UnixStream {
+        loop {
+            match UnixStream::connect(socket_path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn welcome_over_unix_socket() {
+        let socket_path =
+            std::env::temp_dir().join(format!("musing_test_{}.sock", std::process::id()));
+        let config = ServerConfig {
+            socket_path: Some(socket_path.clone()),
+            ..Default::default()
+        };
+        let (tx_request, _rx_request) = tokio_chan::unbounded_channel();
+        let (tx_notify, _) = broadcast::channel(1);
+        let (tx_shutdown, _) = broadcast::channel(1);
+        let server_task = tokio::spawn(async move {
+            let server = Server::new(config);
+            let _ = server.run(tx_request, tx_notify, tx_shutdown).await;
+        });
+
+        let welcome = recv(&mut connect(&socket_path).await).await;
+        server_task.abort();
+        let _ = std::fs::remove_file(&socket_path);
+
+        assert_eq!(welcome["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_socket_removal() {
+        let dir = std::env::temp_dir();
+        let stale = dir.join(format!("musing_test_stale_{}.sock", std::process::id()));
+        let live = dir.join(format!("musing_test_live_{}.sock", std::process::id()));
+        let regular = dir.join(format!("musing_test_regular_{}.sock", std::process::id()));
+        drop(std::os::unix::net::UnixListener::bind(&stale).unwrap());
+        let _listener = std::os::unix::net::UnixListener::bind(&live).unwrap();
+        std::fs::write(&regular, "not a socket").unwrap();
+
+        let stale_res = server_utils::remove_stale_socket(&stale);
+        let live_res = server_utils::remove_stale_socket(&live);
+        let regular_res = server_utils::remove_stale_socket(&regular);
+        let (stale_exists, live_exists, regular_exists) =
+            (stale.exists(), live.exists(), regular.exists());
+        let _ = std::fs::remove_file(&stale);
+        let _ = std::fs::remove_file(&live);
+        let _ = std::fs::remove_file(&regular);
+
+        assert!(stale_res.is_ok() && !stale_exists);
+        assert!(live_res.is_err() && live_exists);
+        assert!(regular_res.is_err() && regular_exists);
+        assert!(server_utils::remove_stale_socket(&stale).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reject_over_max_connections() {
+        let socket_path =
+            std::env::temp_dir().join(format!("musing_test_busy_{}.sock", std::process::id()));
+        let config = ServerConfig {
+            socket_path: Some(socket_path.clone()),
+            max_connections: 1,
+            ..Default::default()
+        };
+        let (tx_request, _rx_request) = tokio_chan::unbounded_channel();
+        let (tx_notify, _) = broadcast::channel(1);
+        let (tx_shutdown, _) = broadcast::channel(1);
+        let server = Server::new(config);
+        let connections = Arc::clone(&server.connections);
+        let server_task = tokio::spawn(async move {
+            let _ = server.run(tx_request, tx_notify, tx_shutdown).await;
+        });
+
+        let mut first = connect(&socket_path).await;
+        let _ = first.read_u32().await.unwrap();
+        let rejected = recv(&mut connect(&socket_path).await).await;
+        // the permit is released once the first client disconnects
+        drop(first);
+        while connections.available_permits() == 0 {
+            tokio::task::yield_now().await;
+        }
+        let accepted = recv(&mut connect(&socket_path).await).await;
+        server_task.abort();
+        let _ = std::fs::remove_file(&socket_path);
+
+        assert_eq!(rejected["status"], "err");
+        assert_eq!(rejected["code"], "busy");
+        assert_eq!(accepted["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn idle_and_noidle() {
+        let (mut client, server_side) = UnixStream::pair().unwrap();
+        let (tx_request, mut rx_request) = tokio_chan::unbounded_channel::<Request>();
+        let (tx_notify, rx_notify) = broadcast::channel(1);
+        let (_tx_shutdown, rx_shutdown) = broadcast::channel(1);
+        tokio::spawn(async move {
+            let mut client_handler = ClientHandler::new(server_side);
+            let _ = client_handler.run(tx_request, rx_notify, rx_shutdown).await;
+        });
+        // a fake player whose volume changes with every request
+        tokio::spawn(async move {
+            let mut volume = 0;
+            while let Some(Request { tx_response, .. }) = rx_request.recv().await {
+                volume += 1;
+                let _ = tx_response.send(Response::new_ok().with_item("volume", &volume));
+            }
+        });
+
+        let _ = recv(&mut client).await;
+        send(&mut client, r#"{"kind": "state"}"#).await;
+        assert_eq!(recv(&mut client).await["volume"], 1);
+
+        send(&mut client, r#"{"kind": "idle"}"#).await;
+        tx_notify.send(()).unwrap();
+        let diff = recv(&mut client).await;
+        assert_eq!(diff["volume"], 2);
+        assert!(diff.get("status").is_none());
+
+        send(&mut client, r#"{"kind": "idle"}"#).await;
+        send(&mut client, r#"{"kind": "noidle"}"#).await;
+        assert_eq!(recv(&mut client).await["volume"], 3);
+
+        send(&mut client, r#"{"kind": "idle"}"#).await;
+        send(&mut client, r#"{"kind": "state"}"#).await;
+        assert_eq!(recv(&mut client).await["status"], "err");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn timeout_when_player_hangs() {
+        let (mut client, server_side) = UnixStream::pair().unwrap();
+        let (tx_request, mut rx_request) = tokio_chan::unbounded_channel::<Request>();
+        let (_tx_notify, rx_notify) = broadcast::channel(1);
+        let (_tx_shutdown, rx_shutdown) = broadcast::channel(1);
+        tokio::spawn(async move {
+            let mut client_handler =
+                ClientHandler::new(server_side).with_request_timeout(Duration::from_millis(50));
+            let _ = client_handler.run(tx_request, rx_notify, rx_shutdown).await;
+        });
+        // a fake player that hangs on the first request and handles the rest
+        tokio::spawn(async move {
+            let mut stalled = Vec::new();
+            while let Some(Request { tx_response, .. }) = rx_request.recv().await {
+                if stalled.is_empty() {
+                    stalled.push(tx_response);
+                } else {
+                    let _ = tx_response.send(Response::new_ok().with_item("volume", &50));
+                }
+            }
+        });
+
+        let _ = recv(&mut client).await;
+        send(&mut client, r#"{"kind": "state"}"#).await;
+        let response = recv(&mut client).await;
+        assert_eq!(response["status"], "err");
+        assert_eq!(response["code"], "timeout");
+
+        send(&mut client, r#"{"kind": "state"}"#).await;
+        assert_eq!(recv(&mut client).await["volume"], 50);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn ping_while_player_busy() {
+        let (tx_request, mut rx_request) = tokio_chan::unbounded_channel::<Request>();
+        let (tx_notify, _) = broadcast::channel::<()>(1);
+        let (tx_shutdown, _) = broadcast::channel::<()>(1);
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let (client, server_side) = UnixStream::pair().unwrap();
+            let (tx_request, rx_notify, rx_shutdown) = (
+                tx_request.clone(),
+                tx_notify.subscribe(),
+                tx_shutdown.subscribe(),
+            );
+            tokio::spawn(async move {
+                let mut client_handler = ClientHandler::new(server_side);
+                let _ = client_handler.run(tx_request, rx_notify, rx_shutdown).await;
+            });
+            clients.push(client);
+        }
+        // a fake player stuck on a long request
+        let (tx_received, rx_received) = oneshot::channel();
+        tokio::spawn(async move {
+            let request = rx_request.recv().await;
+            let _ = tx_received.send(());
+            time::sleep(Duration::from_secs(60)).await;
+            drop(request);
+        });
+
+        let (busy, idle) = clients.split_at_mut(1);
+        let (busy, idle) = (&mut busy[0], &mut idle[0]);
+        let _ = recv(busy).await;
+        let _ = recv(idle).await;
+        send(busy, r#"{"kind": "update"}"#).await;
+        rx_received.await.unwrap();
+        send(idle, r#"{"kind": "ping"}"#).await;
+        let response = time::timeout(Duration::from_secs(5), recv(idle))
+            .await
+            .unwrap();
+        assert_eq!(response["status"], "ok");
+        assert_eq!(response["version"], env!("CARGO_PKG_VERSION"));
+        assert!(response["uptime"].is_u64());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn line_framing() {
+        let (client, server_side) = UnixStream::pair().unwrap();
+        let (tx_request, mut rx_request) = tokio_chan::unbounded_channel::<Request>();
+        let (_tx_notify, rx_notify) = broadcast::channel(1);
+        let (_tx_shutdown, rx_shutdown) = broadcast::channel(1);
+        tokio::spawn(async move {
+            let mut client_handler = ClientHandler::new(server_side).with_framing(Framing::Line);
+            let _ = client_handler.run(tx_request, rx_notify, rx_shutdown).await;
+        });
+        tokio::spawn(async move {
+            while let Some(Request { tx_response, .. }) = rx_request.recv().await {
+                let _ = tx_response.send(Response::new_ok().with_item("volume", &50));
+            }
+        });
+
+        let mut client = BufReader::new(client);
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        let welcome: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(welcome["version"], env!("CARGO_PKG_VERSION"));
+
+        client
+            .write_all(b"\n{\"kind\": \"state\"}\r\n")
+            .await
+            .unwrap();
+        line.clear();
+        client.read_line(&mut line).await.unwrap();
+        assert!(line.ends_with('\n'));
+        let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response["volume"], 50);
+
+        // a line that never ends closes the connection
+        let too_long = vec![b'a'; constants::MAX_FRAME_LEN + 1];
+        client.write_all(&too_long).await.unwrap();
+        line.clear();
+        client.read_line(&mut line).await.unwrap();
+        let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response["code"], "syntax");
+        line.clear();
+        assert_eq!(client.read_line(&mut line).await.unwrap(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn auth_with_password() {
+        let (mut client, server_side) = UnixStream::pair().unwrap();
+        let (tx_request, mut rx_request) = tokio_chan::unbounded_channel::<Request>();
+        let (_tx_notify, rx_notify) = broadcast::channel(1);
+        let (_tx_shutdown, rx_shutdown) = broadcast::channel(1);
+        tokio::spawn(async move {
+            let mut client_handler =
+                ClientHandler::new(server_side).with_password(Some("hunter2".into()));
+            let _ = client_handler.run(tx_request, rx_notify, rx_shutdown).await;
+        });
+        tokio::spawn(async move {
+            while let Some(Request { tx_response, .. }) = rx_request.recv().await {
+                let _ = tx_response.send(Response::new_ok().with_item("volume", &50));
+            }
+        });
+
+        let _ = recv(&mut client).await;
+        send(&mut client, r#"{"kind": "state"}"#).await;
+        assert_eq!(recv(&mut client).await["code"], "unauthorized");
+        // not even parsed
+        send(&mut client, r#"{"kind": "nope"}"#).await;
+        assert_eq!(recv(&mut client).await["code"], "unauthorized");
+        send(&mut client, r#"{"kind": "auth", "password": "hunter3"}"#).await;
+        assert_eq!(recv(&mut client).await["code"], "unauthorized");
+        send(&mut client, r#"{"kind": "state"}"#).await;
+        assert_eq!(recv(&mut client).await["code"], "unauthorized");
+
+        send(&mut client, r#"{"kind": "auth", "password": "hunter2"}"#).await;
+        assert_eq!(recv(&mut client).await["status"], "ok");
+        send(&mut client, r#"{"kind": "state"}"#).await;
+        assert_eq!(recv(&mut client).await["volume"], 50);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_list_as_array() {
+        let (mut client, server_side) = UnixStream::pair().unwrap();
+        let (tx_request, mut rx_request) = tokio_chan::unbounded_channel::<Request>();
+        let (_tx_notify, rx_notify) = broadcast::channel(1);
+        let (_tx_shutdown, rx_shutdown) = broadcast::channel(1);
+        tokio::spawn(async move {
+            let mut client_handler = ClientHandler::new(server_side);
+            let _ = client_handler.run(tx_request, rx_notify, rx_shutdown).await;
+        });
+        tokio::spawn(async move {
+            while let Some(Request { tx_response, .. }) = rx_request.recv().await {
+                let responses = [
+                    json!({"status": "ok"}),
+                    json!({"status": "ok", "volume": 40}),
+                ];
+                let _ = tx_response.send(Response::new_ok().with_item("responses", &responses));
+            }
+        });
+
+        let _ = recv(&mut client).await;
+        send(
+            &mut client,
+            r#"{"kind": "commandlist", "commands": [{"kind": "pause"}, {"kind": "volume", "delta": 0}]}"#,
+        )
+        .await;
+        assert_eq!(
+            recv(&mut client).await,
+            json!([{"status": "ok"}, {"status": "ok", "volume": 40}])
+        );
+        // an invalid list is rejected as a whole
+        send(
+            &mut client,
+            r#"{"kind": "commandlist", "commands": [{"kind": "pause"}, {"kind": "nope"}]}"#,
+        )
+        .await;
+        assert_eq!(recv(&mut client).await["code"], "syntax");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reject_long_frames() {
+        for (password, max_len) in [
+            (None, constants::MAX_FRAME_LEN),
+            (Some("hunter2"), constants::MAX_AUTH_FRAME_LEN),
+        ] {
+            let (mut client, server_side) = UnixStream::pair().unwrap();
+            let (tx_request, _rx_request) = tokio_chan::unbounded_channel::<Request>();
+            let (_tx_notify, rx_notify) = broadcast::channel(1);
+            let (_tx_shutdown, rx_shutdown) = broadcast::channel(1);
+            tokio::spawn(async move {
+                let mut client_handler =
+                    ClientHandler::new(server_side).with_password(password.map(String::from));
+                let _ = client_handler.run(tx_request, rx_notify, rx_shutdown).await;
+            });
+
+            let _ = recv(&mut client).await;
+            // only the length is sent, nothing gets allocated for the rest
+            client.write_u32(max_len as u32 + 1).await.unwrap();
+            assert_eq!(recv(&mut client).await["code"], "syntax");
+            assert!(client.read_u32().await.is_err());
+        }
+    }
+}
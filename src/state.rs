@@ -1,12 +1,24 @@
 use anyhow::Result;
 use bincode::{self, Decode, Encode};
-use std::{fs::File, path::Path};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
 
 use crate::model::{
+    bookmark::Bookmarks,
     decoder::{Speed, Volume},
+    play_count::PlayCounts,
     queue::Queue,
 };
 
+// has to be bumped whenever the layout of `State` changes
+// (otherwise old state files would fail to decode or, worse, decode into garbage)
+const STATE_VERSION: u32 = 4;
+
 #[derive(Debug, Decode, Encode)]
 pub struct AudioState {
     pub volume: Volume,
@@ -17,6 +29,11 @@ pub struct AudioState {
 #[derive(Debug, Decode, Encode)]
 pub struct PlayerState {
     pub queue: Queue,
+    pub bookmarks: Bookmarks,
+    pub play_counts: PlayCounts,
+    // queue id of the song that was playing and how many seconds of it elapsed
+    pub current: Option<u32>,
+    pub elapsed: u64,
 }
 
 #[derive(Debug, Decode, Encode)]
@@ -25,19 +42,104 @@ pub struct State {
     pub player_state: PlayerState,
 }
 
+// the state file was saved by a version of musing with a different `State`
+#[derive(Debug)]
+pub struct VersionMismatch {
+    pub found: u32,
+}
+
+impl Display for VersionMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "state file version {} doesn't match the current one ({})",
+            self.found, STATE_VERSION
+        )
+    }
+}
+
+impl Error for VersionMismatch {}
+
 impl State {
     pub fn try_from_file(path: impl AsRef<Path>) -> Result<Self> {
-        let mut content = File::open(path.as_ref())?;
-        Ok(bincode::decode_from_std_read(
-            &mut content,
-            bincode::config::standard(),
-        )?)
+        let mut stream = BufReader::new(File::open(path.as_ref())?);
+        Self::decode(&mut stream)
     }
 
     pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
-        let mut file = File::create(path.as_ref())?;
-        bincode::encode_into_std_write(self, &mut file, bincode::config::standard())?;
+        let mut stream = BufWriter::new(File::create(path.as_ref())?);
+        self.encode(&mut stream)?;
+        stream.flush()?;
 
         Ok(())
     }
+
+    // the state is preceded by the version of its layout
+    fn decode(stream: &mut impl Read) -> Result<Self> {
+        let config = bincode::config::standard();
+        let version: u32 = bincode::decode_from_std_read(stream, config)?;
+        if version != STATE_VERSION {
+            return Err(VersionMismatch { found: version }.into());
+        }
+
+        Ok(bincode::decode_from_std_read(stream, config)?)
+    }
+
+    fn encode(&self, stream: &mut impl Write) -> Result<()> {
+        let config = bincode::config::standard();
+        bincode::encode_into_std_write(STATE_VERSION, stream, config)?;
+        bincode::encode_into_std_write(self, stream, config)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state() -> State {
+        let mut queue = Queue::default();
+        queue.add("a", None);
+        State {
+            audio_state: AudioState {
+                volume: 42.into(),
+                speed: 150.into(),
+                gapless: true,
+            },
+            player_state: PlayerState {
+                queue,
+                bookmarks: Bookmarks::default(),
+                play_counts: PlayCounts::default(),
+                current: Some(1),
+                elapsed: 123,
+            },
+        }
+    }
+
+    #[test]
+    fn matching_version() {
+        let mut bytes = Vec::new();
+        state().encode(&mut bytes).unwrap();
+
+        let decoded = State::decode(&mut bytes.as_slice()).unwrap();
+        assert_eq!(u8::from(decoded.audio_state.volume), 42);
+        assert_eq!(decoded.audio_state.speed, 150.into());
+        assert!(decoded.audio_state.gapless);
+        assert_eq!(decoded.player_state.queue.inner().len(), 1);
+        assert_eq!(decoded.player_state.current, Some(1));
+        assert_eq!(decoded.player_state.elapsed, 123);
+    }
+
+    #[test]
+    fn mismatched_version() {
+        let config = bincode::config::standard();
+        let mut bytes = Vec::new();
+        bincode::encode_into_std_write(STATE_VERSION + 1, &mut bytes, config).unwrap();
+        bincode::encode_into_std_write(state(), &mut bytes, config).unwrap();
+
+        let err = State::decode(&mut bytes.as_slice()).unwrap_err();
+        let mismatch = err.downcast_ref::<VersionMismatch>().unwrap();
+        assert_eq!(mismatch.found, STATE_VERSION + 1);
+    }
 }
@@ -0,0 +1,85 @@
+use anyhow::Result;
+use notify_debouncer_mini::{
+    DebounceEventResult, Debouncer, new_debouncer,
+    notify::{RecommendedWatcher, RecursiveMode},
+};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc::{self as tokio_chan};
+
+use crate::constants;
+
+// watches the music (and playlist) directories and reports
+// changes inside of them, debounced so that copying a whole album
+// results in a single notification
+pub struct Watcher {
+    debouncer: Debouncer<RecommendedWatcher>,
+    dirs: Vec<PathBuf>,
+    rx_change: tokio_chan::UnboundedReceiver<Vec<PathBuf>>,
+}
+
+impl Watcher {
+    pub fn try_new(music_dirs: &[PathBuf], playlist_dir: &Path) -> Result<Self> {
+        let (tx_change, rx_change) = tokio_chan::unbounded_channel();
+        let debouncer = new_debouncer(
+            Duration::from_secs(constants::WATCHER_DEBOUNCE_SECS),
+            move |res: DebounceEventResult| match res {
+                Ok(events) => {
+                    let _ = tx_change.send(events.into_iter().map(|e| e.path).collect());
+                }
+                Err(e) => log::error!("watcher error ({})", e),
+            },
+        )?;
+        let mut dirs = music_dirs.to_vec();
+        if !music_dirs.iter().any(|dir| playlist_dir.starts_with(dir)) {
+            dirs.push(dunce::canonicalize(playlist_dir).unwrap_or(playlist_dir.into()));
+        }
+
+        let mut watcher = Self {
+            debouncer,
+            dirs,
+            rx_change,
+        };
+        for dir in watcher.dirs.clone() {
+            watcher.watch(&dir);
+        }
+
+        Ok(watcher)
+    }
+
+    // resolves after something changes in one of the watched directories
+    pub async fn changed(&mut self) {
+        while let Some(paths) = self.rx_change.recv().await {
+            let mut any_changed = false;
+            for dir in self.dirs.clone() {
+                // the directory itself was removed or (re)created
+                if paths.contains(&dir) && dir.exists() {
+                    self.watch(&dir);
+                }
+                any_changed |= paths.iter().any(|path| path.starts_with(&dir));
+            }
+            if any_changed {
+                return;
+            }
+        }
+        // the debouncer is owned by `self`, so this is unreachable in practice
+        std::future::pending().await
+    }
+
+    // the parent directory is also watched (non-recursively) to find out
+    // when the directory gets deleted and recreated, since a deleted
+    // directory's watch stops working
+    fn watch(&mut self, dir: &Path) {
+        let watcher = self.debouncer.watcher();
+        let _ = watcher.unwatch(dir);
+        if let Err(e) = watcher.watch(dir, RecursiveMode::Recursive) {
+            log::error!("can't watch `{}` ({})", dir.to_string_lossy(), e);
+        }
+        if let Some(parent) = dir.parent() {
+            let _ = watcher.unwatch(parent);
+            let _ = watcher.watch(parent, RecursiveMode::NonRecursive);
+        }
+    }
+}